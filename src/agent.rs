@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use crate::crypto::VaultKey;
+use crate::error::{NoteError, Result};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum AgentRequest {
+    Get { name: String },
+    Put { name: String, key: String, ttl_secs: u64 },
+    Forget { name: Option<String> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+struct AgentResponse {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// The socket lives in `$XDG_RUNTIME_DIR`, or else in a directory of its own under the
+/// temp dir, which `private_dir` creates readable by the current user only
+pub fn default_socket_path() -> PathBuf {
+    match dirs::runtime_dir() {
+        Some(dir) => dir.join("rust-notes-agent.sock"),
+        None => {
+            let user = std::env::var("USER").unwrap_or_else(|_| "default".to_string());
+            std::env::temp_dir().join(format!("rust-notes-agent-{}", user)).join("agent.sock")
+        }
+    }
+}
+
+fn current_uid() -> u32 {
+    unsafe { libc::getuid() }
+}
+
+/// Creates the directory of `socket_path` if needed, and checks that no other user can
+/// reach into it, so nobody else can put a socket where the client looks for the agent
+fn private_dir(socket_path: &Path) -> Result<()> {
+    let dir = socket_path.parent().unwrap_or(Path::new("."));
+    if !dir.exists() {
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    }
+
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() || metadata.uid() != current_uid() || metadata.mode() & 0o077 != 0 {
+        return Err(NoteError::InvalidInput(format!(
+            "Key agent directory {:?} must be a directory owned by you and closed to other users (mode 700)",
+            dir
+        )));
+    }
+    Ok(())
+}
+
+fn not_ours(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, format!("key agent {} belongs to another user", what))
+}
+
+#[cfg(target_os = "linux")]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    match result {
+        0 => Ok(cred.uid),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Fails unless the process at the other end of `stream` runs as the current user
+#[cfg(target_os = "linux")]
+fn check_peer(stream: &UnixStream) -> io::Result<()> {
+    match peer_uid(stream)? == current_uid() {
+        true => Ok(()),
+        false => Err(not_ours("peer")),
+    }
+}
+
+/// Other systems rely on the socket and its directory being private
+#[cfg(not(target_os = "linux"))]
+fn check_peer(_stream: &UnixStream) -> io::Result<()> {
+    Ok(())
+}
+
+/// Client for the session agent holding unlocked keys in memory, like `ssh-agent`.
+///
+/// The agent listens on a user-private Unix socket, forgets each key once its
+/// timeout elapses and exits when it holds no keys any more.
+pub struct AgentClient {
+    socket_path: PathBuf,
+}
+
+impl AgentClient {
+    pub fn new(socket_path: impl AsRef<Path>) -> Self {
+        Self {
+            socket_path: socket_path.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.connect().is_ok()
+    }
+
+    /// Connects only to an agent of the current user, since keys are sent over the socket
+    fn connect(&self) -> io::Result<UnixStream> {
+        if fs::symlink_metadata(&self.socket_path)?.uid() != current_uid() {
+            return Err(not_ours("socket"));
+        }
+        let stream = UnixStream::connect(&self.socket_path)?;
+        check_peer(&stream)?;
+        Ok(stream)
+    }
+
+    /// Cached key for `name`, or `None` when the agent is not running or has forgotten it
+    pub fn get(&self, name: &str) -> Option<VaultKey> {
+        let response = self.send(&AgentRequest::Get { name: name.to_string() }).ok()?;
+        let key = BASE64.decode(response.key?).ok()?;
+        VaultKey::from_bytes(&key).ok()
+    }
+
+    pub fn put(&self, name: &str, key: &VaultKey, ttl: Duration) -> Result<()> {
+        self.send(&AgentRequest::Put {
+            name: name.to_string(),
+            key: BASE64.encode(key.as_bytes()),
+            ttl_secs: ttl.as_secs(),
+        })?;
+        Ok(())
+    }
+
+    /// Forgets one key, or every key when `name` is `None`
+    pub fn forget(&self, name: Option<&str>) -> Result<()> {
+        if !self.is_running() {
+            return Ok(());
+        }
+        self.send(&AgentRequest::Forget { name: name.map(str::to_string) })?;
+        Ok(())
+    }
+
+    /// Starts a detached agent process unless one is already listening
+    pub fn ensure_running(&self) -> Result<()> {
+        if self.is_running() {
+            return Ok(());
+        }
+        private_dir(&self.socket_path)?;
+
+        Command::new(std::env::current_exe()?)
+            .arg("agent")
+            .arg("--socket")
+            .arg(&self.socket_path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        for _ in 0..50 {
+            if self.is_running() {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        Err(NoteError::IoError(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("Key agent did not start on {:?}", self.socket_path),
+        )))
+    }
+
+    fn send(&self, request: &AgentRequest) -> Result<AgentResponse> {
+        let mut stream = self.connect()?;
+        stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+        writeln!(stream, "{}", serde_json::to_string(request)?)?;
+
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line)?;
+        let response: AgentResponse = serde_json::from_str(&line)?;
+        match response.error {
+            Some(error) => Err(NoteError::InvalidInput(format!("Key agent: {}", error))),
+            None => Ok(response),
+        }
+    }
+}
+
+/// Keys held by the agent, each with the time it expires; `None` never expires
+type KeyCache = HashMap<String, (VaultKey, Option<Instant>)>;
+
+/// Runs the agent in the foreground until it holds no keys
+pub fn run_agent(socket_path: &Path) -> Result<()> {
+    private_dir(socket_path)?;
+    if socket_path.exists() {
+        if UnixStream::connect(socket_path).is_ok() {
+            return Err(NoteError::InvalidInput(format!("A key agent is already running on {:?}", socket_path)));
+        }
+        fs::remove_file(socket_path)?;
+    }
+
+    let listener = UnixListener::bind(socket_path)?;
+    fs::set_permissions(socket_path, fs::Permissions::from_mode(0o600))?;
+    listener.set_nonblocking(true)?;
+
+    let mut keys = KeyCache::new();
+    let started = Instant::now();
+    let result = loop {
+        let now = Instant::now();
+        keys.retain(|_, (_, expires)| expires.is_none_or(|expires| expires > now));
+        // Give the spawning client a moment to hand over its first key
+        if keys.is_empty() && started.elapsed() > Duration::from_secs(5) {
+            break Ok(());
+        }
+
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(e) = serve_client(stream, &mut keys) {
+                    eprintln!("Warning: key agent request failed: {}", e);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(100)),
+            Err(e) => break Err(e.into()),
+        }
+    };
+
+    let _ = fs::remove_file(socket_path);
+    result
+}
+
+fn serve_client(stream: UnixStream, keys: &mut KeyCache) -> Result<()> {
+    check_peer(&stream)?;
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+
+    let response = match serde_json::from_str::<AgentRequest>(&line) {
+        Ok(AgentRequest::Get { name }) => AgentResponse {
+            ok: true,
+            key: keys.get(&name).map(|(key, _)| BASE64.encode(key.as_bytes())),
+            error: None,
+        },
+        Ok(AgentRequest::Put { name, key, ttl_secs }) => {
+            match BASE64.decode(key).map_err(|e| e.to_string()).and_then(|k| VaultKey::from_bytes(&k).map_err(|e| e.to_string())) {
+                Ok(key) => {
+                    // A timeout too long to represent never expires
+                    keys.insert(name, (key, Instant::now().checked_add(Duration::from_secs(ttl_secs))));
+                    AgentResponse { ok: true, ..Default::default() }
+                }
+                Err(error) => AgentResponse { ok: false, key: None, error: Some(error) },
+            }
+        }
+        Ok(AgentRequest::Forget { name }) => {
+            match name {
+                Some(name) => {
+                    keys.remove(&name);
+                }
+                None => keys.clear(),
+            }
+            AgentResponse { ok: true, ..Default::default() }
+        }
+        Err(e) => AgentResponse { ok: false, key: None, error: Some(e.to_string()) },
+    };
+
+    let mut stream = reader.into_inner();
+    writeln!(stream, "{}", serde_json::to_string(&response)?)?;
+    Ok(())
+}
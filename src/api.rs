@@ -0,0 +1,610 @@
+use std::cmp::Reverse;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use crate::backup::AutoBackup;
+use crate::dirsync;
+use crate::error::{validate_note_content, validate_note_title, validate_tag, NoteError, Result};
+use crate::feed::{ChangeFeed, FeedEvent};
+use crate::git::{self, GitStore};
+use crate::http::{Request, Response};
+use crate::index::NoteSummary;
+use crate::note::{Note, NoteId};
+use crate::notebook::NotebookStore;
+use crate::render;
+use crate::storage::FileStorage;
+use crate::tags;
+
+pub const API_PREFIX: &str = "/api/v1";
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+/// Seconds `GET /events` waits for a change when the request does not say
+pub const DEFAULT_EVENT_WAIT: u64 = 25;
+const MAX_EVENT_WAIT: u64 = 60;
+/// Milliseconds an `EventSource` waits before reconnecting for the next changes
+const EVENT_RETRY_MS: u64 = 500;
+
+/// A note together with the notebook it is in
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiNote {
+    pub notebook: String,
+    #[serde(flatten)]
+    pub note: Note,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiSummary {
+    pub notebook: String,
+    #[serde(flatten)]
+    pub summary: NoteSummary,
+}
+
+/// One page of a listing; pass `offset + items.len()` as the next `offset` while it is below `total`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewNote {
+    pub title: String,
+    #[serde(default)]
+    pub content: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// The server's default notebook if not given
+    #[serde(default)]
+    pub notebook: Option<String>,
+}
+
+/// Fields to change on a note; the others are kept
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NoteUpdate {
+    pub title: Option<String>,
+    pub content: Option<String>,
+    pub tags: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TagChange {
+    pub add: Vec<String>,
+    pub remove: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TagCount {
+    pub tag: String,
+    pub count: usize,
+}
+
+/// Body of `POST /tags/rename` and `POST /tags/merge`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TagMove {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TagName {
+    pub tag: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Updated {
+    pub updated: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NotebookInfo {
+    pub name: String,
+    pub notes: usize,
+}
+
+/// Changes returned by `GET /events`; pass `cursor` back to get the ones after them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EventBatch {
+    pub events: Vec<FeedEvent>,
+    pub cursor: u64,
+}
+
+/// Part of a note a search looks at, like `notes search --field`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchField {
+    Title,
+    Content,
+    Tags,
+    All,
+}
+
+/// The options of `notes search`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SearchQuery {
+    pub text: String,
+    pub field: SearchField,
+    pub case_sensitive: bool,
+    /// Only notes with this tag or one below it
+    pub tag: Option<String>,
+}
+
+impl SearchQuery {
+    pub fn new(text: &str) -> Self {
+        SearchQuery { text: text.to_string(), field: SearchField::All, case_sensitive: false, tag: None }
+    }
+
+    pub fn matches(&self, note: &Note) -> bool {
+        if self.tag.as_ref().is_some_and(|tag| !note.has_tag_or_descendant(tag)) {
+            return false;
+        }
+        let contains = |text: &str| match self.case_sensitive {
+            true => text.contains(&self.text),
+            false => text.to_lowercase().contains(&self.text.to_lowercase()),
+        };
+        let in_title = || contains(&note.title);
+        // Content of locked notes is ciphertext
+        let in_content = || !note.is_locked() && contains(&note.content);
+        let in_tags = || note.tags.iter().any(|tag| contains(tag));
+        match self.field {
+            SearchField::Title => in_title(),
+            SearchField::Content => in_content(),
+            SearchField::Tags => in_tags(),
+            SearchField::All => in_title() || in_content() || in_tags(),
+        }
+    }
+}
+
+/// Entity tag of a note, changing with every change to it
+pub fn etag(note: &Note) -> Result<String> {
+    Ok(format!("\"{}\"", dirsync::content_hash(note)?))
+}
+
+/// Serves the notes of `notebooks` as a JSON API under [`API_PREFIX`].
+///
+/// Every request needs an `Authorization: Bearer <token>` header with one of the
+/// configured tokens. Notes are returned with an `ETag`; updates and deletes that
+/// send `If-Match` only apply while the note is unchanged, and updates must send it.
+pub struct ApiServer {
+    notebooks: NotebookStore,
+    default_notebook: String,
+    tokens: Vec<String>,
+    git: Option<Mutex<GitStore>>,
+    backup: Option<Mutex<AutoBackup>>,
+}
+
+impl ApiServer {
+    pub fn new(notebooks: NotebookStore, default_notebook: &str, tokens: Vec<String>) -> Result<Self> {
+        if tokens.is_empty() || tokens.iter().any(|token| token.trim().is_empty()) {
+            return Err(NoteError::InvalidInput(
+                "The API needs at least one non-empty token under [api] tokens in the config".to_string(),
+            ));
+        }
+        Ok(ApiServer { notebooks, default_notebook: default_notebook.to_string(), tokens, git: None, backup: None })
+    }
+
+    /// Commits every change made through the API to the notes repository
+    pub fn with_git(mut self, git: GitStore) -> Self {
+        self.git = Some(Mutex::new(git));
+        self
+    }
+
+    /// Takes the day's automatic snapshot before the first change made through the API
+    pub fn with_auto_backup(mut self, backup: AutoBackup) -> Self {
+        self.backup = Some(Mutex::new(backup));
+        self
+    }
+
+    /// Answers one API request; paths outside [`API_PREFIX`] get a 404
+    pub fn handle(&self, request: &Request) -> Response {
+        let path = match request.path.strip_prefix(API_PREFIX) {
+            Some(path) if path.is_empty() || path.starts_with('/') => path.trim_end_matches('/'),
+            _ => return Response::error(404, "not found"),
+        };
+        self.authorize(request, |request| {
+            self.before_change(request)
+                .and_then(|_| self.route(request, path))
+                .unwrap_or_else(|e| Response::from_error(&e))
+        })
+    }
+
+    /// Answers `request` with `handler` when it carries one of the API's tokens, with a 401 otherwise
+    pub fn authorize(&self, request: &Request, handler: impl FnOnce(&Request) -> Response) -> Response {
+        match self.authorized(request) {
+            true => handler(request),
+            false => Response::error(401, "missing or invalid bearer token").with_header("WWW-Authenticate", "Bearer"),
+        }
+    }
+
+    fn before_change(&self, request: &Request) -> Result<()> {
+        match &self.backup {
+            // One request at a time, so the day gets a single snapshot
+            Some(backup) if request.method != "GET" => {
+                backup.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).before_change()?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn route(&self, request: &Request, path: &str) -> Result<Response> {
+        let segments: Vec<&str> = path.split('/').skip(1).collect();
+        match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["notes"]) => self.list(request),
+            ("POST", ["notes"]) => self.create(request.json()?),
+            ("GET", ["notes", id]) => self.get(request, &NoteId::parse(id)?),
+            ("GET", ["notes", id, "html"]) => self.render(&NoteId::parse(id)?),
+            ("PATCH", ["notes", id]) => self.update(request, &NoteId::parse(id)?),
+            ("DELETE", ["notes", id]) => self.delete(request, &NoteId::parse(id)?),
+            ("POST", ["notes", id, "tags"]) => self.change_tags(request, &NoteId::parse(id)?),
+            ("GET", ["tags"]) => self.tags(request),
+            ("GET", ["notebooks"]) => {
+                let notebooks: Vec<NotebookInfo> =
+                    self.notebooks.list()?.into_iter().map(|(name, notes)| NotebookInfo { name, notes }).collect();
+                Ok(Response::json(200, &notebooks))
+            }
+            ("POST", ["tags", "rename"]) => {
+                let TagMove { from, to } = request.json()?;
+                let message = format!("Rename tag '{}' to '{}'", from, to);
+                self.rewrite_tags(request, message, |storage| tags::rename_tag(storage, &from, &to))
+            }
+            ("POST", ["tags", "merge"]) => {
+                let TagMove { from, to } = request.json()?;
+                let message = format!("Merge tag '{}' into '{}'", from, to);
+                self.rewrite_tags(request, message, |storage| tags::merge_tags(storage, &from, &to))
+            }
+            ("POST", ["tags", "delete"]) => {
+                let TagName { tag } = request.json()?;
+                self.rewrite_tags(request, format!("Delete tag '{}'", tag), |storage| tags::delete_tag(storage, &tag))
+            }
+            ("GET", ["search"]) => self.search(request),
+            ("GET", ["events"]) => self.events(request),
+            (_, ["notes"] | ["notes", _] | ["notes", _, "tags" | "html"] | ["tags"] | ["tags", _] | ["notebooks"] | ["search"] | ["events"]) => {
+                Ok(Response::error(405, "method not allowed"))
+            }
+            _ => Ok(Response::error(404, "not found")),
+        }
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        // Browsers cannot set headers on an EventSource, so the feed also takes the token in the URL
+        let in_query = || match request.path.ends_with("/events") {
+            true => request.query.get("access_token").map(String::as_str),
+            false => None,
+        };
+        let token = match request.header("authorization").and_then(|value| value.strip_prefix("Bearer ")).or_else(in_query) {
+            Some(token) => token.trim(),
+            None => return false,
+        };
+        // Every token is compared in full so timing does not reveal how much of one matched
+        self.tokens.iter().fold(false, |found, known| constant_time_eq(known.as_bytes(), token.as_bytes()) | found)
+    }
+
+    fn list(&self, request: &Request) -> Result<Response> {
+        let tag = request.query.get("tag");
+        let mut items = Vec::new();
+        for name in self.notebook_names(request)? {
+            for summary in self.notebooks.open(&name)?.list_summaries()? {
+                if tag.is_some_and(|tag| !summary.has_tag_or_descendant(tag)) {
+                    continue;
+                }
+                items.push(ApiSummary { notebook: name.clone(), summary });
+            }
+        }
+        items.sort_by_key(|item| Reverse(item.summary.created_at));
+        Ok(Response::json(200, &paginate(items, request)?))
+    }
+
+    fn search(&self, request: &Request) -> Result<Response> {
+        let text = request.query.get("q").ok_or_else(|| NoteError::InvalidInput("missing query parameter q".to_string()))?;
+        let mut query = SearchQuery::new(text);
+        if let Some(field) = request.query.get("field") {
+            query.field = serde_json::from_value(serde_json::Value::String(field.clone()))
+                .map_err(|_| NoteError::InvalidInput(format!("invalid field: {}", field)))?;
+        }
+        query.case_sensitive = flag(request, "case_sensitive")?;
+        query.tag = request.query.get("tag").cloned();
+
+        let mut items = Vec::new();
+        for name in self.notebook_names(request)? {
+            for note in self.notebooks.open(&name)?.list_notes()? {
+                if query.matches(&note) {
+                    items.push(ApiSummary { notebook: name.clone(), summary: NoteSummary::from(&note) });
+                }
+            }
+        }
+        items.sort_by_key(|item| Reverse(item.summary.created_at));
+        Ok(Response::json(200, &paginate(items, request)?))
+    }
+
+    fn get(&self, request: &Request, id: &NoteId) -> Result<Response> {
+        let (notebook, storage) = self.find(id)?;
+        let note = storage.load_note(id)?;
+        let etag = etag(&note)?;
+        if request.header("if-none-match").is_some_and(|header| etag_matches(header, &etag, true)) {
+            return Ok(Response::new(304).with_header("ETag", &etag));
+        }
+        Ok(Response::json(200, &ApiNote { notebook, note }).with_header("ETag", &etag))
+    }
+
+    /// The note's content rendered as an HTML fragment, with everything the note contains escaped
+    fn render(&self, id: &NoteId) -> Result<Response> {
+        let (_, storage) = self.find(id)?;
+        let note = storage.load_note(id)?;
+        if note.is_locked() {
+            return Ok(Response::error(423, "the note is locked; run `notes unlock` first"));
+        }
+        Ok(Response::new(200)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_header("ETag", &etag(&note)?)
+            .with_body(render::markdown_to_html(&note.content).into_bytes()))
+    }
+
+    fn create(&self, new: NewNote) -> Result<Response> {
+        validate_note_title(&new.title)?;
+        validate_note_content(&new.content)?;
+        for tag in &new.tags {
+            validate_tag(tag)?;
+        }
+        let notebook = new.notebook.unwrap_or_else(|| self.default_notebook.clone());
+        let storage = match notebook == self.default_notebook {
+            true => self.notebooks.open_or_create(&notebook)?,
+            false => self.notebooks.open(&notebook)?,
+        };
+
+        let mut note = Note::new(new.title, new.content);
+        for tag in new.tags {
+            note.add_tag(tag);
+        }
+        storage.save_note(&note)?;
+        self.record(&format!("Create {}", self.subject(&note)))?;
+        let etag = etag(&note)?;
+        let location = format!("{}/notes/{}", API_PREFIX, note.id);
+        Ok(Response::json(201, &ApiNote { notebook, note }).with_header("ETag", &etag).with_header("Location", &location))
+    }
+
+    fn update(&self, request: &Request, id: &NoteId) -> Result<Response> {
+        if request.header("if-match").is_none() {
+            return Ok(Response::error(428, "send the note's ETag in If-Match"));
+        }
+        let update: NoteUpdate = request.json()?;
+        if let Some(title) = &update.title {
+            validate_note_title(title)?;
+        }
+        if let Some(content) = &update.content {
+            validate_note_content(content)?;
+        }
+        for tag in update.tags.iter().flatten() {
+            validate_tag(tag)?;
+        }
+
+        self.modify(request, id, |note| {
+            if note.is_locked() && update.content.is_some() {
+                return Err(NoteError::InvalidInput(format!("note {} is locked; run `notes unlock` first", note.id)));
+            }
+            if let Some(title) = update.title {
+                note.title = title;
+            }
+            if let Some(content) = update.content {
+                note.content = content;
+            }
+            if let Some(tags) = update.tags {
+                note.tags = Vec::new();
+                for tag in tags {
+                    note.add_tag(tag);
+                }
+            }
+            Ok(format!("Update {}", self.subject(note)))
+        })
+    }
+
+    fn change_tags(&self, request: &Request, id: &NoteId) -> Result<Response> {
+        let change: TagChange = request.json()?;
+        for tag in change.add.iter().chain(&change.remove) {
+            validate_tag(tag)?;
+        }
+        self.modify(request, id, |note| {
+            for tag in change.add {
+                note.add_tag(tag);
+            }
+            for tag in &change.remove {
+                note.remove_tag(tag);
+            }
+            Ok(format!("Change tags of {}", self.subject(note)))
+        })
+    }
+
+    /// Applies `change` to a note under the notebook lock, if `If-Match` (when sent) still matches
+    fn modify(&self, request: &Request, id: &NoteId, change: impl FnOnce(&mut Note) -> Result<String>) -> Result<Response> {
+        let (notebook, storage) = self.find(id)?;
+        let lock = storage.lock()?;
+        let mut note = storage.load_note(id)?;
+        if let Some(response) = precondition_failed(request, &note)? {
+            return Ok(response);
+        }
+
+        let before = note.clone();
+        let message = change(&mut note)?;
+        if note != before {
+            note.updated_at = Utc::now();
+            storage.update_note_locked(&mut note, &lock)?;
+            drop(lock);
+            self.record(&message)?;
+        }
+        let etag = etag(&note)?;
+        Ok(Response::json(200, &ApiNote { notebook, note }).with_header("ETag", &etag))
+    }
+
+    fn delete(&self, request: &Request, id: &NoteId) -> Result<Response> {
+        let (_, storage) = self.find(id)?;
+        let lock = storage.lock()?;
+        let note = storage.load_note(id)?;
+        if let Some(response) = precondition_failed(request, &note)? {
+            return Ok(response);
+        }
+        storage.delete_note_locked(id, &lock)?;
+        drop(lock);
+        self.record(&format!("Delete {}", self.subject(&note)))?;
+        Ok(Response::new(204))
+    }
+
+    fn tags(&self, request: &Request) -> Result<Response> {
+        let mut summaries = Vec::new();
+        for name in self.notebook_names(request)? {
+            summaries.extend(self.notebooks.open(&name)?.list_summaries()?);
+        }
+        let counts: Vec<TagCount> = tags::count_tags(summaries.iter().map(|s| s.tags.as_slice()))
+            .into_iter()
+            .map(|(tag, count)| TagCount { tag, count })
+            .collect();
+        Ok(Response::json(200, &counts))
+    }
+
+    /// Changes after the cursor in `?cursor=` or `Last-Event-ID`, or after the newest one.
+    /// Waits up to `?wait=` seconds for the first change, and answers with server-sent
+    /// events when the client accepts `text/event-stream`.
+    fn events(&self, request: &Request) -> Result<Response> {
+        let feed = ChangeFeed::new(self.notebooks.root());
+        let cursor = match number(request, "cursor")? {
+            Some(cursor) => cursor,
+            None => match request.header("last-event-id") {
+                Some(id) => id.trim().parse().map_err(|_| NoteError::InvalidInput(format!("invalid Last-Event-ID: {}", id)))?,
+                None => feed.latest()?,
+            },
+        };
+        let wait = number(request, "wait")?.unwrap_or(DEFAULT_EVENT_WAIT).min(MAX_EVENT_WAIT);
+        let events = match feed.wait(cursor, Duration::from_secs(wait))? {
+            Some(events) => events,
+            None => {
+                return Ok(Response::error(410, "the cursor is no longer in the change feed; reload the notes and start from the newest cursor"))
+            }
+        };
+        let cursor = events.last().map_or(cursor, |event| event.seq);
+
+        if !request.header("accept").is_some_and(|accept| accept.contains("text/event-stream")) {
+            return Ok(Response::json(200, &EventBatch { events, cursor }));
+        }
+        let mut stream = format!("retry: {}\n\n", EVENT_RETRY_MS);
+        for event in &events {
+            stream.push_str(&format!("id: {}\nevent: {}\ndata: {}\n\n", event.seq, event.event.as_str(), serde_json::to_string(event)?));
+        }
+        if events.is_empty() {
+            // Hands the cursor to clients that connected without one
+            stream.push_str(&format!("id: {}\n\n", cursor));
+        }
+        Ok(Response::new(200)
+            .with_header("Content-Type", "text/event-stream")
+            .with_header("Cache-Control", "no-cache")
+            .with_body(stream.into_bytes()))
+    }
+
+    /// Renames, merges or deletes a tag in every notebook, or the one given by `?notebook=`
+    fn rewrite_tags(&self, request: &Request, message: String, rewrite: impl Fn(&FileStorage) -> Result<usize>) -> Result<Response> {
+        let mut updated = 0;
+        let mut missing = None;
+        for name in self.notebook_names(request)? {
+            match rewrite(&self.notebooks.open(&name)?) {
+                Ok(count) => updated += count,
+                // Fine for one notebook, as long as another one has the tag
+                Err(NoteError::NotFound(message)) => missing = Some(message),
+                Err(e) => return Err(e),
+            }
+        }
+        if let (0, Some(message)) = (updated, missing) {
+            return Err(NoteError::NotFound(message));
+        }
+        if updated > 0 {
+            self.record(&git::tag_subject(message, updated, self.notebooks.key().is_some()))?;
+        }
+        Ok(Response::json(200, &Updated { updated }))
+    }
+
+    /// Notebooks a request applies to: the one in `?notebook=`, or all of them
+    fn notebook_names(&self, request: &Request) -> Result<Vec<String>> {
+        match request.query.get("notebook") {
+            Some(name) => {
+                self.notebooks.open(name)?;
+                Ok(vec![name.clone()])
+            }
+            None => Ok(self.notebooks.list()?.into_iter().map(|(name, _)| name).collect()),
+        }
+    }
+
+    fn find(&self, id: &NoteId) -> Result<(String, FileStorage)> {
+        let notebook = self
+            .notebooks
+            .locate(id)?
+            .ok_or_else(|| NoteError::NotFound(format!("Note with id '{}' not found", id)))?;
+        let storage = self.notebooks.open(&notebook)?;
+        Ok((notebook, storage))
+    }
+
+    /// Names a note in commit messages, without its title when the store is encrypted
+    fn subject(&self, note: &Note) -> String {
+        git::note_subject(note, self.notebooks.key().is_some())
+    }
+
+    fn record(&self, message: &str) -> Result<()> {
+        if let Some(git) = &self.git {
+            git.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).commit_all(message)?;
+        }
+        Ok(())
+    }
+}
+
+/// A 412 response when the request's `If-Match` does not match the note
+fn precondition_failed(request: &Request, note: &Note) -> Result<Option<Response>> {
+    let etag = etag(note)?;
+    match request.header("if-match") {
+        Some(header) if !etag_matches(header, &etag, false) => {
+            Ok(Some(Response::error(412, "the note changed since it was read").with_header("ETag", &etag)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Whether an `If-Match`/`If-None-Match` header lists `etag`. With `weak`, as for
+/// `If-None-Match`, `W/"x"` matches `"x"`; `If-Match` compares strongly, so it does not.
+fn etag_matches(header: &str, etag: &str, weak: bool) -> bool {
+    header
+        .split(',')
+        .map(|candidate| candidate.trim())
+        .any(|candidate| candidate == "*" || candidate == etag || (weak && candidate.strip_prefix("W/") == Some(etag)))
+}
+
+fn number<T: FromStr>(request: &Request, name: &str) -> Result<Option<T>> {
+    match request.query.get(name) {
+        Some(value) => value.parse().map(Some).map_err(|_| NoteError::InvalidInput(format!("invalid {}: {}", name, value))),
+        None => Ok(None),
+    }
+}
+
+fn flag(request: &Request, name: &str) -> Result<bool> {
+    match request.query.get(name).map(String::as_str) {
+        None | Some("false") | Some("0") => Ok(false),
+        Some("true") | Some("1") | Some("") => Ok(true),
+        Some(value) => Err(NoteError::InvalidInput(format!("invalid {}: {}", name, value))),
+    }
+}
+
+/// The page of `items` selected by the `offset` and `limit` query parameters
+fn paginate<T>(items: Vec<T>, request: &Request) -> Result<Page<T>> {
+    let offset = number(request, "offset")?.unwrap_or(0);
+    let limit = number(request, "limit")?.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let total = items.len();
+    let items = items.into_iter().skip(offset).take(limit).collect();
+    Ok(Page { items, total, offset, limit })
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
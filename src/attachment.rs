@@ -0,0 +1,268 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use crate::crypto::{self, VaultKey};
+use crate::error::{NoteError, Result};
+use crate::note::{Attachment, Note};
+
+/// Content-addressed blob store for note attachments.
+///
+/// Each file is stored once under its SHA-256 hash (`ab/abcdef…`), so attaching
+/// the same file to several notes does not duplicate it on disk. With a vault
+/// key, blobs are sealed with it and still named after their plaintext hash.
+pub struct AttachmentStore {
+    attachments_dir: PathBuf,
+    key: Option<VaultKey>,
+}
+
+impl AttachmentStore {
+    pub fn new(attachments_dir: impl AsRef<Path>) -> io::Result<Self> {
+        let path = attachments_dir.as_ref();
+        if !path.exists() {
+            fs::create_dir_all(path)?;
+        }
+
+        Ok(AttachmentStore {
+            attachments_dir: path.to_path_buf(),
+            key: None,
+        })
+    }
+
+    /// Seals new blobs with `key` and opens sealed ones on read
+    pub fn with_key(mut self, key: VaultKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    pub fn key(&self) -> Option<&VaultKey> {
+        self.key.as_ref()
+    }
+
+    pub fn attachments_dir(&self) -> &Path {
+        &self.attachments_dir
+    }
+
+    /// Copies `source` into the store and describes it; `name` defaults to the file name
+    pub fn store_file(&self, source: &Path, name: Option<&str>) -> Result<Attachment> {
+        let name = match name {
+            Some(name) => name.to_string(),
+            None => source
+                .file_name()
+                .and_then(|s| s.to_str())
+                .map(str::to_string)
+                .ok_or_else(|| NoteError::InvalidInput(format!("Cannot determine a file name for {:?}", source)))?,
+        };
+
+        let file = fs::File::open(source)?;
+        let mime = mime_guess::from_path(&name).first_or_octet_stream().to_string();
+        self.store_reader(file, name, mime)
+    }
+
+    pub fn store_bytes(&self, data: &[u8], name: &str, mime: &str) -> Result<Attachment> {
+        self.store_reader(data, name.to_string(), mime.to_string())
+    }
+
+    /// Streams the data into a temporary file while hashing it, then moves it
+    /// into place unless a blob with the same hash already exists. A sealed
+    /// store reads the data into memory instead, so no plaintext hits the disk.
+    fn store_reader<R: Read>(&self, mut reader: R, name: String, mime: String) -> Result<Attachment> {
+        validate_attachment_name(&name)?;
+        if let Some(key) = &self.key {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data)?;
+            let hash = format!("{:x}", Sha256::digest(&data));
+            let blob_path = self.blob_path(&hash)?;
+            if !blob_path.exists() {
+                self.write_blob(&blob_path, &crypto::seal(key, &data, hash.as_bytes())?)?;
+            }
+            return Ok(Attachment {
+                name,
+                mime,
+                size: data.len() as u64,
+                hash,
+                added_at: Utc::now(),
+            });
+        }
+
+        let temp_path = self.attachments_dir.join(format!(".incoming-{}", uuid::Uuid::new_v4()));
+        let mut temp_file = fs::File::create(&temp_path)?;
+        let mut hasher = Sha256::new();
+        let mut size = 0u64;
+        let mut buffer = [0u8; 8192];
+
+        let copied = (|| -> io::Result<()> {
+            loop {
+                let read = reader.read(&mut buffer)?;
+                if read == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..read]);
+                temp_file.write_all(&buffer[..read])?;
+                size += read as u64;
+            }
+            temp_file.sync_all()
+        })();
+        if let Err(e) = copied {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e.into());
+        }
+
+        let hash = format!("{:x}", hasher.finalize());
+        let blob_path = self.blob_path(&hash)?;
+        if blob_path.exists() {
+            fs::remove_file(&temp_path)?;
+        } else {
+            if let Some(parent) = blob_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(&temp_path, &blob_path)?;
+        }
+
+        Ok(Attachment {
+            name,
+            mime,
+            size,
+            hash,
+            added_at: Utc::now(),
+        })
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.blob_path(hash).map(|path| path.is_file()).unwrap_or(false)
+    }
+
+    pub fn read(&self, attachment: &Attachment) -> Result<Vec<u8>> {
+        let path = self.blob_path(&attachment.hash)?;
+        if !path.is_file() {
+            return Err(NoteError::NotFound(format!(
+                "Attachment '{}' ({}) is missing from the attachment store",
+                attachment.name, attachment.hash
+            )));
+        }
+        let data = fs::read(path)?;
+        match &self.key {
+            _ if !crypto::is_sealed(&data) => Ok(data),
+            Some(key) => crypto::open_sealed(key, &data, attachment.hash.as_bytes()),
+            None => Err(NoteError::InvalidInput(format!(
+                "Attachment '{}' is encrypted; unlock the vault first",
+                attachment.name
+            ))),
+        }
+    }
+
+    /// Seals every blob still stored in plaintext, returning how many were sealed
+    pub fn encrypt_all(&self) -> Result<usize> {
+        let key = self
+            .key
+            .as_ref()
+            .ok_or_else(|| NoteError::InvalidInput("The attachment store has no vault key".to_string()))?;
+        let mut sealed = 0;
+        for hash in self.list_hashes()? {
+            let path = self.blob_path(&hash)?;
+            let data = fs::read(&path)?;
+            if !crypto::is_sealed(&data) {
+                self.write_blob(&path, &crypto::seal(key, &data, hash.as_bytes())?)?;
+                sealed += 1;
+            }
+        }
+        Ok(sealed)
+    }
+
+    /// Replaces a blob atomically, so an interrupted write never leaves half a file
+    fn write_blob(&self, blob_path: &Path, data: &[u8]) -> Result<()> {
+        if let Some(parent) = blob_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temp_path = self.attachments_dir.join(format!(".incoming-{}", uuid::Uuid::new_v4()));
+        let written = fs::File::create(&temp_path).and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        });
+        if let Err(e) = written.and_then(|_| fs::rename(&temp_path, blob_path)) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Writes an attachment back out to `destination`
+    pub fn write_to(&self, attachment: &Attachment, destination: &Path) -> Result<()> {
+        let data = self.read(attachment)?;
+        fs::write(destination, data)?;
+        Ok(())
+    }
+
+    /// Removes a blob; callers are responsible for checking no note still references it
+    pub fn remove(&self, hash: &str) -> Result<bool> {
+        let path = self.blob_path(hash)?;
+        if !path.exists() {
+            return Ok(false);
+        }
+        fs::remove_file(path)?;
+        Ok(true)
+    }
+
+    /// Moves a blob out of the store into `destination_dir`, returning its new path
+    pub fn move_out(&self, hash: &str, destination_dir: &Path) -> Result<PathBuf> {
+        let path = self.blob_path(hash)?;
+        fs::create_dir_all(destination_dir)?;
+        let destination = destination_dir.join(hash);
+        fs::rename(path, &destination)?;
+        Ok(destination)
+    }
+
+    /// Hashes of every blob in the store
+    pub fn list_hashes(&self) -> Result<Vec<String>> {
+        let mut hashes = Vec::new();
+        for shard in fs::read_dir(&self.attachments_dir)? {
+            let shard = shard?.path();
+            if !shard.is_dir() {
+                continue;
+            }
+            for blob in fs::read_dir(&shard)? {
+                let blob = blob?.path();
+                if let Some(hash) = blob.file_name().and_then(|s| s.to_str()) {
+                    if is_valid_hash(hash) {
+                        hashes.push(hash.to_string());
+                    }
+                }
+            }
+        }
+        hashes.sort();
+        Ok(hashes)
+    }
+
+    fn blob_path(&self, hash: &str) -> Result<PathBuf> {
+        if !is_valid_hash(hash) {
+            return Err(NoteError::InvalidInput(format!("Invalid attachment hash '{}'", hash)));
+        }
+        Ok(self.attachments_dir.join(&hash[..2]).join(hash))
+    }
+}
+
+fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase())
+}
+
+/// Checks the attachment names of a note read from disk, an import bundle or a sync peer
+pub fn validate_attachments(note: &Note) -> Result<()> {
+    note.attachments.iter().try_for_each(|attachment| validate_attachment_name(&attachment.name))
+}
+
+pub fn validate_attachment_name(name: &str) -> Result<()> {
+    if name.trim().is_empty() {
+        return Err(NoteError::ValidationError("Attachment name cannot be empty".to_string()));
+    }
+
+    if name.len() > 255 {
+        return Err(NoteError::ValidationError("Attachment name cannot exceed 255 characters".to_string()));
+    }
+
+    if name.contains('/') || name.contains('\\') || name == "." || name == ".." {
+        return Err(NoteError::ValidationError("Attachment name cannot contain path separators".to_string()));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,574 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, TimeZone, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use crate::attachment::AttachmentStore;
+use crate::config::{BackupConfig, Config};
+use crate::crypto::{Vault, VaultKey};
+use crate::dirsync::{SYNC_BASE_DIR, SYNC_STATE_FILE};
+use crate::httpsync::{REMOTES_FILE, SEALED_NOTEBOOK, SYNC_LOG_FILE};
+use crate::sealed::KEYRING_FILE;
+use crate::error::{NoteError, Result};
+use crate::note::NoteId;
+use crate::notebook::{validate_notebook_name, NotebookStore};
+use crate::storage::{is_note_file, FileStorage, StoreLock, LOCK_FILE};
+
+pub const BACKUP_MANIFEST: &str = "backup.json";
+
+const ARCHIVE_PREFIX: &str = "notes-";
+const ARCHIVE_SUFFIX: &str = ".tar.gz";
+const TIMESTAMP_FORMAT: &str = "%Y%m%d-%H%M%S%3f";
+/// What a full restore leaves as it is in the notes directory and each notebook
+const LOCAL_STATE: &[&str] = &[".git", SYNC_STATE_FILE, SYNC_BASE_DIR, SYNC_LOG_FILE, REMOTES_FILE, KEYRING_FILE, SEALED_NOTEBOOK];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SnapshotKind {
+    Manual,
+    Auto,
+    /// Taken automatically right before a restore overwrites the store
+    PreRestore,
+}
+
+impl SnapshotKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SnapshotKind::Manual => "manual",
+            SnapshotKind::Auto => "auto",
+            SnapshotKind::PreRestore => "pre-restore",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "manual" => Some(SnapshotKind::Manual),
+            "auto" => Some(SnapshotKind::Auto),
+            "pre-restore" => Some(SnapshotKind::PreRestore),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Snapshot {
+    pub name: String,
+    pub path: PathBuf,
+    pub created_at: DateTime<Local>,
+    pub kind: SnapshotKind,
+    pub size: u64,
+}
+
+impl Snapshot {
+    /// Parses `notes-<timestamp>-<kind>.tar.gz`
+    fn from_path(path: &Path) -> Option<Self> {
+        let file_name = path.file_name()?.to_str()?;
+        let stem = file_name.strip_prefix(ARCHIVE_PREFIX)?.strip_suffix(ARCHIVE_SUFFIX)?;
+        // The timestamp is `YYYYmmdd-HHMMSSfff`, so the kind starts at the second '-'
+        let split = stem.match_indices('-').nth(1)?.0;
+        let timestamp = NaiveDateTime::parse_from_str(&stem[..split], TIMESTAMP_FORMAT).ok()?;
+        let kind = SnapshotKind::parse(&stem[split + 1..])?;
+
+        Some(Snapshot {
+            name: file_name.to_string(),
+            path: path.to_path_buf(),
+            created_at: Local.from_local_datetime(&timestamp).earliest()?,
+            kind,
+            size: fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BackupManifest {
+    version: u32,
+    created_at: DateTime<Utc>,
+    kind: SnapshotKind,
+    notes: usize,
+}
+
+/// Everything a snapshot captures
+#[derive(Debug, Clone)]
+pub struct BackupSources {
+    pub notes_dir: PathBuf,
+    pub attachments_dir: PathBuf,
+    pub templates_dir: PathBuf,
+    pub config_file: Option<PathBuf>,
+}
+
+impl BackupSources {
+    pub fn from_config(config: &Config, config_file: &Path) -> Self {
+        Self {
+            notes_dir: config.general.notes_dir.clone(),
+            attachments_dir: config.general.attachments_dir.clone(),
+            templates_dir: config.general.templates_dir.clone(),
+            config_file: Some(config_file.to_path_buf()),
+        }
+    }
+
+    /// Archive directory name and live location of each part of the store
+    fn directories(&self) -> [(&'static str, &Path); 3] {
+        [
+            ("notes", &self.notes_dir),
+            ("attachments", &self.attachments_dir),
+            ("templates", &self.templates_dir),
+        ]
+    }
+}
+
+/// The automatic snapshots of the `[backup]` config, taken before the store changes
+#[derive(Debug, Clone)]
+pub struct AutoBackup {
+    pub store: BackupStore,
+    pub sources: BackupSources,
+    pub policy: BackupConfig,
+}
+
+impl AutoBackup {
+    /// Takes the day's snapshot if there is none yet; call it before writing
+    pub fn before_change(&self) -> Result<Option<Snapshot>> {
+        self.store.auto_backup(&self.sources, &self.policy)
+    }
+}
+
+/// Directory of compressed snapshots of the whole store
+#[derive(Debug, Clone)]
+pub struct BackupStore {
+    dir: PathBuf,
+}
+
+impl BackupStore {
+    pub fn new(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Archives the store while holding every notebook lock, so no note is half-written
+    pub fn create(&self, sources: &BackupSources, kind: SnapshotKind) -> Result<Snapshot> {
+        let _locks = lock_notebooks(&sources.notes_dir)?;
+        self.archive(sources, kind)
+    }
+
+    /// Same as [`BackupStore::create`] for callers already holding every notebook lock
+    fn archive(&self, sources: &BackupSources, kind: SnapshotKind) -> Result<Snapshot> {
+        let now = Local::now();
+        let name = format!("{}{}-{}{}", ARCHIVE_PREFIX, now.format(TIMESTAMP_FORMAT), kind.as_str(), ARCHIVE_SUFFIX);
+        let path = self.dir.join(&name);
+        if path.exists() {
+            return Err(NoteError::InvalidInput(format!("Snapshot '{}' already exists", name)));
+        }
+
+        let manifest = BackupManifest {
+            version: 1,
+            created_at: now.with_timezone(&Utc),
+            kind,
+            notes: count_notes(&sources.notes_dir)?,
+        };
+        write_archive(&path, &manifest, sources)?;
+
+        Snapshot::from_path(&path)
+            .ok_or_else(|| NoteError::InvalidInput(format!("Could not read back snapshot '{}'", name)))
+    }
+
+    /// Rewrites every snapshot the way [`NotebookStore::encrypt_all`] and
+    /// [`AttachmentStore::encrypt_all`] rewrite the live store, adding the vault
+    /// manifest, so no plaintext copy of a note outlives `notes vault init`.
+    /// Returns the number of snapshots rewritten.
+    pub fn encrypt_snapshots(&self, notebooks: &NotebookStore, default_notebook: &str) -> Result<usize> {
+        let key = notebooks
+            .key()
+            .ok_or_else(|| NoteError::InvalidInput("The notebook store has no vault key".to_string()))?;
+        let snapshots = self.list()?;
+        for snapshot in &snapshots {
+            let staging = self.unpack(snapshot)?;
+            let result = encrypt_staged(&staging, snapshot, notebooks, key, default_notebook);
+            let _ = fs::remove_dir_all(&staging);
+            result?;
+        }
+        Ok(snapshots.len())
+    }
+
+    /// Snapshots, oldest first
+    pub fn list(&self) -> Result<Vec<Snapshot>> {
+        let mut snapshots: Vec<Snapshot> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| Snapshot::from_path(&entry.path()))
+            .collect();
+        snapshots.sort_by(|a, b| a.created_at.cmp(&b.created_at).then_with(|| a.name.cmp(&b.name)));
+        Ok(snapshots)
+    }
+
+    /// Finds a snapshot by file name, with or without the `.tar.gz` suffix
+    pub fn find(&self, name: &str) -> Result<Snapshot> {
+        self.list()?
+            .into_iter()
+            .find(|snapshot| snapshot.name == name || snapshot.name.strip_suffix(ARCHIVE_SUFFIX) == Some(name))
+            .ok_or_else(|| NoteError::NotFound(format!("Snapshot '{}' not found in {:?}", name, self.dir)))
+    }
+
+    /// Replaces the notes, attachments and templates with a snapshot. The current state
+    /// is saved as a pre-restore snapshot first, which is returned. Every notebook stays
+    /// locked from that snapshot until the swap is done. Git history, sync state and
+    /// `config.toml` are left as they are.
+    pub fn restore(&self, name: &str, sources: &BackupSources) -> Result<Snapshot> {
+        let snapshot = self.find(name)?;
+        let staging = self.unpack(&snapshot)?;
+        let result = (|| {
+            let _locks = lock_notebooks(&sources.notes_dir)?;
+            let safety = self.archive(sources, SnapshotKind::PreRestore)?;
+            swap_in(&staging, sources)?;
+            Ok(safety)
+        })();
+        let _ = fs::remove_dir_all(&staging);
+        result
+    }
+
+    /// Restores one note, and any attachment blobs it needs, from a snapshot into
+    /// the notebook it was in. Returns that notebook's name.
+    pub fn restore_note(
+        &self,
+        name: &str,
+        id: &NoteId,
+        notebooks: &NotebookStore,
+        attachments: &AttachmentStore,
+        default_notebook: &str,
+    ) -> Result<String> {
+        let snapshot = self.find(name)?;
+        let staging = self.unpack(&snapshot)?;
+        let result = restore_note_from(&staging, &snapshot, id, notebooks, attachments, default_notebook);
+        let _ = fs::remove_dir_all(&staging);
+        result
+    }
+
+    /// Takes the day's automatic snapshot unless one exists, then prunes old ones.
+    /// Runs through [`AutoBackup`] before changes to the store.
+    pub fn auto_backup(&self, sources: &BackupSources, policy: &BackupConfig) -> Result<Option<Snapshot>> {
+        if !policy.auto {
+            return Ok(None);
+        }
+
+        let today = Local::now().date_naive();
+        let snapshots = self.list()?;
+        if snapshots.iter().any(|s| s.kind == SnapshotKind::Auto && s.created_at.date_naive() == today) {
+            return Ok(None);
+        }
+
+        let snapshot = self.create(sources, SnapshotKind::Auto)?;
+        self.prune(policy)?;
+        Ok(Some(snapshot))
+    }
+
+    /// Deletes automatic snapshots beyond the newest one per day for `keep_daily`
+    /// days and the newest one per week for `keep_weekly` weeks. Manual and
+    /// pre-restore snapshots are never pruned.
+    pub fn prune(&self, policy: &BackupConfig) -> Result<Vec<Snapshot>> {
+        let mut days = HashSet::new();
+        let mut weeks = HashSet::new();
+        let mut removed = Vec::new();
+
+        for snapshot in self.list()?.into_iter().rev().filter(|s| s.kind == SnapshotKind::Auto) {
+            let day = snapshot.created_at.date_naive();
+            let week = (day.iso_week().year(), day.iso_week().week());
+
+            let mut keep = false;
+            if days.len() < policy.keep_daily && days.insert(day) {
+                keep = true;
+            }
+            if weeks.len() < policy.keep_weekly && !weeks.contains(&week) {
+                weeks.insert(week);
+                keep = true;
+            }
+
+            if !keep {
+                fs::remove_file(&snapshot.path)?;
+                removed.push(snapshot);
+            }
+        }
+        Ok(removed)
+    }
+
+    fn unpack(&self, snapshot: &Snapshot) -> Result<PathBuf> {
+        let staging = self.dir.join(format!(".restore-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&staging)?;
+        let mut archive = tar::Archive::new(GzDecoder::new(fs::File::open(&snapshot.path)?));
+        if let Err(e) = archive.unpack(&staging) {
+            let _ = fs::remove_dir_all(&staging);
+            return Err(NoteError::SerializationError(format!("Snapshot '{}' is damaged: {}", snapshot.name, e)));
+        }
+        Ok(staging)
+    }
+}
+
+/// Writes a snapshot archive through a temporary file, so a crash never leaves a partial one
+fn write_archive(path: &Path, manifest: &BackupManifest, sources: &BackupSources) -> Result<()> {
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let temp_path = path.with_file_name(format!(".{}.tmp", name));
+
+    let mut builder = tar::Builder::new(GzEncoder::new(fs::File::create(&temp_path)?, Compression::default()));
+    let manifest_data = serde_json::to_vec_pretty(manifest)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest_data.len() as u64);
+    header.set_mode(0o600);
+    header.set_mtime(manifest.created_at.timestamp().max(0) as u64);
+    header.set_cksum();
+    builder.append_data(&mut header, BACKUP_MANIFEST, manifest_data.as_slice())?;
+
+    for (name, dir) in sources.directories() {
+        if dir.is_dir() {
+            append_dir(&mut builder, dir, Path::new(name))?;
+        }
+    }
+    if let Some(config_file) = sources.config_file.as_ref().filter(|path| path.is_file()) {
+        builder.append_path_with_name(config_file, "config.toml")?;
+    }
+    builder.into_inner()?.finish()?.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// Encrypts an unpacked snapshot in place and packs it back over the original archive
+fn encrypt_staged(
+    staging: &Path,
+    snapshot: &Snapshot,
+    notebooks: &NotebookStore,
+    key: &VaultKey,
+    default_notebook: &str,
+) -> Result<()> {
+    let manifest: BackupManifest = serde_json::from_slice(&fs::read(staging.join(BACKUP_MANIFEST))?)?;
+    let staged = BackupSources {
+        notes_dir: staging.join("notes"),
+        attachments_dir: staging.join("attachments"),
+        templates_dir: staging.join("templates"),
+        config_file: Some(staging.join("config.toml")),
+    };
+
+    NotebookStore::new(&staged.notes_dir, default_notebook)?.with_key(key.clone()).encrypt_all()?;
+    AttachmentStore::new(&staged.attachments_dir)?.with_key(key.clone()).encrypt_all()?;
+    fs::copy(Vault::manifest_path(notebooks.root()), Vault::manifest_path(&staged.notes_dir))?;
+    write_archive(&snapshot.path, &manifest, &staged)
+}
+
+/// Copies an unpacked snapshot next to the live directories, then swaps each copy in with
+/// a rename. The old directories are kept until every swap succeeded, and put back if one
+/// fails, so the store is never left empty or half-restored.
+fn swap_in(staging: &Path, sources: &BackupSources) -> Result<()> {
+    let tag = uuid::Uuid::new_v4();
+    let mut incoming: Vec<(PathBuf, &Path)> = Vec::new();
+    let mut prepared = Ok(());
+    for (name, target) in sources.directories() {
+        let staged = staging.join(name);
+        if staged.is_dir() {
+            let copy = sibling(target, "incoming", tag);
+            incoming.push((copy.clone(), target));
+            prepared = copy_dir(&staged, &copy).and_then(|_| match name {
+                "notes" => keep_local_state(target, &copy),
+                _ => Ok(()),
+            });
+            if prepared.is_err() {
+                break;
+            }
+        }
+    }
+    if let Err(e) = prepared {
+        for (copy, _) in &incoming {
+            let _ = fs::remove_dir_all(copy);
+        }
+        return Err(e.into());
+    }
+
+    // Only renames from here on, which stay on the same file system
+    let mut swapped: Vec<(Option<PathBuf>, &Path)> = Vec::new();
+    let mut failure = None;
+    for (copy, target) in &incoming {
+        let old = sibling(target, "old", tag);
+        let had_old = target.exists();
+        if had_old {
+            if let Err(e) = fs::rename(target, &old) {
+                failure = Some(e);
+                break;
+            }
+        }
+        if let Err(e) = fs::rename(copy, target) {
+            if had_old {
+                let _ = fs::rename(&old, target);
+            }
+            failure = Some(e);
+            break;
+        }
+        swapped.push((had_old.then_some(old), target));
+    }
+    if let Some(e) = failure {
+        for (old, target) in swapped.into_iter().rev() {
+            let _ = fs::remove_dir_all(target);
+            if let Some(old) = old {
+                let _ = fs::rename(old, target);
+            }
+        }
+        for (copy, _) in &incoming {
+            let _ = fs::remove_dir_all(copy);
+        }
+        return Err(e.into());
+    }
+
+    for old in swapped.into_iter().filter_map(|(old, _)| old) {
+        let _ = fs::remove_dir_all(old);
+    }
+    Ok(())
+}
+
+/// Puts the live git history and sync state into a restored copy of the notes directory,
+/// at its top level and in each notebook. Peers and remotes have already seen the newer
+/// state, so rolling their cursors back would make the next sync re-push or re-delete notes.
+fn keep_local_state(live: &Path, restored: &Path) -> io::Result<()> {
+    let mut dirs = vec![PathBuf::new()];
+    for entry in fs::read_dir(restored)? {
+        let path = entry?.path();
+        if let (true, Some(name)) = (path.is_dir(), path.file_name()) {
+            dirs.push(PathBuf::from(name));
+        }
+    }
+
+    for dir in dirs {
+        for name in LOCAL_STATE {
+            let (from, to) = (live.join(&dir).join(name), restored.join(&dir).join(name));
+            if to.is_dir() {
+                fs::remove_dir_all(&to)?;
+            } else if to.exists() {
+                fs::remove_file(&to)?;
+            }
+            if from.is_dir() {
+                copy_dir(&from, &to)?;
+            } else if from.is_file() {
+                fs::copy(&from, &to)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Hidden path in the same directory as `path`, for staging a replacement of it
+fn sibling(path: &Path, purpose: &str, tag: uuid::Uuid) -> PathBuf {
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!(".{}.{}-{}", name, purpose, tag))
+}
+
+fn restore_note_from(
+    staging: &Path,
+    snapshot: &Snapshot,
+    id: &NoteId,
+    notebooks: &NotebookStore,
+    attachments: &AttachmentStore,
+    default_notebook: &str,
+) -> Result<String> {
+    let staged = NotebookStore::new(staging.join("notes"), default_notebook)?;
+    let staged = match notebooks.key() {
+        Some(key) => staged.with_key(key.clone()),
+        None => staged,
+    };
+    let notebook = staged
+        .locate(id)?
+        .ok_or_else(|| NoteError::NotFound(format!("Note '{}' is not in snapshot '{}'", id, snapshot.name)))?;
+    let mut note = staged.open(&notebook)?.load_note(id)?;
+
+    let blobs = AttachmentStore::new(staging.join("attachments"))?;
+    let blobs = match attachments.key() {
+        Some(key) => blobs.with_key(key.clone()),
+        None => blobs,
+    };
+    for attachment in &note.attachments {
+        if !attachments.contains(&attachment.hash) && blobs.contains(&attachment.hash) {
+            attachments.store_bytes(&blobs.read(attachment)?, &attachment.name, &attachment.mime)?;
+        }
+    }
+
+    let target = notebooks.open_or_create(&notebook)?;
+    let current = notebooks.locate(id)?;
+    if let Some(current) = &current {
+        // Keep the revision moving forward so stale copies held elsewhere still conflict
+        note.revision = notebooks.open(current)?.load_note(id)?.revision + 1;
+    }
+    target.save_note(&note)?;
+    if let Some(current) = current.filter(|current| *current != notebook) {
+        notebooks.open(&current)?.delete_note(id)?;
+    }
+    Ok(notebook)
+}
+
+fn lock_notebooks(notes_dir: &Path) -> Result<Vec<StoreLock>> {
+    let mut locks = Vec::new();
+    if !notes_dir.is_dir() {
+        return Ok(locks);
+    }
+    for entry in fs::read_dir(notes_dir)? {
+        let path = entry?.path();
+        let is_notebook = path.is_dir()
+            && path.file_name().and_then(|s| s.to_str()).is_some_and(|name| validate_notebook_name(name).is_ok());
+        if let (true, Some(dir)) = (is_notebook, path.to_str()) {
+            locks.push(FileStorage::new(dir)?.lock()?);
+        }
+    }
+    Ok(locks)
+}
+
+fn count_notes(notes_dir: &Path) -> Result<usize> {
+    let mut count = 0;
+    if !notes_dir.is_dir() {
+        return Ok(count);
+    }
+    for entry in fs::read_dir(notes_dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            count += fs::read_dir(&path)?
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| is_note_file(&entry.path()))
+                .count();
+        }
+    }
+    Ok(count)
+}
+
+/// Adds a directory tree, leaving out lock files and in-flight temporary files
+fn append_dir<W: io::Write>(builder: &mut tar::Builder<W>, dir: &Path, name: &Path) -> io::Result<()> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)?.filter_map(|e| e.ok().map(|e| e.path())).collect();
+    entries.sort();
+    for path in entries {
+        let file_name = match path.file_name().and_then(|s| s.to_str()) {
+            Some(file_name) => file_name,
+            None => continue,
+        };
+        if file_name == LOCK_FILE || (file_name.starts_with('.') && file_name.ends_with(".tmp")) {
+            continue;
+        }
+
+        if path.is_dir() {
+            append_dir(builder, &path, &name.join(file_name))?;
+        } else if path.is_file() {
+            builder.append_path_with_name(&path, name.join(file_name))?;
+        }
+    }
+    Ok(())
+}
+
+fn copy_dir(source: &Path, destination: &Path) -> io::Result<()> {
+    fs::create_dir_all(destination)?;
+    for entry in fs::read_dir(source)? {
+        let path = entry?.path();
+        if let Some(file_name) = path.file_name() {
+            if path.is_dir() {
+                copy_dir(&path, &destination.join(file_name))?;
+            } else {
+                fs::copy(&path, destination.join(file_name))?;
+            }
+        }
+    }
+    Ok(())
+}
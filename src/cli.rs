@@ -1,8 +1,13 @@
 use clap::{Arg, ArgMatches, Command, value_parser};
 use std::path::PathBuf;
+use crate::note::NoteId;
+use crate::httpsync::DEFAULT_BIND;
+use crate::web::DEFAULT_WEB_BIND;
 
 pub struct CliArgs {
     pub command: CliCommand,
+    /// Notebook to operate on; falls back to `default_notebook` from the config
+    pub notebook: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -11,22 +16,29 @@ pub enum CliCommand {
         title: String,
         content: Option<String>,
         tags: Vec<String>,
+        template: Option<String>,
     },
     List {
         tag: Option<String>,
         limit: Option<usize>,
     },
+    Journal {
+        date: Option<String>,
+        week: bool,
+        previous: bool,
+        next: bool,
+    },
     Show {
-        id: u64,
+        id: NoteId,
     },
     Edit {
-        id: u64,
+        id: NoteId,
         title: Option<String>,
         content: Option<String>,
         tags: Option<Vec<String>>,
     },
     Delete {
-        id: u64,
+        id: NoteId,
         force: bool,
     },
     Search {
@@ -34,10 +46,74 @@ pub enum CliCommand {
         in_content: bool,
     },
     Tag {
-        id: u64,
+        id: NoteId,
         tags: Vec<String>,
         remove: bool,
     },
+    Tags {
+        action: TagsAction,
+    },
+    Notebook {
+        action: NotebookAction,
+    },
+    Attach {
+        id: NoteId,
+        file: PathBuf,
+        name: Option<String>,
+    },
+    Attachments {
+        id: NoteId,
+    },
+    Detach {
+        id: NoteId,
+        attachment: String,
+    },
+    OpenAttachment {
+        id: NoteId,
+        attachment: String,
+        output: Option<PathBuf>,
+    },
+    Vault {
+        action: VaultAction,
+    },
+    Lock {
+        id: NoteId,
+    },
+    Unlock {
+        id: NoteId,
+    },
+    Agent {
+        socket: Option<PathBuf>,
+    },
+    Migrate {
+        dry_run: bool,
+    },
+    Doctor {
+        fix: bool,
+    },
+    Backup {
+        action: BackupAction,
+    },
+    Git {
+        action: GitAction,
+    },
+    Sync {
+        path: Option<PathBuf>,
+        remote: Option<String>,
+        encrypt: bool,
+        rotate_key: bool,
+    },
+    Serve {
+        sync: bool,
+        bind: String,
+    },
+    Web {
+        bind: String,
+    },
+    Lsp,
+    Rpc,
+    Reindex,
+    Watch,
     Export {
         format: ExportFormat,
         output: Option<PathBuf>,
@@ -49,6 +125,68 @@ pub enum CliCommand {
     },
 }
 
+#[derive(Debug, Clone)]
+pub enum TagsAction {
+    List,
+    Rename {
+        old: String,
+        new: String,
+    },
+    Merge {
+        source: String,
+        target: String,
+    },
+    Delete {
+        tag: String,
+        force: bool,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum NotebookAction {
+    Create {
+        name: String,
+    },
+    List,
+    Rename {
+        old: String,
+        new: String,
+    },
+    Delete {
+        name: String,
+        force: bool,
+    },
+    Move {
+        id: NoteId,
+        target: String,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum VaultAction {
+    Init,
+    Unlock,
+    Lock,
+    Status,
+}
+
+#[derive(Debug, Clone)]
+pub enum BackupAction {
+    Create,
+    List,
+    Restore {
+        snapshot: String,
+        only: Option<NoteId>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum GitAction {
+    Log {
+        id: NoteId,
+    },
+}
+
 #[derive(Debug, Clone)]
 pub enum ExportFormat {
     Json,
@@ -66,7 +204,8 @@ impl CliArgs {
     pub fn parse() -> Result<Self, String> {
         let matches = build_cli().get_matches();
         let command = parse_command(&matches)?;
-        Ok(CliArgs { command })
+        let notebook = matches.get_one::<String>("notebook").cloned();
+        Ok(CliArgs { command, notebook })
     }
 
     pub fn parse_from<I, T>(args: I) -> Result<Self, String>
@@ -77,7 +216,40 @@ impl CliArgs {
         let matches = build_cli().try_get_matches_from(args)
             .map_err(|e| e.to_string())?;
         let command = parse_command(&matches)?;
-        Ok(CliArgs { command })
+        let notebook = matches.get_one::<String>("notebook").cloned();
+        Ok(CliArgs { command, notebook })
+    }
+}
+
+/// Rejects malformed ids (including path traversal attempts) while parsing arguments
+fn note_id_parser(value: &str) -> Result<NoteId, String> {
+    NoteId::parse(value).map_err(|e| e.to_string())
+}
+
+impl CliCommand {
+    /// Whether the command may change the store, and so should trigger the automatic backup first
+    pub fn modifies_store(&self) -> bool {
+        match self {
+            CliCommand::List { .. }
+            | CliCommand::Show { .. }
+            | CliCommand::Search { .. }
+            | CliCommand::Attachments { .. }
+            | CliCommand::OpenAttachment { .. }
+            | CliCommand::Agent { .. }
+            | CliCommand::Export { .. }
+            | CliCommand::Backup { .. }
+            | CliCommand::Git { .. }
+            | CliCommand::Reindex
+            | CliCommand::Watch
+            | CliCommand::Lsp => false,
+            CliCommand::Journal { week, .. } => !week,
+            CliCommand::Tags { action } => !matches!(action, TagsAction::List),
+            CliCommand::Notebook { action } => !matches!(action, NotebookAction::List),
+            CliCommand::Vault { action } => matches!(action, VaultAction::Init),
+            CliCommand::Migrate { dry_run } => !dry_run,
+            CliCommand::Doctor { fix } => *fix,
+            _ => true,
+        }
     }
 }
 
@@ -88,6 +260,14 @@ fn build_cli() -> Command {
         .about("A powerful command-line note-taking application")
         .subcommand_required(true)
         .arg_required_else_help(true)
+        .arg(
+            Arg::new("notebook")
+                .help("Notebook to operate on")
+                .short('n')
+                .long("notebook")
+                .value_name("NAME")
+                .global(true)
+        )
         .subcommand(
             Command::new("create")
                 .about("Create a new note")
@@ -114,6 +294,13 @@ fn build_cli() -> Command {
                         .value_name("TAG")
                         .action(clap::ArgAction::Append)
                 )
+                .arg(
+                    Arg::new("template")
+                        .help("Create the note from a template in the templates directory")
+                        .long("template")
+                        .value_name("NAME")
+                        .conflicts_with("content")
+                )
         )
         .subcommand(
             Command::new("list")
@@ -135,6 +322,310 @@ fn build_cli() -> Command {
                         .value_parser(value_parser!(usize))
                 )
         )
+        .subcommand(
+            Command::new("journal")
+                .about("Open the journal entry for a day, creating it if missing")
+                .arg(
+                    Arg::new("date")
+                        .help("Day to open: YYYY-MM-DD, today, yesterday or tomorrow")
+                        .index(1)
+                )
+                .arg(
+                    Arg::new("week")
+                        .help("List the journal entries of the last seven days")
+                        .long("week")
+                        .action(clap::ArgAction::SetTrue)
+                        .conflicts_with_all(["date", "prev", "next"])
+                )
+                .arg(
+                    Arg::new("prev")
+                        .help("Open the closest entry before the given day")
+                        .long("prev")
+                        .action(clap::ArgAction::SetTrue)
+                        .conflicts_with("next")
+                )
+                .arg(
+                    Arg::new("next")
+                        .help("Open the closest entry after the given day")
+                        .long("next")
+                        .action(clap::ArgAction::SetTrue)
+                )
+        )
+        .subcommand(
+            Command::new("today")
+                .about("Open today's journal entry, creating it if missing")
+        )
+        .subcommand(
+            Command::new("tags")
+                .about("Manage tags across all notes")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("list")
+                        .about("List all tags with note counts")
+                        .alias("ls")
+                )
+                .subcommand(
+                    Command::new("rename")
+                        .about("Rename a tag and its descendants on every note")
+                        .arg(Arg::new("old").help("Current tag").required(true).index(1))
+                        .arg(Arg::new("new").help("New tag").required(true).index(2))
+                )
+                .subcommand(
+                    Command::new("merge")
+                        .about("Merge a tag into another existing tag")
+                        .arg(Arg::new("source").help("Tag to merge away").required(true).index(1))
+                        .arg(Arg::new("target").help("Tag to merge into").required(true).index(2))
+                )
+                .subcommand(
+                    Command::new("delete")
+                        .about("Remove a tag and its descendants from every note")
+                        .arg(Arg::new("tag").help("Tag to delete").required(true).index(1))
+                        .arg(
+                            Arg::new("force")
+                                .help("Skip confirmation")
+                                .short('f')
+                                .long("force")
+                                .action(clap::ArgAction::SetTrue)
+                        )
+                )
+        )
+        .subcommand(
+            Command::new("notebook")
+                .about("Manage notebooks")
+                .alias("nb")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("create")
+                        .about("Create a new notebook")
+                        .arg(Arg::new("name").help("Notebook name").required(true).index(1))
+                )
+                .subcommand(
+                    Command::new("list")
+                        .about("List notebooks with note counts")
+                        .alias("ls")
+                )
+                .subcommand(
+                    Command::new("rename")
+                        .about("Rename a notebook")
+                        .arg(Arg::new("old").help("Current name").required(true).index(1))
+                        .arg(Arg::new("new").help("New name").required(true).index(2))
+                )
+                .subcommand(
+                    Command::new("delete")
+                        .about("Delete a notebook")
+                        .arg(Arg::new("name").help("Notebook name").required(true).index(1))
+                        .arg(
+                            Arg::new("force")
+                                .help("Also delete the notes it contains")
+                                .short('f')
+                                .long("force")
+                                .action(clap::ArgAction::SetTrue)
+                        )
+                )
+                .subcommand(
+                    Command::new("move")
+                        .about("Move a note into another notebook")
+                        .arg(Arg::new("id").help("Note ID").required(true).index(1).value_parser(note_id_parser))
+                        .arg(Arg::new("target").help("Destination notebook").required(true).index(2))
+                )
+        )
+        .subcommand(
+            Command::new("attach")
+                .about("Attach a file to a note")
+                .arg(Arg::new("id").help("Note ID").required(true).index(1).value_parser(note_id_parser))
+                .arg(
+                    Arg::new("file")
+                        .help("File to attach")
+                        .required(true)
+                        .index(2)
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    Arg::new("name")
+                        .help("Name to store the attachment under (defaults to the file name)")
+                        .long("name")
+                        .value_name("NAME")
+                )
+        )
+        .subcommand(
+            Command::new("attachments")
+                .about("List the attachments of a note")
+                .arg(Arg::new("id").help("Note ID").required(true).index(1).value_parser(note_id_parser))
+        )
+        .subcommand(
+            Command::new("detach")
+                .about("Remove an attachment from a note")
+                .arg(Arg::new("id").help("Note ID").required(true).index(1).value_parser(note_id_parser))
+                .arg(Arg::new("attachment").help("Attachment name or hash prefix").required(true).index(2))
+        )
+        .subcommand(
+            Command::new("open-attachment")
+                .about("Write an attachment back out to a file")
+                .arg(Arg::new("id").help("Note ID").required(true).index(1).value_parser(note_id_parser))
+                .arg(Arg::new("attachment").help("Attachment name or hash prefix").required(true).index(2))
+                .arg(
+                    Arg::new("output")
+                        .help("Destination path (defaults to the attachment name)")
+                        .short('o')
+                        .long("output")
+                        .value_name("FILE")
+                        .value_parser(value_parser!(PathBuf))
+                )
+        )
+        .subcommand(
+            Command::new("vault")
+                .about("Manage at-rest encryption of the whole note store")
+                .subcommand_required(true)
+                .subcommand(Command::new("init").about("Encrypt the note store with a passphrase"))
+                .subcommand(Command::new("unlock").about("Unlock the store and cache its key in the session agent"))
+                .subcommand(Command::new("lock").about("Forget all cached keys"))
+                .subcommand(Command::new("status").about("Show whether the store is encrypted and unlocked"))
+        )
+        .subcommand(
+            Command::new("lock")
+                .about("Encrypt the content of a note with its own passphrase")
+                .arg(Arg::new("id").help("Note ID").required(true).index(1).value_parser(note_id_parser))
+        )
+        .subcommand(
+            Command::new("unlock")
+                .about("Permanently remove the passphrase from a locked note")
+                .arg(Arg::new("id").help("Note ID").required(true).index(1).value_parser(note_id_parser))
+        )
+        .subcommand(
+            Command::new("agent")
+                .about("Run the session key agent in the foreground")
+                .hide(true)
+                .arg(
+                    Arg::new("socket")
+                        .long("socket")
+                        .value_name("PATH")
+                        .value_parser(value_parser!(PathBuf))
+                )
+        )
+        .subcommand(
+            Command::new("migrate")
+                .about("Upgrade every note to the current storage schema")
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .help("Only report the notes that would be upgraded")
+                        .action(clap::ArgAction::SetTrue)
+                )
+        )
+        .subcommand(
+            Command::new("doctor")
+                .about("Check the note store for damaged or inconsistent files")
+                .arg(
+                    Arg::new("fix")
+                        .long("fix")
+                        .help("Repair what can be repaired and quarantine unreadable files")
+                        .action(clap::ArgAction::SetTrue)
+                )
+        )
+        .subcommand(
+            Command::new("backup")
+                .about("Create, list and restore snapshots of the whole store")
+                .subcommand_required(true)
+                .subcommand(Command::new("create").about("Take a snapshot now"))
+                .subcommand(Command::new("list").about("List snapshots, oldest first"))
+                .subcommand(
+                    Command::new("restore")
+                        .about("Restore the store, or a single note, from a snapshot")
+                        .arg(Arg::new("snapshot").help("Snapshot name").required(true).index(1))
+                        .arg(
+                            Arg::new("only")
+                                .long("only")
+                                .value_name("ID")
+                                .help("Only restore this note")
+                                .value_parser(note_id_parser)
+                        )
+                )
+        )
+        .subcommand(
+            Command::new("git")
+                .about("Inspect the history of a git-backed note store")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("log")
+                        .about("Show the commits that changed a note")
+                        .arg(Arg::new("id").help("Note ID").required(true).index(1).value_parser(note_id_parser))
+                )
+        )
+        .subcommand(
+            Command::new("sync")
+                .about("Sync with another notes directory, or with the configured git remote")
+                .arg(
+                    Arg::new("path")
+                        .help("Notes directory to sync with in both directions")
+                        .index(1)
+                        .value_parser(value_parser!(PathBuf))
+                )
+                .arg(
+                    Arg::new("remote")
+                        .long("remote")
+                        .value_name("URL")
+                        .help("Sync with a `notes serve --sync` server, e.g. http://host:7878")
+                        .conflicts_with("path")
+                )
+                .arg(
+                    Arg::new("encrypt")
+                        .long("encrypt")
+                        .help("Encrypt notes end to end with a sync passphrase before they reach the server")
+                        .requires("remote")
+                        .action(clap::ArgAction::SetTrue)
+                )
+                .arg(
+                    Arg::new("rotate-key")
+                        .long("rotate-key")
+                        .help("Replace the server's sync key and passphrase, and encrypt every note again")
+                        .requires("remote")
+                        .action(clap::ArgAction::SetTrue)
+                )
+        )
+        .subcommand(
+            Command::new("serve")
+                .about("Serve the notes directory over HTTP: the API when [api] tokens are configured, and the sync protocol with --sync")
+                .arg(
+                    Arg::new("sync")
+                        .long("sync")
+                        .help("Serve the sync protocol used by `notes sync --remote`")
+                        .action(clap::ArgAction::SetTrue)
+                )
+                .arg(
+                    Arg::new("bind")
+                        .long("bind")
+                        .value_name("ADDR")
+                        .help("Address to listen on")
+                        .default_value(DEFAULT_BIND)
+                )
+        )
+        .subcommand(
+            Command::new("web")
+                .about("Serve a web UI for browsing, searching and editing notes")
+                .arg(
+                    Arg::new("bind")
+                        .long("bind")
+                        .value_name("ADDR")
+                        .help("Address to listen on")
+                        .default_value(DEFAULT_WEB_BIND)
+                )
+        )
+        .subcommand(
+            Command::new("lsp")
+                .about("Run a language server on stdin/stdout for editing note Markdown in an editor")
+        )
+        .subcommand(
+            Command::new("rpc")
+                .about("Answer JSON-RPC 2.0 requests on stdin/stdout, one per line, for editors and scripts")
+        )
+        .subcommand(
+            Command::new("reindex")
+                .about("Rebuild the metadata index used by list and tag counts")
+        )
+        .subcommand(
+            Command::new("watch")
+                .about("Watch for notes changed by other tools and print each change as a JSON line")
+        )
         .subcommand(
             Command::new("show")
                 .about("Show a specific note")
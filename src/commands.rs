@@ -1,32 +1,273 @@
+use std::cmp::Reverse;
 use std::fs;
-use std::path::Path;
-use chrono::{DateTime, Utc};
-use serde_json;
-use crate::note::{Note, NoteMetadata};
-use crate::storage::Storage;
-use crate::error::NoteError;
+use std::io::{self, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use chrono::{Local, NaiveDate, Utc};
+use serde::Serialize;
+use serde_json::{self, Value};
+use crate::note::{Attachment, Note, NoteId};
+use crate::storage::FileStorage;
+use crate::error::{validate_tag, NoteError};
+use crate::template::{TemplateContext, TemplateStore};
+use crate::config::{GitConfig, JournalConfig, SyncConfig};
+use crate::journal;
+use crate::tags;
+use crate::notebook::NotebookStore;
+use crate::schema::CURRENT_SCHEMA_VERSION;
+use crate::doctor::{self, DoctorReport};
+use crate::backup::{AutoBackup, BackupSources, BackupStore, Snapshot, SnapshotKind};
+use crate::cli::CliCommand;
+use crate::git::{self, CommitInfo, GitStore, SyncReport};
+use crate::dirsync::{self, SyncSummary};
+use crate::http;
+use crate::httpsync::{self, RemoteOptions, SyncServer};
+use crate::api::{ApiServer, NotebookInfo, TagCount, API_PREFIX};
+use crate::web::{self, WebServer};
+use crate::lsp;
+use crate::rpc;
+use crate::jsonrpc::RpcError;
+use crate::index::NoteSummary;
+use crate::watch::{self, Watcher};
+use crate::attachment::AttachmentStore;
+use crate::export;
+use crate::config::{ApiConfig, SecurityConfig};
+use crate::crypto::{self, KdfParams, Vault, VaultKey};
+#[cfg(unix)]
+use crate::agent::{self, AgentClient};
+
+/// Prints like `println!` unless the handler is quiet, as it is under `notes rpc`
+macro_rules! say {
+    ($handler:expr, $($arg:tt)*) => {
+        if !$handler.quiet {
+            println!($($arg)*);
+        }
+    };
+}
+
+fn prompt_for(label: &str) -> Result<String, NoteError> {
+    print!("{}: ", label);
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Stands in for `prompt_for` under `notes rpc`, where stdin carries the requests
+fn no_prompt(label: &str) -> Result<String, NoteError> {
+    Err(NoteError::InvalidInput(format!("'{}' must be answered at a terminal; run this command from the CLI", label)))
+}
+
+fn read_passphrase(prompt: &str) -> Result<String, NoteError> {
+    // Lets scripts and tests supply the passphrase without a terminal
+    if let Ok(passphrase) = std::env::var("NOTES_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+    Ok(rpassword::prompt_password(prompt)?)
+}
+
+fn read_new_passphrase() -> Result<String, NoteError> {
+    let passphrase = read_passphrase("New passphrase: ")?;
+    if std::env::var("NOTES_PASSPHRASE").is_err() && read_passphrase("Repeat passphrase: ")? != passphrase {
+        return Err(NoteError::ValidationError("Passphrases do not match".to_string()));
+    }
+    Ok(passphrase)
+}
+
+fn vault_key_name(root: &Path) -> String {
+    let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+    format!("vault:{}", root.display())
+}
+
+#[cfg(unix)]
+fn cached_key(name: &str) -> Option<VaultKey> {
+    AgentClient::new(agent::default_socket_path()).get(name)
+}
+
+#[cfg(not(unix))]
+fn cached_key(_name: &str) -> Option<VaultKey> {
+    None
+}
+
+#[cfg(unix)]
+fn cache_key(name: &str, key: &VaultKey, security: &SecurityConfig) -> Result<(), NoteError> {
+    let client = AgentClient::new(agent::default_socket_path());
+    client.ensure_running()?;
+    client.put(name, key, std::time::Duration::from_secs(security.agent_timeout_secs))
+}
+
+#[cfg(not(unix))]
+fn cache_key(_name: &str, _key: &VaultKey, _security: &SecurityConfig) -> Result<(), NoteError> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn forget_keys() -> Result<(), NoteError> {
+    AgentClient::new(agent::default_socket_path()).forget(None)
+}
+
+#[cfg(not(unix))]
+fn forget_keys() -> Result<(), NoteError> {
+    Ok(())
+}
+
+fn reply<T: Serialize>(value: T) -> Result<Value, RpcError> {
+    serde_json::to_value(value).map_err(|e| RpcError::from(NoteError::from(e)))
+}
+
+/// Key of the encrypted store at `root`, from the session agent or by asking for
+/// the passphrase. Returns `None` for stores that are not encrypted.
+pub fn vault_key(root: &Path, security: &SecurityConfig) -> Result<Option<VaultKey>, NoteError> {
+    if !Vault::is_initialized(root) {
+        return Ok(None);
+    }
+
+    let vault = Vault::load(root)?;
+    let name = vault_key_name(root);
+    if let Some(key) = cached_key(&name) {
+        if vault.check_key(&key).is_ok() {
+            return Ok(Some(key));
+        }
+    }
+
+    let key = vault.unlock(&read_passphrase("Vault passphrase: ")?)?;
+    cache_key(&name, &key, security)?;
+    Ok(Some(key))
+}
+
+/// Whether the note store is encrypted, and whether its key is cached
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VaultStatus {
+    Unencrypted,
+    Locked,
+    Unlocked,
+}
+
+/// Everything besides the current notebook that the calls of `notes rpc` may need
+pub struct RpcContext<'a> {
+    pub notebooks: &'a NotebookStore,
+    /// Name of the notebook the handler's storage belongs to
+    pub notebook: &'a str,
+    /// `[general] default_notebook`, which takes notes found outside any notebook
+    pub default_notebook: &'a str,
+    pub templates: &'a TemplateStore,
+    pub journal: &'a JournalConfig,
+    pub attachments: &'a AttachmentStore,
+    pub backups: &'a BackupStore,
+    pub backup_sources: &'a BackupSources,
+    pub security: &'a SecurityConfig,
+    pub git: &'a GitConfig,
+    pub sync: &'a SyncConfig,
+}
 
 pub struct CommandHandler {
-    storage: Storage,
+    storage: FileStorage,
+    git: Option<GitStore>,
+    auto_backup: Option<AutoBackup>,
+    quiet: bool,
 }
 
 impl CommandHandler {
-    pub fn new(storage: Storage) -> Self {
-        Self { storage }
+    pub fn new(storage: FileStorage) -> Self {
+        Self { storage, git: None, auto_backup: None, quiet: false }
+    }
+
+    /// Commits every change made through this handler to the notes repository
+    pub fn with_git(mut self, git: GitStore) -> Self {
+        self.git = Some(git);
+        self
+    }
+
+    /// Takes the day's automatic snapshot before commands that change the store
+    pub fn with_auto_backup(mut self, auto_backup: AutoBackup) -> Self {
+        self.auto_backup = Some(auto_backup);
+        self
+    }
+
+    /// Runs before every command; snapshots the store first when `command` may change it
+    pub fn backup_before(&self, command: &CliCommand) -> Result<Option<Snapshot>, NoteError> {
+        match &self.auto_backup {
+            Some(auto_backup) if command.modifies_store() => auto_backup.before_change(),
+            _ => Ok(None),
+        }
     }
 
-    pub fn create_note(&mut self, title: String, content: String, tags: Vec<String>) -> Result<String, NoteError> {
+    /// The API server over `notebooks`, committing and snapshotting like this handler
+    fn api_server(&self, notebooks: &NotebookStore, default_notebook: &str, tokens: Vec<String>) -> Result<ApiServer, NoteError> {
+        let mut server = ApiServer::new(notebooks.clone(), default_notebook, tokens)?;
+        if let Some(git) = &self.git {
+            server = server.with_git(git.clone());
+        }
+        if let Some(auto_backup) = &self.auto_backup {
+            server = server.with_auto_backup(auto_backup.clone());
+        }
+        Ok(server)
+    }
+
+    /// Commits the changes of the command that just ran, when the store is git-backed
+    fn record(&self, message: &str) -> Result<(), NoteError> {
+        if let Some(git) = &self.git {
+            git.commit_all(message)?;
+        }
+        Ok(())
+    }
+
+    /// Names a note in commit messages, without its title when the store is encrypted
+    fn subject(&self, note: &Note) -> String {
+        git::note_subject(note, self.storage.is_encrypted())
+    }
+
+    fn prompt(&self) -> fn(&str) -> Result<String, NoteError> {
+        match self.quiet {
+            true => no_prompt,
+            false => prompt_for,
+        }
+    }
+
+    fn git(&self) -> Result<&GitStore, NoteError> {
+        self.git.as_ref().ok_or_else(|| {
+            NoteError::InvalidInput("The note store is not git-backed; set `enabled = true` under [git] in the config".to_string())
+        })
+    }
+
+    pub fn create_note(&mut self, title: String, content: String, tags: Vec<String>) -> Result<NoteId, NoteError> {
         let note = Note::new(title, content, tags);
         let id = note.id.clone();
         
         self.storage.save_note(&note)?;
+        self.record(&format!("Create {}", self.subject(&note)))?;
         
-        println!("Note created successfully with ID: {}", id);
+        say!(self, "Note created successfully with ID: {}", id);
         Ok(id)
     }
 
-    pub fn list_notes(&self, limit: Option<usize>) -> Result<Vec<NoteMetadata>, NoteError> {
-        let mut notes = self.storage.list_notes()?;
+    pub fn create_note_from_template(&mut self, templates: &TemplateStore, template_name: &str, title: String, tags: Vec<String>) -> Result<NoteId, NoteError> {
+        let template = templates.load(template_name)?;
+        let mut context = TemplateContext::new(title, self.prompt());
+        let mut note = template.render(&mut context)?;
+
+        for tag in tags {
+            validate_tag(&tag)?;
+            note.add_tag(tag);
+        }
+
+        let id = note.id.clone();
+        self.storage.save_note(&note)?;
+        self.record(&format!("Create {} from template '{}'", self.subject(&note), template_name))?;
+
+        say!(self, "Note created from template '{}' with ID: {}", template_name, id);
+        Ok(id)
+    }
+
+    pub fn list_notes(&self, tag: Option<&str>, limit: Option<usize>) -> Result<Vec<NoteSummary>, NoteError> {
+        let mut notes = self.storage.list_summaries()?;
+        
+        // A parent tag also matches its descendants (`work` matches `work/clients/acme`)
+        if let Some(tag) = tag {
+            notes.retain(|note| note.has_tag_or_descendant(tag));
+        }
         
         // Sort by creation date, newest first
         notes.sort_by(|a, b| b.created_at.cmp(&a.created_at));
@@ -36,18 +277,18 @@ impl CommandHandler {
         }
         
         if notes.is_empty() {
-            println!("No notes found.");
+            say!(self, "No notes found.");
         } else {
-            println!("Found {} note(s):", notes.len());
+            say!(self, "Found {} note(s):", notes.len());
             for (index, note) in notes.iter().enumerate() {
-                println!("{}. [{}] {} ({})", 
+                say!(self, "{}. [{}] {} ({})", 
                     index + 1,
                     note.id[..8].to_string(),
                     note.title,
                     note.created_at.format("%Y-%m-%d %H:%M")
                 );
                 if !note.tags.is_empty() {
-                    println!("   Tags: {}", note.tags.join(", "));
+                    say!(self, "   Tags: {}", note.tags.join(", "));
                 }
             }
         }
@@ -55,29 +296,781 @@ impl CommandHandler {
         Ok(notes)
     }
 
-    pub fn view_note(&self, id: &str) -> Result<Note, NoteError> {
+    pub fn open_journal(&mut self, templates: &TemplateStore, config: &JournalConfig, date: NaiveDate) -> Result<Note, NoteError> {
+        let (note, created) = journal::open_entry(&self.storage, templates, config, date, self.prompt())?;
+        if created {
+            self.record(&format!("Create journal entry for {}", date))?;
+            say!(self, "Created journal entry for {}.", date);
+        }
+
+        self.view_note(&note.id)?;
+
+        let (previous, next) = journal::neighbours(&self.storage, date)?;
+        say!(self, "Previous: {}  Next: {}",
+            previous.map(|d| d.to_string()).unwrap_or_else(|| "-".to_string()),
+            next.map(|d| d.to_string()).unwrap_or_else(|| "-".to_string())
+        );
+
+        Ok(note)
+    }
+
+    pub fn step_journal(&mut self, templates: &TemplateStore, config: &JournalConfig, date: NaiveDate, forward: bool) -> Result<Note, NoteError> {
+        let (previous, next) = journal::neighbours(&self.storage, date)?;
+        let target = if forward { next } else { previous };
+
+        match target {
+            Some(target) => self.open_journal(templates, config, target),
+            None => Err(NoteError::NotFound(format!(
+                "No journal entry {} {}",
+                if forward { "after" } else { "before" },
+                date
+            ))),
+        }
+    }
+
+    pub fn list_journal_week(&self, today: NaiveDate) -> Result<Vec<Note>, NoteError> {
+        let entries = journal::last_week(&self.storage, today)?;
+
+        if entries.is_empty() {
+            say!(self, "No journal entries in the last seven days.");
+        } else {
+            for note in &entries {
+                let date = journal::entry_date(note).map(|d| d.to_string()).unwrap_or_default();
+                say!(self, "{} [{}] {} ({} words)", date, &note.id[..8], note.title, note.word_count());
+            }
+        }
+
+        Ok(entries)
+    }
+
+    pub fn list_tags(&self) -> Result<Vec<(String, usize)>, NoteError> {
+        let summaries = self.storage.list_summaries()?;
+        let counts: Vec<(String, usize)> = tags::count_tags(summaries.iter().map(|s| s.tags.as_slice()))
+            .into_iter()
+            .collect();
+
+        if counts.is_empty() {
+            say!(self, "No tags found.");
+        } else {
+            for (tag, count) in &counts {
+                let depth = tag.matches(tags::TAG_SEPARATOR).count();
+                let name = tag.rsplit(tags::TAG_SEPARATOR).next().unwrap_or(tag);
+                say!(self, "{}{} ({})", "  ".repeat(depth), name, count);
+            }
+        }
+
+        Ok(counts)
+    }
+
+    pub fn rename_tag(&mut self, old: &str, new: &str) -> Result<usize, NoteError> {
+        let updated = tags::rename_tag(&self.storage, old, new)?;
+        self.record(&git::tag_subject(format!("Rename tag '{}' to '{}'", old, new), updated, self.storage.is_encrypted()))?;
+        say!(self, "Renamed tag '{}' to '{}' on {} note(s).", old, new, updated);
+        Ok(updated)
+    }
+
+    pub fn merge_tags(&mut self, source: &str, target: &str) -> Result<usize, NoteError> {
+        let updated = tags::merge_tags(&self.storage, source, target)?;
+        self.record(&git::tag_subject(format!("Merge tag '{}' into '{}'", source, target), updated, self.storage.is_encrypted()))?;
+        say!(self, "Merged tag '{}' into '{}' on {} note(s).", source, target, updated);
+        Ok(updated)
+    }
+
+    pub fn delete_tag(&mut self, tag: &str) -> Result<usize, NoteError> {
+        let updated = tags::delete_tag(&self.storage, tag)?;
+        self.record(&git::tag_subject(format!("Delete tag '{}'", tag), updated, self.storage.is_encrypted()))?;
+        say!(self, "Removed tag '{}' from {} note(s).", tag, updated);
+        Ok(updated)
+    }
+
+    pub fn create_notebook(&self, notebooks: &NotebookStore, name: &str) -> Result<(), NoteError> {
+        notebooks.create(name)?;
+        self.record(&format!("Create notebook '{}'", name))?;
+        say!(self, "Notebook '{}' created.", name);
+        Ok(())
+    }
+
+    pub fn list_notebooks(&self, notebooks: &NotebookStore, current: &str) -> Result<Vec<(String, usize)>, NoteError> {
+        let list = notebooks.list()?;
+
+        if list.is_empty() {
+            say!(self, "No notebooks found.");
+        } else {
+            for (name, count) in &list {
+                let marker = if name == current { "*" } else { " " };
+                say!(self, "{} {} ({} note(s))", marker, name, count);
+            }
+        }
+
+        Ok(list)
+    }
+
+    pub fn rename_notebook(&self, notebooks: &NotebookStore, old: &str, new: &str) -> Result<(), NoteError> {
+        notebooks.rename(old, new)?;
+        self.record(&format!("Rename notebook '{}' to '{}'", old, new))?;
+        say!(self, "Notebook '{}' renamed to '{}'.", old, new);
+        Ok(())
+    }
+
+    pub fn delete_notebook(&self, notebooks: &NotebookStore, name: &str, force: bool) -> Result<(), NoteError> {
+        let removed = notebooks.delete(name, force)?;
+        self.record(&format!("Delete notebook '{}'", name))?;
+        say!(self, "Notebook '{}' deleted ({} note(s) removed).", name, removed);
+        Ok(())
+    }
+
+    pub fn move_note_to_notebook(&self, notebooks: &NotebookStore, id: &NoteId, target: &str) -> Result<(), NoteError> {
+        let source = notebooks.move_note(id, target)?;
+        self.record(&format!("Move note {} from '{}' to '{}'", id, source, target))?;
+        say!(self, "Note {} moved from '{}' to '{}'.", id, source, target);
+        Ok(())
+    }
+
+    pub fn attach_file(&mut self, attachments: &AttachmentStore, id: &NoteId, file: &Path, name: Option<&str>) -> Result<(), NoteError> {
+        let mut note = self.storage.load_note(id)?;
+        let attachment = attachments.store_file(file, name)?;
+
+        say!(self, "Attached '{}' ({}, {} bytes) to note {}.", attachment.name, attachment.mime, attachment.size, id);
+        let message = match self.storage.is_encrypted() {
+            true => format!("Attach a file to {}", self.subject(&note)),
+            false => format!("Attach '{}' to {}", attachment.name, self.subject(&note)),
+        };
+        note.add_attachment(attachment);
+        self.storage.update_note(&mut note)?;
+        self.record(&message)?;
+        Ok(())
+    }
+
+    pub fn list_attachments(&self, id: &NoteId) -> Result<Vec<Attachment>, NoteError> {
         let note = self.storage.load_note(id)?;
+
+        if note.attachments.is_empty() {
+            say!(self, "Note has no attachments.");
+        } else {
+            for attachment in &note.attachments {
+                say!(self, "{}  {}  {} bytes  {}",
+                    &attachment.hash[..12],
+                    attachment.name,
+                    attachment.size,
+                    attachment.mime
+                );
+            }
+        }
+
+        Ok(note.attachments)
+    }
+
+    pub fn detach(&mut self, id: &NoteId, attachment: &str) -> Result<(), NoteError> {
+        let mut note = self.storage.load_note(id)?;
+        let removed = note.remove_attachment(attachment)
+            .ok_or_else(|| NoteError::NotFound(format!("Attachment '{}' not found on note {}", attachment, id)))?;
+
+        self.storage.update_note(&mut note)?;
+        let message = match self.storage.is_encrypted() {
+            true => format!("Detach a file from {}", self.subject(&note)),
+            false => format!("Detach '{}' from {}", removed.name, self.subject(&note)),
+        };
+        self.record(&message)?;
+        say!(self, "Detached '{}' from note {}.", removed.name, id);
+        Ok(())
+    }
+
+    pub fn open_attachment(&self, attachments: &AttachmentStore, id: &NoteId, attachment: &str, output: Option<&Path>) -> Result<(), NoteError> {
+        let note = self.storage.load_note(id)?;
+        let found = note.find_attachment(attachment)
+            .ok_or_else(|| NoteError::NotFound(format!("Attachment '{}' not found on note {}", attachment, id)))?;
+
+        // Only the file name, whatever the note says, so the default never leaves the working directory
+        let destination = match output {
+            Some(output) => output.to_path_buf(),
+            None => Path::new(&found.name).file_name().map(PathBuf::from).ok_or_else(|| {
+                NoteError::ValidationError(format!("Attachment name '{}' is not a file name", found.name))
+            })?,
+        };
+        attachments.write_to(found, &destination)?;
+        say!(self, "Wrote '{}' to {:?}.", found.name, destination);
+        Ok(())
+    }
+
+    pub fn export_json_bundle(&self, attachments: &AttachmentStore, output: Option<&Path>, tag: Option<&str>) -> Result<usize, NoteError> {
+        let mut notes = self.storage.list_notes()?;
+        if let Some(tag) = tag {
+            notes.retain(|note| note.has_tag_or_descendant(tag));
+        }
+
+        let bundle = export::build_json_bundle(notes, attachments)?;
+        let json_data = serde_json::to_string_pretty(&bundle)?;
+        match output {
+            Some(path) => fs::write(path, json_data)?,
+            None => say!(self, "{}", json_data),
+        }
+
+        Ok(bundle.notes.len())
+    }
+
+    pub fn import_json_bundle(&mut self, attachments: &AttachmentStore, file: &Path) -> Result<usize, NoteError> {
+        let json_data = fs::read_to_string(file)?;
+        let bundle: export::JsonBundle = serde_json::from_str(&json_data)?;
+        let imported = export::restore_json_bundle(&bundle, &self.storage, attachments)?;
+        self.record(&format!("Import {} note(s) from {}", imported, file.display()))?;
+
+        say!(self, "Imported {} note(s) and {} attachment(s).", imported, bundle.attachments.len());
+        Ok(imported)
+    }
+
+    /// Encrypts the store with a new passphrase: the notes, their sync bases, the
+    /// attachment blobs and every backup snapshot. The change feed is redacted and
+    /// the quarantine deleted, so no plaintext copy is left behind.
+    pub fn vault_init(
+        &self,
+        notebooks: &NotebookStore,
+        attachments: &AttachmentStore,
+        backups: &BackupStore,
+        default_notebook: &str,
+        security: &SecurityConfig,
+    ) -> Result<usize, NoteError> {
+        let root = notebooks.root();
+        let key = Vault::init(root, &read_new_passphrase()?, KdfParams::generate()?)?;
+        let notebooks = notebooks.clone().with_key(key.clone());
+
+        let encrypted = notebooks.encrypt_all()?;
+        let blobs = AttachmentStore::new(attachments.attachments_dir())?.with_key(key.clone()).encrypt_all()?;
+        let snapshots = backups.encrypt_snapshots(&notebooks, default_notebook)?;
+
+        self.record("Encrypt note store")?;
+        cache_key(&vault_key_name(root), &key, security)?;
+        say!(
+            self,
+            "Vault initialized; {} note(s), {} attachment(s) and {} snapshot(s) encrypted.",
+            encrypted, blobs, snapshots
+        );
+        Ok(encrypted)
+    }
+
+    pub fn vault_unlock(&self, root: &Path, security: &SecurityConfig) -> Result<(), NoteError> {
+        let vault = Vault::load(root)?;
+        let key = vault.unlock(&read_passphrase("Vault passphrase: ")?)?;
+        cache_key(&vault_key_name(root), &key, security)?;
+        say!(self, "Vault unlocked for {} minute(s).", security.agent_timeout_secs / 60);
+        Ok(())
+    }
+
+    pub fn vault_lock(&self) -> Result<(), NoteError> {
+        forget_keys()?;
+        say!(self, "All cached keys forgotten.");
+        Ok(())
+    }
+
+    pub fn vault_status(&self, root: &Path) -> Result<VaultStatus, NoteError> {
+        let status = if !Vault::is_initialized(root) {
+            VaultStatus::Unencrypted
+        } else if cached_key(&vault_key_name(root)).is_some() {
+            VaultStatus::Unlocked
+        } else {
+            VaultStatus::Locked
+        };
+        match status {
+            VaultStatus::Unencrypted => say!(self, "Store is not encrypted."),
+            VaultStatus::Unlocked => say!(self, "Store is encrypted and unlocked."),
+            VaultStatus::Locked => say!(self, "Store is encrypted and locked."),
+        }
+        Ok(status)
+    }
+
+    pub fn lock_note(&mut self, id: &NoteId, security: &SecurityConfig) -> Result<(), NoteError> {
+        let mut note = self.storage.load_note(id)?;
+        let kdf = KdfParams::generate()?;
+        let key = kdf.derive_key(&read_new_passphrase()?)?;
+
+        crypto::lock_note(&mut note, &key, kdf)?;
+        note.updated_at = Utc::now();
+        self.storage.update_note(&mut note)?;
+        self.record(&format!("Lock {}", self.subject(&note)))?;
+        cache_key(&format!("note:{}", note.id), &key, security)?;
+
+        say!(self, "Note {} locked.", id);
+        Ok(())
+    }
+
+    pub fn unlock_note(&mut self, id: &NoteId) -> Result<(), NoteError> {
+        let mut note = self.storage.load_note(id)?;
+        let key = self.note_key(&note)?;
+
+        crypto::unlock_note(&mut note, &key)?;
+        note.updated_at = Utc::now();
+        self.storage.update_note(&mut note)?;
+        self.record(&format!("Unlock {}", self.subject(&note)))?;
+
+        say!(self, "Note {} unlocked.", id);
+        Ok(())
+    }
+
+    /// Upgrades notes in every notebook to the current schema
+    pub fn migrate_store(&self, notebooks: &NotebookStore, dry_run: bool) -> Result<usize, NoteError> {
+        let mut total = 0;
+        for (name, _) in notebooks.list()? {
+            let migrated = notebooks.open(&name)?.migrate(dry_run)?;
+            for (id, version) in &migrated {
+                say!(self, "{}/{}: schema version {} -> {}", name, id, version, CURRENT_SCHEMA_VERSION);
+            }
+            total += migrated.len();
+        }
+
+        if dry_run {
+            say!(self, "{} note(s) would be migrated.", total);
+        } else {
+            self.record(&format!("Migrate notes to schema version {}", CURRENT_SCHEMA_VERSION))?;
+            say!(self, "{} note(s) migrated to schema version {}.", total, CURRENT_SCHEMA_VERSION);
+        }
+        Ok(total)
+    }
+
+    pub fn doctor(&self, notebooks: &NotebookStore, attachments: &AttachmentStore, fix: bool) -> Result<DoctorReport, NoteError> {
+        let report = doctor::check(notebooks, attachments, fix)?;
+        if fix {
+            self.record("Repair note store")?;
+        }
+        for issue in &report.issues {
+            let status = if issue.fixed { " (fixed)" } else { "" };
+            say!(self, "{}: {}{}", issue.path.display(), issue.kind, status);
+        }
+
+        if report.is_healthy() {
+            say!(self, "Checked {} note(s); no problems found.", report.notes_checked);
+        } else {
+            say!(self,
+                "Checked {} note(s); {} problem(s) found, {} remaining.",
+                report.notes_checked,
+                report.issues.len(),
+                report.remaining()
+            );
+            if !fix && report.remaining() > 0 {
+                say!(self, "Run `notes doctor --fix` to repair what can be repaired safely.");
+            }
+        }
+        Ok(report)
+    }
+
+    pub fn backup_create(&self, backups: &BackupStore, sources: &BackupSources) -> Result<Snapshot, NoteError> {
+        let snapshot = backups.create(sources, SnapshotKind::Manual)?;
+        say!(self, "Snapshot {} created ({} bytes).", snapshot.name, snapshot.size);
+        Ok(snapshot)
+    }
+
+    pub fn backup_list(&self, backups: &BackupStore) -> Result<Vec<Snapshot>, NoteError> {
+        let snapshots = backups.list()?;
+        if snapshots.is_empty() {
+            say!(self, "No snapshots in {}.", backups.dir().display());
+        }
+        for snapshot in &snapshots {
+            say!(self,
+                "{}  {:<11}  {:>10} bytes  {}",
+                snapshot.created_at.format("%Y-%m-%d %H:%M:%S"),
+                snapshot.kind.as_str(),
+                snapshot.size,
+                snapshot.name
+            );
+        }
+        Ok(snapshots)
+    }
+
+    pub fn backup_restore(
+        &self,
+        backups: &BackupStore,
+        sources: &BackupSources,
+        notebooks: &NotebookStore,
+        attachments: &AttachmentStore,
+        default_notebook: &str,
+        snapshot: &str,
+        only: Option<&NoteId>,
+    ) -> Result<(), NoteError> {
+        match only {
+            Some(id) => {
+                let notebook = backups.restore_note(snapshot, id, notebooks, attachments, default_notebook)?;
+                self.record(&format!("Restore note {} from {}", id, snapshot))?;
+                say!(self, "Note {} restored into notebook '{}'.", id, notebook);
+            }
+            None => {
+                let safety = backups.restore(snapshot, sources)?;
+                self.record(&format!("Restore note store from {}", snapshot))?;
+                say!(self, "Store restored from {}.", snapshot);
+                say!(self, "The previous state was saved as {}.", safety.name);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn git_log(&self, id: &NoteId) -> Result<Vec<CommitInfo>, NoteError> {
+        let commits = self.git()?.log(id)?;
+        if commits.is_empty() {
+            say!(self, "No history for note {}.", id);
+        }
+        for commit in &commits {
+            say!(self, "{}  {}  {:<20}  {}", &commit.hash[..10], commit.date.format("%Y-%m-%d %H:%M"), commit.author, commit.subject);
+        }
+        Ok(commits)
+    }
+
+    pub fn sync(&self, config: &GitConfig) -> Result<SyncReport, NoteError> {
+        let remote = config.remote.as_deref().ok_or_else(|| {
+            NoteError::InvalidInput("No git remote configured; set `remote` under [git] in the config".to_string())
+        })?;
+        let report = self.git()?.sync(remote, &config.branch)?;
+        say!(self, "Synced with {}: {} commit(s) pulled, {} pushed.", remote, report.pulled, report.pushed);
+        Ok(report)
+    }
+
+    /// Syncs every notebook with the notes directory `other` in both directions
+    pub fn sync_with(&self, notebooks: &NotebookStore, other: &NotebookStore, config: &SyncConfig) -> Result<SyncSummary, NoteError> {
+        let results = dirsync::sync_notebooks(notebooks, other, config.tombstone_age())?;
+        self.report_sync(&results, &other.root().display().to_string())
+    }
+
+    /// Syncs every notebook with the `notes serve --sync` server at `url`; `encrypt`
+    /// turns on end-to-end encryption for a server without notes yet
+    pub fn sync_remote(&self, notebooks: &NotebookStore, url: &str, encrypt: bool, rotate_key: bool, config: &SyncConfig) -> Result<SyncSummary, NoteError> {
+        let mut options = RemoteOptions::new(|prompt| read_passphrase(&format!("{}: ", prompt)));
+        options.encrypt = encrypt;
+        options.rotate_key = rotate_key;
+        options.token = std::env::var("NOTES_SYNC_TOKEN").ok();
+        options.tombstone_age = config.tombstone_age();
+        let results = httpsync::sync_remote(notebooks, url, options)?;
+        let total = self.report_sync(&results, url)?;
+        if total.rejected > 0 {
+            say!(self, "{} local change(s) raced with changes on the server; run `notes sync --remote {}` again.", total.rejected, url);
+        }
+        Ok(total)
+    }
+
+    fn report_sync(&self, results: &[(String, SyncSummary)], other: &str) -> Result<SyncSummary, NoteError> {
+        for (name, summary) in results {
+            if summary.pulled + summary.pushed + summary.deleted_here + summary.deleted_there > 0 {
+                say!(self, "{}: {} pulled, {} pushed, {} deleted here, {} deleted there",
+                    name, summary.pulled, summary.pushed, summary.deleted_here, summary.deleted_there);
+            }
+            for id in &summary.unresolved {
+                say!(self, "{}: note {} was changed on both sides; resolve the conflict markers in it", name, id);
+            }
+            for (id, copy) in &summary.conflicts {
+                say!(self, "{}: note {} was changed on both sides; the older version is now note {}", name, id, copy);
+            }
+        }
+
+        let total = dirsync::total(results);
+        if !total.is_empty() {
+            self.record(&format!("Sync with {}", other))?;
+        }
+        say!(self, "Synced with {}: {} pulled, {} pushed, {} merged, {} conflict(s).",
+            other, total.pulled, total.pushed, total.merged,
+            total.unresolved.len() + total.conflicts.len());
+        Ok(total)
+    }
+
+    /// Serves the API when tokens are configured, and the sync protocol with `sync`, on `bind` until killed.
+    /// The sync protocol then takes the same tokens; without any it is open to everyone who can connect.
+    pub fn serve(&self, notebooks: &NotebookStore, default_notebook: &str, api: &ApiConfig, bind: &str, sync: bool) -> Result<(), NoteError> {
+        let api = match api.tokens.is_empty() {
+            true => None,
+            false => Some(self.api_server(notebooks, default_notebook, api.tokens.clone())?),
+        };
+        if api.is_none() && !sync {
+            return Err(NoteError::InvalidInput(
+                "Nothing to serve: set tokens under [api] in the config, or pass --sync".to_string(),
+            ));
+        }
+        let sync = match sync {
+            true => Some(SyncServer::new(notebooks.clone())?),
+            false => None,
+        };
+
+        let listener = TcpListener::bind(bind)?;
+        let addr = listener.local_addr()?;
+        if api.is_some() {
+            eprintln!("Serving the API of {} at http://{}{}...", notebooks.root().display(), addr, API_PREFIX);
+        }
+        if sync.is_some() {
+            eprintln!("Serving {} for `notes sync --remote http://{}`...", notebooks.root().display(), addr);
+        }
+        http::run(listener, move |request| match (&sync, &api) {
+            (Some(sync), Some(api)) if request.path.starts_with("/sync/") => api.authorize(&request, |request| sync.handle(request)),
+            (Some(sync), None) if request.path.starts_with("/sync/") => sync.handle(&request),
+            (_, Some(api)) => api.handle(&request),
+            _ => http::Response::error(404, "not found"),
+        })
+    }
+
+    /// Serves the web UI with a fresh token, printed as part of the address to open
+    pub fn web(&self, notebooks: &NotebookStore, default_notebook: &str, api: &ApiConfig, bind: &str) -> Result<(), NoteError> {
+        let token = web::session_token()?;
+        let mut tokens = api.tokens.clone();
+        tokens.push(token.clone());
+        let server = WebServer::new(self.api_server(notebooks, default_notebook, tokens)?);
+
+        let listener = TcpListener::bind(bind)?;
+        let addr = listener.local_addr()?;
+        if !addr.ip().is_loopback() {
+            eprintln!("Warning: the web UI is reachable from other machines at {}", addr);
+        }
+        say!(self, "Open http://{}/#token={}", addr, token);
+        http::run(listener, move |request| server.handle(&request))
+    }
+
+    /// Speaks LSP on stdin and stdout until the editor exits
+    pub fn lsp(&self, notebooks: &NotebookStore) -> Result<(), NoteError> {
+        lsp::run(notebooks.clone(), io::stdin().lock(), io::stdout().lock())
+    }
+
+    /// Answers JSON-RPC 2.0 calls on stdin and stdout until stdin closes. Methods are named
+    /// after the handler's own, plus `search`; nothing is printed besides the replies.
+    pub fn rpc(&mut self, context: &RpcContext) -> Result<(), NoteError> {
+        self.quiet = true;
+        rpc::serve(io::stdin().lock(), io::stdout().lock(), |method, params| self.call(context, method, params))
+    }
+
+    fn call(&mut self, context: &RpcContext, method: &str, params: Value) -> Result<Value, RpcError> {
+        let today = Local::now().date_naive();
+        match method {
+            "create_note" => {
+                let p: rpc::CreateParams = rpc::params(params)?;
+                reply(self.create_note(p.title, p.content, p.tags)?)
+            }
+            "create_note_from_template" => {
+                let p: rpc::TemplateParams = rpc::params(params)?;
+                reply(self.create_note_from_template(context.templates, &p.template, p.title, p.tags)?)
+            }
+            "list_notes" => {
+                let p: rpc::ListParams = rpc::params(params)?;
+                reply(self.list_notes(p.tag.as_deref(), p.limit)?)
+            }
+            "view_note" => {
+                let p: rpc::IdParams = rpc::params(params)?;
+                reply(self.view_note(&p.id)?)
+            }
+            "update_note" => {
+                let p: rpc::UpdateParams = rpc::params(params)?;
+                reply(self.update_note(&p.id, p.title, p.content, p.tags)?)
+            }
+            "delete_note" => {
+                let p: rpc::IdParams = rpc::params(params)?;
+                reply(self.delete_note(&p.id)?)
+            }
+            "search" => {
+                let p: rpc::SearchParams = rpc::params(params)?;
+                let query = p.to_query();
+                let mut notes: Vec<NoteSummary> = self.storage.list_notes()?.iter()
+                    .filter(|note| query.matches(note))
+                    .map(NoteSummary::from)
+                    .collect();
+                notes.sort_by_key(|note| Reverse(note.created_at));
+                reply(notes)
+            }
+            "open_journal" => {
+                let p: rpc::JournalParams = rpc::params(params)?;
+                reply(self.open_journal(context.templates, context.journal, p.date.unwrap_or(today))?)
+            }
+            "step_journal" => {
+                let p: rpc::JournalParams = rpc::params(params)?;
+                reply(self.step_journal(context.templates, context.journal, p.date.unwrap_or(today), p.forward)?)
+            }
+            "list_journal_week" => {
+                let p: rpc::JournalParams = rpc::params(params)?;
+                reply(self.list_journal_week(p.date.unwrap_or(today))?)
+            }
+            "list_tags" => {
+                let counts = self.list_tags()?;
+                reply(counts.into_iter().map(|(tag, count)| TagCount { tag, count }).collect::<Vec<_>>())
+            }
+            "rename_tag" => {
+                let p: rpc::RenameParams = rpc::params(params)?;
+                reply(self.rename_tag(&p.old, &p.new)?)
+            }
+            "merge_tags" => {
+                let p: rpc::MergeParams = rpc::params(params)?;
+                reply(self.merge_tags(&p.source, &p.target)?)
+            }
+            "delete_tag" => {
+                let p: rpc::TagParams = rpc::params(params)?;
+                reply(self.delete_tag(&p.tag)?)
+            }
+            "create_notebook" => {
+                let p: rpc::NotebookParams = rpc::params(params)?;
+                reply(self.create_notebook(context.notebooks, &p.name)?)
+            }
+            "list_notebooks" => {
+                let list = self.list_notebooks(context.notebooks, context.notebook)?;
+                reply(list.into_iter().map(|(name, notes)| NotebookInfo { name, notes }).collect::<Vec<_>>())
+            }
+            "rename_notebook" => {
+                let p: rpc::RenameParams = rpc::params(params)?;
+                reply(self.rename_notebook(context.notebooks, &p.old, &p.new)?)
+            }
+            "delete_notebook" => {
+                let p: rpc::NotebookParams = rpc::params(params)?;
+                reply(self.delete_notebook(context.notebooks, &p.name, p.force)?)
+            }
+            "move_note_to_notebook" => {
+                let p: rpc::MoveParams = rpc::params(params)?;
+                reply(self.move_note_to_notebook(context.notebooks, &p.id, &p.notebook)?)
+            }
+            "attach_file" => {
+                let p: rpc::AttachParams = rpc::params(params)?;
+                reply(self.attach_file(context.attachments, &p.id, &p.file, p.name.as_deref())?)
+            }
+            "list_attachments" => {
+                let p: rpc::IdParams = rpc::params(params)?;
+                reply(self.list_attachments(&p.id)?)
+            }
+            "detach" => {
+                let p: rpc::AttachmentParams = rpc::params(params)?;
+                reply(self.detach(&p.id, &p.attachment)?)
+            }
+            "open_attachment" => {
+                let p: rpc::AttachmentParams = rpc::params(params)?;
+                reply(self.open_attachment(context.attachments, &p.id, &p.attachment, p.output.as_deref())?)
+            }
+            "export_json_bundle" => {
+                let p: rpc::ExportParams = rpc::params(params)?;
+                reply(self.export_json_bundle(context.attachments, Some(&p.output), p.tag.as_deref())?)
+            }
+            "import_json_bundle" => {
+                let p: rpc::ImportParams = rpc::params(params)?;
+                reply(self.import_json_bundle(context.attachments, &p.file)?)
+            }
+            "vault_init" => reply(self.vault_init(
+                context.notebooks,
+                context.attachments,
+                context.backups,
+                context.default_notebook,
+                context.security,
+            )?),
+            "vault_unlock" => reply(self.vault_unlock(context.notebooks.root(), context.security)?),
+            "vault_lock" => reply(self.vault_lock()?),
+            "vault_status" => reply(self.vault_status(context.notebooks.root())?),
+            "lock_note" => {
+                let p: rpc::IdParams = rpc::params(params)?;
+                reply(self.lock_note(&p.id, context.security)?)
+            }
+            "unlock_note" => {
+                let p: rpc::IdParams = rpc::params(params)?;
+                reply(self.unlock_note(&p.id)?)
+            }
+            "migrate_store" => {
+                let p: rpc::MigrateParams = rpc::params(params)?;
+                reply(self.migrate_store(context.notebooks, p.dry_run)?)
+            }
+            "doctor" => {
+                let p: rpc::DoctorParams = rpc::params(params)?;
+                reply(self.doctor(context.notebooks, context.attachments, p.fix)?)
+            }
+            "backup_create" => reply(self.backup_create(context.backups, context.backup_sources)?),
+            "backup_list" => reply(self.backup_list(context.backups)?),
+            "backup_restore" => {
+                let p: rpc::RestoreParams = rpc::params(params)?;
+                reply(self.backup_restore(
+                    context.backups,
+                    context.backup_sources,
+                    context.notebooks,
+                    context.attachments,
+                    context.default_notebook,
+                    &p.snapshot,
+                    p.id.as_ref(),
+                )?)
+            }
+            "git_log" => {
+                let p: rpc::IdParams = rpc::params(params)?;
+                reply(self.git_log(&p.id)?)
+            }
+            "sync" => reply(self.sync(context.git)?),
+            "sync_with" => {
+                let p: rpc::SyncWithParams = rpc::params(params)?;
+                let other = NotebookStore::new(&p.path, context.notebook)?;
+                reply(self.sync_with(context.notebooks, &other, context.sync)?)
+            }
+            "sync_remote" => {
+                let p: rpc::SyncRemoteParams = rpc::params(params)?;
+                reply(self.sync_remote(context.notebooks, &p.url, p.encrypt, p.rotate_key, context.sync)?)
+            }
+            "reindex" => reply(self.reindex(context.notebooks)?),
+            // serve, web, lsp, watch and rpc run until killed, so they are not calls
+            _ => Err(RpcError::method_not_found(method)),
+        }
+    }
+
+    pub fn reindex(&self, notebooks: &NotebookStore) -> Result<usize, NoteError> {
+        let mut total = 0;
+        for (name, _) in notebooks.list()? {
+            let indexed = notebooks.open(&name)?.rebuild_index()?;
+            say!(self, "{}: {} note(s) indexed", name, indexed);
+            total += indexed;
+        }
+        say!(self, "Rebuilt the index of {} note(s).", total);
+        Ok(total)
+    }
+
+    /// Prints a JSON line for every note file created, changed or removed from now on. Runs until killed.
+    pub fn watch(&self, notebooks: &NotebookStore) -> Result<(), NoteError> {
+        let mut watcher = Watcher::new(notebooks)?;
+        eprintln!("Watching {} for changes...", notebooks.root().display());
+
+        let stdout = io::stdout();
+        loop {
+            for event in watcher.wait(watch::POLL_INTERVAL)? {
+                let mut out = stdout.lock();
+                writeln!(out, "{}", serde_json::to_string(&event)?)?;
+                out.flush()?;
+            }
+        }
+    }
+
+    fn note_key(&self, note: &Note) -> Result<VaultKey, NoteError> {
+        let locked = note.locked.as_ref()
+            .ok_or_else(|| NoteError::InvalidInput(format!("Note {} is not locked", note.id)))?;
+
+        if let Some(key) = cached_key(&format!("note:{}", note.id)) {
+            if locked.check_key(&key).is_ok() {
+                return Ok(key);
+            }
+        }
+        locked.derive_key(&read_passphrase("Note passphrase: ")?)
+    }
+
+    pub fn view_note(&self, id: &NoteId) -> Result<Note, NoteError> {
+        let note = self.storage.load_note(id)?;
+        // Locked content is only revealed for printing
+        if self.quiet {
+            return Ok(note);
+        }
         
-        println!("Title: {}", note.title);
-        println!("ID: {}", note.id);
-        println!("Created: {}", note.created_at.format("%Y-%m-%d %H:%M:%S UTC"));
-        println!("Updated: {}", note.updated_at.format("%Y-%m-%d %H:%M:%S UTC"));
+        say!(self, "Title: {}", note.title);
+        say!(self, "ID: {}", note.id);
+        say!(self, "Created: {}", note.created_at.format("%Y-%m-%d %H:%M:%S UTC"));
+        say!(self, "Updated: {}", note.updated_at.format("%Y-%m-%d %H:%M:%S UTC"));
         
         if !note.tags.is_empty() {
-            println!("Tags: {}", note.tags.join(", "));
+            say!(self, "Tags: {}", note.tags.join(", "));
         }
         
-        println!("\nContent:");
-        println!("{}", "-".repeat(50));
-        println!("{}", note.content);
-        println!("{}", "-".repeat(50));
+        let content = if note.is_locked() {
+            crypto::reveal_note(&note, &self.note_key(&note)?)?
+        } else {
+            note.content.clone()
+        };
+
+        say!(self, "\nContent:");
+        say!(self, "{}", "-".repeat(50));
+        say!(self, "{}", content);
+        say!(self, "{}", "-".repeat(50));
         
         Ok(note)
     }
 
-    pub fn update_note(&mut self, id: &str, title: Option<String>, content: Option<String>, tags: Option<Vec<String>>) -> Result<(), NoteError> {
+    pub fn update_note(&mut self, id: &NoteId, title: Option<String>, content: Option<String>, tags: Option<Vec<String>>) -> Result<(), NoteError> {
         let mut note = self.storage.load_note(id)?;
         
+        if note.is_locked() && content.is_some() {
+            return Err(NoteError::InvalidInput(format!("Note {} is locked; run `notes unlock {}` first", id, id)));
+        }
+        
         let mut updated = false;
         
         if let Some(new_title) = title {
@@ -103,13 +1096,14 @@ impl CommandHandler {
         
         if updated {
             note.updated_at = Utc::now();
-            self.storage.save_note(&note)?;
-            println!("Note updated successfully.");
+            self.storage.update_note(&mut note)?;
+            self.record(&format!("Update {}", self.subject(&note)))?;
+            say!(self, "Note updated successfully.");
         } else {
-            println!("No changes detected.");
+            say!(self, "No changes detected.");
         }
         
         Ok(())
     }
 
-    pub fn delete_note(&mut self, id: &str
\ No newline at end of file
+    pub fn delete_note(&mut self, id: &NoteId
\ No newline at end of file
@@ -0,0 +1,226 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::error::{NoteError, Result};
+
+/// Application configuration, loaded from `~/.config/rust-notes/config.toml`
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+    pub general: GeneralConfig,
+    pub display: DisplayConfig,
+    pub search: SearchConfig,
+    pub journal: JournalConfig,
+    pub security: SecurityConfig,
+    pub backup: BackupConfig,
+    pub git: GitConfig,
+    pub sync: SyncConfig,
+    pub api: ApiConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GeneralConfig {
+    pub notes_dir: PathBuf,
+    pub default_notebook: String,
+    pub attachments_dir: PathBuf,
+    pub templates_dir: PathBuf,
+    pub default_editor: String,
+    pub date_format: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DisplayConfig {
+    pub max_title_length: usize,
+    pub show_tags: bool,
+    pub show_dates: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SearchConfig {
+    pub case_sensitive: bool,
+    pub fuzzy_search: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JournalConfig {
+    /// `chrono` format string used to build the title of a journal entry
+    pub title_format: String,
+    /// Template applied when a new entry is created
+    pub template: Option<String>,
+    pub tag: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SecurityConfig {
+    /// How long the session agent keeps an unlocked key, in seconds
+    pub agent_timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BackupConfig {
+    pub dir: PathBuf,
+    /// Take a snapshot before the first change of each day
+    pub auto: bool,
+    /// Number of days, then weeks, for which one automatic snapshot is kept
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GitConfig {
+    /// Keep the notes directory in a git repository and commit every change
+    pub enabled: bool,
+    /// Remote URL used by `notes sync`
+    pub remote: Option<String>,
+    pub branch: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SyncConfig {
+    /// Days after which a tombstone is dropped, even if a replica may not have seen the delete
+    pub tombstone_days: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct ApiConfig {
+    /// Bearer tokens accepted by the API of `notes serve`; the API is off while empty
+    pub tokens: Vec<String>,
+}
+
+impl Default for GeneralConfig {
+    fn default() -> Self {
+        Self {
+            notes_dir: Config::data_dir().join("notes"),
+            default_notebook: "personal".to_string(),
+            attachments_dir: Config::data_dir().join("attachments"),
+            templates_dir: Config::config_dir().join("templates"),
+            default_editor: "nano".to_string(),
+            date_format: "%Y-%m-%d %H:%M:%S".to_string(),
+        }
+    }
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        Self {
+            max_title_length: 50,
+            show_tags: true,
+            show_dates: true,
+        }
+    }
+}
+
+impl Default for SearchConfig {
+    fn default() -> Self {
+        Self {
+            case_sensitive: false,
+            fuzzy_search: true,
+        }
+    }
+}
+
+impl Default for JournalConfig {
+    fn default() -> Self {
+        Self {
+            title_format: "Journal %Y-%m-%d".to_string(),
+            template: None,
+            tag: "journal".to_string(),
+        }
+    }
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        Self {
+            agent_timeout_secs: 15 * 60,
+        }
+    }
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        Self {
+            dir: Config::data_dir().join("backups"),
+            auto: true,
+            keep_daily: 7,
+            keep_weekly: 4,
+        }
+    }
+}
+
+impl Default for GitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            remote: None,
+            branch: "main".to_string(),
+        }
+    }
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        Self { tombstone_days: crate::dirsync::TOMBSTONE_DAYS }
+    }
+}
+
+impl SyncConfig {
+    pub fn tombstone_age(&self) -> chrono::Duration {
+        chrono::Duration::days(self.tombstone_days.into())
+    }
+}
+
+impl Config {
+    /// Directory holding `config.toml` and user templates
+    pub fn config_dir() -> PathBuf {
+        dirs::config_dir()
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("rust-notes")
+    }
+
+    /// Directory holding the note store
+    pub fn data_dir() -> PathBuf {
+        dirs::data_local_dir()
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("rust-notes")
+    }
+
+    pub fn default_path() -> PathBuf {
+        Self::config_dir().join("config.toml")
+    }
+
+    /// Loads the configuration from the default location, falling back to defaults
+    pub fn load() -> Result<Self> {
+        Self::load_from(&Self::default_path())
+    }
+
+    pub fn load_from(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let data = fs::read_to_string(path)?;
+        toml::from_str(&data)
+            .map_err(|e| NoteError::SerializationError(format!("Invalid config file {:?}: {}", path, e)))
+    }
+
+    pub fn save_to(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let data = toml::to_string_pretty(self)
+            .map_err(|e| NoteError::SerializationError(e.to_string()))?;
+        fs::write(path, data)?;
+        Ok(())
+    }
+}
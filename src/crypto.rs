@@ -0,0 +1,294 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroize;
+use crate::error::{NoteError, Result};
+use crate::note::Note;
+
+pub const VAULT_MANIFEST: &str = "vault.json";
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const VERIFIER_PLAINTEXT: &[u8] = b"rust-notes vault key check";
+/// Leading bytes of a binary file sealed with [`seal`]
+const SEALED_MAGIC: &[u8] = b"rust-notes sealed v1\n";
+
+/// A 256-bit symmetric key, wiped from memory when dropped
+#[derive(Clone)]
+pub struct VaultKey([u8; KEY_LEN]);
+
+impl VaultKey {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let bytes: [u8; KEY_LEN] = bytes
+            .try_into()
+            .map_err(|_| NoteError::InvalidInput(format!("Vault keys must be {} bytes", KEY_LEN)))?;
+        Ok(VaultKey(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Drop for VaultKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl std::fmt::Debug for VaultKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("VaultKey(..)")
+    }
+}
+
+/// Argon2id parameters and salt used to turn a passphrase into a [`VaultKey`]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct KdfParams {
+    pub salt: String,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl KdfParams {
+    /// Fresh salt with the recommended Argon2id cost
+    pub fn generate() -> Result<Self> {
+        Self::generate_with_cost(19 * 1024, 2, 1)
+    }
+
+    pub fn generate_with_cost(memory_kib: u32, iterations: u32, parallelism: u32) -> Result<Self> {
+        Ok(Self {
+            salt: BASE64.encode(random_bytes::<SALT_LEN>()?),
+            memory_kib,
+            iterations,
+            parallelism,
+        })
+    }
+
+    pub fn derive_key(&self, passphrase: &str) -> Result<VaultKey> {
+        let salt = BASE64
+            .decode(&self.salt)
+            .map_err(|e| NoteError::CorruptedCiphertext(format!("Invalid key salt: {}", e)))?;
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, Some(KEY_LEN))
+            .map_err(|e| NoteError::InvalidInput(format!("Invalid key derivation parameters: {}", e)))?;
+
+        let mut key = [0u8; KEY_LEN];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|e| NoteError::InvalidInput(format!("Key derivation failed: {}", e)))?;
+        Ok(VaultKey(key))
+    }
+}
+
+/// XChaCha20-Poly1305 ciphertext with its random nonce, both base64-encoded
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct EncryptedBlob {
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+/// On-disk form of a note in an encrypted store
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedEnvelope {
+    pub encrypted: EncryptedBlob,
+}
+
+/// Encrypts `plaintext`, binding it to `context` (e.g. the note id) so a blob
+/// copied onto another note fails to decrypt.
+pub fn encrypt(key: &VaultKey, plaintext: &[u8], context: &[u8]) -> Result<EncryptedBlob> {
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key.as_bytes()));
+    let nonce_bytes = random_bytes::<NONCE_LEN>()?;
+    let ciphertext = cipher
+        .encrypt(XNonce::from_slice(&nonce_bytes), Payload { msg: plaintext, aad: context })
+        .map_err(|_| NoteError::InvalidInput("Encryption failed".to_string()))?;
+
+    Ok(EncryptedBlob {
+        nonce: BASE64.encode(nonce_bytes),
+        ciphertext: BASE64.encode(ciphertext),
+    })
+}
+
+/// Decrypts a blob. Any failure is reported as corruption; callers that can
+/// tell a wrong key apart (see [`Vault::unlock`]) check the key first.
+pub fn decrypt(key: &VaultKey, blob: &EncryptedBlob, context: &[u8]) -> Result<Vec<u8>> {
+    let nonce = BASE64
+        .decode(&blob.nonce)
+        .map_err(|e| NoteError::CorruptedCiphertext(format!("Invalid nonce: {}", e)))?;
+    let ciphertext = BASE64
+        .decode(&blob.ciphertext)
+        .map_err(|e| NoteError::CorruptedCiphertext(format!("Invalid ciphertext encoding: {}", e)))?;
+    if nonce.len() != NONCE_LEN {
+        return Err(NoteError::CorruptedCiphertext("Invalid nonce length".to_string()));
+    }
+
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key.as_bytes()));
+    cipher
+        .decrypt(XNonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: context })
+        .map_err(|_| NoteError::CorruptedCiphertext("Authentication tag mismatch".to_string()))
+}
+
+/// Encrypts a binary file such as an attachment blob into its raw on-disk form:
+/// a magic prefix, the nonce and the ciphertext, with no base64 overhead.
+pub fn seal(key: &VaultKey, plaintext: &[u8], context: &[u8]) -> Result<Vec<u8>> {
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key.as_bytes()));
+    let nonce_bytes = random_bytes::<NONCE_LEN>()?;
+    let ciphertext = cipher
+        .encrypt(XNonce::from_slice(&nonce_bytes), Payload { msg: plaintext, aad: context })
+        .map_err(|_| NoteError::InvalidInput("Encryption failed".to_string()))?;
+
+    let mut sealed = Vec::with_capacity(SEALED_MAGIC.len() + NONCE_LEN + ciphertext.len());
+    sealed.extend_from_slice(SEALED_MAGIC);
+    sealed.extend_from_slice(&nonce_bytes);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(SEALED_MAGIC)
+}
+
+/// Decrypts the output of [`seal`]
+pub fn open_sealed(key: &VaultKey, sealed: &[u8], context: &[u8]) -> Result<Vec<u8>> {
+    let body = sealed
+        .strip_prefix(SEALED_MAGIC)
+        .filter(|body| body.len() >= NONCE_LEN)
+        .ok_or_else(|| NoteError::CorruptedCiphertext("Not a sealed file".to_string()))?;
+    let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key.as_bytes()));
+    cipher
+        .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad: context })
+        .map_err(|_| NoteError::CorruptedCiphertext("Authentication tag mismatch".to_string()))
+}
+
+pub(crate) fn random_bytes<const N: usize>() -> Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|e| NoteError::IoError(std::io::Error::other(e.to_string())))?;
+    Ok(bytes)
+}
+
+/// Manifest stored as `vault.json` in the notes directory of an encrypted store.
+/// The verifier lets a wrong passphrase be told apart from corrupted notes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Vault {
+    pub version: u32,
+    pub kdf: KdfParams,
+    pub verifier: EncryptedBlob,
+}
+
+impl Vault {
+    pub fn manifest_path(root: &Path) -> PathBuf {
+        root.join(VAULT_MANIFEST)
+    }
+
+    pub fn is_initialized(root: &Path) -> bool {
+        Self::manifest_path(root).is_file()
+    }
+
+    pub fn load(root: &Path) -> Result<Self> {
+        let path = Self::manifest_path(root);
+        if !path.is_file() {
+            return Err(NoteError::NotFound(format!("No encrypted vault in {:?}", root)));
+        }
+        let json_data = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json_data)?)
+    }
+
+    /// Creates the manifest for a new vault and returns its key
+    pub fn init(root: &Path, passphrase: &str, kdf: KdfParams) -> Result<VaultKey> {
+        if Self::is_initialized(root) {
+            return Err(NoteError::InvalidInput(format!("{:?} is already an encrypted vault", root)));
+        }
+        if passphrase.is_empty() {
+            return Err(NoteError::ValidationError("Passphrase cannot be empty".to_string()));
+        }
+
+        let key = kdf.derive_key(passphrase)?;
+        let vault = Vault {
+            version: 1,
+            verifier: encrypt(&key, VERIFIER_PLAINTEXT, VAULT_MANIFEST.as_bytes())?,
+            kdf,
+        };
+        fs::create_dir_all(root)?;
+        fs::write(Self::manifest_path(root), serde_json::to_string_pretty(&vault)?)?;
+        Ok(key)
+    }
+
+    pub fn unlock(&self, passphrase: &str) -> Result<VaultKey> {
+        let key = self.kdf.derive_key(passphrase)?;
+        self.check_key(&key)?;
+        Ok(key)
+    }
+
+    pub fn check_key(&self, key: &VaultKey) -> Result<()> {
+        match decrypt(key, &self.verifier, VAULT_MANIFEST.as_bytes()) {
+            Ok(plaintext) if plaintext == VERIFIER_PLAINTEXT => Ok(()),
+            Ok(_) => Err(NoteError::CorruptedCiphertext("Vault verifier has unexpected contents".to_string())),
+            Err(_) => Err(NoteError::WrongPassphrase),
+        }
+    }
+}
+
+/// Content of a note locked with its own passphrase via `notes lock`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LockedContent {
+    pub kdf: KdfParams,
+    pub verifier: EncryptedBlob,
+    pub content: EncryptedBlob,
+}
+
+impl LockedContent {
+    pub fn derive_key(&self, passphrase: &str) -> Result<VaultKey> {
+        let key = self.kdf.derive_key(passphrase)?;
+        self.check_key(&key)?;
+        Ok(key)
+    }
+
+    pub fn check_key(&self, key: &VaultKey) -> Result<()> {
+        decrypt(key, &self.verifier, VERIFIER_PLAINTEXT)
+            .map(|_| ())
+            .map_err(|_| NoteError::WrongPassphrase)
+    }
+}
+
+/// Encrypts the content of `note` with `key`, leaving title and tags readable
+pub fn lock_note(note: &mut Note, key: &VaultKey, kdf: KdfParams) -> Result<()> {
+    if note.locked.is_some() {
+        return Err(NoteError::InvalidInput(format!("Note {} is already locked", note.id)));
+    }
+
+    note.locked = Some(LockedContent {
+        kdf,
+        verifier: encrypt(key, note.id.as_bytes(), VERIFIER_PLAINTEXT)?,
+        content: encrypt(key, note.content.as_bytes(), note.id.as_bytes())?,
+    });
+    note.content.zeroize();
+    Ok(())
+}
+
+/// Decrypts the content of a locked note without modifying it
+pub fn reveal_note(note: &Note, key: &VaultKey) -> Result<String> {
+    let locked = note
+        .locked
+        .as_ref()
+        .ok_or_else(|| NoteError::InvalidInput(format!("Note {} is not locked", note.id)))?;
+    locked.check_key(key)?;
+
+    let plaintext = decrypt(key, &locked.content, note.id.as_bytes())?;
+    String::from_utf8(plaintext)
+        .map_err(|_| NoteError::CorruptedCiphertext("Locked content is not valid UTF-8".to_string()))
+}
+
+/// Permanently removes the lock from a note, restoring its plaintext content
+pub fn unlock_note(note: &mut Note, key: &VaultKey) -> Result<()> {
+    note.content = reveal_note(note, key)?;
+    note.locked = None;
+    Ok(())
+}
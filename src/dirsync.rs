@@ -0,0 +1,410 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use crate::error::{NoteError, Result};
+use crate::merge;
+use crate::note::{Note, NoteId};
+use crate::notebook::NotebookStore;
+use crate::schema;
+use crate::storage::{is_note_file, FileStorage};
+
+/// Per-directory record of past syncs and deleted notes
+pub const SYNC_STATE_FILE: &str = "sync.json";
+/// Directory inside each notebook keeping, per peer, every note as of the last sync
+pub const SYNC_BASE_DIR: &str = ".sync-base";
+/// Metadata key linking a conflict copy to the note it was split from
+pub const CONFLICT_OF_KEY: &str = "conflict_of";
+/// Default age, in days, after which a tombstone is dropped even if a peer has not synced since
+pub const TOMBSTONE_DAYS: u32 = 90;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncState {
+    /// Identifies this directory among the replicas it syncs with
+    pub replica_id: String,
+    /// Per peer replica, the content hash of every note as of the last sync
+    #[serde(default)]
+    pub peers: BTreeMap<String, BTreeMap<NoteId, String>>,
+    /// Deleted notes and when they were deleted
+    #[serde(default)]
+    pub tombstones: BTreeMap<NoteId, DateTime<Utc>>,
+}
+
+impl SyncState {
+    /// Loads the state of `dir`, starting a fresh one if it has never been synced
+    pub fn load(dir: &Path) -> Result<Self> {
+        match fs::read_to_string(dir.join(SYNC_STATE_FILE)) {
+            Ok(data) => serde_json::from_str(&data).map_err(|e| {
+                NoteError::SerializationError(format!("{:?} is damaged: {}", dir.join(SYNC_STATE_FILE), e))
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(SyncState {
+                replica_id: Uuid::new_v4().to_string(),
+                peers: BTreeMap::new(),
+                tombstones: BTreeMap::new(),
+            }),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Drops the tombstones of notes no known peer's base still holds, since every
+    /// replica this one has synced with has seen the delete. Nothing is dropped while
+    /// no peer is known. A replica this one never synced with, or has not synced with
+    /// since it got the note from a third one, still holds the note and brings it back
+    /// on its next sync here; only a tombstone kept until it expires prevents that.
+    pub fn prune_tombstones(&mut self) -> bool {
+        let (before, peers) = (self.tombstones.len(), &self.peers);
+        if peers.is_empty() {
+            return false;
+        }
+        self.tombstones.retain(|id, _| peers.values().any(|base| base.contains_key(id)));
+        self.tombstones.len() != before
+    }
+
+    /// Drops the tombstones older than `max_age`
+    pub fn expire_tombstones(&mut self, max_age: Duration) -> bool {
+        let (before, cutoff) = (self.tombstones.len(), Utc::now() - max_age);
+        self.tombstones.retain(|_, deleted_at| *deleted_at > cutoff);
+        self.tombstones.len() != before
+    }
+
+    /// Writes through a temporary file and a rename, like note files
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let temp_path = dir.join(format!(".{}.{}.tmp", SYNC_STATE_FILE, Uuid::new_v4()));
+        let mut file = fs::File::create(&temp_path)?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, dir.join(SYNC_STATE_FILE))?;
+        Ok(())
+    }
+}
+
+/// Remembers that `id` was deleted from `dir`. Callers hold the directory lock.
+pub fn record_tombstone(dir: &Path, id: &NoteId) -> Result<()> {
+    let mut state = SyncState::load(dir)?;
+    state.tombstones.insert(id.clone(), Utc::now());
+    state.save(dir)
+}
+
+/// Drops the tombstones older than `max_age` in every notebook of `notebooks`
+pub fn expire_tombstones(notebooks: &NotebookStore, max_age: Duration) -> Result<()> {
+    for (name, _) in notebooks.list()? {
+        let storage = notebooks.open(&name)?;
+        let _lock = storage.lock()?;
+        let mut state = SyncState::load(storage.storage_dir())?;
+        if state.expire_tombstones(max_age) {
+            state.save(storage.storage_dir())?;
+        }
+    }
+    Ok(())
+}
+
+/// Hash of a note's stored form, independent of encryption and field order
+pub fn content_hash(note: &Note) -> Result<String> {
+    let document = serde_json::to_vec(&schema::note_to_document(note)?)?;
+    Ok(format!("{:x}", Sha256::digest(&document)))
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SyncSummary {
+    /// Notes copied from the other store into this one
+    pub pulled: usize,
+    /// Notes copied from this store into the other one
+    pub pushed: usize,
+    pub deleted_here: usize,
+    pub deleted_there: usize,
+    /// Notes changed on both sides and merged without conflicts
+    pub merged: usize,
+    /// Notes changed on both sides whose merge left conflict markers to resolve by hand
+    pub unresolved: Vec<NoteId>,
+    /// Notes changed on both sides that could not be merged, with the id of the copy
+    /// holding the older version
+    pub conflicts: Vec<(NoteId, NoteId)>,
+    /// Local changes a sync server refused because it changed the note first; sent again on the next sync
+    pub rejected: usize,
+}
+
+impl SyncSummary {
+    pub fn is_empty(&self) -> bool {
+        *self == SyncSummary::default()
+    }
+
+    pub(crate) fn add(&mut self, other: SyncSummary) {
+        self.pulled += other.pulled;
+        self.pushed += other.pushed;
+        self.deleted_here += other.deleted_here;
+        self.deleted_there += other.deleted_there;
+        self.merged += other.merged;
+        self.unresolved.extend(other.unresolved);
+        self.conflicts.extend(other.conflicts);
+        self.rejected += other.rejected;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Action {
+    Pull,
+    Push,
+    DeleteHere,
+    DeleteThere,
+    Conflict,
+    None,
+}
+
+/// What to do with one note, given its hash on each side, its hash as of the
+/// last sync and any tombstone the other side holds for it.
+pub(crate) fn decide(
+    here: Option<(&Note, &str)>,
+    there: Option<(&Note, &str)>,
+    base: Option<&str>,
+    here_deleted: Option<DateTime<Utc>>,
+    there_deleted: Option<DateTime<Utc>>,
+) -> Action {
+    match (here, there) {
+        (Some((_, here_hash)), Some((_, there_hash))) => {
+            if here_hash == there_hash {
+                Action::None
+            } else if base == Some(here_hash) {
+                Action::Pull
+            } else if base == Some(there_hash) {
+                Action::Push
+            } else {
+                Action::Conflict
+            }
+        }
+        // Deleted on the other side, unless it was edited here since: an edit beats a delete
+        (Some((note, hash)), None) => match (base, there_deleted) {
+            (Some(base), _) if base == hash => Action::DeleteHere,
+            (None, Some(deleted_at)) if note.updated_at <= deleted_at => Action::DeleteHere,
+            _ => Action::Push,
+        },
+        (None, Some((note, hash))) => match (base, here_deleted) {
+            (Some(base), _) if base == hash => Action::DeleteThere,
+            (None, Some(deleted_at)) if note.updated_at <= deleted_at => Action::DeleteThere,
+            _ => Action::Pull,
+        },
+        (None, None) => Action::None,
+    }
+}
+
+/// Every note of a store, failing on the first unreadable file. A note that is
+/// merely unreadable must not look deleted, or the sync would delete it elsewhere.
+pub(crate) fn load_all(storage: &FileStorage) -> Result<BTreeMap<NoteId, (Note, String)>> {
+    let mut notes = BTreeMap::new();
+    for entry in fs::read_dir(storage.storage_dir())? {
+        let path = entry?.path();
+        if !is_note_file(&path) {
+            continue;
+        }
+        let id = match path.file_stem().and_then(|s| s.to_str()).map(NoteId::parse) {
+            Some(Ok(id)) => id,
+            _ => continue,
+        };
+        let note = storage.load_note(&id).map_err(|e| {
+            NoteError::InvalidInput(format!(
+                "cannot sync while {:?} is unreadable ({}); run `notes doctor` first",
+                path,
+                NoteError::from(e)
+            ))
+        })?;
+        let hash = content_hash(&note)?;
+        notes.insert(id, (note, hash));
+    }
+    Ok(notes)
+}
+
+/// The older side of a conflict, saved as a new note next to the winner
+pub(crate) fn conflict_copy(note: &Note) -> Note {
+    let mut copy = note.clone();
+    copy.id = NoteId::new();
+    copy.revision = 0;
+    copy.title = format!("{} (conflict copy {})", note.title, note.updated_at.format("%Y-%m-%d %H:%M"));
+    copy.metadata.insert(CONFLICT_OF_KEY.to_string(), note.id.to_string());
+    copy
+}
+
+/// The versions of notes as of the last sync with one peer, stored and encrypted like notes
+fn base_store(storage: &FileStorage, peer: &str) -> Result<FileStorage> {
+    let dir = storage.storage_dir().join(SYNC_BASE_DIR).join(peer);
+    let base = FileStorage::new(&dir.to_string_lossy())?;
+    Ok(match storage.key() {
+        Some(key) => base.with_key(key.clone()),
+        None => base,
+    })
+}
+
+/// Syncs two note directories in both directions.
+///
+/// A note changed on one side since the last sync is copied to the other; a note
+/// deleted on one side and unchanged on the other is deleted there too. When both
+/// sides changed, the two edits are merged against the version of the last sync
+/// (see [`merge::merge_notes`]). Without that version, or for locked notes, the newer
+/// edit keeps the id and the older one is saved as a conflict copy on both sides,
+/// so no edit is lost.
+pub fn sync_stores(here: &FileStorage, there: &FileStorage) -> Result<SyncSummary> {
+    if same_dir(here.storage_dir(), there.storage_dir()) {
+        return Err(NoteError::InvalidInput("cannot sync a notes directory with itself".to_string()));
+    }
+    let here_lock = here.lock()?;
+    let there_lock = there.lock()?;
+
+    let here_state = SyncState::load(here.storage_dir())?;
+    let mut there_state = SyncState::load(there.storage_dir())?;
+    if there_state.replica_id == here_state.replica_id {
+        // The directory was copied, state file included; it is a replica of its own from now on
+        there_state.replica_id = Uuid::new_v4().to_string();
+    }
+    // Fix the replica ids of stores synced for the first time before anything else is written
+    here_state.save(here.storage_dir())?;
+    there_state.save(there.storage_dir())?;
+
+    let base = here_state.peers.get(&there_state.replica_id).cloned().unwrap_or_default();
+    let here_base = base_store(here, &there_state.replica_id)?;
+    let there_base = base_store(there, &here_state.replica_id)?;
+    let labels = (here.storage_dir().display().to_string(), there.storage_dir().display().to_string());
+    let here_notes = load_all(here)?;
+    let there_notes = load_all(there)?;
+
+    let ids: BTreeSet<&NoteId> = here_notes.keys().chain(there_notes.keys()).collect();
+    let mut summary = SyncSummary::default();
+    for id in ids {
+        let here_note = here_notes.get(id).map(|(note, hash)| (note, hash.as_str()));
+        let there_note = there_notes.get(id).map(|(note, hash)| (note, hash.as_str()));
+        let action = decide(
+            here_note,
+            there_note,
+            base.get(id).map(String::as_str),
+            here_state.tombstones.get(id).copied(),
+            there_state.tombstones.get(id).copied(),
+        );
+
+        match action {
+            Action::Pull => {
+                here.save_note_locked(&there_notes[id].0, &here_lock)?;
+                summary.pulled += 1;
+            }
+            Action::Push => {
+                there.save_note_locked(&here_notes[id].0, &there_lock)?;
+                summary.pushed += 1;
+            }
+            Action::DeleteHere => {
+                here.delete_note_locked(id, &here_lock)?;
+                summary.deleted_here += 1;
+            }
+            Action::DeleteThere => {
+                there.delete_note_locked(id, &there_lock)?;
+                summary.deleted_there += 1;
+            }
+            Action::Conflict => {
+                let (here_note, there_note) = (&here_notes[id].0, &there_notes[id].0);
+                let ancestor = here_base
+                    .load_note(id)
+                    .ok()
+                    .filter(|note| content_hash(note).ok().as_ref() == base.get(id));
+                let merged = ancestor.and_then(|ancestor| {
+                    merge::merge_notes(&ancestor, here_note, there_note, (&labels.0, &labels.1)).ok()
+                });
+                if let Some(merged) = merged {
+                    here.save_note_locked(&merged.note, &here_lock)?;
+                    there.save_note_locked(&merged.note, &there_lock)?;
+                    if merged.is_clean() {
+                        summary.merged += 1;
+                    } else {
+                        summary.unresolved.push(id.clone());
+                    }
+                    continue;
+                }
+
+                let copy = if there_note.updated_at > here_note.updated_at {
+                    here.save_note_locked(there_note, &here_lock)?;
+                    conflict_copy(here_note)
+                } else {
+                    there.save_note_locked(here_note, &there_lock)?;
+                    conflict_copy(there_note)
+                };
+                here.save_note_locked(&copy, &here_lock)?;
+                there.save_note_locked(&copy, &there_lock)?;
+                summary.conflicts.push((id.clone(), copy.id));
+            }
+            Action::None => {}
+        }
+    }
+
+    // Both sides now hold the same notes; record them as the base of the next sync
+    let synced = load_all(here)?;
+    let hashes: BTreeMap<NoteId, String> = synced.iter().map(|(id, (_, hash))| (id.clone(), hash.clone())).collect();
+    let mut here_state = SyncState::load(here.storage_dir())?;
+    let mut there_state = SyncState::load(there.storage_dir())?;
+    let mut tombstones = here_state.tombstones.clone();
+    for (id, deleted_at) in &there_state.tombstones {
+        let entry = tombstones.entry(id.clone()).or_insert(*deleted_at);
+        *entry = (*entry).max(*deleted_at);
+    }
+    tombstones.retain(|id, _| !synced.contains_key(id));
+
+    for (id, (note, hash)) in &synced {
+        if base.get(id) != Some(hash) {
+            here_base.save_note(note)?;
+            there_base.save_note(note)?;
+        }
+    }
+    for id in base.keys().filter(|id| !synced.contains_key(*id)) {
+        // Deleted notes must not live on in the sync base
+        for store in [&here_base, &there_base] {
+            let path = store.storage_dir().join(format!("{}.json", id));
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+    }
+
+    here_state.peers.insert(there_state.replica_id.clone(), hashes.clone());
+    here_state.tombstones = tombstones.clone();
+    there_state.peers.insert(here_state.replica_id.clone(), hashes);
+    there_state.tombstones = tombstones;
+    here_state.prune_tombstones();
+    there_state.prune_tombstones();
+    here_state.save(here.storage_dir())?;
+    there_state.save(there.storage_dir())?;
+    Ok(summary)
+}
+
+/// Syncs every notebook of two note stores, creating notebooks missing on either side.
+/// Tombstones older than `tombstone_age` are then dropped on both sides.
+pub fn sync_notebooks(here: &NotebookStore, there: &NotebookStore, tombstone_age: Duration) -> Result<Vec<(String, SyncSummary)>> {
+    if same_dir(here.root(), there.root()) {
+        return Err(NoteError::InvalidInput("cannot sync a notes directory with itself".to_string()));
+    }
+
+    let mut names: Vec<String> = here.list()?.into_iter().chain(there.list()?).map(|(name, _)| name).collect();
+    names.sort();
+    names.dedup();
+
+    let mut results = Vec::new();
+    for name in names {
+        let summary = sync_stores(&here.open_or_create(&name)?, &there.open_or_create(&name)?)?;
+        results.push((name, summary));
+    }
+    expire_tombstones(here, tombstone_age)?;
+    expire_tombstones(there, tombstone_age)?;
+    Ok(results)
+}
+
+/// Totals over the notebooks of [`sync_notebooks`]
+pub fn total(results: &[(String, SyncSummary)]) -> SyncSummary {
+    let mut total = SyncSummary::default();
+    for (_, summary) in results {
+        total.add(summary.clone());
+    }
+    total
+}
+
+fn same_dir(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
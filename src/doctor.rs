@@ -0,0 +1,309 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use chrono::Utc;
+use serde::Serialize;
+use crate::attachment::AttachmentStore;
+use crate::crypto::VAULT_MANIFEST;
+use crate::dirsync::SYNC_STATE_FILE;
+use crate::error::{validate_note_content, validate_tag, NoteError, Result, MAX_TAG_BYTES};
+use crate::index::{MetadataIndex, NoteSummary, INDEX_FILE};
+use crate::note::{Note, NoteId};
+use crate::notebook::NotebookStore;
+use crate::schema::STORE_MANIFEST;
+use crate::storage::{FileStorage, StoreLock, LOCK_FILE};
+
+/// Directory under the notes root where `notes doctor --fix` moves files it cannot repair
+pub const QUARANTINE_DIR: &str = ".quarantine";
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind {
+    /// The file could not be read or decoded as a note
+    Unparsable(String),
+    /// A `.json` file whose name is not a note id
+    UnrecognizedFile,
+    /// The note inside the file carries a different id than its file name
+    IdMismatch { note_id: NoteId },
+    /// Another file already holds a note with the same id
+    DuplicateId { other: PathBuf },
+    InvalidTag { tag: String, reason: String },
+    OversizedContent(usize),
+    TimestampsReversed,
+    /// The note lists an attachment whose blob is gone
+    MissingAttachment { name: String, hash: String },
+    /// A blob no note refers to any more
+    OrphanAttachment { hash: String },
+    /// A temporary file left behind by an interrupted write
+    StaleTempFile,
+    /// `index.json` lists notes, or summaries of them, that differ from the note files
+    StaleIndex,
+}
+
+impl fmt::Display for IssueKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IssueKind::Unparsable(msg) => write!(f, "unreadable note file: {}", msg),
+            IssueKind::UnrecognizedFile => write!(f, "file name is not a note id"),
+            IssueKind::IdMismatch { note_id } => write!(f, "file contains note {}", note_id),
+            IssueKind::DuplicateId { other } => write!(f, "same note id as {:?}", other),
+            IssueKind::InvalidTag { tag, reason } => write!(f, "invalid tag '{}': {}", tag, reason),
+            IssueKind::OversizedContent(len) => write!(f, "content is {} bytes, over the 10,000 limit", len),
+            IssueKind::TimestampsReversed => write!(f, "updated_at is earlier than created_at"),
+            IssueKind::MissingAttachment { name, hash } => write!(f, "attachment '{}' ({}) is missing", name, hash),
+            IssueKind::OrphanAttachment { hash } => write!(f, "attachment blob {} is not used by any note", hash),
+            IssueKind::StaleTempFile => write!(f, "leftover temporary file"),
+            IssueKind::StaleIndex => write!(f, "note index is out of date"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Issue {
+    pub path: PathBuf,
+    pub kind: IssueKind,
+    pub fixed: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DoctorReport {
+    pub notes_checked: usize,
+    pub issues: Vec<Issue>,
+}
+
+impl DoctorReport {
+    pub fn is_healthy(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// Issues still present after the run
+    pub fn remaining(&self) -> usize {
+        self.issues.iter().filter(|issue| !issue.fixed).count()
+    }
+
+    fn push(&mut self, path: &Path, kind: IssueKind, fixed: bool) {
+        self.issues.push(Issue { path: path.to_path_buf(), kind, fixed });
+    }
+}
+
+/// Checks every notebook and the attachment store. With `fix`, repairs what can be
+/// repaired without losing data and moves unreadable files to [`QUARANTINE_DIR`].
+pub fn check(notebooks: &NotebookStore, attachments: &AttachmentStore, fix: bool) -> Result<DoctorReport> {
+    let mut report = DoctorReport::default();
+    let mut seen: HashMap<NoteId, PathBuf> = HashMap::new();
+    let mut referenced: HashSet<String> = HashSet::new();
+    let mut complete = true;
+
+    for (name, _) in notebooks.list()? {
+        let storage = notebooks.open(&name)?;
+        let lock = if fix { Some(storage.lock()?) } else { None };
+        let quarantine = notebooks.root().join(QUARANTINE_DIR).join(&name);
+        // What the index should hold once the checks are done, by file name
+        let mut indexed: BTreeMap<NoteId, NoteSummary> = BTreeMap::new();
+
+        let mut paths: Vec<PathBuf> = fs::read_dir(storage.storage_dir())?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.is_file())
+            .collect();
+        paths.sort();
+
+        for path in paths {
+            let file_name = path.file_name().and_then(|s| s.to_str()).unwrap_or_default().to_string();
+            if file_name == LOCK_FILE
+                || file_name == VAULT_MANIFEST
+                || file_name == STORE_MANIFEST
+                || file_name == INDEX_FILE
+                || file_name == SYNC_STATE_FILE
+            {
+                continue;
+            }
+
+            if file_name.starts_with('.') && file_name.ends_with(".tmp") {
+                // Only safe to delete while holding the lock that writers take
+                let fixed = fix && fs::remove_file(&path).is_ok();
+                report.push(&path, IssueKind::StaleTempFile, fixed);
+                continue;
+            }
+
+            if path.extension().and_then(|s| s.to_str()) != Some("json") {
+                continue;
+            }
+
+            let file_id = match path.file_stem().and_then(|s| s.to_str()).map(NoteId::parse) {
+                Some(Ok(id)) => id,
+                _ => {
+                    complete = false;
+                    let fixed = fix && quarantine_file(&path, &quarantine).is_ok();
+                    report.push(&path, IssueKind::UnrecognizedFile, fixed);
+                    continue;
+                }
+            };
+
+            let note = match storage.load_note(&file_id).map_err(NoteError::from) {
+                Ok(note) => note,
+                Err(e @ NoteError::VaultLocked(_)) => return Err(e),
+                Err(e) => {
+                    complete = false;
+                    let fixed = fix && quarantine_file(&path, &quarantine).is_ok();
+                    report.push(&path, IssueKind::Unparsable(e.to_string()), fixed);
+                    continue;
+                }
+            };
+            report.notes_checked += 1;
+            referenced.extend(note.attachments.iter().map(|a| a.hash.clone()));
+            indexed.insert(file_id.clone(), NoteSummary::from(&note));
+
+            if let Some(other) = seen.get(&note.id) {
+                // Either copy may be the one the user expects, so leave the choice to them
+                report.push(&path, IssueKind::DuplicateId { other: other.clone() }, false);
+                continue;
+            }
+
+            let mut note = note;
+            let mut path = path;
+            if note.id != file_id {
+                let kind = IssueKind::IdMismatch { note_id: note.id.clone() };
+                let target = path.with_file_name(format!("{}.json", note.id));
+                match &lock {
+                    Some(lock) if !target.exists() => {
+                        storage.save_note_locked(&note, lock)?;
+                        fs::remove_file(&path)?;
+                        indexed.remove(&file_id);
+                        report.push(&path, kind, true);
+                        path = target;
+                    }
+                    _ => {
+                        report.push(&path, kind, false);
+                        continue;
+                    }
+                }
+            }
+            seen.insert(note.id.clone(), path.clone());
+
+            check_note(&storage, lock.as_ref(), attachments, &path, &mut note, &mut report)?;
+            indexed.insert(note.id.clone(), NoteSummary::from(&note));
+        }
+
+        let index = MetadataIndex::load(storage.storage_dir(), storage.key());
+        if index.len() != indexed.len() || indexed.iter().any(|(id, summary)| index.get(id) != Some(summary)) {
+            let fixed = match &lock {
+                Some(lock) => storage.rebuild_index_locked(lock).is_ok(),
+                None => false,
+            };
+            report.push(&storage.storage_dir().join(INDEX_FILE), IssueKind::StaleIndex, fixed);
+        }
+    }
+
+    for hash in attachments.list_hashes()? {
+        if referenced.contains(&hash) {
+            continue;
+        }
+        let path = attachments.attachments_dir().join(&hash[..2]).join(&hash);
+        // A note we could not read might still use the blob, so only move it once every note parsed
+        let fixed = fix
+            && complete
+            && attachments.move_out(&hash, &notebooks.root().join(QUARANTINE_DIR).join("attachments")).is_ok();
+        report.push(&path, IssueKind::OrphanAttachment { hash }, fixed);
+    }
+
+    Ok(report)
+}
+
+/// Per-note checks, repairing the note in place when a lock is held
+fn check_note(
+    storage: &FileStorage,
+    lock: Option<&StoreLock>,
+    attachments: &AttachmentStore,
+    path: &Path,
+    note: &mut Note,
+    report: &mut DoctorReport,
+) -> Result<()> {
+    let mut repairs = Vec::new();
+
+    let mut tags = Vec::new();
+    for tag in &note.tags {
+        match validate_tag(tag) {
+            Ok(()) => tags.push(tag.clone()),
+            Err(e) => {
+                let reason = match e {
+                    NoteError::ValidationError(msg) => msg,
+                    other => other.to_string(),
+                };
+                repairs.push(IssueKind::InvalidTag { tag: tag.clone(), reason });
+                if let Some(cleaned) = sanitize_tag(tag) {
+                    if !tags.contains(&cleaned) {
+                        tags.push(cleaned);
+                    }
+                }
+            }
+        }
+    }
+
+    if validate_note_content(&note.content).is_err() {
+        report.push(path, IssueKind::OversizedContent(note.content.len()), false);
+    }
+
+    if note.updated_at < note.created_at {
+        repairs.push(IssueKind::TimestampsReversed);
+    }
+
+    let mut missing = Vec::new();
+    for attachment in &note.attachments {
+        if !attachments.contains(&attachment.hash) {
+            missing.push(attachment.hash.clone());
+            repairs.push(IssueKind::MissingAttachment {
+                name: attachment.name.clone(),
+                hash: attachment.hash.clone(),
+            });
+        }
+    }
+
+    if repairs.is_empty() {
+        return Ok(());
+    }
+
+    let fixed = match lock {
+        Some(lock) => {
+            note.tags = tags;
+            if note.updated_at < note.created_at {
+                note.updated_at = note.created_at;
+            }
+            note.attachments.retain(|a| !missing.contains(&a.hash));
+            storage.update_note_locked(note, lock)?;
+            true
+        }
+        None => false,
+    };
+    for kind in repairs {
+        report.push(path, kind, fixed);
+    }
+    Ok(())
+}
+
+/// Closest valid form of an invalid tag, or `None` when nothing usable is left
+fn sanitize_tag(tag: &str) -> Option<String> {
+    let cleaned: String = tag
+        .split('/')
+        .map(|segment| segment.split_whitespace().collect::<Vec<_>>().join("-"))
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>()
+        .join("/");
+    let mut end = cleaned.len().min(MAX_TAG_BYTES);
+    while !cleaned.is_char_boundary(end) {
+        end -= 1;
+    }
+    let cleaned = cleaned[..end].trim_end_matches('/').to_string();
+    validate_tag(&cleaned).ok().map(|_| cleaned)
+}
+
+fn quarantine_file(path: &Path, quarantine: &Path) -> Result<PathBuf> {
+    fs::create_dir_all(quarantine)?;
+    let file_name = path.file_name().and_then(|s| s.to_str()).unwrap_or("note.json");
+    let mut destination = quarantine.join(file_name);
+    if destination.exists() {
+        destination = quarantine.join(format!("{}.{}", Utc::now().format("%Y%m%d%H%M%S%f"), file_name));
+    }
+    fs::rename(path, &destination)?;
+    Ok(destination)
+}
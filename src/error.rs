@@ -10,6 +10,12 @@ pub enum NoteError {
     NotFound(String),
     InvalidInput(String),
     DatabaseError(String),
+    WrongPassphrase,
+    CorruptedCiphertext(String),
+    VaultLocked(String),
+    Conflict(String),
+    IncompatibleSchema(String),
+    Git(String),
 }
 
 impl fmt::Display for NoteError {
@@ -21,6 +27,12 @@ impl fmt::Display for NoteError {
             NoteError::NotFound(msg) => write!(f, "Not found: {}", msg),
             NoteError::InvalidInput(msg) => write!(f, "Invalid input: {}", msg),
             NoteError::DatabaseError(msg) => write!(f, "Database error: {}", msg),
+            NoteError::WrongPassphrase => write!(f, "Wrong passphrase"),
+            NoteError::CorruptedCiphertext(msg) => write!(f, "Corrupted encrypted data: {}", msg),
+            NoteError::VaultLocked(msg) => write!(f, "Vault is locked: {}", msg),
+            NoteError::Conflict(msg) => write!(f, "Conflict: {}", msg),
+            NoteError::IncompatibleSchema(msg) => write!(f, "Incompatible schema: {}", msg),
+            NoteError::Git(msg) => write!(f, "Git error: {}", msg),
         }
     }
 }
@@ -36,10 +48,25 @@ impl Error for NoteError {
 
 impl From<io::Error> for NoteError {
     fn from(err: io::Error) -> Self {
+        // Storage layers that only speak io::Error carry a NoteError inside it
+        if err.get_ref().is_some_and(|inner| inner.is::<NoteError>()) {
+            let inner = err.into_inner().expect("checked by get_ref");
+            return *inner.downcast::<NoteError>().expect("checked by is");
+        }
         NoteError::IoError(err)
     }
 }
 
+impl From<NoteError> for io::Error {
+    fn from(err: NoteError) -> Self {
+        match err {
+            NoteError::IoError(err) => err,
+            NoteError::NotFound(_) => io::Error::new(io::ErrorKind::NotFound, err),
+            other => io::Error::new(io::ErrorKind::InvalidData, other),
+        }
+    }
+}
+
 impl From<serde_json::Error> for NoteError {
     fn from(err: serde_json::Error) -> Self {
         NoteError::SerializationError(err.to_string())
@@ -68,18 +95,25 @@ pub fn validate_note_content(content: &str) -> Result<()> {
     Ok(())
 }
 
+/// Longest tag accepted, in bytes of UTF-8
+pub const MAX_TAG_BYTES: usize = 50;
+
 pub fn validate_tag(tag: &str) -> Result<()> {
     if tag.trim().is_empty() {
         return Err(NoteError::ValidationError("Tag cannot be empty".to_string()));
     }
     
-    if tag.len() > 50 {
+    if tag.len() > MAX_TAG_BYTES {
         return Err(NoteError::ValidationError("Tag cannot exceed 50 characters".to_string()));
     }
     
     if tag.contains(' ') {
         return Err(NoteError::ValidationError("Tags cannot contain spaces".to_string()));
     }
-    
+
+    if tag.split('/').any(|segment| segment.is_empty()) {
+        return Err(NoteError::ValidationError("Tag path segments cannot be empty".to_string()));
+    }
+
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,82 @@
+use std::collections::BTreeMap;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::attachment::{self, AttachmentStore};
+use crate::error::{NoteError, Result};
+use crate::note::Note;
+use crate::storage::FileStorage;
+
+pub const BUNDLE_VERSION: u32 = 1;
+
+/// Self-contained JSON export: the notes plus the bytes of every attachment
+/// they reference, base64-encoded and keyed by hash.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JsonBundle {
+    pub version: u32,
+    pub exported_at: DateTime<Utc>,
+    pub notes: Vec<Note>,
+    #[serde(default)]
+    pub attachments: BTreeMap<String, String>,
+}
+
+pub fn build_json_bundle(notes: Vec<Note>, attachments: &AttachmentStore) -> Result<JsonBundle> {
+    let mut blobs = BTreeMap::new();
+    for attachment in notes.iter().flat_map(|note| note.attachments.iter()) {
+        if !blobs.contains_key(&attachment.hash) {
+            let data = attachments.read(attachment)?;
+            blobs.insert(attachment.hash.clone(), BASE64.encode(data));
+        }
+    }
+
+    Ok(JsonBundle {
+        version: BUNDLE_VERSION,
+        exported_at: Utc::now(),
+        notes,
+        attachments: blobs,
+    })
+}
+
+/// Writes the bundle's attachments and notes into the given stores,
+/// checking every attachment against its recorded hash first.
+pub fn restore_json_bundle(bundle: &JsonBundle, storage: &FileStorage, attachments: &AttachmentStore) -> Result<usize> {
+    if bundle.version > BUNDLE_VERSION {
+        return Err(NoteError::InvalidInput(format!(
+            "Export bundle version {} is newer than the supported version {}",
+            bundle.version, BUNDLE_VERSION
+        )));
+    }
+
+    for (hash, encoded) in &bundle.attachments {
+        let data = BASE64
+            .decode(encoded)
+            .map_err(|e| NoteError::SerializationError(format!("Invalid attachment data for {}: {}", hash, e)))?;
+        let actual = format!("{:x}", Sha256::digest(&data));
+        if &actual != hash {
+            return Err(NoteError::ValidationError(format!(
+                "Attachment {} does not match its content hash {}",
+                hash, actual
+            )));
+        }
+        attachments.store_bytes(&data, hash, "application/octet-stream")?;
+    }
+
+    for note in &bundle.notes {
+        attachment::validate_attachments(note)?;
+    }
+    for attachment in bundle.notes.iter().flat_map(|note| note.attachments.iter()) {
+        if !attachments.contains(&attachment.hash) {
+            return Err(NoteError::NotFound(format!(
+                "Export bundle is missing attachment '{}' ({})",
+                attachment.name, attachment.hash
+            )));
+        }
+    }
+
+    for note in &bundle.notes {
+        storage.save_note(note)?;
+    }
+    Ok(bundle.notes.len())
+}
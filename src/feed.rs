@@ -0,0 +1,217 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use crate::error::Result;
+use crate::note::{Note, NoteId};
+
+/// Change feed of a notes directory, one JSON event per line
+pub const FEED_FILE: &str = "events.jsonl";
+/// Once the feed grows past this size, its older half is dropped
+const MAX_FEED_BYTES: u64 = 2 * 1024 * 1024;
+/// Bytes read from the end of the feed to find the newest cursor
+const TAIL_BYTES: u64 = 16 * 1024;
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FeedKind {
+    Created,
+    Updated,
+    /// Only the tags of the note changed
+    Tagged,
+    Deleted,
+}
+
+impl FeedKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FeedKind::Created => "created",
+            FeedKind::Updated => "updated",
+            FeedKind::Tagged => "tagged",
+            FeedKind::Deleted => "deleted",
+        }
+    }
+}
+
+/// One change to a note, numbered in the order the changes happened
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FeedEvent {
+    /// Cursor of this event; resuming from it returns only later events
+    pub seq: u64,
+    pub event: FeedKind,
+    pub notebook: String,
+    pub id: NoteId,
+    /// Title after the change, or the last known title of a deleted note.
+    /// Left out, like the tags, for encrypted stores.
+    pub title: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub revision: Option<u64>,
+    pub at: DateTime<Utc>,
+}
+
+impl FeedEvent {
+    /// An event not numbered yet; [`ChangeFeed::append`] assigns `seq`
+    pub fn new(event: FeedKind, notebook: &str, id: &NoteId, note: Option<&Note>) -> Self {
+        FeedEvent {
+            seq: 0,
+            event,
+            notebook: notebook.to_string(),
+            id: id.clone(),
+            title: note.map(|note| note.title.clone()),
+            tags: note.map(|note| note.tags.clone()).unwrap_or_default(),
+            revision: note.map(|note| note.revision),
+            at: Utc::now(),
+        }
+    }
+}
+
+/// Append-only log of the notes created, changed and deleted through a
+/// [`NotebookStore`](crate::notebook::NotebookStore), by any command, the API or a sync.
+/// Live clients read it from a cursor, so a client that reconnects misses nothing.
+#[derive(Debug, Clone)]
+pub struct ChangeFeed {
+    path: PathBuf,
+}
+
+impl ChangeFeed {
+    pub fn new(root: &Path) -> Self {
+        ChangeFeed { path: root.join(FEED_FILE) }
+    }
+
+    /// Numbers `event` after the newest one in the feed, appends it and returns its cursor
+    pub fn append(&self, mut event: FeedEvent) -> Result<u64> {
+        let mut file = OpenOptions::new().create(true).read(true).append(true).open(&self.path)?;
+        FileExt::lock_exclusive(&file)?;
+        let (seq, complete) = last_seq(&mut file)?;
+        event.seq = seq + 1;
+
+        // A writer that crashed mid-line leaves a fragment, which readers skip
+        let mut line = if complete { String::new() } else { "\n".to_string() };
+        line.push_str(&serde_json::to_string(&event)?);
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        if file.metadata()?.len() > MAX_FEED_BYTES {
+            compact(&mut file)?;
+        }
+        Ok(event.seq)
+    }
+
+    /// Cursor of the newest event, 0 while the feed is empty
+    pub fn latest(&self) -> Result<u64> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        FileExt::lock_shared(&file)?;
+        Ok(last_seq(&mut file)?.0)
+    }
+
+    /// Events after `cursor`, oldest first. `None` when the cursor is no longer (or
+    /// not yet) in the feed, so the client has to reload everything and start over.
+    pub fn since(&self, cursor: u64) -> Result<Option<Vec<FeedEvent>>> {
+        let events = self.read()?;
+        let first = events.first().map_or(1, |event| event.seq);
+        let latest = events.last().map_or(0, |event| event.seq);
+        if cursor + 1 < first || cursor > latest {
+            return Ok(None);
+        }
+        Ok(Some(events.into_iter().filter(|event| event.seq > cursor).collect()))
+    }
+
+    /// Like [`ChangeFeed::since`], but waits up to `timeout` for an event after `cursor`
+    pub fn wait(&self, cursor: u64, timeout: Duration) -> Result<Option<Vec<FeedEvent>>> {
+        let started = Instant::now();
+        let size = || fs::metadata(&self.path).map(|metadata| metadata.len()).ok();
+        loop {
+            let seen = size();
+            let events = self.since(cursor)?;
+            if !matches!(&events, Some(events) if events.is_empty()) {
+                return Ok(events);
+            }
+            while size() == seen {
+                if started.elapsed() >= timeout {
+                    return Ok(events);
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+
+    /// Drops the titles and tags of every event, for a store that was just encrypted
+    pub fn redact(&self) -> Result<()> {
+        let mut file = match OpenOptions::new().read(true).write(true).open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        FileExt::lock_exclusive(&file)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+
+        let mut redacted = String::new();
+        for mut event in contents.lines().filter_map(|line| serde_json::from_str::<FeedEvent>(line).ok()) {
+            event.title = None;
+            event.tags.clear();
+            redacted.push_str(&serde_json::to_string(&event)?);
+            redacted.push('\n');
+        }
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(redacted.as_bytes())?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    fn read(&self) -> Result<Vec<FeedEvent>> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        FileExt::lock_shared(&file)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        Ok(contents.lines().filter_map(|line| serde_json::from_str(line).ok()).collect())
+    }
+}
+
+/// Cursor of the newest readable event, and whether the file ends with a complete line
+fn last_seq(file: &mut File) -> Result<(u64, bool)> {
+    let len = file.metadata()?.len();
+    let mut start = len.saturating_sub(TAIL_BYTES);
+    loop {
+        file.seek(SeekFrom::Start(start))?;
+        let mut tail = Vec::new();
+        file.read_to_end(&mut tail)?;
+        let complete = tail.last().is_none_or(|byte| *byte == b'\n');
+
+        // The first line of a window not starting at the beginning may be cut off
+        let text = String::from_utf8_lossy(&tail);
+        let lines: Vec<&str> = text.lines().skip(usize::from(start > 0)).collect();
+        let newest = lines.iter().rev().find_map(|line| serde_json::from_str::<FeedEvent>(line).ok());
+        match newest {
+            Some(event) => return Ok((event.seq, complete)),
+            None if start == 0 => return Ok((0, complete)),
+            None => start = 0,
+        }
+    }
+}
+
+/// Drops the older half of the feed; runs under the writer's lock
+fn compact(file: &mut File) -> Result<()> {
+    let mut contents = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut contents)?;
+    let half = contents.len() / 2;
+    let start = contents[half..].iter().position(|byte| *byte == b'\n').map_or(contents.len(), |i| half + i + 1);
+    file.set_len(0)?;
+    file.write_all(&contents[start..])?;
+    Ok(())
+}
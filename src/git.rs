@@ -0,0 +1,244 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use chrono::{DateTime, FixedOffset};
+use serde::Serialize;
+use crate::error::{NoteError, Result};
+use crate::note::{Note, NoteId};
+
+pub const DEFAULT_REMOTE: &str = "origin";
+
+/// Lock files, in-flight writes, quarantined files and per-machine manifests, indexes and sync state stay out of history
+const GITIGNORE: &[&str] = &[".lock", ".*.tmp", ".quarantine/", "store.json", "index.json", "sync.json", ".sync-base/", "sync-log.json", "remotes.json", "sync-keyring.json", ".sealed/", "events.jsonl"];
+
+/// Identity used when git has no `user.name`/`user.email` configured
+const FALLBACK_NAME: &str = "notes";
+const FALLBACK_EMAIL: &str = "notes@localhost";
+
+/// How a commit message names a note; an encrypted store only gives the id, so no title reaches the history
+pub fn note_subject(note: &Note, encrypted: bool) -> String {
+    match encrypted {
+        true => format!("note {}", note.id),
+        false => format!("note '{}' ({})", note.title, note.id),
+    }
+}
+
+/// Commit message of a tag rewrite; an encrypted store only gives the number of notes changed
+pub fn tag_subject(message: String, updated: usize, encrypted: bool) -> String {
+    match encrypted {
+        true => format!("Rewrite tags of {} note(s)", updated),
+        false => message,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CommitInfo {
+    pub hash: String,
+    pub author: String,
+    pub date: DateTime<FixedOffset>,
+    pub subject: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct SyncReport {
+    /// Commits fetched from the remote
+    pub pulled: usize,
+    /// Local commits pushed to the remote
+    pub pushed: usize,
+}
+
+/// A notes directory kept in a git repository, committing after every change.
+///
+/// Everything goes through the `git` command line, so any remote git can reach,
+/// including a local bare repository, works for `notes sync`.
+#[derive(Clone)]
+pub struct GitStore {
+    dir: PathBuf,
+}
+
+impl GitStore {
+    pub fn is_repository(dir: &Path) -> bool {
+        dir.join(".git").exists()
+    }
+
+    /// Opens the repository in `dir`, initializing it on `branch` if needed.
+    /// Only a repository without commits gets the initial one; changes found in an
+    /// existing repository go into the next command's commit.
+    pub fn open(dir: impl AsRef<Path>, branch: &str) -> Result<Self> {
+        let store = GitStore { dir: dir.as_ref().to_path_buf() };
+        fs::create_dir_all(&store.dir)?;
+
+        let created = !Self::is_repository(&store.dir);
+        if created {
+            store.git(&["init", "--quiet"])?;
+            store.git(&["symbolic-ref", "HEAD", &format!("refs/heads/{}", branch)])?;
+        }
+        store.update_gitignore()?;
+        if created || !store.has_commits() {
+            store.commit_all("Initialize note store")?;
+        }
+        Ok(store)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Commits every pending change; returns false when there was nothing to commit
+    pub fn commit_all(&self, message: &str) -> Result<bool> {
+        self.git(&["add", "--all"])?;
+        if self.git(&["status", "--porcelain"])?.trim().is_empty() {
+            return Ok(false);
+        }
+        self.commit(message)?;
+        Ok(true)
+    }
+
+    /// Commits that touched a note, newest first. Follows the note across notebooks.
+    pub fn log(&self, id: &NoteId) -> Result<Vec<CommitInfo>> {
+        if !self.has_commits() {
+            return Ok(Vec::new());
+        }
+        let output = self.git(&[
+            "log",
+            "--format=%H%x1f%an%x1f%aI%x1f%s",
+            "--",
+            &format!(":(glob)**/{}.json", id),
+        ])?;
+
+        output
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let fields: Vec<&str> = line.splitn(4, '\x1f').collect();
+                if fields.len() != 4 {
+                    return Err(NoteError::Git(format!("Unexpected git log output: {}", line)));
+                }
+                let date = DateTime::parse_from_rfc3339(fields[2])
+                    .map_err(|e| NoteError::Git(format!("Invalid commit date '{}': {}", fields[2], e)))?;
+                Ok(CommitInfo {
+                    hash: fields[0].to_string(),
+                    author: fields[1].to_string(),
+                    date,
+                    subject: fields[3].to_string(),
+                })
+            })
+            .collect()
+    }
+
+    /// Points `origin` at `url`, adding the remote if it does not exist yet
+    pub fn set_remote(&self, url: &str) -> Result<()> {
+        match self.git(&["remote", "get-url", DEFAULT_REMOTE]) {
+            Ok(current) if current.trim() == url => Ok(()),
+            Ok(_) => self.git(&["remote", "set-url", DEFAULT_REMOTE, url]).map(|_| ()),
+            Err(_) => self.git(&["remote", "add", DEFAULT_REMOTE, url]).map(|_| ()),
+        }
+    }
+
+    /// Pulls with rebase, then pushes. A rebase conflict is rolled back and
+    /// reported as `NoteError::Conflict`, leaving the local history untouched.
+    pub fn sync(&self, url: &str, branch: &str) -> Result<SyncReport> {
+        self.set_remote(url)?;
+        self.commit_all("Commit pending changes before sync")?;
+        self.git(&["fetch", "--quiet", DEFAULT_REMOTE])?;
+
+        let remote_branch = format!("refs/remotes/{}/{}", DEFAULT_REMOTE, branch);
+        let mut report = SyncReport::default();
+        if self.git(&["rev-parse", "--verify", "--quiet", &remote_branch]).is_ok() {
+            if self.has_commits() {
+                report.pulled = self.count(&format!("HEAD..{}", remote_branch))?;
+                if let Err(e) = self.git_with_identity(&["rebase", "--quiet", &remote_branch]) {
+                    let _ = self.git(&["rebase", "--abort"]);
+                    return Err(NoteError::Conflict(format!(
+                        "local and remote changes to the same notes could not be combined; \
+                         nothing was pushed ({})",
+                        e
+                    )));
+                }
+            } else {
+                report.pulled = self.count(&remote_branch)?;
+                self.git(&["reset", "--hard", "--quiet", &remote_branch])?;
+            }
+            report.pushed = self.count(&format!("{}..HEAD", remote_branch))?;
+        } else {
+            report.pushed = if self.has_commits() { self.count("HEAD")? } else { 0 };
+        }
+
+        if report.pushed > 0 {
+            self.git(&["push", "--quiet", "--set-upstream", DEFAULT_REMOTE, &format!("HEAD:refs/heads/{}", branch)])?;
+        }
+        Ok(report)
+    }
+
+    /// Adds the patterns from [`GITIGNORE`] that `.gitignore` does not list yet
+    fn update_gitignore(&self) -> Result<()> {
+        let path = self.dir.join(".gitignore");
+        let mut contents = fs::read_to_string(&path).unwrap_or_default();
+        let missing: Vec<&str> = GITIGNORE
+            .iter()
+            .copied()
+            .filter(|pattern| !contents.lines().any(|line| line.trim() == *pattern))
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            contents.push('\n');
+        }
+        for pattern in missing {
+            contents.push_str(pattern);
+            contents.push('\n');
+        }
+        fs::write(&path, contents)?;
+        Ok(())
+    }
+
+    fn has_commits(&self) -> bool {
+        self.git(&["rev-parse", "--verify", "--quiet", "HEAD"]).is_ok()
+    }
+
+    fn count(&self, range: &str) -> Result<usize> {
+        let output = self.git(&["rev-list", "--count", range])?;
+        output
+            .trim()
+            .parse()
+            .map_err(|_| NoteError::Git(format!("Unexpected git rev-list output: {}", output)))
+    }
+
+    fn commit(&self, message: &str) -> Result<()> {
+        self.git_with_identity(&["commit", "--quiet", "--no-verify", "-m", message])?;
+        Ok(())
+    }
+
+    /// Runs a command that creates commits, falling back to a neutral identity
+    fn git_with_identity(&self, args: &[&str]) -> Result<String> {
+        if self.git(&["config", "user.email"]).is_ok() {
+            return self.git(args);
+        }
+        let name = format!("user.name={}", FALLBACK_NAME);
+        let email = format!("user.email={}", FALLBACK_EMAIL);
+        let mut with_identity = vec!["-c", &name, "-c", &email];
+        with_identity.extend_from_slice(args);
+        self.git(&with_identity)
+    }
+
+    fn git(&self, args: &[&str]) -> Result<String> {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(&self.dir)
+            .env("GIT_TERMINAL_PROMPT", "0")
+            .output()
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => NoteError::Git("the git executable was not found in PATH".to_string()),
+                _ => NoteError::IoError(e),
+            })?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(NoteError::Git(format!("git {} failed: {}", args.join(" "), stderr.trim())));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
@@ -0,0 +1,427 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader as AsyncBufReader};
+use tokio::net::TcpListener;
+use crate::error::{NoteError, Result};
+
+/// Largest request or response body accepted
+pub const MAX_BODY: usize = 32 * 1024 * 1024;
+const MAX_HEADER_LINE: usize = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Time a client gets to send its whole request
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Default)]
+pub struct Request {
+    pub method: String,
+    /// Percent-decoded path without the query string
+    pub path: String,
+    pub query: HashMap<String, String>,
+    /// Header names are lowercased
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_ascii_lowercase()).map(String::as_str)
+    }
+
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        serde_json::from_slice(&self.body)
+            .map_err(|e| NoteError::InvalidInput(format!("invalid request body: {}", e)))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Response { status, headers: Vec::new(), body: Vec::new() }
+    }
+
+    pub fn json<T: Serialize>(status: u16, value: &T) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => Response::new(status).with_header("Content-Type", "application/json").with_body(body),
+            Err(e) => Response::error(500, &e.to_string()),
+        }
+    }
+
+    pub fn text(status: u16, text: &str) -> Self {
+        Response::new(status)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(text.as_bytes().to_vec())
+    }
+
+    /// `{"error": message}` with the given status
+    pub fn error(status: u16, message: &str) -> Self {
+        Response::json(status, &serde_json::json!({ "error": message }))
+    }
+
+    /// Maps an error from the note layer to the closest HTTP status
+    pub fn from_error(error: &NoteError) -> Self {
+        let status = match error {
+            NoteError::NotFound(_) => 404,
+            NoteError::InvalidInput(_) | NoteError::ValidationError(_) => 400,
+            NoteError::Conflict(_) => 409,
+            NoteError::VaultLocked(_) => 423,
+            NoteError::IoError(e) if e.kind() == io::ErrorKind::NotFound => 404,
+            _ => 500,
+        };
+        Response::error(status, &error.to_string())
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    pub fn json_body<T: DeserializeOwned>(&self) -> Result<T> {
+        serde_json::from_slice(&self.body)
+            .map_err(|e| NoteError::SerializationError(format!("invalid response body: {}", e)))
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        412 => "Precondition Failed",
+        413 => "Payload Too Large",
+        423 => "Locked",
+        428 => "Precondition Required",
+        _ if status >= 500 => "Internal Server Error",
+        _ => "",
+    }
+}
+
+/// Serves HTTP/1.1 on `listener`, one request per connection. `handler` runs on the
+/// blocking thread pool since every store operation touches the disk.
+pub async fn serve<H>(listener: TcpListener, handler: Arc<H>) -> io::Result<()>
+where
+    H: Fn(Request) -> Response + Send + Sync + 'static,
+{
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let handler = Arc::clone(&handler);
+        tokio::spawn(async move {
+            let (reader, mut writer) = stream.into_split();
+            let request = tokio::time::timeout(READ_TIMEOUT, read_request(AsyncBufReader::new(reader)))
+                .await
+                .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "request timeout")));
+            let response = match request {
+                Ok(Some(request)) => tokio::task::spawn_blocking(move || handler(request))
+                    .await
+                    .unwrap_or_else(|_| Response::error(500, "request handler panicked")),
+                Ok(None) => return,
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => Response::error(400, &e.to_string()),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => Response::error(408, &e.to_string()),
+                Err(_) => return,
+            };
+            let _ = writer.write_all(&encode_response(&response)).await;
+            let _ = writer.shutdown().await;
+        });
+    }
+}
+
+/// Serves on an already bound listener until the process is stopped. Binding first
+/// lets callers (and tests) learn the port before the server starts.
+pub fn run<H>(listener: std::net::TcpListener, handler: H) -> Result<()>
+where
+    H: Fn(Request) -> Response + Send + Sync + 'static,
+{
+    listener.set_nonblocking(true)?;
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async { serve(TcpListener::from_std(listener)?, Arc::new(handler)).await })?;
+    Ok(())
+}
+
+async fn read_request<R: tokio::io::AsyncBufRead + Unpin>(mut reader: R) -> io::Result<Option<Request>> {
+    let mut line = String::new();
+    if (&mut reader).take(MAX_HEADER_LINE as u64).read_line(&mut line).await? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => (method, target),
+        _ => return Err(invalid("malformed request line")),
+    };
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut request = Request {
+        method: method.to_ascii_uppercase(),
+        path: percent_decode(path),
+        query: parse_query(query),
+        ..Request::default()
+    };
+
+    loop {
+        let mut header = String::new();
+        if (&mut reader).take(MAX_HEADER_LINE as u64).read_line(&mut header).await? == 0 {
+            return Err(invalid("connection closed inside the headers"));
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if request.headers.len() >= MAX_HEADERS {
+            return Err(invalid("too many headers"));
+        }
+        let (name, value) = header.split_once(':').ok_or_else(|| invalid("malformed header"))?;
+        request.headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+    }
+
+    // The body grows as it arrives, so a large Content-Length alone reserves nothing
+    let length = content_length(&request.headers)?;
+    if (&mut reader).take(length as u64).read_to_end(&mut request.body).await? < length {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed inside the body"));
+    }
+    Ok(Some(request))
+}
+
+fn encode_response(response: &Response) -> Vec<u8> {
+    let mut head = format!("HTTP/1.1 {} {}\r\n", response.status, reason(response.status));
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n\r\n", response.body.len()));
+    let mut bytes = head.into_bytes();
+    bytes.extend_from_slice(&response.body);
+    bytes
+}
+
+fn content_length(headers: &HashMap<String, String>) -> io::Result<usize> {
+    let length = match headers.get("content-length") {
+        Some(value) => value.parse::<usize>().map_err(|_| invalid("invalid Content-Length"))?,
+        None => 0,
+    };
+    if length > MAX_BODY {
+        return Err(invalid("body too large"));
+    }
+    Ok(length)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+pub fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (form_decode(key), form_decode(value))
+        })
+        .collect()
+}
+
+/// Decodes a query component, where `+` stands for a space
+fn form_decode(text: &str) -> String {
+    percent_decode(&text.replace('+', " "))
+}
+
+/// Decodes `%XX` escapes; a `+` stays a `+`, as it does in paths
+pub fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => match bytes.get(i + 1..i + 3).and_then(hex_byte) {
+                Some(byte) => {
+                    decoded.push(byte);
+                    i += 3;
+                }
+                None => {
+                    decoded.push(b'%');
+                    i += 1;
+                }
+            },
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn hex_byte(digits: &[u8]) -> Option<u8> {
+    u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()
+}
+
+pub fn percent_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// `http://host:port[/prefix]`, the only kind of URL the client speaks
+#[derive(Debug, Clone, PartialEq)]
+pub struct Url {
+    pub host: String,
+    pub port: u16,
+    pub prefix: String,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Result<Self> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| NoteError::InvalidInput(format!("'{}' is not an http:// URL", url)))?;
+        let (authority, prefix) = match rest.find('/') {
+            Some(index) => (&rest[..index], rest[index..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse().map_err(|_| NoteError::InvalidInput(format!("invalid port in '{}'", url)))?,
+            ),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(NoteError::InvalidInput(format!("no host in '{}'", url)));
+        }
+        Ok(Url { host: host.to_string(), port, prefix: prefix.to_string() })
+    }
+
+    fn socket_addr(&self) -> Result<SocketAddr> {
+        (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| NoteError::InvalidInput(format!("cannot resolve '{}'", self.host)))
+    }
+}
+
+/// Blocking HTTP client for talking to a `notes serve` instance
+#[derive(Debug, Clone)]
+pub struct Client {
+    url: Url,
+    headers: Vec<(String, String)>,
+}
+
+impl Client {
+    pub fn new(url: &str) -> Result<Self> {
+        Ok(Client { url: Url::parse(url)?, headers: Vec::new() })
+    }
+
+    /// Sends `name: value` with every request
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn get(&self, path: &str) -> Result<Response> {
+        self.send("GET", path, None)
+    }
+
+    pub fn post_json<T: Serialize>(&self, path: &str, body: &T) -> Result<Response> {
+        self.send("POST", path, Some(&serde_json::to_vec(body)?))
+    }
+
+    pub fn put_json<T: Serialize>(&self, path: &str, body: &T) -> Result<Response> {
+        self.send("PUT", path, Some(&serde_json::to_vec(body)?))
+    }
+
+    /// Sends one request; `path` may carry a query string
+    pub fn send(&self, method: &str, path: &str, body: Option<&[u8]>) -> Result<Response> {
+        let stream = TcpStream::connect_timeout(&self.url.socket_addr()?, CLIENT_TIMEOUT)?;
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+        let mut head = format!("{} {}{} HTTP/1.1\r\nHost: {}:{}\r\nConnection: close\r\n", method, self.url.prefix, path, self.url.host, self.url.port);
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        let body = body.unwrap_or_default();
+        if !body.is_empty() {
+            head.push_str("Content-Type: application/json\r\n");
+        }
+        head.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+
+        let mut writer = &stream;
+        writer.write_all(head.as_bytes())?;
+        writer.write_all(body)?;
+        writer.flush()?;
+        read_response(BufReader::new(&stream))
+    }
+}
+
+fn read_response<R: BufRead>(mut reader: R) -> Result<Response> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| NoteError::SerializationError(format!("invalid HTTP status line: {}", line.trim())))?;
+
+    let mut response = Response::new(status);
+    let mut headers = HashMap::new();
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            break;
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+            response.headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    match headers.get("content-length") {
+        Some(_) => {
+            let length = content_length(&headers)?;
+            response.body = vec![0; length];
+            reader.read_exact(&mut response.body)?;
+        }
+        None => {
+            reader.take(MAX_BODY as u64).read_to_end(&mut response.body)?;
+        }
+    }
+    Ok(response)
+}
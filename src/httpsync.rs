@@ -0,0 +1,1048 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::crypto::KdfParams;
+use crate::dirsync::{self, Action, SyncState, SyncSummary, SYNC_BASE_DIR};
+use crate::error::{NoteError, Result};
+use crate::http::{Client, Request, Response};
+use crate::merge;
+use crate::note::{Note, NoteId};
+use crate::notebook::{validate_notebook_name, NotebookStore};
+use crate::sealed::{Keyring, SealedNote, SyncKeys, KEYRING_FILE};
+use crate::storage::{lock_dir, FileStorage};
+use crate::watch::note_files;
+
+/// Change log of a sync server, kept in the notes directory it serves
+pub const SYNC_LOG_FILE: &str = "sync-log.json";
+/// Device id and per-server cursors of a sync client, kept in its notes directory
+pub const REMOTES_FILE: &str = "remotes.json";
+/// Notebook end-to-end encrypted notes travel in, and the server directory keeping
+/// them. Notebook names never start with a dot, so it cannot clash with one.
+pub const SEALED_NOTEBOOK: &str = ".sealed";
+pub const DEFAULT_BIND: &str = "127.0.0.1:7878";
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Changes fetched and notes pushed per request
+pub const BATCH_SIZE: usize = 100;
+const MAX_BATCH: usize = 1000;
+/// Pull/push rounds per sync, so pushes refused because of a concurrent change are retried at once
+const MAX_ROUNDS: usize = 3;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub server_id: String,
+    pub protocol: u32,
+    /// Whether the server holds a keyring and only accepts end-to-end encrypted notes
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+/// The latest state of one note on the server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    pub seq: u64,
+    pub notebook: String,
+    pub id: NoteId,
+    /// Content hash (or version tag, for sealed notes) of the note, `None` once it was deleted
+    pub hash: Option<String>,
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Device that pushed the change; `None` for edits made on the server itself
+    pub device: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChangeBatch {
+    pub changes: Vec<Change>,
+    /// Pass as `since` to fetch the next batch
+    pub cursor: u64,
+    pub more: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct NoteRef {
+    pub notebook: String,
+    pub id: NoteId,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PullRequest {
+    pub items: Vec<NoteRef>,
+}
+
+/// A note as the server has it: plaintext, or sealed in [`SEALED_NOTEBOOK`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PulledNote {
+    pub notebook: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<Note>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed: Option<SealedNote>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PullResponse {
+    pub notes: Vec<PulledNote>,
+}
+
+/// A note written or deleted on a device since it last saw the server's version
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PushItem {
+    pub notebook: String,
+    pub id: NoteId,
+    /// Hash of the server's version the change was made on; `None` for new notes
+    pub base: Option<String>,
+    /// `None`, with no `sealed` note either, deletes the note
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<Note>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed: Option<SealedNote>,
+    /// Version tag of a sealed note, which the server cannot compute itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PushRequest {
+    pub device: String,
+    pub items: Vec<PushItem>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PushResponse {
+    pub accepted: Vec<NoteRef>,
+    /// Items whose base no longer matches the server's version; pull first, then push again
+    pub rejected: Vec<NoteRef>,
+    pub cursor: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LogEntry {
+    seq: u64,
+    hash: Option<String>,
+    deleted_at: Option<DateTime<Utc>>,
+    device: Option<String>,
+    /// Stamp of the note file when it was last hashed
+    modified: Option<DateTime<Utc>>,
+    len: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ChangeLog {
+    server_id: String,
+    next_seq: u64,
+    notebooks: BTreeMap<String, BTreeMap<NoteId, LogEntry>>,
+}
+
+impl ChangeLog {
+    fn load(root: &Path) -> Result<Self> {
+        match fs::read_to_string(root.join(SYNC_LOG_FILE)) {
+            Ok(data) => serde_json::from_str(&data).map_err(|e| {
+                NoteError::SerializationError(format!("{:?} is damaged: {}", root.join(SYNC_LOG_FILE), e))
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(ChangeLog {
+                server_id: Uuid::new_v4().to_string(),
+                next_seq: 1,
+                notebooks: BTreeMap::new(),
+            }),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, root: &Path) -> Result<()> {
+        save_json(root, SYNC_LOG_FILE, self)
+    }
+
+    fn record(&mut self, notebook: &str, id: &NoteId, mut entry: LogEntry) {
+        entry.seq = self.next_seq;
+        self.next_seq += 1;
+        self.notebooks.entry(notebook.to_string()).or_default().insert(id.clone(), entry);
+    }
+
+    fn hash(&self, notebook: &str, id: &NoteId) -> Option<&String> {
+        self.notebooks.get(notebook)?.get(id)?.hash.as_ref()
+    }
+
+    fn has_plaintext_notes(&self) -> bool {
+        self.notebooks
+            .iter()
+            .filter(|(name, _)| name.as_str() != SEALED_NOTEBOOK)
+            .any(|(_, notes)| notes.values().any(|entry| entry.hash.is_some()))
+    }
+
+    /// Logs the notes changed on disk since the last call, whoever changed them.
+    /// Files are only hashed again when their modification time or length changed.
+    fn refresh(&mut self, notebooks: &NotebookStore) -> Result<bool> {
+        let mut changed = false;
+        let mut present: BTreeSet<(String, NoteId)> = BTreeSet::new();
+        for (name, _) in notebooks.list()? {
+            let storage = notebooks.open(&name)?;
+            for (id, path) in note_files(storage.storage_dir())? {
+                present.insert((name.clone(), id.clone()));
+                let (modified, len) = match fs::metadata(&path) {
+                    Ok(metadata) => (metadata.modified().ok().map(DateTime::<Utc>::from), metadata.len()),
+                    Err(_) => continue,
+                };
+                let entry = self.notebooks.get(&name).and_then(|notes| notes.get(&id));
+                if entry.is_some_and(|e| e.hash.is_some() && e.modified == modified && e.len == len) {
+                    continue;
+                }
+
+                let hash = match storage.load_note(&id).map_err(NoteError::from).and_then(|n| dirsync::content_hash(&n)) {
+                    Ok(hash) => hash,
+                    Err(e) => {
+                        // Left at its last logged state until it can be read again
+                        eprintln!("Warning: Failed to read note file {:?}: {}", path, e);
+                        continue;
+                    }
+                };
+                changed = true;
+                match self.notebooks.get_mut(&name).and_then(|notes| notes.get_mut(&id)) {
+                    Some(entry) if entry.hash.as_ref() == Some(&hash) => {
+                        entry.modified = modified;
+                        entry.len = len;
+                    }
+                    _ => {
+                        let entry = LogEntry { seq: 0, hash: Some(hash), deleted_at: None, device: None, modified, len };
+                        self.record(&name, &id, entry);
+                    }
+                }
+            }
+        }
+
+        // Sealed notes only ever change through pushes
+        let removed: Vec<(String, NoteId)> = self
+            .notebooks
+            .iter()
+            .filter(|(name, _)| name.as_str() != SEALED_NOTEBOOK)
+            .flat_map(|(name, notes)| notes.iter().map(move |(id, entry)| (name, id, entry)))
+            .filter(|(name, id, entry)| entry.hash.is_some() && !present.contains(&((*name).clone(), (*id).clone())))
+            .map(|(name, id, _)| (name.clone(), id.clone()))
+            .collect();
+        for (name, id) in removed {
+            let deleted_at = match notebooks.exists(&name) {
+                true => SyncState::load(&notebooks.root().join(&name))?.tombstones.get(&id).copied(),
+                false => None,
+            };
+            let entry = LogEntry {
+                seq: 0,
+                hash: None,
+                deleted_at: Some(deleted_at.unwrap_or_else(Utc::now)),
+                device: None,
+                modified: None,
+                len: 0,
+            };
+            self.record(&name, &id, entry);
+            changed = true;
+        }
+        Ok(changed)
+    }
+}
+
+/// A sealed note with the version tag it was pushed with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredSeal {
+    hash: String,
+    sealed: SealedNote,
+}
+
+struct ServerState {
+    log: ChangeLog,
+    keyring: Option<Keyring>,
+}
+
+/// Serves the notes directory of `notebooks` to `notes sync --remote` clients.
+///
+/// Every change to a note gets the next sequence number of the server's change log,
+/// so clients fetch what changed since their cursor in batches. Pushes are
+/// optimistic: a note is only written when the client's base hash still matches
+/// the server's version, otherwise the client pulls and merges first.
+///
+/// Once a client stored a [`Keyring`], the server only accepts sealed notes and
+/// keeps them as opaque blobs in [`SEALED_NOTEBOOK`], keyed by note id and revision.
+pub struct SyncServer {
+    notebooks: NotebookStore,
+    state: Mutex<ServerState>,
+}
+
+impl SyncServer {
+    pub fn new(notebooks: NotebookStore) -> Result<Self> {
+        let mut log = ChangeLog::load(notebooks.root())?;
+        log.refresh(&notebooks)?;
+        log.save(notebooks.root())?;
+        let keyring = match fs::read_to_string(notebooks.root().join(KEYRING_FILE)) {
+            Ok(data) => Some(serde_json::from_str(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(SyncServer { notebooks, state: Mutex::new(ServerState { log, keyring }) })
+    }
+
+    pub fn server_id(&self) -> String {
+        self.lock_state().log.server_id.clone()
+    }
+
+    /// Answers one request of the sync protocol; unknown paths get a 404
+    pub fn handle(&self, request: &Request) -> Response {
+        let result = match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/sync/v1/info") => self.with_state(|_, state| {
+                let info = ServerInfo {
+                    server_id: state.log.server_id.clone(),
+                    protocol: PROTOCOL_VERSION,
+                    encrypted: state.keyring.is_some(),
+                };
+                Ok(Response::json(200, &info))
+            }),
+            ("GET", "/sync/v1/changes") => self.with_state(|_, state| changes(&state.log, request)),
+            ("POST", "/sync/v1/pull") => self.with_state(|notebooks, _| pull(notebooks, &request.json()?)),
+            ("POST", "/sync/v1/push") => self.with_state(|notebooks, state| push(notebooks, state, request.json()?)),
+            ("GET", "/sync/v1/keyring") => self.with_state(|_, state| match &state.keyring {
+                Some(keyring) => Ok(Response::json(200, keyring)),
+                None => Ok(Response::error(404, "this server has no keyring")),
+            }),
+            ("PUT", "/sync/v1/keyring") => {
+                self.with_state(|notebooks, state| put_keyring(notebooks, state, request.json()?))
+            }
+            (_, "/sync/v1/info" | "/sync/v1/changes" | "/sync/v1/pull" | "/sync/v1/push" | "/sync/v1/keyring") => {
+                Ok(Response::error(405, "method not allowed"))
+            }
+            _ => Ok(Response::error(404, "not found")),
+        };
+        result.unwrap_or_else(|e| Response::from_error(&e))
+    }
+
+    /// Runs `f` on an up to date change log, saving it if anything was logged
+    fn with_state(&self, f: impl FnOnce(&NotebookStore, &mut ServerState) -> Result<Response>) -> Result<Response> {
+        let mut state = self.lock_state();
+        let changed = state.log.refresh(&self.notebooks)?;
+        let next_seq = state.log.next_seq;
+        let response = f(&self.notebooks, &mut state);
+        if changed || state.log.next_seq != next_seq {
+            state.log.save(self.notebooks.root())?;
+        }
+        response
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, ServerState> {
+        // A panicking handler leaves the log as consistent as its last save
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn changes(log: &ChangeLog, request: &Request) -> Result<Response> {
+    let number = |name: &str, default: u64| -> Result<u64> {
+        match request.query.get(name) {
+            Some(value) => value.parse().map_err(|_| NoteError::InvalidInput(format!("invalid {}: {}", name, value))),
+            None => Ok(default),
+        }
+    };
+    let since = number("since", 0)?;
+    let limit = number("limit", BATCH_SIZE as u64)?.clamp(1, MAX_BATCH as u64) as usize;
+
+    let mut changes: Vec<Change> = log
+        .notebooks
+        .iter()
+        .flat_map(|(notebook, notes)| notes.iter().map(move |(id, entry)| (notebook, id, entry)))
+        .filter(|(_, _, entry)| entry.seq > since)
+        .map(|(notebook, id, entry)| Change {
+            seq: entry.seq,
+            notebook: notebook.clone(),
+            id: id.clone(),
+            hash: entry.hash.clone(),
+            deleted_at: entry.deleted_at,
+            device: entry.device.clone(),
+        })
+        .collect();
+    changes.sort_by_key(|change| change.seq);
+    let more = changes.len() > limit;
+    changes.truncate(limit);
+    let cursor = changes.last().map_or(since, |change| change.seq);
+    Ok(Response::json(200, &ChangeBatch { changes, cursor, more }))
+}
+
+fn pull(notebooks: &NotebookStore, request: &PullRequest) -> Result<Response> {
+    if request.items.len() > MAX_BATCH {
+        return Err(NoteError::InvalidInput(format!("at most {} notes per pull", MAX_BATCH)));
+    }
+    let mut notes = Vec::new();
+    for item in &request.items {
+        if item.notebook == SEALED_NOTEBOOK {
+            if let Some(stored) = load_seal(notebooks.root(), &item.id)? {
+                notes.push(PulledNote { notebook: item.notebook.clone(), note: None, sealed: Some(stored.sealed) });
+            }
+            continue;
+        }
+        if !notebooks.exists(&item.notebook) {
+            continue;
+        }
+        match notebooks.open(&item.notebook)?.load_note(&item.id).map_err(NoteError::from) {
+            Ok(note) => notes.push(PulledNote { notebook: item.notebook.clone(), note: Some(note), sealed: None }),
+            // Deleted since the client saw the change; the next batch tells it so
+            Err(NoteError::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Response::json(200, &PullResponse { notes }))
+}
+
+fn push(notebooks: &NotebookStore, state: &mut ServerState, request: PushRequest) -> Result<Response> {
+    if request.items.len() > MAX_BATCH {
+        return Err(NoteError::InvalidInput(format!("at most {} notes per push", MAX_BATCH)));
+    }
+    let mut accepted = Vec::new();
+    let mut rejected = Vec::new();
+    for item in request.items {
+        let target = match &state.keyring {
+            Some(keyring) => sealed_target(keyring, &item)?,
+            None => {
+                validate_notebook_name(&item.notebook)?;
+                if item.note.as_ref().is_some_and(|note| note.id != item.id) {
+                    return Err(NoteError::InvalidInput(format!("pushed note does not match id {}", item.id)));
+                }
+                item.note.as_ref().map(dirsync::content_hash).transpose()?
+            }
+        };
+        let note_ref = NoteRef { notebook: item.notebook.clone(), id: item.id.clone() };
+        let current = state.log.hash(&item.notebook, &item.id).cloned();
+
+        // A batch sent again after a lost response finds its changes already applied
+        if current == target {
+            accepted.push(note_ref);
+            continue;
+        }
+        if current != item.base {
+            rejected.push(note_ref);
+            continue;
+        }
+
+        let mut entry = LogEntry {
+            seq: 0,
+            hash: target.clone(),
+            deleted_at: None,
+            device: Some(request.device.clone()),
+            modified: None,
+            len: 0,
+        };
+        match (item.sealed, target) {
+            (Some(sealed), Some(hash)) => {
+                let dir = notebooks.root().join(SEALED_NOTEBOOK);
+                fs::create_dir_all(&dir)?;
+                save_json(&dir, &format!("{}.json", item.id), &StoredSeal { hash, sealed })?;
+            }
+            (None, _) if state.keyring.is_some() => {
+                let path = notebooks.root().join(SEALED_NOTEBOOK).join(format!("{}.json", item.id));
+                if path.exists() {
+                    fs::remove_file(path)?;
+                }
+                entry.deleted_at = Some(Utc::now());
+            }
+            _ => {
+                let storage = notebooks.open_or_create(&item.notebook)?;
+                let lock = storage.lock()?;
+                match &item.note {
+                    Some(note) => {
+                        storage.save_note_locked(note, &lock)?;
+                        let metadata = fs::metadata(storage.storage_dir().join(format!("{}.json", note.id)))?;
+                        entry.modified = metadata.modified().ok().map(DateTime::<Utc>::from);
+                        entry.len = metadata.len();
+                    }
+                    None => {
+                        storage.delete_note_locked(&item.id, &lock)?;
+                        entry.deleted_at = Some(Utc::now());
+                    }
+                }
+            }
+        }
+        state.log.record(&item.notebook, &item.id, entry);
+        accepted.push(note_ref);
+    }
+    Ok(Response::json(200, &PushResponse { accepted, rejected, cursor: state.log.next_seq - 1 }))
+}
+
+/// Checks an item pushed to a server with a keyring, giving its version tag
+fn sealed_target(keyring: &Keyring, item: &PushItem) -> Result<Option<String>> {
+    if item.notebook != SEALED_NOTEBOOK || item.note.is_some() {
+        return Err(NoteError::InvalidInput("this server only accepts end-to-end encrypted notes".to_string()));
+    }
+    let sealed = match &item.sealed {
+        Some(sealed) => sealed,
+        None => return Ok(None),
+    };
+    if sealed.id != item.id {
+        return Err(NoteError::InvalidInput(format!("pushed note does not match id {}", item.id)));
+    }
+    if sealed.key_id != keyring.key_id {
+        return Err(NoteError::Conflict("the sync key was rotated; sync again to get the new one".to_string()));
+    }
+    match &item.hash {
+        Some(hash) => Ok(Some(hash.clone())),
+        None => Err(NoteError::InvalidInput(format!("sealed note {} has no version tag", item.id))),
+    }
+}
+
+/// Stores the next version of the keyring. The first one is refused while the
+/// server holds plaintext notes, which would otherwise stay readable on it.
+fn put_keyring(notebooks: &NotebookStore, state: &mut ServerState, keyring: Keyring) -> Result<Response> {
+    let expected = state.keyring.as_ref().map_or(1, |current| current.version + 1);
+    if keyring.version != expected {
+        return Err(NoteError::Conflict(format!(
+            "expected keyring version {}, got {}; sync again to get the current one",
+            expected, keyring.version
+        )));
+    }
+    if state.keyring.is_none() && state.log.has_plaintext_notes() {
+        return Err(NoteError::Conflict("this server already holds plaintext notes".to_string()));
+    }
+    save_json(notebooks.root(), KEYRING_FILE, &keyring)?;
+    state.keyring = Some(keyring);
+    Ok(Response::new(204))
+}
+
+fn load_seal(root: &Path, id: &NoteId) -> Result<Option<StoredSeal>> {
+    match fs::read_to_string(root.join(SEALED_NOTEBOOK).join(format!("{}.json", id))) {
+        Ok(data) => Ok(Some(serde_json::from_str(&data)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct RemoteState {
+    server_id: String,
+    /// Sequence number of the last change applied from the server
+    cursor: u64,
+    /// Per local notebook, the hash of every note as the server last had it
+    bases: BTreeMap<String, BTreeMap<NoteId, String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Remotes {
+    /// Identifies this notes directory to every server it syncs with
+    device_id: String,
+    remotes: BTreeMap<String, RemoteState>,
+}
+
+impl Remotes {
+    fn load(root: &Path) -> Result<Self> {
+        match fs::read_to_string(root.join(REMOTES_FILE)) {
+            Ok(data) => serde_json::from_str(&data).map_err(|e| {
+                NoteError::SerializationError(format!("{:?} is damaged: {}", root.join(REMOTES_FILE), e))
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(Remotes { device_id: Uuid::new_v4().to_string(), remotes: BTreeMap::new() })
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, root: &Path) -> Result<()> {
+        save_json(root, REMOTES_FILE, self)
+    }
+}
+
+/// The id this notes directory sends to sync servers, created on first use
+pub fn device_id(notebooks: &NotebookStore) -> Result<String> {
+    let _lock = lock_dir(notebooks.root())?;
+    let remotes = Remotes::load(notebooks.root())?;
+    remotes.save(notebooks.root())?;
+    Ok(remotes.device_id)
+}
+
+/// Asks for a passphrase, given a prompt such as "Sync passphrase"
+pub type PassphrasePrompt<'a> = Box<dyn FnMut(&str) -> Result<String> + 'a>;
+
+pub struct RemoteOptions<'a> {
+    pub batch_size: usize,
+    /// Store a new keyring on a server without one, so notes are end-to-end encrypted from now on
+    pub encrypt: bool,
+    /// After syncing, replace the server's sync key and seal every note again with the new one
+    pub rotate_key: bool,
+    /// Only asked when the server holds, or is about to hold, a keyring
+    pub passphrase: PassphrasePrompt<'a>,
+    /// Key derivation parameters, with a fresh salt, for new keyrings
+    pub kdf: fn() -> Result<KdfParams>,
+    /// Sent as a bearer token, for servers that also serve the API
+    pub token: Option<String>,
+    /// Local tombstones older than this are dropped after the sync
+    pub tombstone_age: chrono::Duration,
+}
+
+impl<'a> RemoteOptions<'a> {
+    pub fn new(passphrase: impl FnMut(&str) -> Result<String> + 'a) -> Self {
+        RemoteOptions {
+            batch_size: BATCH_SIZE,
+            encrypt: false,
+            rotate_key: false,
+            passphrase: Box::new(passphrase),
+            kdf: KdfParams::generate,
+            token: None,
+            tombstone_age: chrono::Duration::days(dirsync::TOMBSTONE_DAYS.into()),
+        }
+    }
+}
+
+/// Syncs every notebook with the `notes serve --sync` server at `url`.
+///
+/// Changes are pulled from the server's change log in batches, and the cursor is
+/// saved after each batch, so an interrupted sync resumes where it stopped. Notes
+/// changed on both sides are merged against the version of the last sync like
+/// [`dirsync::sync_stores`] does. Local changes are then pushed in batches; a push
+/// the server refuses because the note changed there meanwhile is pulled, merged
+/// and pushed again.
+///
+/// When the server holds a [`Keyring`], notes are sealed together with their
+/// notebook name before they leave this device.
+pub fn sync_remote(notebooks: &NotebookStore, url: &str, mut options: RemoteOptions) -> Result<Vec<(String, SyncSummary)>> {
+    let client = match &options.token {
+        Some(token) => Client::new(url)?.with_header("Authorization", &format!("Bearer {}", token)),
+        None => Client::new(url)?,
+    };
+    let _lock = lock_dir(notebooks.root())?;
+    let mut remotes = Remotes::load(notebooks.root())?;
+
+    let response = client.get("/sync/v1/info")?;
+    let info: ServerInfo = expect_success(response)?.json_body()?;
+    if info.protocol != PROTOCOL_VERSION {
+        return Err(NoteError::IncompatibleSchema(format!(
+            "{} speaks sync protocol {}, this version of notes speaks {}",
+            url, info.protocol, PROTOCOL_VERSION
+        )));
+    }
+    let key = url.trim_end_matches('/').to_string();
+    let state = remotes.remotes.entry(key.clone()).or_default();
+    if state.server_id != info.server_id {
+        // A new server, or one whose change log was reset: start over from its first change
+        *state = RemoteState { server_id: info.server_id, ..RemoteState::default() };
+    }
+    remotes.save(notebooks.root())?;
+
+    let (keyring, keys) = if info.encrypted {
+        let keyring: Keyring = expect_success(client.get("/sync/v1/keyring")?)?.json_body()?;
+        let keys = keyring.unlock(&(options.passphrase)("Sync passphrase")?)?;
+        (Some(keyring), Some(keys))
+    } else if options.encrypt {
+        let (keyring, keys) = Keyring::create(&(options.passphrase)("New sync passphrase")?, (options.kdf)()?)?;
+        expect_success(client.put_json("/sync/v1/keyring", &keyring)?)?;
+        (Some(keyring), Some(keys))
+    } else if options.rotate_key {
+        return Err(NoteError::InvalidInput(format!("{} has no sync key to rotate; use --encrypt first", url)));
+    } else {
+        (None, None)
+    };
+
+    let mut session = Session {
+        client,
+        notebooks,
+        remotes,
+        key,
+        keys,
+        batch_size: options.batch_size.max(1),
+        summaries: BTreeMap::new(),
+    };
+    let mut rejected = 0;
+    for _ in 0..MAX_ROUNDS {
+        session.pull()?;
+        rejected = session.push()?;
+        if rejected == 0 {
+            break;
+        }
+    }
+
+    if let (true, Some(keyring), Some(keys)) = (options.rotate_key, &keyring, &session.keys) {
+        let passphrase = (options.passphrase)("New sync passphrase")?;
+        let (keyring, keys) = keyring.rotate(keys, &passphrase, (options.kdf)()?)?;
+        expect_success(session.client.put_json("/sync/v1/keyring", &keyring)?)?;
+        // Version tags depend on the key, so every note is sealed and pushed again
+        session.keys = Some(keys);
+        rejected = session.push()?;
+    }
+
+    dirsync::expire_tombstones(notebooks, options.tombstone_age)?;
+    let mut results: Vec<(String, SyncSummary)> = session.summaries.into_iter().collect();
+    if rejected > 0 {
+        match results.first_mut() {
+            Some((_, summary)) => summary.rejected += rejected,
+            None => results.push((String::new(), SyncSummary { rejected, ..SyncSummary::default() })),
+        }
+    }
+    Ok(results)
+}
+
+/// A version of a note with the notebook it is in and its hash
+struct Version {
+    notebook: String,
+    note: Note,
+    hash: String,
+}
+
+struct Session<'a> {
+    client: Client,
+    notebooks: &'a NotebookStore,
+    remotes: Remotes,
+    /// Entry of `remotes` for the server being synced
+    key: String,
+    /// Set when the server holds end-to-end encrypted notes
+    keys: Option<SyncKeys>,
+    batch_size: usize,
+    summaries: BTreeMap<String, SyncSummary>,
+}
+
+impl Session<'_> {
+    fn state(&mut self) -> &mut RemoteState {
+        self.remotes.remotes.get_mut(&self.key).expect("inserted before the session starts")
+    }
+
+    fn summary(&mut self, notebook: &str) -> &mut SyncSummary {
+        self.summaries.entry(notebook.to_string()).or_default()
+    }
+
+    /// Content hash of a note, or its version tag on an encrypted server
+    fn hash(&self, notebook: &str, note: &Note) -> Result<String> {
+        match &self.keys {
+            Some(keys) => keys.tag(notebook, note),
+            None => dirsync::content_hash(note),
+        }
+    }
+
+    /// The server's version of a note as of the last sync. Sealed notes do not tell
+    /// the server their notebook, so their bases are looked up in every notebook.
+    fn base(&self, notebook: &str, id: &NoteId) -> Option<String> {
+        let bases = &self.remotes.remotes[&self.key].bases;
+        match self.keys {
+            Some(_) => bases.values().find_map(|notes| notes.get(id)).cloned(),
+            None => bases.get(notebook)?.get(id).cloned(),
+        }
+    }
+
+    fn base_store(&self) -> Result<FileStorage> {
+        let dir = self.notebooks.root().join(SYNC_BASE_DIR).join(&self.remotes.remotes[&self.key].server_id);
+        let base = FileStorage::new(&dir.to_string_lossy())?;
+        Ok(match self.notebooks.key() {
+            Some(key) => base.with_key(key.clone()),
+            None => base,
+        })
+    }
+
+    /// Records `note` as the server's version of a note, or forgets the note
+    fn set_base(&mut self, notebook: &str, id: &NoteId, note: Option<(&Note, String)>) -> Result<()> {
+        let base_store = self.base_store()?;
+        let sealed = self.keys.is_some();
+        let bases = &mut self.state().bases;
+        let previous = match sealed {
+            true => bases.values_mut().find_map(|notes| notes.remove(id)),
+            false => bases.get_mut(notebook).and_then(|notes| notes.remove(id)),
+        };
+        match note {
+            Some((note, hash)) => {
+                if previous.as_ref() != Some(&hash) {
+                    base_store.save_note(note)?;
+                }
+                bases.entry(notebook.to_string()).or_default().insert(id.clone(), hash);
+            }
+            None => {
+                let path = base_store.storage_dir().join(format!("{}.json", id));
+                if path.exists() {
+                    fs::remove_file(path)?;
+                }
+            }
+        }
+        bases.retain(|_, notes| !notes.is_empty());
+        Ok(())
+    }
+
+    fn save_state(&self) -> Result<()> {
+        self.remotes.save(self.notebooks.root())
+    }
+
+    /// The local version of a note the server listed. A sealed note may be in any notebook.
+    fn find_local(&self, change: &Change) -> Result<Option<Version>> {
+        let notebook = match self.keys {
+            Some(_) => self.notebooks.locate(&change.id)?,
+            None if self.notebooks.exists(&change.notebook) => Some(change.notebook.clone()),
+            None => None,
+        };
+        let notebook = match notebook {
+            Some(notebook) => notebook,
+            None => return Ok(None),
+        };
+        match load_local(&self.notebooks.open(&notebook)?, &change.id)? {
+            Some(note) => {
+                let hash = self.hash(&notebook, &note)?;
+                Ok(Some(Version { notebook, note, hash }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Decrypts a pulled note if it is sealed
+    fn open_pulled(&self, pulled: PulledNote) -> Result<Version> {
+        let (notebook, note) = match (&self.keys, pulled.note, pulled.sealed) {
+            (None, Some(note), _) => (pulled.notebook, note),
+            (Some(keys), _, Some(sealed)) => keys.open(&sealed)?,
+            _ => return Err(NoteError::SerializationError("the sync server sent a note in the wrong form".to_string())),
+        };
+        validate_notebook_name(&notebook)?;
+        let hash = self.hash(&notebook, &note)?;
+        Ok(Version { notebook, note, hash })
+    }
+
+    /// Applies the server's changes since the saved cursor, one batch at a time
+    fn pull(&mut self) -> Result<()> {
+        let sealed = self.keys.is_some();
+        loop {
+            let cursor = self.state().cursor;
+            let path = format!("/sync/v1/changes?since={}&limit={}", cursor, self.batch_size);
+            let batch: ChangeBatch = expect_success(self.client.get(&path)?)?.json_body()?;
+
+            // Plaintext notes never live on a server with a keyring, see `put_keyring`
+            let changes: Vec<&Change> =
+                batch.changes.iter().filter(|change| (change.notebook == SEALED_NOTEBOOK) == sealed).collect();
+            let mut locals = Vec::new();
+            let mut wanted = Vec::new();
+            for change in &changes {
+                if !sealed {
+                    validate_notebook_name(&change.notebook)?;
+                }
+                let local = self.find_local(change)?;
+                if change.hash.is_some() && local.as_ref().map(|local| &local.hash) != change.hash.as_ref() {
+                    wanted.push(NoteRef { notebook: change.notebook.clone(), id: change.id.clone() });
+                }
+                locals.push(local);
+            }
+
+            let mut pulled = BTreeMap::new();
+            if !wanted.is_empty() {
+                let response = self.client.post_json("/sync/v1/pull", &PullRequest { items: wanted })?;
+                let notes: PullResponse = expect_success(response)?.json_body()?;
+                for pulled_note in notes.notes {
+                    let wire_notebook = pulled_note.notebook.clone();
+                    let version = self.open_pulled(pulled_note)?;
+                    pulled.insert((wire_notebook, version.note.id.clone()), version);
+                }
+            }
+
+            for (change, local) in changes.into_iter().zip(locals) {
+                let remote = pulled.remove(&(change.notebook.clone(), change.id.clone()));
+                self.apply(change, local, remote)?;
+            }
+            self.state().cursor = batch.cursor;
+            self.save_state()?;
+            if !batch.more {
+                return Ok(());
+            }
+        }
+    }
+
+    fn apply(&mut self, change: &Change, local: Option<Version>, remote: Option<Version>) -> Result<()> {
+        let remote = match (&change.hash, remote) {
+            (Some(_), Some(remote)) => Some(remote),
+            (Some(hash), None) => match &local {
+                Some(local) if &local.hash == hash => {
+                    Some(Version { notebook: local.notebook.clone(), note: local.note.clone(), hash: hash.clone() })
+                }
+                // Changed again since this batch was listed; a later batch has it
+                _ => return Ok(()),
+            },
+            (None, _) => None,
+        };
+        // Where the note is, or was, on this device
+        let notebook = match (&local, &remote) {
+            (Some(version), _) | (None, Some(version)) => version.notebook.clone(),
+            (None, None) if self.keys.is_none() => change.notebook.clone(),
+            // Deleted on both sides; nothing tells which notebook the sealed note was in
+            (None, None) => return self.set_base(&change.notebook, &change.id, None),
+        };
+        let base = self.base(&notebook, &change.id);
+        let here_deleted = match self.notebooks.exists(&notebook) {
+            true => SyncState::load(&self.notebooks.root().join(&notebook))?.tombstones.get(&change.id).copied(),
+            false => None,
+        };
+        let here = local.as_ref().map(|local| (&local.note, local.hash.as_str()));
+        let there = remote.as_ref().map(|remote| (&remote.note, remote.hash.as_str()));
+
+        match dirsync::decide(here, there, base.as_deref(), here_deleted, change.deleted_at) {
+            Action::Pull => {
+                let remote = remote.expect("pulled notes exist on the server");
+                let storage = self.notebooks.open_or_create(&remote.notebook)?;
+                storage.save_note_locked(&remote.note, &storage.lock()?)?;
+                if let Some(local) = local.filter(|local| local.notebook != remote.notebook) {
+                    // Moved to another notebook on another device
+                    let old = self.notebooks.open(&local.notebook)?;
+                    old.delete_note_locked(&change.id, &old.lock()?)?;
+                }
+                self.set_base(&remote.notebook, &change.id, Some((&remote.note, remote.hash)))?;
+                self.summary(&remote.notebook).pulled += 1;
+            }
+            Action::DeleteHere => {
+                let storage = self.notebooks.open(&notebook)?;
+                storage.delete_note_locked(&change.id, &storage.lock()?)?;
+                self.set_base(&notebook, &change.id, None)?;
+                self.summary(&notebook).deleted_here += 1;
+            }
+            Action::Conflict => {
+                let local = local.expect("conflicts have a local version");
+                let remote = remote.expect("conflicts have a server version");
+                let storage = self.notebooks.open(&notebook)?;
+                let lock = storage.lock()?;
+                let ancestor = self.base_store()?.load_note(&change.id).ok().filter(|ancestor| {
+                    [&local.notebook, &remote.notebook]
+                        .iter()
+                        .any(|notebook| self.hash(notebook, ancestor).ok() == base)
+                });
+                let labels = (storage.storage_dir().display().to_string(), self.key.clone());
+                let merged = ancestor.and_then(|ancestor| {
+                    merge::merge_notes(&ancestor, &local.note, &remote.note, (&labels.0, &labels.1)).ok()
+                });
+
+                // The server's version becomes the base, so the merged or newer note is pushed over it
+                match merged {
+                    Some(merged) => {
+                        storage.save_note_locked(&merged.note, &lock)?;
+                        let summary = self.summary(&notebook);
+                        if merged.is_clean() {
+                            summary.merged += 1;
+                        } else {
+                            summary.unresolved.push(change.id.clone());
+                        }
+                    }
+                    None => {
+                        let copy = if remote.note.updated_at > local.note.updated_at {
+                            storage.save_note_locked(&remote.note, &lock)?;
+                            dirsync::conflict_copy(&local.note)
+                        } else {
+                            dirsync::conflict_copy(&remote.note)
+                        };
+                        storage.save_note_locked(&copy, &lock)?;
+                        self.summary(&notebook).conflicts.push((change.id.clone(), copy.id));
+                    }
+                }
+                self.set_base(&notebook, &change.id, Some((&remote.note, remote.hash)))?;
+            }
+            // Changed or deleted here: it is pushed over the server's version, which becomes the base
+            Action::Push | Action::DeleteThere | Action::None => match remote {
+                Some(remote) => self.set_base(&notebook, &change.id, Some((&remote.note, remote.hash)))?,
+                None => self.set_base(&notebook, &change.id, None)?,
+            },
+        }
+        Ok(())
+    }
+
+    /// Pushes every note that differs from the server's version; returns how many were refused
+    fn push(&mut self) -> Result<usize> {
+        let mut locals = Vec::new();
+        for (name, _) in self.notebooks.list()? {
+            for (_, (note, _)) in dirsync::load_all(&self.notebooks.open(&name)?)? {
+                let hash = self.hash(&name, &note)?;
+                locals.push(Version { notebook: name.clone(), note, hash });
+            }
+        }
+        let present: BTreeSet<(&str, &NoteId)> = locals.iter().map(|l| (l.notebook.as_str(), &l.note.id)).collect();
+        let present_ids: BTreeSet<&NoteId> = present.iter().map(|(_, id)| *id).collect();
+
+        // Each item with the local notebook and the version to record as base once accepted
+        let mut items: Vec<(PushItem, String, Option<Version>)> = Vec::new();
+        for (notebook, bases) in &self.remotes.remotes[&self.key].bases {
+            for (id, hash) in bases {
+                let gone = match self.keys {
+                    Some(_) => !present_ids.contains(id),
+                    None => !present.contains(&(notebook.as_str(), id)),
+                };
+                if gone {
+                    let item = PushItem {
+                        notebook: self.wire_notebook(notebook),
+                        id: id.clone(),
+                        base: Some(hash.clone()),
+                        note: None,
+                        sealed: None,
+                        hash: None,
+                    };
+                    items.push((item, notebook.clone(), None));
+                }
+            }
+        }
+        for local in locals {
+            let base = self.base(&local.notebook, &local.note.id);
+            if base.as_ref() == Some(&local.hash) {
+                continue;
+            }
+            let (note, sealed, hash) = match &self.keys {
+                Some(keys) => (None, Some(keys.seal(&local.notebook, &local.note)?), Some(local.hash.clone())),
+                None => (Some(local.note.clone()), None, None),
+            };
+            let item = PushItem { notebook: self.wire_notebook(&local.notebook), id: local.note.id.clone(), base, note, sealed, hash };
+            items.push((item, local.notebook.clone(), Some(local)));
+        }
+
+        let device = self.remotes.device_id.clone();
+        let mut rejected = 0;
+        for batch in items.chunks(self.batch_size) {
+            let request = PushRequest { device: device.clone(), items: batch.iter().map(|(item, _, _)| item.clone()).collect() };
+            let response: PushResponse = expect_success(self.client.post_json("/sync/v1/push", &request)?)?.json_body()?;
+            let accepted: BTreeSet<NoteRef> = response.accepted.into_iter().collect();
+            for (item, notebook, version) in batch {
+                if !accepted.contains(&NoteRef { notebook: item.notebook.clone(), id: item.id.clone() }) {
+                    continue;
+                }
+                match version {
+                    Some(version) => {
+                        self.set_base(notebook, &item.id, Some((&version.note, version.hash.clone())))?;
+                        self.summary(notebook).pushed += 1;
+                    }
+                    None => {
+                        self.set_base(notebook, &item.id, None)?;
+                        self.summary(notebook).deleted_there += 1;
+                    }
+                }
+            }
+            rejected += response.rejected.len();
+            self.save_state()?;
+        }
+        Ok(rejected)
+    }
+
+    /// Notebook a local notebook's notes are pushed to
+    fn wire_notebook(&self, notebook: &str) -> String {
+        match self.keys {
+            Some(_) => SEALED_NOTEBOOK.to_string(),
+            None => notebook.to_string(),
+        }
+    }
+}
+
+/// A local note, `None` if it does not exist. Unreadable notes fail the sync,
+/// since treating them as missing would delete them on the server.
+fn load_local(storage: &FileStorage, id: &NoteId) -> Result<Option<Note>> {
+    match storage.load_note(id).map_err(NoteError::from) {
+        Ok(note) => Ok(Some(note)),
+        Err(NoteError::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(NoteError::InvalidInput(format!(
+            "cannot sync while note {} is unreadable ({}); run `notes doctor` first",
+            id, e
+        ))),
+    }
+}
+
+fn expect_success(response: Response) -> Result<Response> {
+    if response.is_success() {
+        return Ok(response);
+    }
+    let message = response
+        .json_body::<serde_json::Value>()
+        .ok()
+        .and_then(|body| body.get("error").and_then(|e| e.as_str()).map(str::to_string))
+        .unwrap_or_else(|| String::from_utf8_lossy(&response.body).into_owned());
+    Err(NoteError::InvalidInput(format!("sync server answered {}: {}", response.status, message)))
+}
+
+/// Writes through a temporary file and a rename, like note files
+fn save_json<T: Serialize>(dir: &Path, file_name: &str, value: &T) -> Result<()> {
+    let temp_path = dir.join(format!(".{}.{}.tmp", file_name, Uuid::new_v4()));
+    let mut file = fs::File::create(&temp_path)?;
+    file.write_all(serde_json::to_string_pretty(value)?.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp_path, dir.join(file_name))?;
+    Ok(())
+}
@@ -0,0 +1,192 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::crypto::{self, EncryptedEnvelope, VaultKey};
+use crate::error::NoteError;
+use crate::note::{Note, NoteId};
+
+/// Metadata cache kept next to the notes it describes
+pub const INDEX_FILE: &str = "index.json";
+/// Characters of content kept as the preview of each note
+pub const PREVIEW_CHARS: usize = 100;
+/// Bumped whenever the index layout changes; older indexes are rebuilt
+const INDEX_VERSION: u32 = 1;
+
+/// What listings and tag counts need from a note, without its content
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NoteSummary {
+    pub id: NoteId,
+    pub title: String,
+    pub tags: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub is_archived: bool,
+    /// Content length in bytes
+    pub size: usize,
+    pub preview: String,
+}
+
+impl NoteSummary {
+    pub fn has_tag_or_descendant(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| crate::tags::is_same_or_descendant(t, tag))
+    }
+}
+
+impl From<&Note> for NoteSummary {
+    fn from(note: &Note) -> Self {
+        NoteSummary {
+            id: note.id.clone(),
+            title: note.title.clone(),
+            tags: note.tags.clone(),
+            created_at: note.created_at,
+            updated_at: note.updated_at,
+            is_archived: note.is_archived,
+            size: note.content.len(),
+            preview: note.get_preview(PREVIEW_CHARS),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IndexedNote {
+    /// Modification time and length of the note file when it was indexed
+    modified: DateTime<Utc>,
+    len: u64,
+    summary: NoteSummary,
+}
+
+/// Summaries of every note in a store directory.
+///
+/// Each entry remembers the modification time and length of its file, so notes
+/// changed behind our back (git pulls, restores, other tools) are re-read on the
+/// next [`MetadataIndex::refresh`] instead of being served stale.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetadataIndex {
+    version: u32,
+    notes: BTreeMap<NoteId, IndexedNote>,
+}
+
+impl Default for MetadataIndex {
+    fn default() -> Self {
+        MetadataIndex { version: INDEX_VERSION, notes: BTreeMap::new() }
+    }
+}
+
+impl MetadataIndex {
+    /// Loads the index of `dir`. The index is only a cache, so a missing,
+    /// unreadable or outdated one loads as empty and gets rebuilt.
+    pub fn load(dir: &Path, key: Option<&VaultKey>) -> Self {
+        fs::read_to_string(dir.join(INDEX_FILE))
+            .ok()
+            .and_then(|data| decode(&data, key))
+            .filter(|index: &MetadataIndex| index.version == INDEX_VERSION)
+            .unwrap_or_default()
+    }
+
+    /// Writes the index atomically, encrypted when a vault key is given
+    pub fn save(&self, dir: &Path, key: Option<&VaultKey>) -> io::Result<()> {
+        let mut data = serde_json::to_string_pretty(self)?;
+        if let Some(key) = key {
+            let envelope = EncryptedEnvelope {
+                encrypted: crypto::encrypt(key, data.as_bytes(), INDEX_FILE.as_bytes())?,
+            };
+            data = serde_json::to_string_pretty(&envelope)?;
+        }
+
+        let temp_path = dir.join(format!(".{}.{}.tmp", INDEX_FILE, Uuid::new_v4()));
+        let mut file = fs::File::create(&temp_path)?;
+        file.write_all(data.as_bytes())?;
+        file.sync_all()?;
+        if let Err(e) = fs::rename(&temp_path, dir.join(INDEX_FILE)) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Records `note` as stored in the file at `path`
+    pub fn insert(&mut self, path: &Path, note: &Note) -> io::Result<()> {
+        let (modified, len) = file_stamp(path)?;
+        self.notes.insert(note.id.clone(), IndexedNote { modified, len, summary: NoteSummary::from(note) });
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: &NoteId) -> bool {
+        self.notes.remove(id).is_some()
+    }
+
+    pub fn get(&self, id: &NoteId) -> Option<&NoteSummary> {
+        self.notes.get(id).map(|entry| &entry.summary)
+    }
+
+    pub fn len(&self) -> usize {
+        self.notes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.notes.is_empty()
+    }
+
+    pub fn summaries(&self) -> impl Iterator<Item = &NoteSummary> {
+        self.notes.values().map(|entry| &entry.summary)
+    }
+
+    /// Brings the index in line with the note files in `files`, re-reading only
+    /// files whose modification time or length changed. Returns whether anything changed.
+    pub fn refresh<F>(&mut self, files: &[(NoteId, PathBuf)], mut load: F) -> io::Result<bool>
+    where
+        F: FnMut(&NoteId) -> io::Result<Note>,
+    {
+        let present: HashSet<&NoteId> = files.iter().map(|(id, _)| id).collect();
+        let before = self.notes.len();
+        self.notes.retain(|id, _| present.contains(id));
+        let mut changed = self.notes.len() != before;
+
+        for (id, path) in files {
+            // Stamp before reading, so a write racing with us leaves the entry stale rather than wrong
+            let (modified, len) = match file_stamp(path) {
+                Ok(stamp) => stamp,
+                Err(_) => continue,
+            };
+            if self.notes.get(id).is_some_and(|entry| entry.modified == modified && entry.len == len) {
+                continue;
+            }
+
+            changed = true;
+            match load(id) {
+                Ok(note) => {
+                    self.notes.insert(id.clone(), IndexedNote { modified, len, summary: NoteSummary::from(&note) });
+                }
+                Err(e) => {
+                    // A locked vault affects every note, so report it instead of warning per file
+                    let e = NoteError::from(e);
+                    if let NoteError::VaultLocked(_) = e {
+                        return Err(e.into());
+                    }
+                    eprintln!("Warning: Failed to index note file {:?}: {}", path, e);
+                    self.notes.remove(id);
+                }
+            }
+        }
+        Ok(changed)
+    }
+}
+
+fn decode(data: &str, key: Option<&VaultKey>) -> Option<MetadataIndex> {
+    match serde_json::from_str::<EncryptedEnvelope>(data) {
+        Ok(envelope) => {
+            let plaintext = crypto::decrypt(key?, &envelope.encrypted, INDEX_FILE.as_bytes()).ok()?;
+            serde_json::from_slice(&plaintext).ok()
+        }
+        Err(_) => serde_json::from_str(data).ok(),
+    }
+}
+
+fn file_stamp(path: &Path) -> io::Result<(DateTime<Utc>, u64)> {
+    let metadata = fs::metadata(path)?;
+    Ok((DateTime::<Utc>::from(metadata.modified()?), metadata.len()))
+}
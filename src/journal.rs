@@ -0,0 +1,113 @@
+use std::fmt::Write;
+use chrono::{Duration, Local, NaiveDate, TimeZone};
+use crate::config::JournalConfig;
+use crate::error::{validate_note_title, NoteError, Result};
+use crate::note::Note;
+use crate::storage::FileStorage;
+use crate::template::{TemplateContext, TemplateStore};
+
+/// Metadata key holding the `YYYY-MM-DD` date a journal entry belongs to
+pub const JOURNAL_DATE_KEY: &str = "journal_date";
+
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// Parses `today`, `yesterday`, `tomorrow` or a `YYYY-MM-DD` date
+pub fn parse_journal_date(input: &str, today: NaiveDate) -> Result<NaiveDate> {
+    match input.trim().to_lowercase().as_str() {
+        "today" => Ok(today),
+        "yesterday" => Ok(today - Duration::days(1)),
+        "tomorrow" => Ok(today + Duration::days(1)),
+        other => NaiveDate::parse_from_str(other, DATE_FORMAT).map_err(|_| {
+            NoteError::InvalidInput(format!("Invalid journal date '{}', expected YYYY-MM-DD", input))
+        }),
+    }
+}
+
+pub fn entry_date(note: &Note) -> Option<NaiveDate> {
+    note.metadata
+        .get(JOURNAL_DATE_KEY)
+        .and_then(|date| NaiveDate::parse_from_str(date, DATE_FORMAT).ok())
+}
+
+/// All journal entries in the store, oldest first
+pub fn entries(storage: &FileStorage) -> Result<Vec<(NaiveDate, Note)>> {
+    let mut entries: Vec<(NaiveDate, Note)> = storage
+        .list_notes()?
+        .into_iter()
+        .filter_map(|note| entry_date(&note).map(|date| (date, note)))
+        .collect();
+    entries.sort_by_key(|(date, _)| *date);
+    Ok(entries)
+}
+
+pub fn find_entry(storage: &FileStorage, date: NaiveDate) -> Result<Option<Note>> {
+    Ok(entries(storage)?
+        .into_iter()
+        .find(|(entry_date, _)| *entry_date == date)
+        .map(|(_, note)| note))
+}
+
+/// Returns the entry for `date`, creating it first if it does not exist.
+/// The boolean is `true` when a new entry was created.
+pub fn open_entry<F>(
+    storage: &FileStorage,
+    templates: &TemplateStore,
+    config: &JournalConfig,
+    date: NaiveDate,
+    prompt: F,
+) -> Result<(Note, bool)>
+where
+    F: FnMut(&str) -> Result<String>,
+{
+    if let Some(note) = find_entry(storage, date)? {
+        return Ok((note, false));
+    }
+
+    // `to_string` would panic on an invalid format from the config
+    let mut title = String::new();
+    write!(title, "{}", date.format(&config.title_format)).map_err(|_| {
+        NoteError::InvalidInput(format!("Invalid title_format '{}' under [journal] in the config", config.title_format))
+    })?;
+    let mut note = match &config.template {
+        Some(name) => {
+            let template = templates.load(name)?;
+            let mut context = TemplateContext::new(title, prompt);
+            if let Some(midnight) = date.and_hms_opt(0, 0, 0) {
+                if let Some(local) = Local.from_local_datetime(&midnight).earliest() {
+                    context.now = local;
+                }
+            }
+            template.render(&mut context)?
+        }
+        None => {
+            validate_note_title(&title)?;
+            Note::new(title, String::new())
+        }
+    };
+
+    note.add_tag(config.tag.clone());
+    note.metadata.insert(JOURNAL_DATE_KEY.to_string(), date.format(DATE_FORMAT).to_string());
+    storage.save_note(&note)?;
+
+    Ok((note, true))
+}
+
+/// Entries from the seven days ending at `today`, newest first
+pub fn last_week(storage: &FileStorage, today: NaiveDate) -> Result<Vec<Note>> {
+    let start = today - Duration::days(6);
+    let mut week: Vec<Note> = entries(storage)?
+        .into_iter()
+        .filter(|(date, _)| *date >= start && *date <= today)
+        .map(|(_, note)| note)
+        .collect();
+    week.reverse();
+    Ok(week)
+}
+
+/// Dates of the closest existing entries before and after `date`
+pub fn neighbours(storage: &FileStorage, date: NaiveDate) -> Result<(Option<NaiveDate>, Option<NaiveDate>)> {
+    let dates: Vec<NaiveDate> = entries(storage)?.into_iter().map(|(date, _)| date).collect();
+    let previous = dates.iter().rev().find(|d| **d < date).copied();
+    let next = dates.iter().find(|d| **d > date).copied();
+    Ok((previous, next))
+}
@@ -0,0 +1,161 @@
+use std::io::{self, BufRead, Write};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::error::NoteError;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+
+/// Codes for failures of the call itself, one per `NoteError` variant
+pub const IO_ERROR: i64 = -32000;
+pub const SERIALIZATION_ERROR: i64 = -32001;
+pub const VALIDATION_ERROR: i64 = -32002;
+pub const NOT_FOUND: i64 = -32003;
+pub const INVALID_INPUT: i64 = -32004;
+pub const DATABASE_ERROR: i64 = -32005;
+pub const WRONG_PASSPHRASE: i64 = -32006;
+pub const CORRUPTED_CIPHERTEXT: i64 = -32007;
+pub const VAULT_LOCKED: i64 = -32008;
+pub const CONFLICT: i64 = -32009;
+pub const INCOMPATIBLE_SCHEMA: i64 = -32010;
+pub const GIT_ERROR: i64 = -32011;
+
+/// Largest message accepted from a client
+const MAX_MESSAGE: usize = 64 * 1024 * 1024;
+
+/// The `error` member of a JSON-RPC 2.0 response
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError { code, message: message.into(), data: None }
+    }
+
+    pub fn invalid_params(message: impl std::fmt::Display) -> Self {
+        RpcError::new(INVALID_PARAMS, format!("invalid params: {}", message))
+    }
+
+    pub fn method_not_found(method: &str) -> Self {
+        RpcError::new(METHOD_NOT_FOUND, format!("method not found: {}", method))
+    }
+}
+
+/// Carries the variant name as `data.kind`, so clients need not parse the message
+impl From<NoteError> for RpcError {
+    fn from(error: NoteError) -> Self {
+        let (code, kind) = match &error {
+            // Storage reports missing notes as missing files
+            NoteError::IoError(e) if e.kind() == io::ErrorKind::NotFound => (NOT_FOUND, "NotFound"),
+            NoteError::IoError(_) => (IO_ERROR, "IoError"),
+            NoteError::SerializationError(_) => (SERIALIZATION_ERROR, "SerializationError"),
+            NoteError::ValidationError(_) => (VALIDATION_ERROR, "ValidationError"),
+            NoteError::NotFound(_) => (NOT_FOUND, "NotFound"),
+            NoteError::InvalidInput(_) => (INVALID_INPUT, "InvalidInput"),
+            NoteError::DatabaseError(_) => (DATABASE_ERROR, "DatabaseError"),
+            NoteError::WrongPassphrase => (WRONG_PASSPHRASE, "WrongPassphrase"),
+            NoteError::CorruptedCiphertext(_) => (CORRUPTED_CIPHERTEXT, "CorruptedCiphertext"),
+            NoteError::VaultLocked(_) => (VAULT_LOCKED, "VaultLocked"),
+            NoteError::Conflict(_) => (CONFLICT, "Conflict"),
+            NoteError::IncompatibleSchema(_) => (INCOMPATIBLE_SCHEMA, "IncompatibleSchema"),
+            NoteError::Git(_) => (GIT_ERROR, "Git"),
+        };
+        RpcError { code, message: error.to_string(), data: Some(json!({ "kind": kind })) }
+    }
+}
+
+impl From<io::Error> for RpcError {
+    fn from(error: io::Error) -> Self {
+        RpcError::from(NoteError::from(error))
+    }
+}
+
+/// One incoming request or notification; notifications have no `id`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Message {
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+    #[serde(default)]
+    pub id: Option<Value>,
+}
+
+impl Message {
+    /// Parses a request, answering malformed ones with the error to send back
+    pub fn parse(value: Value) -> Result<Self, Value> {
+        let id = value.get("id").cloned().unwrap_or(Value::Null);
+        if value.get("jsonrpc").and_then(Value::as_str) != Some("2.0") {
+            return Err(response(id, Err(RpcError::new(INVALID_REQUEST, "expected a JSON-RPC 2.0 request"))));
+        }
+        serde_json::from_value(value)
+            .map_err(|e| response(id, Err(RpcError::new(INVALID_REQUEST, format!("invalid request: {}", e)))))
+    }
+
+    pub fn params<T: serde::de::DeserializeOwned>(&self) -> Result<T, RpcError> {
+        serde_json::from_value(self.params.clone()).map_err(RpcError::invalid_params)
+    }
+}
+
+pub fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(error) => json!({ "jsonrpc": "2.0", "id": id, "error": error }),
+    }
+}
+
+pub fn notification(method: &str, params: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params })
+}
+
+/// Reads one message framed with a `Content-Length` header, as LSP does; `None` at end of input
+pub fn read_framed(reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return match length {
+                None => Ok(None),
+                Some(_) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "input ended inside a message header")),
+            };
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                let value: usize = value.trim().parse().map_err(|_| invalid("invalid Content-Length"))?;
+                if value > MAX_MESSAGE {
+                    return Err(invalid("message too large"));
+                }
+                length = Some(value);
+            }
+        }
+    }
+
+    let mut body = vec![0; length.unwrap_or(0)];
+    reader.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+pub fn write_framed(writer: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = serde_json::to_vec(message)?;
+    write!(writer, "Content-Length: {}\r\n\r\n", body.len())?;
+    writer.write_all(&body)?;
+    writer.flush()
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -8,12 +8,43 @@ pub mod storage;
 pub mod search;
 pub mod error;
 pub mod config;
+pub mod template;
+pub mod journal;
+pub mod tags;
+pub mod notebook;
+pub mod attachment;
+pub mod export;
+pub mod crypto;
+pub mod schema;
+pub mod doctor;
+pub mod backup;
+pub mod git;
+pub mod markdown;
+pub mod index;
+pub mod memory;
+pub mod watch;
+pub mod dirsync;
+pub mod merge;
+pub mod http;
+pub mod httpsync;
+pub mod api;
+pub mod feed;
+pub mod render;
+pub mod web;
+pub mod jsonrpc;
+pub mod lsp;
+pub mod rpc;
+pub mod sealed;
+#[cfg(unix)]
+pub mod agent;
 
 pub use note::{Note, NoteId, Priority, Tag};
 pub use storage::{Storage, FileStorage};
+pub use memory::MemoryStorage;
 pub use search::{SearchEngine, SearchResult};
 pub use error::{NoteError, Result};
 pub use config::Config;
+pub use template::{Template, TemplateContext, TemplateStore};
 
 use std::collections::HashMap;
 use chrono::{DateTime, Utc};
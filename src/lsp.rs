@@ -0,0 +1,553 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::error::{validate_note_title, validate_tag, NoteError, Result};
+use crate::jsonrpc::{self, Message, RpcError, PARSE_ERROR};
+use crate::markdown;
+use crate::note::NoteId;
+use crate::notebook::NotebookStore;
+use crate::tags;
+
+/// Characters of a note shown when hovering over a link to it
+const HOVER_PREVIEW_CHARS: usize = 300;
+const SEVERITY_ERROR: u8 = 1;
+const SEVERITY_WARNING: u8 = 2;
+const COMPLETION_KEYWORD: u8 = 14;
+const COMPLETION_REFERENCE: u8 = 18;
+const SYMBOL_FILE: u8 = 1;
+/// Trailing characters that end a `#tag` rather than belong to it
+const TAG_TERMINATORS: &[char] = &['.', ',', ';', ':', '!', '?', ')', ']', '}', '"', '\''];
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TextDocumentItem {
+    uri: String,
+    text: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DocumentId {
+    uri: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DidOpenParams {
+    text_document: TextDocumentItem,
+}
+
+/// Only full-text changes are asked for in the capabilities
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DidChangeParams {
+    text_document: DocumentId,
+    content_changes: Vec<ContentChange>,
+}
+
+#[derive(Deserialize)]
+struct ContentChange {
+    text: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DocumentParams {
+    text_document: DocumentId,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PositionParams {
+    text_document: DocumentId,
+    position: Position,
+}
+
+/// Line and UTF-16 column, as LSP counts them
+#[derive(Debug, Clone, Copy, Deserialize)]
+struct Position {
+    line: u32,
+    character: u32,
+}
+
+#[derive(Deserialize)]
+struct SymbolParams {
+    #[serde(default)]
+    query: String,
+}
+
+/// A note `[[links]]` can point to
+struct Target {
+    notebook: String,
+    id: NoteId,
+    title: String,
+}
+
+/// Language server for note Markdown, speaking LSP over stdio (`notes lsp`).
+///
+/// Completes `#tags` from the tags in use and `[[links]]` from note titles, jumps
+/// from a link to the note, previews linked notes on hover, lists note titles as
+/// workspace symbols, and reports invalid titles, invalid tags and links to notes
+/// that do not exist. The notes are read from the store on every request, so
+/// changes made elsewhere are picked up right away.
+pub struct LanguageServer {
+    notebooks: NotebookStore,
+    /// Text of the open documents by URI
+    documents: HashMap<String, String>,
+}
+
+impl LanguageServer {
+    pub fn new(notebooks: NotebookStore) -> Self {
+        LanguageServer { notebooks, documents: HashMap::new() }
+    }
+
+    /// Answers one message, returning what to send back: the response to a request,
+    /// and diagnostics for documents that were opened or changed
+    pub fn handle(&mut self, message: Message) -> Vec<Value> {
+        let result = match message.method.as_str() {
+            "initialize" => Ok(capabilities()),
+            "shutdown" => Ok(Value::Null),
+            "textDocument/didOpen" => {
+                return match message.params::<DidOpenParams>() {
+                    Ok(params) => self.update(params.text_document.uri, params.text_document.text),
+                    Err(_) => Vec::new(),
+                }
+            }
+            "textDocument/didChange" => {
+                return match message.params::<DidChangeParams>() {
+                    Ok(params) => match params.content_changes.into_iter().last() {
+                        Some(change) => self.update(params.text_document.uri, change.text),
+                        None => Vec::new(),
+                    },
+                    Err(_) => Vec::new(),
+                }
+            }
+            "textDocument/didClose" => {
+                return match message.params::<DocumentParams>() {
+                    Ok(params) => {
+                        self.documents.remove(&params.text_document.uri);
+                        vec![publish(&params.text_document.uri, Vec::new())]
+                    }
+                    Err(_) => Vec::new(),
+                }
+            }
+            "textDocument/completion" => message.params().and_then(|params| internal(self.completion(params))),
+            "textDocument/definition" => message.params().and_then(|params| internal(self.definition(params))),
+            "textDocument/hover" => message.params().and_then(|params| internal(self.hover(params))),
+            "workspace/symbol" => message.params().and_then(|params| internal(self.symbols(params))),
+            method => Err(RpcError::method_not_found(method)),
+        };
+        // Notifications, known or not, get no answer
+        match message.id {
+            Some(id) => vec![jsonrpc::response(id, result)],
+            None => Vec::new(),
+        }
+    }
+
+    fn update(&mut self, uri: String, text: String) -> Vec<Value> {
+        let diagnostics = match self.targets() {
+            Ok(targets) => diagnose(&text, &targets),
+            Err(e) => {
+                eprintln!("Warning: Failed to read the notes: {}", e);
+                Vec::new()
+            }
+        };
+        self.documents.insert(uri.clone(), text);
+        vec![publish(&uri, diagnostics)]
+    }
+
+    fn line(&self, uri: &str, line: u32) -> &str {
+        self.documents.get(uri).and_then(|text| text.lines().nth(line as usize)).unwrap_or("")
+    }
+
+    fn completion(&self, params: PositionParams) -> Result<Value> {
+        let line = self.line(&params.text_document.uri, params.position.line);
+        let cursor = byte_offset(line, params.position.character);
+        let before = &line[..cursor];
+
+        if let Some(open) = before.rfind("[[").filter(|open| !before[open + 2..].contains("]]")) {
+            let range = range(params.position.line, line, open + 2, cursor);
+            let close = if line[cursor..].starts_with("]]") { "" } else { "]]" };
+            let items: Vec<Value> = self
+                .targets()?
+                .into_iter()
+                .map(|target| {
+                    json!({
+                        "label": target.title,
+                        "kind": COMPLETION_REFERENCE,
+                        "detail": target.notebook,
+                        "textEdit": { "range": range, "newText": format!("{}{}", target.title, close) },
+                    })
+                })
+                .collect();
+            return Ok(json!(items));
+        }
+
+        if let Some((start, _)) = tag_before(before) {
+            let range = range(params.position.line, line, start + 1, cursor);
+            let mut summaries = Vec::new();
+            for (name, _) in self.notebooks.list()? {
+                summaries.extend(self.notebooks.open(&name)?.list_summaries()?);
+            }
+            let items: Vec<Value> = tags::count_tags(summaries.iter().map(|s| s.tags.as_slice()))
+                .into_iter()
+                .map(|(tag, count)| {
+                    json!({
+                        "label": tag,
+                        "kind": COMPLETION_KEYWORD,
+                        "detail": format!("{} note(s)", count),
+                        "textEdit": { "range": range, "newText": tag },
+                    })
+                })
+                .collect();
+            return Ok(json!(items));
+        }
+        Ok(json!([]))
+    }
+
+    fn definition(&self, params: PositionParams) -> Result<Value> {
+        let line = self.line(&params.text_document.uri, params.position.line);
+        let title = match link_at(line, byte_offset(line, params.position.character)) {
+            Some((_, _, title)) => title,
+            None => return Ok(Value::Null),
+        };
+
+        // An open document with that title wins over the stored note
+        let open = self
+            .documents
+            .iter()
+            .find(|(_, text)| document_title(text).is_some_and(|(_, candidate)| same_title(&candidate, title)));
+        if let Some((uri, _)) = open {
+            return Ok(json!({ "uri": uri, "range": range(0, "", 0, 0) }));
+        }
+        match self.resolve(title)? {
+            Some(target) => Ok(json!({ "uri": self.uri(&target), "range": range(0, "", 0, 0) })),
+            None => Ok(Value::Null),
+        }
+    }
+
+    fn hover(&self, params: PositionParams) -> Result<Value> {
+        let line = self.line(&params.text_document.uri, params.position.line);
+        let (start, end, title) = match link_at(line, byte_offset(line, params.position.character)) {
+            Some(link) => link,
+            None => return Ok(Value::Null),
+        };
+        let target = match self.resolve(title)? {
+            Some(target) => target,
+            None => return Ok(Value::Null),
+        };
+
+        let note = self.notebooks.open(&target.notebook)?.load_note(&target.id)?;
+        let preview = match note.is_locked() {
+            true => "*This note is locked.*".to_string(),
+            false => note.get_preview(HOVER_PREVIEW_CHARS),
+        };
+        let mut value = format!("**{}**", note.title);
+        if !note.tags.is_empty() {
+            value.push_str(&format!(" · {}", note.tags.iter().map(|tag| format!("#{}", tag)).collect::<Vec<_>>().join(" ")));
+        }
+        value.push_str(&format!("\n\n{}", preview));
+        Ok(json!({
+            "contents": { "kind": "markdown", "value": value },
+            "range": range(params.position.line, line, start, end),
+        }))
+    }
+
+    fn symbols(&self, params: SymbolParams) -> Result<Value> {
+        let query = params.query.to_lowercase();
+        let mut symbols = Vec::new();
+        for target in self.targets()? {
+            if !target.title.to_lowercase().contains(&query) {
+                continue;
+            }
+            symbols.push(json!({
+                "name": target.title,
+                "kind": SYMBOL_FILE,
+                "location": { "uri": self.uri(&target), "range": range(0, "", 0, 0) },
+                "containerName": target.notebook,
+            }));
+        }
+        Ok(json!(symbols))
+    }
+
+    /// Every note in every notebook
+    fn targets(&self) -> Result<Vec<Target>> {
+        let mut targets = Vec::new();
+        for (name, _) in self.notebooks.list()? {
+            for summary in self.notebooks.open(&name)?.list_summaries()? {
+                targets.push(Target { notebook: name.clone(), id: summary.id, title: summary.title });
+            }
+        }
+        Ok(targets)
+    }
+
+    /// URI of the file a note is stored in
+    fn uri(&self, target: &Target) -> String {
+        file_uri(&self.notebooks.root().join(&target.notebook).join(format!("{}.json", target.id)))
+    }
+
+    fn resolve(&self, title: &str) -> Result<Option<Target>> {
+        Ok(self.targets()?.into_iter().find(|target| same_title(&target.title, title)))
+    }
+}
+
+/// Serves LSP on `input` and `output` until the client sends `exit` or closes the input
+pub fn run(notebooks: NotebookStore, mut input: impl BufRead, mut output: impl Write) -> Result<()> {
+    let mut server = LanguageServer::new(notebooks);
+    while let Some(body) = jsonrpc::read_framed(&mut input)? {
+        let replies = match serde_json::from_slice::<Value>(&body) {
+            Ok(value) => match Message::parse(value) {
+                Ok(message) if message.method == "exit" => return Ok(()),
+                Ok(message) => server.handle(message),
+                Err(reply) => vec![reply],
+            },
+            Err(e) => vec![jsonrpc::response(Value::Null, Err(RpcError::new(PARSE_ERROR, e.to_string())))],
+        };
+        for reply in replies {
+            jsonrpc::write_framed(&mut output, &reply)?;
+        }
+    }
+    Ok(())
+}
+
+fn capabilities() -> Value {
+    json!({
+        "capabilities": {
+            "textDocumentSync": { "openClose": true, "change": 1 },
+            "completionProvider": { "triggerCharacters": ["#", "["] },
+            "definitionProvider": true,
+            "hoverProvider": true,
+            "workspaceSymbolProvider": true,
+        },
+        "serverInfo": { "name": "notes", "version": env!("CARGO_PKG_VERSION") },
+    })
+}
+
+fn internal(result: Result<Value>) -> std::result::Result<Value, RpcError> {
+    result.map_err(RpcError::from)
+}
+
+fn publish(uri: &str, diagnostics: Vec<Value>) -> Value {
+    jsonrpc::notification("textDocument/publishDiagnostics", json!({ "uri": uri, "diagnostics": diagnostics }))
+}
+
+fn diagnostic(line_number: usize, line: &str, start: usize, end: usize, severity: u8, message: String) -> Value {
+    json!({
+        "range": range(line_number as u32, line, start, end),
+        "severity": severity,
+        "source": "notes",
+        "message": message,
+    })
+}
+
+/// Problems in a note document: an invalid title, invalid tags in the front matter
+/// or the text, and `[[links]]` to notes that do not exist
+fn diagnose(text: &str, targets: &[Target]) -> Vec<Value> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut diagnostics = Vec::new();
+    let body_start = front_matter_end(&lines).map_or(0, |end| end + 1);
+
+    if body_start > 0 {
+        match markdown::from_markdown(text) {
+            Ok(Some(note)) => {
+                for tag in &note.tags {
+                    if let Err(e) = validate_tag(tag) {
+                        let number = (1..body_start).find(|i| lines[*i].contains(tag.as_str())).unwrap_or(0);
+                        diagnostics.push(diagnostic(number, lines[number], 0, lines[number].len(), SEVERITY_ERROR, message(e)));
+                    }
+                }
+            }
+            Ok(None) => {}
+            Err(e) => diagnostics.push(diagnostic(0, lines[0], 0, lines[0].len(), SEVERITY_ERROR, message(e))),
+        }
+    }
+    if let Some((number, title)) = document_title(text) {
+        if let Err(e) = validate_note_title(&title) {
+            diagnostics.push(diagnostic(number, lines[number], 0, lines[number].len(), SEVERITY_ERROR, message(e)));
+        }
+    }
+
+    let mut in_fence = false;
+    for (number, line) in lines.iter().enumerate().skip(body_start) {
+        if line.trim_start().starts_with("```") || line.trim_start().starts_with("~~~") {
+            in_fence = !in_fence;
+            continue;
+        }
+        if in_fence {
+            continue;
+        }
+        for (start, tag) in tags_in(line) {
+            if let Err(e) = validate_tag(tag) {
+                diagnostics.push(diagnostic(number, line, start, start + 1 + tag.len(), SEVERITY_ERROR, message(e)));
+            }
+        }
+        for (start, end, title) in links_in(line) {
+            if !targets.iter().any(|target| same_title(&target.title, title)) {
+                let text = format!("No note is titled '{}'", title);
+                diagnostics.push(diagnostic(number, line, start, end, SEVERITY_WARNING, text));
+            }
+        }
+    }
+    diagnostics
+}
+
+fn message(error: NoteError) -> String {
+    match error {
+        NoteError::ValidationError(message) => message,
+        error => error.to_string(),
+    }
+}
+
+/// Index of the line closing the front matter, if the document starts with one
+fn front_matter_end(lines: &[&str]) -> Option<usize> {
+    if lines.first()?.trim_end() != "---" {
+        return None;
+    }
+    (1..lines.len()).find(|i| lines[*i].trim_end() == "---")
+}
+
+/// Line and text of a document's title: `title:` in the front matter, else the first `# ` heading
+fn document_title(text: &str) -> Option<(usize, String)> {
+    let lines: Vec<&str> = text.lines().collect();
+    if let Some(end) = front_matter_end(&lines) {
+        let note = markdown::from_markdown(text).ok()??;
+        let number = (1..end).find(|i| lines[*i].starts_with("title:")).unwrap_or(0);
+        return Some((number, note.title));
+    }
+    lines.iter().enumerate().find_map(|(number, line)| line.strip_prefix("# ").map(|title| (number, title.trim().to_string())))
+}
+
+fn same_title(a: &str, b: &str) -> bool {
+    a.trim().to_lowercase() == b.trim().to_lowercase()
+}
+
+/// Byte ranges of the parts of a line outside `inline code`
+fn outside_code(line: &str) -> Vec<(usize, usize)> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut in_code = false;
+    for (i, c) in line.char_indices() {
+        if c == '`' {
+            if !in_code {
+                parts.push((start, i));
+            }
+            in_code = !in_code;
+            start = i + 1;
+        }
+    }
+    if !in_code {
+        parts.push((start, line.len()));
+    }
+    parts
+}
+
+/// `#tags` on a line outside inline code, with the byte offset of their `#`
+fn tags_in(line: &str) -> Vec<(usize, &str)> {
+    let mut found = Vec::new();
+    for (from, to) in outside_code(line) {
+        let part = &line[from..to];
+        for (i, _) in part.match_indices('#') {
+            if let Some(tag) = tag_at(part, i) {
+                found.push((from + i, tag));
+            }
+        }
+    }
+    found
+}
+
+/// The tag starting with the `#` at byte `i`, if that `#` starts a word and is not a heading marker
+fn tag_at(text: &str, i: usize) -> Option<&str> {
+    if !starts_word(&text[..i]) {
+        return None;
+    }
+    let rest = &text[i + 1..];
+    let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    let tag = rest[..end].trim_end_matches(TAG_TERMINATORS);
+    match tag.starts_with('#') || tag.is_empty() {
+        true => None,
+        false => Some(tag),
+    }
+}
+
+/// The `#tag` being typed at the end of `before`: byte offset of its `#` and the part typed so far
+fn tag_before(before: &str) -> Option<(usize, &str)> {
+    let start = before.rfind('#')?;
+    let typed = &before[start + 1..];
+    if typed.contains(char::is_whitespace) || typed.starts_with('#') {
+        return None;
+    }
+    match starts_word(&before[..start]) {
+        true => Some((start, typed)),
+        false => None,
+    }
+}
+
+/// Whether a `#` after `before` can start a tag: not inside a word, a heading marker,
+/// an HTML entity or a URL
+fn starts_word(before: &str) -> bool {
+    !before.chars().next_back().is_some_and(|c| c.is_alphanumeric() || matches!(c, '#' | '&' | '/'))
+}
+
+/// `[[links]]` on a line outside inline code: byte range of the whole link and its title
+fn links_in(line: &str) -> Vec<(usize, usize, &str)> {
+    let mut found = Vec::new();
+    for (from, to) in outside_code(line) {
+        let mut offset = from;
+        while let Some(open) = line[offset..to].find("[[") {
+            let start = offset + open;
+            let close = match line[start + 2..to].find("]]") {
+                Some(close) => start + 2 + close,
+                None => break,
+            };
+            let title = line[start + 2..close].trim();
+            if !title.is_empty() && !title.contains('[') {
+                found.push((start, close + 2, title));
+            }
+            offset = close + 2;
+        }
+    }
+    found
+}
+
+/// The link around byte `cursor`
+fn link_at(line: &str, cursor: usize) -> Option<(usize, usize, &str)> {
+    links_in(line).into_iter().find(|(start, end, _)| (*start..=*end).contains(&cursor))
+}
+
+/// Byte offset in `line` of a UTF-16 column, clamped to the line
+fn byte_offset(line: &str, character: u32) -> usize {
+    let mut units = 0;
+    for (i, c) in line.char_indices() {
+        if units >= character as usize {
+            return i;
+        }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+fn range(line_number: u32, line: &str, start: usize, end: usize) -> Value {
+    let column = |byte: usize| line[..byte.min(line.len())].encode_utf16().count();
+    json!({
+        "start": { "line": line_number, "character": column(start) },
+        "end": { "line": line_number, "character": column(end) },
+    })
+}
+
+/// `file://` URI of an absolute path
+pub fn file_uri(path: &Path) -> String {
+    let mut uri = "file://".to_string();
+    let path = path.to_string_lossy().replace('\\', "/");
+    if !path.starts_with('/') {
+        uri.push('/');
+    }
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => uri.push(byte as char),
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
@@ -0,0 +1,465 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::SystemTime;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::crypto::LockedContent;
+use crate::attachment;
+use crate::error::NoteError;
+use crate::note::{Attachment, Note, NoteId};
+use crate::schema::{self, CURRENT_SCHEMA_VERSION};
+use crate::storage::{lock_dir, Storage};
+
+const FRONT_MATTER_DELIMITER: &str = "---";
+/// Revisions of notes last edited outside `notes`, kept beside the files so those are never rewritten
+const REVISIONS_FILE: &str = ".revisions.json";
+const MAX_SLUG_LENGTH: usize = 60;
+
+/// The YAML header of a note file; everything after it is the note content
+#[derive(Debug, Serialize, Deserialize)]
+struct FrontMatter {
+    id: NoteId,
+    title: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    #[serde(default)]
+    archived: bool,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    metadata: BTreeMap<String, String>,
+    #[serde(default)]
+    revision: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<Attachment>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    locked: Option<LockedContent>,
+    #[serde(default = "current_schema_version")]
+    schema_version: u32,
+}
+
+/// Hand-written front matter has no version and is read as current
+fn current_schema_version() -> u32 {
+    CURRENT_SCHEMA_VERSION
+}
+
+/// A change made to the notes directory by something other than this store
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExternalChange {
+    Added { id: NoteId, path: PathBuf },
+    Modified { id: NoteId, path: PathBuf },
+    Removed { id: NoteId, path: PathBuf },
+}
+
+/// File state as last written or read by this store
+#[derive(Debug, Clone)]
+struct IndexEntry {
+    path: PathBuf,
+    modified: SystemTime,
+    len: u64,
+    /// May be ahead of the front matter, for files edited outside `notes`
+    revision: u64,
+}
+
+/// Stores each note as `<slug>.md` with YAML front matter, readable by any editor.
+///
+/// Files are found through an id-to-path index rebuilt from the directory. Before
+/// every operation the directory is rescanned, so files added or edited by other
+/// tools are picked up: files without front matter are adopted as new notes, and
+/// outside edits bump the revision so stale in-memory copies conflict. That revision
+/// is recorded in a `.revisions.json` sidecar; the edited file itself is left alone.
+pub struct MarkdownStorage {
+    dir: PathBuf,
+    index: Mutex<HashMap<NoteId, IndexEntry>>,
+}
+
+/// File-name form of a title: lowercase words joined by `-`
+pub fn slugify(title: &str) -> String {
+    let mut slug = String::new();
+    for c in title.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug: String = slug.chars().take(MAX_SLUG_LENGTH).collect();
+    let slug = slug.trim_end_matches('-');
+    if slug.is_empty() {
+        "untitled".to_string()
+    } else {
+        slug.to_string()
+    }
+}
+
+/// Renders a note as Markdown with front matter
+pub fn to_markdown(note: &Note) -> Result<String, NoteError> {
+    let front_matter = FrontMatter {
+        id: note.id.clone(),
+        title: note.title.clone(),
+        tags: note.tags.clone(),
+        created_at: note.created_at,
+        updated_at: note.updated_at,
+        archived: note.is_archived,
+        metadata: note.metadata.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+        revision: note.revision,
+        attachments: note.attachments.clone(),
+        locked: note.locked.clone(),
+        schema_version: CURRENT_SCHEMA_VERSION,
+    };
+    let yaml = serde_yaml::to_string(&front_matter).map_err(|e| NoteError::SerializationError(e.to_string()))?;
+    Ok(format!("{}\n{}{}\n{}", FRONT_MATTER_DELIMITER, yaml, FRONT_MATTER_DELIMITER, note.content))
+}
+
+/// Parses a Markdown note; returns `None` for files without front matter
+pub fn from_markdown(text: &str) -> Result<Option<Note>, NoteError> {
+    let (yaml, content) = match split_front_matter(text) {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+    let front_matter: FrontMatter = serde_yaml::from_str(yaml)
+        .map_err(|e| NoteError::SerializationError(format!("Invalid front matter: {}", e)))?;
+    schema::check_supported(front_matter.schema_version, &format!("note '{}'", front_matter.id))?;
+
+    let note = Note {
+        id: front_matter.id,
+        title: front_matter.title,
+        content: content.to_string(),
+        tags: front_matter.tags,
+        created_at: front_matter.created_at,
+        updated_at: front_matter.updated_at,
+        is_archived: front_matter.archived,
+        metadata: front_matter.metadata.into_iter().collect(),
+        revision: front_matter.revision,
+        attachments: front_matter.attachments,
+        locked: front_matter.locked,
+    };
+    attachment::validate_attachments(&note)?;
+    Ok(Some(note))
+}
+
+fn split_front_matter(text: &str) -> Option<(&str, &str)> {
+    let rest = text.strip_prefix(FRONT_MATTER_DELIMITER)?;
+    let rest = rest.strip_prefix("\r\n").or_else(|| rest.strip_prefix('\n'))?;
+
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if line.trim_end() == FRONT_MATTER_DELIMITER {
+            return Some((&rest[..offset], &rest[offset + line.len()..]));
+        }
+        offset += line.len();
+    }
+    None
+}
+
+impl MarkdownStorage {
+    pub fn new(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let storage = MarkdownStorage { dir, index: Mutex::new(HashMap::new()) };
+        storage.scan()?;
+        Ok(storage)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Current file of a note
+    pub fn path_of(&self, id: &NoteId) -> io::Result<PathBuf> {
+        let _lock = lock_dir(&self.dir)?;
+        let mut index = self.index();
+        self.scan_locked(&mut index)?;
+        index.get(id).map(|entry| entry.path.clone()).ok_or_else(|| not_found(id))
+    }
+
+    /// Rescans the directory and reports what other programs changed since the last scan
+    pub fn scan(&self) -> io::Result<Vec<ExternalChange>> {
+        let _lock = lock_dir(&self.dir)?;
+        let mut index = self.index();
+        self.scan_locked(&mut index)
+    }
+
+    fn index(&self) -> std::sync::MutexGuard<'_, HashMap<NoteId, IndexEntry>> {
+        self.index.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn scan_locked(&self, index: &mut HashMap<NoteId, IndexEntry>) -> io::Result<Vec<ExternalChange>> {
+        let mut revisions = self.load_revisions()?;
+        let recorded = revisions.clone();
+        let mut changes = Vec::new();
+        let mut seen: HashSet<NoteId> = HashSet::new();
+        let by_path: HashMap<PathBuf, NoteId> =
+            index.iter().map(|(id, entry)| (entry.path.clone(), id.clone())).collect();
+
+        for path in self.markdown_files()? {
+            let metadata = fs::metadata(&path)?;
+            let modified = metadata.modified()?;
+            if let Some(id) = by_path.get(&path) {
+                let entry = &index[id];
+                if entry.path == path && entry.modified == modified && entry.len == metadata.len() && !seen.contains(id) {
+                    seen.insert(id.clone());
+                    continue;
+                }
+            }
+
+            let text = match fs::read_to_string(&path) {
+                Ok(text) => text,
+                Err(e) => {
+                    eprintln!("Warning: Failed to read note file {:?}: {}", path, e);
+                    continue;
+                }
+            };
+            let parsed = match from_markdown(&text) {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    eprintln!("Warning: Failed to parse note file {:?}: {}", path, e);
+                    continue;
+                }
+            };
+
+            let (change, note) = match parsed {
+                // Written by another editor from scratch: give it an id and front matter
+                None => {
+                    let mut note = Note::new(title_from_text(&text, &path), text);
+                    note.created_at = metadata.created().unwrap_or(modified).into();
+                    note.updated_at = modified.into();
+                    self.write_file(&path, &note)?;
+                    (ExternalChange::Added { id: note.id.clone(), path: path.clone() }, note)
+                }
+                Some(mut note) => {
+                    note.revision = note.revision.max(revisions.get(&note.id).copied().unwrap_or(0));
+                    let known = index.get(&note.id).cloned();
+                    let copied = seen.contains(&note.id)
+                        || known.as_ref().is_some_and(|entry| entry.path != path && entry.path.exists());
+                    if copied {
+                        // A duplicated file becomes a note of its own
+                        note.id = NoteId::new();
+                        note.revision = 0;
+                        self.write_file(&path, &note)?;
+                        (ExternalChange::Added { id: note.id.clone(), path: path.clone() }, note)
+                    } else if let Some(entry) = known {
+                        // An edit that kept the revision came from outside `notes`
+                        if note.revision == entry.revision {
+                            note.revision += 1;
+                            revisions.insert(note.id.clone(), note.revision);
+                        }
+                        (ExternalChange::Modified { id: note.id.clone(), path: path.clone() }, note)
+                    } else {
+                        (ExternalChange::Added { id: note.id.clone(), path: path.clone() }, note)
+                    }
+                }
+            };
+
+            seen.insert(note.id.clone());
+            index.insert(note.id.clone(), self.entry_for(&path, note.revision)?);
+            changes.push(change);
+        }
+
+        let removed: Vec<NoteId> = index.keys().filter(|id| !seen.contains(*id)).cloned().collect();
+        for id in removed {
+            if let Some(entry) = index.remove(&id) {
+                changes.push(ExternalChange::Removed { id, path: entry.path });
+            }
+        }
+        revisions.retain(|id, _| seen.contains(id));
+        if revisions != recorded {
+            self.save_revisions(&revisions)?;
+        }
+        Ok(changes)
+    }
+
+    fn load_revisions(&self) -> io::Result<BTreeMap<NoteId, u64>> {
+        match fs::read_to_string(self.dir.join(REVISIONS_FILE)) {
+            Ok(data) => serde_json::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save_revisions(&self, revisions: &BTreeMap<NoteId, u64>) -> io::Result<()> {
+        let path = self.dir.join(REVISIONS_FILE);
+        if revisions.is_empty() {
+            return match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        let temp_path = self.dir.join(format!("{}.tmp", REVISIONS_FILE));
+        fs::write(&temp_path, serde_json::to_string_pretty(revisions).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?)?;
+        fs::rename(&temp_path, path)
+    }
+
+    /// Drops the sidecar revision of a note whose file now carries its own
+    fn forget_revision(&self, id: &NoteId) -> io::Result<()> {
+        let mut revisions = self.load_revisions()?;
+        if revisions.remove(id).is_some() {
+            self.save_revisions(&revisions)?;
+        }
+        Ok(())
+    }
+
+    fn markdown_files(&self) -> io::Result<Vec<PathBuf>> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| {
+                path.is_file()
+                    && path.extension().and_then(|s| s.to_str()) == Some("md")
+                    && !path.file_name().and_then(|s| s.to_str()).unwrap_or(".").starts_with('.')
+            })
+            .collect();
+        paths.sort();
+        Ok(paths)
+    }
+
+    fn entry_for(&self, path: &Path, revision: u64) -> io::Result<IndexEntry> {
+        let metadata = fs::metadata(path)?;
+        Ok(IndexEntry {
+            path: path.to_path_buf(),
+            modified: metadata.modified()?,
+            len: metadata.len(),
+            revision,
+        })
+    }
+
+    /// File a note should live in: its title's slug, numbered if another note has it
+    fn path_for(&self, note: &Note, current: Option<&Path>) -> PathBuf {
+        let slug = slugify(&note.title);
+        let mut candidate = self.dir.join(format!("{}.md", slug));
+        let mut n = 2;
+        while candidate.exists() && Some(candidate.as_path()) != current {
+            candidate = self.dir.join(format!("{}-{}.md", slug, n));
+            n += 1;
+        }
+        candidate
+    }
+
+    fn write_file(&self, path: &Path, note: &Note) -> io::Result<()> {
+        let temp_path = self.dir.join(format!(".{}.md.tmp", note.id));
+        fs::write(&temp_path, to_markdown(note)?)?;
+        fs::rename(&temp_path, path)
+    }
+
+    /// Writes a note to its slug path, renaming the file when the title changed
+    fn store(&self, note: &Note, index: &mut HashMap<NoteId, IndexEntry>) -> io::Result<()> {
+        let current = index.get(&note.id).map(|entry| entry.path.clone());
+        let keeps_name = current.as_ref().is_some_and(|path| has_slug(path, &slugify(&note.title)));
+        let target = match (&current, keeps_name) {
+            (Some(path), true) => path.clone(),
+            _ => self.path_for(note, current.as_deref()),
+        };
+
+        self.write_file(&target, note)?;
+        if let Some(old) = current.filter(|old| *old != target) {
+            fs::remove_file(old)?;
+        }
+        self.forget_revision(&note.id)?;
+        index.insert(note.id.clone(), self.entry_for(&target, note.revision)?);
+        Ok(())
+    }
+
+    /// Reads a note, with the revision and time of the last outside edit the file does not carry
+    fn read_file(&self, id: &NoteId, entry: &IndexEntry) -> io::Result<Note> {
+        let text = fs::read_to_string(&entry.path)?;
+        let mut note = from_markdown(&text)?.ok_or_else(|| not_found(id))?;
+        if entry.revision > note.revision {
+            note.revision = entry.revision;
+            note.updated_at = note.updated_at.max(entry.modified.into());
+        }
+        Ok(note)
+    }
+}
+
+impl Storage for MarkdownStorage {
+    fn save_note(&self, note: &Note) -> io::Result<()> {
+        let _lock = lock_dir(&self.dir)?;
+        let mut index = self.index();
+        self.scan_locked(&mut index)?;
+        self.store(note, &mut index)
+    }
+
+    fn update_note(&self, note: &mut Note) -> io::Result<()> {
+        let _lock = lock_dir(&self.dir)?;
+        let mut index = self.index();
+        self.scan_locked(&mut index)?;
+
+        let entry = index.get(&note.id).ok_or_else(|| not_found(&note.id))?;
+        if entry.revision != note.revision {
+            return Err(NoteError::Conflict(format!(
+                "note '{}' was changed elsewhere since it was loaded (revision {} is now {}); reload it and try again",
+                note.id, note.revision, entry.revision
+            )).into());
+        }
+
+        note.revision += 1;
+        if let Err(e) = self.store(note, &mut index) {
+            note.revision -= 1;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn load_note(&self, id: &NoteId) -> io::Result<Note> {
+        let _lock = lock_dir(&self.dir)?;
+        let mut index = self.index();
+        self.scan_locked(&mut index)?;
+        let entry = index.get(id).ok_or_else(|| not_found(id))?;
+        self.read_file(id, entry)
+    }
+
+    fn delete_note(&self, id: &NoteId) -> io::Result<()> {
+        let _lock = lock_dir(&self.dir)?;
+        let mut index = self.index();
+        self.scan_locked(&mut index)?;
+        let entry = index.remove(id).ok_or_else(|| not_found(id))?;
+        fs::remove_file(entry.path)?;
+        self.forget_revision(id)
+    }
+
+    fn list_notes(&self) -> io::Result<Vec<Note>> {
+        let _lock = lock_dir(&self.dir)?;
+        let mut index = self.index();
+        self.scan_locked(&mut index)?;
+
+        let mut notes = Vec::new();
+        for (id, entry) in index.iter() {
+            match self.read_file(id, entry) {
+                Ok(note) => notes.push(note),
+                Err(e) => eprintln!("Warning: Failed to read note file {:?}: {}", entry.path, e),
+            }
+        }
+        notes.sort_by_key(|note| std::cmp::Reverse(note.created_at));
+        Ok(notes)
+    }
+}
+
+/// Whether a file is named after `slug`, possibly with a `-N` collision suffix
+fn has_slug(path: &Path, slug: &str) -> bool {
+    match path.file_stem().and_then(|s| s.to_str()).and_then(|stem| stem.strip_prefix(slug)) {
+        Some("") => true,
+        Some(rest) => rest.strip_prefix('-').is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit())),
+        None => false,
+    }
+}
+
+/// Title for an adopted file: its first `# heading`, else the file name
+fn title_from_text(text: &str, path: &Path) -> String {
+    text.lines()
+        .find_map(|line| line.strip_prefix("# ").map(str::trim))
+        .filter(|heading| !heading.is_empty())
+        .map(str::to_string)
+        .unwrap_or_else(|| {
+            path.file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or("Untitled")
+                .replace(['-', '_'], " ")
+        })
+}
+
+fn not_found(id: &NoteId) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("Note with id '{}' not found", id))
+}
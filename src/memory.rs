@@ -0,0 +1,163 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::sync::Mutex;
+use crate::error::NoteError;
+use crate::note::{Note, NoteId};
+use crate::storage::Storage;
+
+/// Errors to inject into a [`MemoryStorage`]
+#[derive(Debug, Clone, Default)]
+struct Faults {
+    /// Writes left before the failing one; `Some(0)` fails the next write
+    fail_write_in: Option<usize>,
+    /// Notes whose reads fail as if their file were damaged
+    corrupt: HashSet<NoteId>,
+}
+
+/// Notes kept in memory, with the same semantics as [`FileStorage`](crate::storage::FileStorage).
+///
+/// Meant for tests and for embedding the library without a notes directory. Faults
+/// can be injected to exercise error paths: see [`MemoryStorage::fail_write`] and
+/// [`MemoryStorage::corrupt`].
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    notes: Mutex<BTreeMap<NoteId, Note>>,
+    faults: Mutex<Faults>,
+    writes: Mutex<usize>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A store already holding `notes`; filling it does not count as writes
+    pub fn with_notes(notes: impl IntoIterator<Item = Note>) -> Self {
+        let storage = Self::new();
+        storage.notes.lock().unwrap().extend(notes.into_iter().map(|note| (note.id.clone(), note)));
+        storage
+    }
+
+    /// Makes the `n`th write from now fail (`1` is the next one). Saves, updates and
+    /// deletes all count as writes, and a failed write leaves the store unchanged.
+    pub fn fail_write(&self, n: usize) {
+        self.faults.lock().unwrap().fail_write_in = n.checked_sub(1);
+    }
+
+    /// Makes every read of `id` fail with `InvalidData`, as a damaged note file would
+    pub fn corrupt(&self, id: &NoteId) {
+        self.faults.lock().unwrap().corrupt.insert(id.clone());
+    }
+
+    /// Removes every injected fault
+    pub fn clear_faults(&self) {
+        *self.faults.lock().unwrap() = Faults::default();
+    }
+
+    /// Number of writes that succeeded so far
+    pub fn write_count(&self) -> usize {
+        *self.writes.lock().unwrap()
+    }
+
+    pub fn len(&self) -> usize {
+        self.notes.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.notes.lock().unwrap().is_empty()
+    }
+
+    /// Counts a write attempt, failing it if it is the one chosen by [`MemoryStorage::fail_write`]
+    fn begin_write(&self) -> io::Result<()> {
+        let mut faults = self.faults.lock().unwrap();
+        match faults.fail_write_in {
+            Some(0) => {
+                faults.fail_write_in = None;
+                Err(io::Error::other("Injected write failure"))
+            }
+            Some(n) => {
+                faults.fail_write_in = Some(n - 1);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn finish_write(&self) {
+        *self.writes.lock().unwrap() += 1;
+    }
+
+    fn check_readable(&self, id: &NoteId) -> io::Result<()> {
+        if self.faults.lock().unwrap().corrupt.contains(id) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Note '{}' is corrupted (injected fault)", id),
+            ));
+        }
+        Ok(())
+    }
+
+    fn not_found(id: &NoteId) -> io::Error {
+        io::Error::new(io::ErrorKind::NotFound, format!("Note with id '{}' not found", id))
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn save_note(&self, note: &Note) -> io::Result<()> {
+        self.begin_write()?;
+        self.notes.lock().unwrap().insert(note.id.clone(), note.clone());
+        self.finish_write();
+        Ok(())
+    }
+
+    fn update_note(&self, note: &mut Note) -> io::Result<()> {
+        self.check_readable(&note.id)?;
+        let mut notes = self.notes.lock().unwrap();
+        let current = notes.get(&note.id).ok_or_else(|| Self::not_found(&note.id))?;
+        if current.revision != note.revision {
+            return Err(NoteError::Conflict(format!(
+                "note '{}' was changed by another process since it was loaded (revision {} is now {}); reload it and try again",
+                note.id, note.revision, current.revision
+            )).into());
+        }
+
+        self.begin_write()?;
+        note.revision += 1;
+        notes.insert(note.id.clone(), note.clone());
+        self.finish_write();
+        Ok(())
+    }
+
+    fn load_note(&self, id: &NoteId) -> io::Result<Note> {
+        self.check_readable(id)?;
+        self.notes.lock().unwrap().get(id).cloned().ok_or_else(|| Self::not_found(id))
+    }
+
+    fn delete_note(&self, id: &NoteId) -> io::Result<()> {
+        let mut notes = self.notes.lock().unwrap();
+        if !notes.contains_key(id) {
+            return Err(Self::not_found(id));
+        }
+
+        self.begin_write()?;
+        notes.remove(id);
+        self.finish_write();
+        Ok(())
+    }
+
+    /// Every readable note, newest first; corrupted notes are skipped like unparsable files
+    fn list_notes(&self) -> io::Result<Vec<Note>> {
+        let corrupt = self.faults.lock().unwrap().corrupt.clone();
+        let mut notes: Vec<Note> = self
+            .notes
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|note| !corrupt.contains(&note.id))
+            .cloned()
+            .collect();
+        notes.sort_by_key(|note| Reverse(note.created_at));
+        Ok(notes)
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use crate::error::{NoteError, Result};
+use crate::note::Note;
+
+/// Tag added to a merged note that still holds conflict markers or conflicting fields
+pub const CONFLICT_TAG: &str = "conflict";
+/// Prefix of the metadata keys that keep the losing value of a conflicting field
+pub const CONFLICT_KEY_PREFIX: &str = "conflict:";
+/// Above this many base × side lines, changed regions are compared as whole blocks
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergedNote {
+    pub note: Note,
+    /// Content hunks and fields both sides changed differently
+    pub conflicts: usize,
+}
+
+impl MergedNote {
+    pub fn is_clean(&self) -> bool {
+        self.conflicts == 0
+    }
+}
+
+/// Three-way merge of two edits of the same note, given the revision both started from.
+///
+/// Changes made on only one side are taken as they are. Tags and attachments are
+/// merged as sets, so additions and removals from both sides survive, and metadata
+/// is merged key by key. Content is merged line by line; hunks changed differently
+/// on both sides are kept between conflict markers labelled with `labels`. A title
+/// or metadata value changed differently on both sides takes the more recently
+/// updated value and keeps the other under a `conflict:` metadata key. Either kind
+/// of conflict adds the [`CONFLICT_TAG`] tag.
+pub fn merge_notes(base: &Note, ours: &Note, theirs: &Note, labels: (&str, &str)) -> Result<MergedNote> {
+    if ours.id != theirs.id || base.id != ours.id {
+        return Err(NoteError::InvalidInput("only versions of the same note can be merged".to_string()));
+    }
+    if base.locked.is_some() || ours.locked.is_some() || theirs.locked.is_some() {
+        return Err(NoteError::InvalidInput(format!("note '{}' is locked; its content cannot be merged", ours.id)));
+    }
+
+    let ours_newer = theirs.updated_at <= ours.updated_at;
+    let mut conflicts = 0;
+    let mut losing: Vec<(String, String)> = Vec::new();
+    let mut note = ours.clone();
+
+    note.title = match merge_value(&base.title, &ours.title, &theirs.title) {
+        Some(title) => title.clone(),
+        None => {
+            conflicts += 1;
+            let (kept, lost) = if ours_newer { (&ours.title, &theirs.title) } else { (&theirs.title, &ours.title) };
+            losing.push(("title".to_string(), lost.clone()));
+            kept.clone()
+        }
+    };
+
+    let (content, hunks) = merge_text(&base.content, &ours.content, &theirs.content, labels);
+    note.content = content;
+    conflicts += hunks;
+
+    // Booleans cannot change in two different directions
+    note.is_archived = *merge_value(&base.is_archived, &ours.is_archived, &theirs.is_archived).unwrap_or(&ours.is_archived);
+    note.tags = merge_set(&base.tags, &ours.tags, &theirs.tags, |tag| tag.clone());
+    note.attachments = merge_set(&base.attachments, &ours.attachments, &theirs.attachments, |a| (a.name.clone(), a.hash.clone()));
+
+    let mut keys: Vec<&String> = ours.metadata.keys().chain(theirs.metadata.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    let mut metadata = HashMap::new();
+    for key in keys {
+        let (b, o, t) = (base.metadata.get(key), ours.metadata.get(key), theirs.metadata.get(key));
+        let value = match merge_value(&b, &o, &t) {
+            Some(value) => *value,
+            None => {
+                conflicts += 1;
+                let (kept, lost) = if ours_newer { (o, t) } else { (t, o) };
+                losing.push((format!("metadata.{}", key), lost.cloned().unwrap_or_default()));
+                kept
+            }
+        };
+        if let Some(value) = value {
+            metadata.insert(key.clone(), value.clone());
+        }
+    }
+    for (field, value) in losing {
+        metadata.insert(format!("{}{}", CONFLICT_KEY_PREFIX, field), value);
+    }
+    note.metadata = metadata;
+
+    if conflicts > 0 && !note.tags.iter().any(|tag| tag == CONFLICT_TAG) {
+        note.tags.push(CONFLICT_TAG.to_string());
+    }
+    note.created_at = ours.created_at.min(theirs.created_at);
+    note.updated_at = ours.updated_at.max(theirs.updated_at);
+    // Newer than both inputs, so editors still holding either one get a conflict
+    note.revision = ours.revision.max(theirs.revision) + 1;
+
+    Ok(MergedNote { note, conflicts })
+}
+
+/// The merged value of a field, or `None` when both sides changed it differently
+fn merge_value<'a, T: PartialEq>(base: &'a T, ours: &'a T, theirs: &'a T) -> Option<&'a T> {
+    if ours == theirs || theirs == base {
+        Some(ours)
+    } else if ours == base {
+        Some(theirs)
+    } else {
+        None
+    }
+}
+
+/// Keeps what both sides kept and adds what either side added, in order of appearance
+fn merge_set<T: Clone, K: Eq + Hash>(base: &[T], ours: &[T], theirs: &[T], key: impl Fn(&T) -> K) -> Vec<T> {
+    let base_keys: HashSet<K> = base.iter().map(&key).collect();
+    let ours_keys: HashSet<K> = ours.iter().map(&key).collect();
+    let theirs_keys: HashSet<K> = theirs.iter().map(&key).collect();
+
+    let mut seen = HashSet::new();
+    let mut merged = Vec::new();
+    for item in ours.iter().chain(theirs) {
+        let k = key(item);
+        let removed = base_keys.contains(&k) && (!ours_keys.contains(&k) || !theirs_keys.contains(&k));
+        if !removed && seen.insert(k) {
+            merged.push(item.clone());
+        }
+    }
+    merged
+}
+
+/// Line-based three-way merge (diff3). Returns the merged text and the number of
+/// hunks left between conflict markers.
+pub fn merge_text(base: &str, ours: &str, theirs: &str, labels: (&str, &str)) -> (String, usize) {
+    let base: Vec<&str> = base.split_inclusive('\n').collect();
+    let ours: Vec<&str> = ours.split_inclusive('\n').collect();
+    let theirs: Vec<&str> = theirs.split_inclusive('\n').collect();
+
+    // For every base line, the line it is matched with on each side
+    let mut in_ours = vec![None; base.len()];
+    for (b, o) in matching_lines(&base, &ours) {
+        in_ours[b] = Some(o);
+    }
+    let mut in_theirs = vec![None; base.len()];
+    for (b, t) in matching_lines(&base, &theirs) {
+        in_theirs[b] = Some(t);
+    }
+
+    let mut merged = String::new();
+    let mut conflicts = 0;
+    let (mut b, mut o, mut t) = (0, 0, 0);
+    loop {
+        // Next base line kept unchanged by both sides, or the end of all three texts
+        let stable = (b..base.len()).find(|&i| in_ours[i].is_some() && in_theirs[i].is_some());
+        let (next_b, next_o, next_t) = match stable {
+            Some(i) => (i, in_ours[i].unwrap(), in_theirs[i].unwrap()),
+            None => (base.len(), ours.len(), theirs.len()),
+        };
+
+        let (base_hunk, ours_hunk, theirs_hunk) = (&base[b..next_b], &ours[o..next_o], &theirs[t..next_t]);
+        if ours_hunk == theirs_hunk || theirs_hunk == base_hunk {
+            merged.extend(ours_hunk.iter().copied());
+        } else if ours_hunk == base_hunk {
+            merged.extend(theirs_hunk.iter().copied());
+        } else {
+            conflicts += 1;
+            merged.push_str(&format!("<<<<<<< {}\n", labels.0));
+            push_lines(&mut merged, ours_hunk);
+            merged.push_str("=======\n");
+            push_lines(&mut merged, theirs_hunk);
+            merged.push_str(&format!(">>>>>>> {}\n", labels.1));
+        }
+
+        match stable {
+            Some(i) => {
+                merged.push_str(base[i]);
+                b = i + 1;
+                o = next_o + 1;
+                t = next_t + 1;
+            }
+            None => break,
+        }
+    }
+    (merged, conflicts)
+}
+
+/// Appends a hunk inside conflict markers, ending it with a newline so the next marker starts a line
+fn push_lines(text: &mut String, lines: &[&str]) {
+    for line in lines {
+        text.push_str(line);
+    }
+    if !text.ends_with('\n') {
+        text.push('\n');
+    }
+}
+
+/// Pairs of equal lines (index in `a`, index in `b`) forming a longest common subsequence
+fn matching_lines(a: &[&str], b: &[&str]) -> Vec<(usize, usize)> {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..].iter().rev().zip(b[prefix..].iter().rev()).take_while(|(x, y)| x == y).count();
+    let (a_mid, b_mid) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+
+    let mut pairs: Vec<(usize, usize)> = (0..prefix).map(|i| (i, i)).collect();
+    let (n, m) = (a_mid.len(), b_mid.len());
+    if n > 0 && m > 0 && n * m <= MAX_DIFF_CELLS {
+        // lengths[i * (m + 1) + j]: LCS length of a_mid[i..] and b_mid[j..]
+        let mut lengths = vec![0u32; (n + 1) * (m + 1)];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lengths[i * (m + 1) + j] = if a_mid[i] == b_mid[j] {
+                    lengths[(i + 1) * (m + 1) + j + 1] + 1
+                } else {
+                    lengths[(i + 1) * (m + 1) + j].max(lengths[i * (m + 1) + j + 1])
+                };
+            }
+        }
+
+        let (mut i, mut j) = (0, 0);
+        while i < n && j < m {
+            if a_mid[i] == b_mid[j] {
+                pairs.push((prefix + i, prefix + j));
+                i += 1;
+                j += 1;
+            } else if lengths[(i + 1) * (m + 1) + j] >= lengths[i * (m + 1) + j + 1] {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+    }
+    pairs.extend((0..suffix).map(|i| (a.len() - suffix + i, b.len() - suffix + i)));
+    pairs
+}
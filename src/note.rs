@@ -1,11 +1,92 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
 use chrono::{DateTime, Utc};
 use uuid::Uuid;
+use crate::crypto::LockedContent;
+use crate::error::NoteError;
+
+/// Identifier of a note: a lowercase hyphenated UUID.
+///
+/// Note ids end up in file names, so anything else (`../../etc/passwd`, empty
+/// strings, path separators) is rejected when the id is parsed.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct NoteId(String);
+
+impl NoteId {
+    pub fn new() -> Self {
+        NoteId(Uuid::new_v4().to_string())
+    }
+
+    pub fn parse(id: &str) -> Result<Self, NoteError> {
+        let uuid = Uuid::try_parse(id)
+            .map_err(|_| NoteError::InvalidInput(format!("'{}' is not a valid note id", id.escape_debug())))?;
+        let canonical = uuid.hyphenated().to_string();
+        if !canonical.eq_ignore_ascii_case(id) {
+            return Err(NoteError::InvalidInput(format!("'{}' is not a valid note id", id.escape_debug())));
+        }
+        Ok(NoteId(canonical))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Default for NoteId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for NoteId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Deref for NoteId {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for NoteId {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for NoteId {
+    type Err = NoteError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        NoteId::parse(s)
+    }
+}
+
+impl TryFrom<String> for NoteId {
+    type Error = NoteError;
+
+    fn try_from(id: String) -> Result<Self, Self::Error> {
+        NoteId::parse(&id)
+    }
+}
+
+impl From<NoteId> for String {
+    fn from(id: NoteId) -> Self {
+        id.0
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Note {
-    pub id: String,
+    pub id: NoteId,
     pub title: String,
     pub content: String,
     pub tags: Vec<String>,
@@ -13,13 +94,31 @@ pub struct Note {
     pub updated_at: DateTime<Utc>,
     pub is_archived: bool,
     pub metadata: HashMap<String, String>,
+    /// Incremented on every saved edit; used to detect concurrent modifications
+    #[serde(default)]
+    pub revision: u64,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    /// Set when the content is encrypted with a per-note passphrase (`notes lock`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locked: Option<LockedContent>,
+}
+
+/// A file attached to a note; the bytes live in the attachment store under `hash`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Attachment {
+    pub name: String,
+    pub mime: String,
+    pub size: u64,
+    pub hash: String,
+    pub added_at: DateTime<Utc>,
 }
 
 impl Note {
     pub fn new(title: String, content: String) -> Self {
         let now = Utc::now();
         Self {
-            id: Uuid::new_v4().to_string(),
+            id: NoteId::new(),
             title,
             content,
             tags: Vec::new(),
@@ -27,6 +126,9 @@ impl Note {
             updated_at: now,
             is_archived: false,
             metadata: HashMap::new(),
+            revision: 0,
+            attachments: Vec::new(),
+            locked: None,
         }
     }
 
@@ -86,10 +188,46 @@ impl Note {
         result
     }
 
+    /// Adds an attachment, replacing any existing attachment with the same name
+    pub fn add_attachment(&mut self, attachment: Attachment) {
+        self.attachments.retain(|a| a.name != attachment.name);
+        self.attachments.push(attachment);
+        self.updated_at = Utc::now();
+    }
+
+    /// Finds an attachment by exact name or by a prefix of its hash
+    pub fn find_attachment(&self, name_or_hash: &str) -> Option<&Attachment> {
+        self.attachments
+            .iter()
+            .find(|a| a.name == name_or_hash)
+            .or_else(|| {
+                let mut matches = self.attachments.iter().filter(|a| a.hash.starts_with(name_or_hash));
+                match (matches.next(), matches.next()) {
+                    (Some(a), None) if name_or_hash.len() >= 6 => Some(a),
+                    _ => None,
+                }
+            })
+    }
+
+    pub fn remove_attachment(&mut self, name_or_hash: &str) -> Option<Attachment> {
+        let found = self.find_attachment(name_or_hash)?;
+        let pos = self.attachments.iter().position(|a| a == found)?;
+        self.updated_at = Utc::now();
+        Some(self.attachments.remove(pos))
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.is_some()
+    }
+
     pub fn has_tag(&self, tag: &str) -> bool {
         self.tags.iter().any(|t| t == tag)
     }
 
+    pub fn has_tag_or_descendant(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| crate::tags::is_same_or_descendant(t, tag))
+    }
+
     pub fn matches_search(&self, query: &str) -> bool {
         let query_lower = query.to_lowercase();
         self.title.to_lowercase().contains(&query_lower)
@@ -0,0 +1,256 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::error::{NoteError, Result};
+use crate::note::{Note, NoteId};
+use crate::storage::{is_note_file, FileStorage};
+use crate::crypto::VaultKey;
+use crate::dirsync::SYNC_BASE_DIR;
+use crate::doctor::QUARANTINE_DIR;
+use crate::feed::ChangeFeed;
+
+/// A collection of notebooks, each one a subdirectory of the notes directory
+/// holding its own `FileStorage`.
+#[derive(Clone)]
+pub struct NotebookStore {
+    root: PathBuf,
+    key: Option<VaultKey>,
+}
+
+pub fn validate_notebook_name(name: &str) -> Result<()> {
+    if name.trim().is_empty() {
+        return Err(NoteError::ValidationError("Notebook name cannot be empty".to_string()));
+    }
+
+    if name.len() > 50 {
+        return Err(NoteError::ValidationError("Notebook name cannot exceed 50 characters".to_string()));
+    }
+
+    if name.starts_with('.') || !name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.') {
+        return Err(NoteError::ValidationError(
+            "Notebook names may only contain letters, digits, '-', '_' and '.'".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+impl NotebookStore {
+    /// Opens the notebook root, moving notes left directly in it (from before
+    /// notebooks existed) into `default_notebook`.
+    pub fn new(root: impl AsRef<Path>, default_notebook: &str) -> Result<Self> {
+        validate_notebook_name(default_notebook)?;
+        let root = root.as_ref().to_path_buf();
+        if !root.exists() {
+            fs::create_dir_all(&root)?;
+        }
+
+        let store = NotebookStore { root, key: None };
+        store.adopt_loose_notes(default_notebook)?;
+        Ok(store)
+    }
+
+    /// Opens every notebook with the vault key of an encrypted store
+    pub fn with_key(mut self, key: VaultKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    pub fn key(&self) -> Option<&VaultKey> {
+        self.key.as_ref()
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        validate_notebook_name(name).is_ok() && self.root.join(name).is_dir()
+    }
+
+    /// Storage for a single notebook; only that notebook's directory is ever read
+    pub fn open(&self, name: &str) -> Result<FileStorage> {
+        validate_notebook_name(name)?;
+        if !self.exists(name) {
+            return Err(NoteError::NotFound(format!("Notebook '{}' does not exist", name)));
+        }
+        self.storage(name)
+    }
+
+    pub fn create(&self, name: &str) -> Result<FileStorage> {
+        validate_notebook_name(name)?;
+        if self.exists(name) {
+            return Err(NoteError::InvalidInput(format!("Notebook '{}' already exists", name)));
+        }
+        self.storage(name)
+    }
+
+    /// Opens a notebook, creating it on first use
+    pub fn open_or_create(&self, name: &str) -> Result<FileStorage> {
+        validate_notebook_name(name)?;
+        self.storage(name)
+    }
+
+    /// Notebook names with the number of notes in each
+    pub fn list(&self) -> Result<Vec<(String, usize)>> {
+        let mut notebooks = Vec::new();
+        for entry in fs::read_dir(&self.root)? {
+            let path = entry?.path();
+            if !path.is_dir() {
+                continue;
+            }
+            if let Some(name) = path.file_name().and_then(|s| s.to_str()) {
+                if validate_notebook_name(name).is_ok() {
+                    notebooks.push((name.to_string(), count_notes(&path)?));
+                }
+            }
+        }
+        notebooks.sort();
+        Ok(notebooks)
+    }
+
+    pub fn rename(&self, old: &str, new: &str) -> Result<()> {
+        self.open(old)?;
+        validate_notebook_name(new)?;
+        if self.exists(new) {
+            return Err(NoteError::InvalidInput(format!("Notebook '{}' already exists", new)));
+        }
+
+        fs::rename(self.root.join(old), self.root.join(new))?;
+        Ok(())
+    }
+
+    /// Deletes a notebook; a non-empty notebook is only removed with `force`
+    pub fn delete(&self, name: &str, force: bool) -> Result<usize> {
+        let storage = self.open(name)?;
+        let count = count_notes(storage.storage_dir())?;
+        if count > 0 && !force {
+            return Err(NoteError::InvalidInput(format!(
+                "Notebook '{}' still contains {} note(s); use --force to delete them",
+                name, count
+            )));
+        }
+
+        fs::remove_dir_all(self.root.join(name))?;
+        Ok(count)
+    }
+
+    /// Name of the notebook containing the note with `id`
+    pub fn locate(&self, id: &NoteId) -> Result<Option<String>> {
+        for (name, _) in self.list()? {
+            if self.root.join(&name).join(format!("{}.json", id)).is_file() {
+                return Ok(Some(name));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Moves a note into another notebook, returning the notebook it came from.
+    /// Both notebooks stay locked for the whole move, taken in name order so two
+    /// moves in opposite directions cannot deadlock.
+    pub fn move_note(&self, id: &NoteId, target: &str) -> Result<String> {
+        let source = self
+            .locate(id)?
+            .ok_or_else(|| NoteError::NotFound(format!("Note with id '{}' not found in any notebook", id)))?;
+        if source == target {
+            return Err(NoteError::InvalidInput(format!("Note is already in notebook '{}'", target)));
+        }
+
+        let from = self.open(&source)?;
+        let to = self.open(target)?;
+        let (from_lock, to_lock) = if source.as_str() < target {
+            let from_lock = from.lock()?;
+            (from_lock, to.lock()?)
+        } else {
+            let to_lock = to.lock()?;
+            (from.lock()?, to_lock)
+        };
+        let note: Note = from.load_note(id)?;
+        to.save_note_locked(&note, &to_lock)?;
+        from.delete_note_locked(id, &from_lock)?;
+        Ok(source)
+    }
+
+    /// Rewrites every note with the vault key, including the copies kept as sync
+    /// bases, redacts the change feed and deletes the quarantine of unreadable files.
+    /// Returns the number of notes encrypted in the notebooks themselves.
+    pub fn encrypt_all(&self) -> Result<usize> {
+        let key = self
+            .key
+            .as_ref()
+            .ok_or_else(|| NoteError::InvalidInput("The notebook store has no vault key".to_string()))?;
+
+        let mut encrypted = 0;
+        for (name, _) in self.list()? {
+            let storage = self.open(&name)?;
+            encrypted += encrypt_notes(&storage)?;
+
+            let bases = storage.storage_dir().join(SYNC_BASE_DIR);
+            if bases.is_dir() {
+                for entry in fs::read_dir(&bases)? {
+                    let path = entry?.path();
+                    if let (true, Some(dir)) = (path.is_dir(), path.to_str()) {
+                        encrypt_notes(&FileStorage::new(dir)?.with_key(key.clone()))?;
+                    }
+                }
+            }
+        }
+
+        ChangeFeed::new(&self.root).redact()?;
+        let quarantine = self.root.join(QUARANTINE_DIR);
+        if quarantine.exists() {
+            fs::remove_dir_all(quarantine)?;
+        }
+        Ok(encrypted)
+    }
+
+    fn storage(&self, name: &str) -> Result<FileStorage> {
+        let path = self.root.join(name);
+        let path = path
+            .to_str()
+            .ok_or_else(|| NoteError::InvalidInput(format!("Notebook path {:?} is not valid UTF-8", path)))?;
+        let storage = FileStorage::new(path)?.with_feed(ChangeFeed::new(&self.root), name);
+        Ok(match &self.key {
+            Some(key) => storage.with_key(key.clone()),
+            None => storage,
+        })
+    }
+
+    fn adopt_loose_notes(&self, default_notebook: &str) -> Result<()> {
+        let loose: Vec<PathBuf> = fs::read_dir(&self.root)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| is_note_file(path))
+            .collect();
+        if loose.is_empty() {
+            return Ok(());
+        }
+
+        let target = self.open_or_create(default_notebook)?;
+        for path in loose {
+            if let Some(file_name) = path.file_name() {
+                fs::rename(&path, target.storage_dir().join(file_name))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes every note of a keyed storage back, which encrypts it, then the index
+fn encrypt_notes(storage: &FileStorage) -> io::Result<usize> {
+    let notes = storage.list_notes()?;
+    for note in &notes {
+        storage.save_note(note)?;
+    }
+    storage.rebuild_index()?;
+    Ok(notes.len())
+}
+
+fn count_notes(dir: &Path) -> io::Result<usize> {
+    let mut count = 0;
+    for entry in fs::read_dir(dir)? {
+        if is_note_file(&entry?.path()) {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
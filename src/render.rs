@@ -0,0 +1,218 @@
+/// Renders note Markdown as an HTML fragment for the web UI.
+///
+/// Covers the Markdown notes are usually written in: headings, paragraphs, lists
+/// (with task boxes), block quotes, fenced code, rules, emphasis, inline code,
+/// links and `[[wiki links]]`. Raw HTML in a note is always escaped, and links
+/// only keep `http`, `https`, `mailto` and relative targets.
+pub fn markdown_to_html(markdown: &str) -> String {
+    let lines: Vec<&str> = markdown.lines().collect();
+    let mut html = String::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        let trimmed = line.trim();
+
+        if trimmed.is_empty() {
+            i += 1;
+        } else if let Some(fence) = fence(trimmed) {
+            let language = trimmed[fence.len()..].trim();
+            let mut code = Vec::new();
+            i += 1;
+            while i < lines.len() && !lines[i].trim_start().starts_with(fence) {
+                code.push(lines[i]);
+                i += 1;
+            }
+            i += 1;
+            match language.is_empty() {
+                true => html.push_str("<pre><code>"),
+                false => html.push_str(&format!("<pre><code class=\"language-{}\">", escape(language))),
+            }
+            html.push_str(&escape(&code.join("\n")));
+            html.push_str("</code></pre>\n");
+        } else if let Some((level, text)) = heading(trimmed) {
+            html.push_str(&format!("<h{0}>{1}</h{0}>\n", level, inline(text)));
+            i += 1;
+        } else if is_rule(trimmed) {
+            html.push_str("<hr>\n");
+            i += 1;
+        } else if trimmed.starts_with('>') {
+            let mut quoted = Vec::new();
+            while i < lines.len() && lines[i].trim().starts_with('>') {
+                let text = lines[i].trim()[1..].strip_prefix(' ').unwrap_or(&lines[i].trim()[1..]);
+                quoted.push(text);
+                i += 1;
+            }
+            html.push_str(&format!("<blockquote>\n{}</blockquote>\n", markdown_to_html(&quoted.join("\n"))));
+        } else if let Some((ordered, _)) = list_item(trimmed) {
+            let tag = if ordered { "ol" } else { "ul" };
+            html.push_str(&format!("<{}>\n", tag));
+            while i < lines.len() {
+                let item = match list_item(lines[i].trim()) {
+                    Some((kind, text)) if kind == ordered => text,
+                    _ => break,
+                };
+                let mut text = item.to_string();
+                i += 1;
+                // Indented lines continue the item
+                while i < lines.len() && lines[i].starts_with(' ') && !lines[i].trim().is_empty() && list_item(lines[i].trim()).is_none() {
+                    text.push(' ');
+                    text.push_str(lines[i].trim());
+                    i += 1;
+                }
+                html.push_str(&format!("<li>{}</li>\n", list_entry(&text)));
+            }
+            html.push_str(&format!("</{}>\n", tag));
+        } else {
+            let mut paragraph = Vec::new();
+            while i < lines.len() && starts_paragraph_line(lines[i]) {
+                paragraph.push(lines[i].trim());
+                i += 1;
+            }
+            let text: Vec<String> = paragraph.iter().map(|line| inline(line)).collect();
+            html.push_str(&format!("<p>{}</p>\n", text.join("<br>\n")));
+        }
+    }
+    html
+}
+
+/// Escapes text for use in HTML content and attribute values
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn fence(line: &str) -> Option<&'static str> {
+    ["```", "~~~"].into_iter().find(|fence| line.starts_with(fence))
+}
+
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.chars().take_while(|c| *c == '#').count();
+    match (1..=6).contains(&level) {
+        true if line.len() == level => Some((level, "")),
+        true => line[level..].strip_prefix(' ').map(|text| (level, text.trim().trim_end_matches('#').trim_end())),
+        false => None,
+    }
+}
+
+fn is_rule(line: &str) -> bool {
+    let compact: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+    compact.len() >= 3 && ['-', '*', '_'].iter().any(|marker| compact.chars().all(|c| c == *marker))
+}
+
+/// `(ordered, text)` of a list item line
+fn list_item(line: &str) -> Option<(bool, &str)> {
+    for marker in ["- ", "* ", "+ "] {
+        if let Some(text) = line.strip_prefix(marker) {
+            return Some((false, text));
+        }
+    }
+    let digits = line.chars().take_while(|c| c.is_ascii_digit()).count();
+    match digits {
+        1..=9 => line[digits..].strip_prefix(". ").map(|text| (true, text)),
+        _ => None,
+    }
+}
+
+fn list_entry(text: &str) -> String {
+    match text.get(..4) {
+        Some("[ ] ") => format!("<input type=\"checkbox\" disabled> {}", inline(&text[4..])),
+        Some("[x] ") | Some("[X] ") => format!("<input type=\"checkbox\" checked disabled> {}", inline(&text[4..])),
+        _ => inline(text),
+    }
+}
+
+fn starts_paragraph_line(line: &str) -> bool {
+    let trimmed = line.trim();
+    !trimmed.is_empty()
+        && fence(trimmed).is_none()
+        && heading(trimmed).is_none()
+        && !is_rule(trimmed)
+        && !trimmed.starts_with('>')
+        && list_item(trimmed).is_none()
+}
+
+/// Renders the inline Markdown of one line; everything else is escaped
+fn inline(text: &str) -> String {
+    let mut html = String::new();
+    let mut rest = text;
+    let mut previous = ' ';
+    while let Some(c) = rest.chars().next() {
+        // Underscores inside words, as in snake_case, are not emphasis
+        let in_word = c == '_' && previous.is_alphanumeric();
+        match span(rest).filter(|_| !in_word) {
+            Some((rendered, used)) => {
+                html.push_str(&rendered);
+                previous = rest[..used].chars().last().unwrap_or(c);
+                rest = &rest[used..];
+            }
+            None => {
+                html.push_str(&escape(&rest[..c.len_utf8()]));
+                previous = c;
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    html
+}
+
+/// An inline element at the start of `text`, with the number of bytes it spans
+fn span(text: &str) -> Option<(String, usize)> {
+    if let Some(after) = text.strip_prefix('`') {
+        let end = after.find('`')?;
+        return Some((format!("<code>{}</code>", escape(&after[..end])), end + 2));
+    }
+    if let Some(after) = text.strip_prefix("[[") {
+        let end = after.find("]]")?;
+        let title = &after[..end];
+        if title.trim().is_empty() || title.contains('[') {
+            return None;
+        }
+        let link = format!("<a class=\"wikilink\" href=\"#\" data-title=\"{0}\">{0}</a>", escape(title.trim()));
+        return Some((link, end + 4));
+    }
+    if let Some(after) = text.strip_prefix('[') {
+        let label_end = after.find("](")?;
+        let target_start = label_end + 2;
+        let target_end = target_start + after[target_start..].find(')')?;
+        let label = &after[..label_end];
+        let target = after[target_start..target_end].trim();
+        let rendered = match safe_link(target) {
+            true => format!("<a href=\"{}\" rel=\"noopener noreferrer\" target=\"_blank\">{}</a>", escape(target), inline(label)),
+            false => inline(label),
+        };
+        return Some((rendered, target_end + 2));
+    }
+    for (marker, tag) in [("**", "strong"), ("__", "strong"), ("~~", "del"), ("*", "em"), ("_", "em")] {
+        if let Some(after) = text.strip_prefix(marker) {
+            let end = after.find(marker)?;
+            let inner = &after[..end];
+            if inner.is_empty() || inner.starts_with(' ') || inner.ends_with(' ') {
+                return None;
+            }
+            return Some((format!("<{0}>{1}</{0}>", tag, inline(inner)), end + 2 * marker.len()));
+        }
+    }
+    None
+}
+
+fn safe_link(target: &str) -> bool {
+    let lower = target.to_ascii_lowercase();
+    match lower.find(':') {
+        // A colon after the first '/', '?' or '#' is part of a relative link
+        Some(colon) => {
+            ["http:", "https:", "mailto:"].iter().any(|scheme| lower.starts_with(scheme))
+                || lower[..colon].contains(['/', '?', '#'])
+        }
+        None => !target.is_empty(),
+    }
+}
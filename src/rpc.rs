@@ -0,0 +1,243 @@
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use chrono::NaiveDate;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+use crate::api::{SearchField, SearchQuery};
+use crate::error::Result;
+use crate::jsonrpc::{self, Message, RpcError, INVALID_REQUEST, PARSE_ERROR};
+use crate::note::NoteId;
+
+/// Answers JSON-RPC 2.0 requests of `notes rpc`, one request or batch per line, until `input` ends.
+///
+/// `dispatch` runs one call. Each reply is written as a single line. Notifications get no reply,
+/// and neither does a batch made only of notifications.
+pub fn serve<R, W, F>(input: R, mut output: W, mut dispatch: F) -> Result<()>
+where
+    R: BufRead,
+    W: Write,
+    F: FnMut(&str, Value) -> std::result::Result<Value, RpcError>,
+{
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(reply) = answer(&line, &mut dispatch) {
+            serde_json::to_writer(&mut output, &reply)?;
+            output.write_all(b"\n")?;
+            output.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Reply to one line of input, or `None` when nothing should be sent back
+pub fn answer<F>(line: &str, dispatch: &mut F) -> Option<Value>
+where
+    F: FnMut(&str, Value) -> std::result::Result<Value, RpcError>,
+{
+    let value: Value = match serde_json::from_str(line) {
+        Ok(value) => value,
+        Err(e) => return Some(jsonrpc::response(Value::Null, Err(RpcError::new(PARSE_ERROR, format!("parse error: {}", e))))),
+    };
+    match value {
+        Value::Array(batch) if batch.is_empty() => {
+            Some(jsonrpc::response(Value::Null, Err(RpcError::new(INVALID_REQUEST, "empty batch"))))
+        }
+        Value::Array(batch) => {
+            let replies: Vec<Value> = batch.into_iter().filter_map(|call| run(call, dispatch)).collect();
+            (!replies.is_empty()).then_some(Value::Array(replies))
+        }
+        call => run(call, dispatch),
+    }
+}
+
+/// Decodes the `params` of a call; calls without params are treated like ones with `{}`
+pub fn params<T: DeserializeOwned>(params: Value) -> std::result::Result<T, RpcError> {
+    let params = match params {
+        Value::Null => Value::Object(Default::default()),
+        params => params,
+    };
+    serde_json::from_value(params).map_err(RpcError::invalid_params)
+}
+
+fn run<F>(call: Value, dispatch: &mut F) -> Option<Value>
+where
+    F: FnMut(&str, Value) -> std::result::Result<Value, RpcError>,
+{
+    let message = match Message::parse(call) {
+        Ok(message) => message,
+        Err(reply) => return Some(reply),
+    };
+    let result = dispatch(&message.method, message.params);
+    message.id.map(|id| jsonrpc::response(id, result))
+}
+
+/// Params of calls naming one note
+#[derive(Debug, Clone, Deserialize)]
+pub struct IdParams {
+    pub id: NoteId,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateParams {
+    pub title: String,
+    #[serde(default)]
+    pub content: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TemplateParams {
+    pub template: String,
+    pub title: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ListParams {
+    pub tag: Option<String>,
+    pub limit: Option<usize>,
+}
+
+/// Fields to change on a note; the others are kept
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpdateParams {
+    pub id: NoteId,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub content: Option<String>,
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+}
+
+/// `date` is today when not given
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct JournalParams {
+    pub date: Option<NaiveDate>,
+    pub forward: bool,
+}
+
+/// Params of the rename calls for tags and notebooks
+#[derive(Debug, Clone, Deserialize)]
+pub struct RenameParams {
+    pub old: String,
+    pub new: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MergeParams {
+    pub source: String,
+    pub target: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TagParams {
+    pub tag: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NotebookParams {
+    pub name: String,
+    #[serde(default)]
+    pub force: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MoveParams {
+    pub id: NoteId,
+    pub notebook: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AttachParams {
+    pub id: NoteId,
+    pub file: PathBuf,
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+/// Params of the calls on one attachment; `output` is only read by `open_attachment`
+#[derive(Debug, Clone, Deserialize)]
+pub struct AttachmentParams {
+    pub id: NoteId,
+    pub attachment: String,
+    #[serde(default)]
+    pub output: Option<PathBuf>,
+}
+
+/// Stdout carries the replies, so the bundle always goes to a file
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExportParams {
+    pub output: PathBuf,
+    #[serde(default)]
+    pub tag: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ImportParams {
+    pub file: PathBuf,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MigrateParams {
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct DoctorParams {
+    pub fix: bool,
+}
+
+/// Restores only note `id` when given, otherwise the whole store
+#[derive(Debug, Clone, Deserialize)]
+pub struct RestoreParams {
+    pub snapshot: String,
+    #[serde(default)]
+    pub id: Option<NoteId>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SyncWithParams {
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SyncRemoteParams {
+    pub url: String,
+    #[serde(default)]
+    pub encrypt: bool,
+    #[serde(default)]
+    pub rotate_key: bool,
+}
+
+/// Params of `search`, the options of `notes search`
+#[derive(Debug, Clone, Deserialize)]
+pub struct SearchParams {
+    pub query: String,
+    #[serde(default)]
+    pub field: Option<SearchField>,
+    #[serde(default)]
+    pub case_sensitive: bool,
+    #[serde(default)]
+    pub tag: Option<String>,
+}
+
+impl SearchParams {
+    pub fn to_query(&self) -> SearchQuery {
+        let mut query = SearchQuery::new(&self.query);
+        query.field = self.field.unwrap_or(SearchField::All);
+        query.case_sensitive = self.case_sensitive;
+        query.tag = self.tag.clone();
+        query
+    }
+}
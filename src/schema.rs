@@ -0,0 +1,153 @@
+use std::fs;
+use std::path::Path;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use crate::attachment;
+use crate::error::{NoteError, Result};
+use crate::note::Note;
+
+/// Version of the note document format written by this binary
+pub const CURRENT_SCHEMA_VERSION: u32 = 2;
+
+/// Version assumed for documents written before `schema_version` existed
+pub const LEGACY_SCHEMA_VERSION: u32 = 1;
+
+pub const STORE_MANIFEST: &str = "store.json";
+
+const VERSION_FIELD: &str = "schema_version";
+
+/// One upgrade step, turning a version `from` document into a version `from + 1` one
+pub struct Migration {
+    pub from: u32,
+    pub description: &'static str,
+    apply: fn(&mut Map<String, Value>) -> Result<()>,
+}
+
+/// Every upgrade step in order; adding a field to `Note` means appending one here
+/// and bumping [`CURRENT_SCHEMA_VERSION`].
+pub const MIGRATIONS: &[Migration] = &[Migration {
+    from: 1,
+    description: "add revision counter and attachment list",
+    apply: add_revision_and_attachments,
+}];
+
+fn add_revision_and_attachments(document: &mut Map<String, Value>) -> Result<()> {
+    document.entry("revision").or_insert(Value::from(0u64));
+    document.entry("attachments").or_insert(Value::Array(Vec::new()));
+    Ok(())
+}
+
+/// Schema version of a raw note document
+pub fn document_version(document: &Value) -> Result<u32> {
+    match document.get(VERSION_FIELD) {
+        None => Ok(LEGACY_SCHEMA_VERSION),
+        Some(version) => version
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| NoteError::SerializationError(format!("Invalid {}: {}", VERSION_FIELD, version))),
+    }
+}
+
+/// Refuses data written by a newer binary instead of silently dropping fields it does not know
+pub fn check_supported(version: u32, what: &str) -> Result<()> {
+    if version > CURRENT_SCHEMA_VERSION {
+        return Err(NoteError::IncompatibleSchema(format!(
+            "{} uses schema version {}, but this version of notes only understands up to version {}; \
+             upgrade notes to open it",
+            what, version, CURRENT_SCHEMA_VERSION
+        )));
+    }
+    Ok(())
+}
+
+/// Upgrades a raw document to the current schema in place.
+/// Returns the version it started from.
+pub fn migrate_document(document: &mut Value) -> Result<u32> {
+    let original = document_version(document)?;
+    let what = match document.get("id").and_then(Value::as_str) {
+        Some(id) => format!("note '{}'", id),
+        None => "note".to_string(),
+    };
+    check_supported(original, &what)?;
+
+    let fields = document
+        .as_object_mut()
+        .ok_or_else(|| NoteError::SerializationError(format!("{} is not a JSON object", what)))?;
+    let mut version = original;
+    for migration in MIGRATIONS.iter().filter(|m| m.from >= original) {
+        if migration.from != version {
+            return Err(NoteError::SerializationError(format!(
+                "No migration from schema version {} for {}",
+                version, what
+            )));
+        }
+        (migration.apply)(fields)?;
+        version = migration.from + 1;
+    }
+    fields.insert(VERSION_FIELD.to_string(), Value::from(version));
+
+    Ok(original)
+}
+
+/// Serializes a note as a current-schema document
+pub fn note_to_document(note: &Note) -> Result<Value> {
+    let mut document = serde_json::to_value(note)?;
+    if let Some(fields) = document.as_object_mut() {
+        fields.insert(VERSION_FIELD.to_string(), Value::from(CURRENT_SCHEMA_VERSION));
+    }
+    Ok(document)
+}
+
+/// Deserializes a note document of any supported version
+pub fn note_from_document(mut document: Value) -> Result<Note> {
+    migrate_document(&mut document)?;
+    let note = serde_json::from_value(document)?;
+    attachment::validate_attachments(&note)?;
+    Ok(note)
+}
+
+/// Per-directory manifest recording the oldest schema version its notes may use
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct StoreManifest {
+    pub schema_version: u32,
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub migrated_at: Option<DateTime<Utc>>,
+}
+
+impl StoreManifest {
+    pub fn load(dir: &Path) -> Result<Option<Self>> {
+        let path = dir.join(STORE_MANIFEST);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let manifest: StoreManifest = serde_json::from_str(&fs::read_to_string(path)?)?;
+        Ok(Some(manifest))
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        // Several processes may open a new store at once, so never expose a partial file
+        let temp_path = dir.join(format!(".{}.{}.tmp", STORE_MANIFEST, uuid::Uuid::new_v4()));
+        fs::write(&temp_path, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&temp_path, dir.join(STORE_MANIFEST))?;
+        Ok(())
+    }
+
+    /// Loads the manifest of a store directory, writing one for stores that
+    /// predate it, and refuses stores written by a newer binary.
+    pub fn ensure(dir: &Path, has_notes: bool) -> Result<Self> {
+        if let Some(manifest) = Self::load(dir)? {
+            check_supported(manifest.schema_version, &format!("Note store {:?}", dir))?;
+            return Ok(manifest);
+        }
+
+        let manifest = StoreManifest {
+            schema_version: if has_notes { LEGACY_SCHEMA_VERSION } else { CURRENT_SCHEMA_VERSION },
+            created_at: Utc::now(),
+            migrated_at: None,
+        };
+        manifest.save(dir)?;
+        Ok(manifest)
+    }
+}
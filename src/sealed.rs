@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use crate::crypto::{self, EncryptedBlob, KdfParams, VaultKey};
+use crate::dirsync;
+use crate::error::{NoteError, Result};
+use crate::note::{Note, NoteId};
+
+/// Keyring of an end-to-end encrypted sync server, kept in the notes directory it serves
+pub const KEYRING_FILE: &str = "sync-keyring.json";
+const VERIFIER_PLAINTEXT: &[u8] = b"rust-notes sync key check";
+const TAG_KEY_CONTEXT: &[u8] = b"rust-notes sync tag key";
+
+/// Keys of an end-to-end encrypted sync server. The server stores it for its clients
+/// but cannot read it: the current key is derived from a passphrase only the
+/// devices know, and earlier keys are kept encrypted with the current one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Keyring {
+    /// Bumped on every change, so two devices rotating at once cannot overwrite each other
+    pub version: u64,
+    pub key_id: String,
+    pub created_at: DateTime<Utc>,
+    pub kdf: KdfParams,
+    pub verifier: EncryptedBlob,
+    /// Earlier keys, for notes not sealed again since the last rotation
+    #[serde(default)]
+    pub retired: Vec<RetiredKey>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RetiredKey {
+    pub key_id: String,
+    /// The key itself, encrypted with the current key
+    pub key: EncryptedBlob,
+}
+
+/// Keys unlocked from a [`Keyring`]; new payloads are always sealed with the current one
+#[derive(Debug, Clone)]
+pub struct SyncKeys {
+    key_id: String,
+    key: VaultKey,
+    tag_key: VaultKey,
+    retired: HashMap<String, VaultKey>,
+}
+
+/// A note as a sync server stores it: ciphertext keyed by note id and revision
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SealedNote {
+    pub id: NoteId,
+    pub revision: u64,
+    pub key_id: String,
+    pub payload: EncryptedBlob,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Payload {
+    notebook: String,
+    note: Note,
+}
+
+impl Keyring {
+    /// A keyring with a single key derived from `passphrase`
+    pub fn create(passphrase: &str, kdf: KdfParams) -> Result<(Keyring, SyncKeys)> {
+        Self::with_key(1, passphrase, kdf)
+    }
+
+    pub fn unlock(&self, passphrase: &str) -> Result<SyncKeys> {
+        let key = self.kdf.derive_key(passphrase)?;
+        match crypto::decrypt(&key, &self.verifier, self.key_id.as_bytes()) {
+            Ok(plaintext) if plaintext == VERIFIER_PLAINTEXT => {}
+            _ => return Err(NoteError::WrongPassphrase),
+        }
+
+        let mut retired = HashMap::new();
+        for old in &self.retired {
+            let bytes = crypto::decrypt(&key, &old.key, old.key_id.as_bytes())?;
+            retired.insert(old.key_id.clone(), VaultKey::from_bytes(&bytes)?);
+        }
+        Ok(SyncKeys::new(self.key_id.clone(), key, retired))
+    }
+
+    /// The next version of the keyring, with a new current key derived from
+    /// `passphrase`. Every earlier key stays readable by holders of the new one.
+    pub fn rotate(&self, keys: &SyncKeys, passphrase: &str, kdf: KdfParams) -> Result<(Keyring, SyncKeys)> {
+        let (mut keyring, mut new_keys) = Self::with_key(self.version + 1, passphrase, kdf)?;
+        let old_keys = keys.retired.iter().chain(std::iter::once((&keys.key_id, &keys.key)));
+        for (key_id, key) in old_keys {
+            keyring.retired.push(RetiredKey {
+                key_id: key_id.clone(),
+                key: crypto::encrypt(&new_keys.key, key.as_bytes(), key_id.as_bytes())?,
+            });
+            new_keys.retired.insert(key_id.clone(), key.clone());
+        }
+        Ok((keyring, new_keys))
+    }
+
+    fn with_key(version: u64, passphrase: &str, kdf: KdfParams) -> Result<(Keyring, SyncKeys)> {
+        if passphrase.is_empty() {
+            return Err(NoteError::ValidationError("Passphrase cannot be empty".to_string()));
+        }
+        let key_id = Uuid::new_v4().to_string();
+        let key = kdf.derive_key(passphrase)?;
+        let keyring = Keyring {
+            version,
+            verifier: crypto::encrypt(&key, VERIFIER_PLAINTEXT, key_id.as_bytes())?,
+            key_id: key_id.clone(),
+            created_at: Utc::now(),
+            kdf,
+            retired: Vec::new(),
+        };
+        Ok((keyring, SyncKeys::new(key_id, key, HashMap::new())))
+    }
+}
+
+impl SyncKeys {
+    fn new(key_id: String, key: VaultKey, retired: HashMap<String, VaultKey>) -> Self {
+        let tag_key = VaultKey::from_bytes(&hmac_sha256(key.as_bytes(), TAG_KEY_CONTEXT)).expect("HMAC-SHA256 output is a key");
+        SyncKeys { key_id, key, tag_key, retired }
+    }
+
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// Version tag of a note in `notebook`: equal for equal notes under the same key,
+    /// without revealing anything about the note to the server
+    pub fn tag(&self, notebook: &str, note: &Note) -> Result<String> {
+        let hash = dirsync::content_hash(note)?;
+        let message = format!("{}\n{}", notebook, hash);
+        Ok(hex(&hmac_sha256(self.tag_key.as_bytes(), message.as_bytes())))
+    }
+
+    /// Encrypts a note together with the name of its notebook
+    pub fn seal(&self, notebook: &str, note: &Note) -> Result<SealedNote> {
+        let payload = serde_json::to_vec(&Payload { notebook: notebook.to_string(), note: note.clone() })?;
+        let context = context(&note.id, note.revision, &self.key_id);
+        Ok(SealedNote {
+            id: note.id.clone(),
+            revision: note.revision,
+            key_id: self.key_id.clone(),
+            payload: crypto::encrypt(&self.key, &payload, context.as_bytes())?,
+        })
+    }
+
+    /// Decrypts a sealed note into its notebook and note
+    pub fn open(&self, sealed: &SealedNote) -> Result<(String, Note)> {
+        let key = if sealed.key_id == self.key_id {
+            &self.key
+        } else {
+            self.retired.get(&sealed.key_id).ok_or_else(|| {
+                NoteError::CorruptedCiphertext(format!("note {} is sealed with an unknown key", sealed.id))
+            })?
+        };
+        let plaintext = crypto::decrypt(key, &sealed.payload, context(&sealed.id, sealed.revision, &sealed.key_id).as_bytes())?;
+        let payload: Payload = serde_json::from_slice(&plaintext)?;
+        if payload.note.id != sealed.id || payload.note.revision != sealed.revision {
+            return Err(NoteError::CorruptedCiphertext(format!("sealed note {} does not match its contents", sealed.id)));
+        }
+        Ok((payload.notebook, payload.note))
+    }
+}
+
+/// Binds a payload to its note, revision and key, so the server cannot swap blobs around
+fn context(id: &NoteId, revision: u64, key_id: &str) -> String {
+    format!("{}:{}:{}", id, revision, key_id)
+}
+
+/// HMAC-SHA256 (RFC 2104) for keys of at most one block
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; 64];
+    block[..key.len()].copy_from_slice(key);
+    let mut inner = Sha256::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
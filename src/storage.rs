@@ -1,11 +1,110 @@
+use std::cmp::Reverse;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::io::{self, Write};
-use serde_json;
-use crate::note::Note;
+use std::thread;
+use std::time::{Duration, Instant};
+use fs2::FileExt;
+use serde_json::{self, Value};
+use chrono::Utc;
+use crate::note::{Note, NoteId};
+use crate::crypto::{self, EncryptedEnvelope, VaultKey};
+use crate::attachment;
+use crate::error::NoteError;
+use crate::index::{self, MetadataIndex, NoteSummary};
+use crate::dirsync;
+use crate::feed::{ChangeFeed, FeedEvent, FeedKind};
+use crate::schema::{self, StoreManifest, CURRENT_SCHEMA_VERSION};
+
+pub const LOCK_FILE: &str = ".lock";
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether `path` looks like a stored note: `<id>.json` with a valid note id
+pub fn is_note_file(path: &Path) -> bool {
+    path.is_file()
+        && path.extension().and_then(|s| s.to_str()) == Some("json")
+        && path.file_stem().and_then(|s| s.to_str()).is_some_and(|stem| NoteId::parse(stem).is_ok())
+}
+
+/// Operations every note backend provides
+pub trait Storage {
+    /// Writes a note unconditionally
+    fn save_note(&self, note: &Note) -> io::Result<()>;
+    /// Saves an edited note, failing with `NoteError::Conflict` if it changed since it was loaded
+    fn update_note(&self, note: &mut Note) -> io::Result<()>;
+    fn load_note(&self, id: &NoteId) -> io::Result<Note>;
+    fn delete_note(&self, id: &NoteId) -> io::Result<()>;
+    fn list_notes(&self) -> io::Result<Vec<Note>>;
+
+    /// Metadata of every note, newest first. Backends with an index answer without reading content.
+    fn list_summaries(&self) -> io::Result<Vec<NoteSummary>> {
+        Ok(self.list_notes()?.iter().map(NoteSummary::from).collect())
+    }
+}
+
+/// Takes the advisory lock of a store directory, waiting up to ten seconds for other processes
+pub fn lock_dir(dir: &Path) -> io::Result<StoreLock> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join(LOCK_FILE))?;
+
+    let started = Instant::now();
+    loop {
+        match FileExt::try_lock_exclusive(&file) {
+            Ok(()) => return Ok(StoreLock { _file: file }),
+            Err(e) if started.elapsed() < LOCK_TIMEOUT && e.kind() == fs2::lock_contended_error().kind() => {
+                thread::sleep(Duration::from_millis(25));
+            }
+            Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!("Note store {:?} is locked by another process", dir),
+                ));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
 
 pub struct FileStorage {
     storage_dir: String,
+    key: Option<VaultKey>,
+    /// Feed every change is reported to, with the name of this notebook
+    feed: Option<(ChangeFeed, String)>,
+}
+
+/// Exclusive advisory lock on a storage directory, released when dropped.
+/// Every mutating operation holds it, so concurrent `notes` processes take turns.
+pub struct StoreLock {
+    _file: fs::File,
+}
+
+impl Storage for FileStorage {
+    fn save_note(&self, note: &Note) -> io::Result<()> {
+        FileStorage::save_note(self, note)
+    }
+
+    fn update_note(&self, note: &mut Note) -> io::Result<()> {
+        FileStorage::update_note(self, note)
+    }
+
+    fn load_note(&self, id: &NoteId) -> io::Result<Note> {
+        FileStorage::load_note(self, id)
+    }
+
+    fn delete_note(&self, id: &NoteId) -> io::Result<()> {
+        FileStorage::delete_note(self, id)
+    }
+
+    fn list_notes(&self) -> io::Result<Vec<Note>> {
+        FileStorage::list_notes(self)
+    }
+
+    fn list_summaries(&self) -> io::Result<Vec<NoteSummary>> {
+        FileStorage::list_summaries(self)
+    }
 }
 
 impl FileStorage {
@@ -14,29 +113,150 @@ impl FileStorage {
         if !path.exists() {
             fs::create_dir_all(path)?;
         }
+
+        let has_notes = fs::read_dir(path)?
+            .filter_map(|entry| entry.ok())
+            .any(|entry| is_note_file(&entry.path()));
+        StoreManifest::ensure(path, has_notes)?;
         
         Ok(FileStorage {
             storage_dir: storage_dir.to_string(),
+            key: None,
+            feed: None,
         })
     }
 
+    /// Encrypts every note written from now on and decrypts encrypted notes on load
+    pub fn with_key(mut self, key: VaultKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Reports every note written or deleted from now on to `feed` as part of `notebook`
+    pub fn with_feed(mut self, feed: ChangeFeed, notebook: &str) -> Self {
+        self.feed = Some((feed, notebook.to_string()));
+        self
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.key.is_some()
+    }
+
+    pub fn key(&self) -> Option<&VaultKey> {
+        self.key.as_ref()
+    }
+
+    pub fn storage_dir(&self) -> &Path {
+        Path::new(&self.storage_dir)
+    }
+
+    /// Path of a note file. Only validated ids reach this point, so the
+    /// result always stays inside the storage directory.
+    fn note_path(&self, id: &NoteId) -> PathBuf {
+        Path::new(&self.storage_dir).join(format!("{}.json", id))
+    }
+
+    /// Takes the directory lock, waiting up to ten seconds for other processes
+    pub fn lock(&self) -> io::Result<StoreLock> {
+        lock_dir(self.storage_dir())
+    }
+
+    /// Writes a note unconditionally; used for new notes, imports and restores.
+    /// Edits of existing notes go through [`FileStorage::update_note`].
     pub fn save_note(&self, note: &Note) -> io::Result<()> {
-        let filename = format!("{}.json", note.id);
-        let file_path = Path::new(&self.storage_dir).join(filename);
+        let lock = self.lock()?;
+        self.save_note_locked(note, &lock)
+    }
+
+    /// Same as [`FileStorage::save_note`] for callers already holding the lock
+    pub fn save_note_locked(&self, note: &Note, _lock: &StoreLock) -> io::Result<()> {
+        let kind = match self.note_path(&note.id).exists() {
+            true => FeedKind::Updated,
+            false => FeedKind::Created,
+        };
+        self.write_note(note)?;
+        self.publish(kind, &note.id, Some(note));
+        Ok(())
+    }
+
+    /// Saves an edited note if nobody else changed it since it was loaded,
+    /// bumping its revision. Fails with `NoteError::Conflict` otherwise.
+    pub fn update_note(&self, note: &mut Note) -> io::Result<()> {
+        let lock = self.lock()?;
+        self.update_note_locked(note, &lock)
+    }
+
+    /// Same as [`FileStorage::update_note`] for callers already holding the lock
+    pub fn update_note_locked(&self, note: &mut Note, _lock: &StoreLock) -> io::Result<()> {
+        let current = self.load_note(&note.id)?;
+        if current.revision != note.revision {
+            return Err(NoteError::Conflict(format!(
+                "note '{}' was changed by another process since it was loaded (revision {} is now {}); reload it and try again",
+                note.id, note.revision, current.revision
+            )).into());
+        }
+
+        note.revision += 1;
+        if let Err(e) = self.write_note(note) {
+            note.revision -= 1;
+            return Err(e);
+        }
+        let kind = match current.tags != note.tags && current.title == note.title && current.content == note.content {
+            true => FeedKind::Tagged,
+            false => FeedKind::Updated,
+        };
+        self.publish(kind, &note.id, Some(note));
+        Ok(())
+    }
+
+    /// Writes through a temporary file and a rename so readers never see a half-written note
+    fn write_note(&self, note: &Note) -> io::Result<()> {
+        attachment::validate_attachments(note)?;
+        let file_path = self.note_path(&note.id);
+        let temp_path = Path::new(&self.storage_dir).join(format!(".{}.json.tmp", note.id));
         
-        let json_data = serde_json::to_string_pretty(note)
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let json_data = self.encode_note(note)?;
         
-        let mut file = fs::File::create(file_path)?;
+        let mut file = fs::File::create(&temp_path)?;
         file.write_all(json_data.as_bytes())?;
-        file.flush()?;
-        
+        file.sync_all()?;
+        fs::rename(&temp_path, &file_path)?;
+
+        self.update_index(|index| index.insert(&file_path, note));
         Ok(())
     }
 
-    pub fn load_note(&self, id: &str) -> io::Result<Note> {
-        let filename = format!("{}.json", id);
-        let file_path = Path::new(&self.storage_dir).join(filename);
+    /// Applies a change to the metadata index. The index is only a cache, so a
+    /// failure here never fails the write; [`FileStorage::list_summaries`] repairs it.
+    fn update_index<F>(&self, change: F)
+    where
+        F: FnOnce(&mut MetadataIndex) -> io::Result<()>,
+    {
+        let mut index = MetadataIndex::load(self.storage_dir(), self.key.as_ref());
+        let result = change(&mut index).and_then(|_| index.save(self.storage_dir(), self.key.as_ref()));
+        if let Err(e) = result {
+            eprintln!("Warning: Failed to update the note index: {}", e);
+        }
+    }
+
+    /// Reports a change to the change feed, if there is one. Like the index, the feed
+    /// never fails the write it describes. Titles and tags stay out of the feed of an
+    /// encrypted store.
+    fn publish(&self, kind: FeedKind, id: &NoteId, note: Option<&Note>) {
+        if let Some((feed, notebook)) = &self.feed {
+            let mut event = FeedEvent::new(kind, notebook, id, note);
+            if self.key.is_some() {
+                event.title = None;
+                event.tags.clear();
+            }
+            if let Err(e) = feed.append(event) {
+                eprintln!("Warning: Failed to update the change feed: {}", e);
+            }
+        }
+    }
+
+    pub fn load_note(&self, id: &NoteId) -> io::Result<Note> {
+        let file_path = self.note_path(id);
         
         if !file_path.exists() {
             return Err(io::Error::new(
@@ -46,15 +266,57 @@ impl FileStorage {
         }
         
         let json_data = fs::read_to_string(file_path)?;
-        let note: Note = serde_json::from_str(&json_data)
+        self.decode_note(id, &json_data)
+    }
+
+    fn encode_note(&self, note: &Note) -> io::Result<String> {
+        let json_data = serde_json::to_string_pretty(&schema::note_to_document(note)?)
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-        
-        Ok(note)
+
+        match &self.key {
+            Some(key) => {
+                let envelope = EncryptedEnvelope {
+                    encrypted: crypto::encrypt(key, json_data.as_bytes(), note.id.as_bytes())?,
+                };
+                serde_json::to_string_pretty(&envelope)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+            None => Ok(json_data),
+        }
+    }
+
+    fn decode_note(&self, id: &NoteId, json_data: &str) -> io::Result<Note> {
+        let document = self.decode_document(id, json_data)?;
+        Ok(schema::note_from_document(document)?)
+    }
+
+    /// Raw JSON document of a note, decrypted but not yet migrated
+    fn decode_document(&self, id: &NoteId, json_data: &str) -> io::Result<Value> {
+        let envelope = match serde_json::from_str::<EncryptedEnvelope>(json_data) {
+            Ok(envelope) => envelope,
+            Err(_) => {
+                return serde_json::from_str(json_data)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+        };
+
+        let key = self.key.as_ref().ok_or_else(|| {
+            NoteError::VaultLocked(format!("note '{}' is encrypted, run `notes vault unlock` first", id))
+        })?;
+        let plaintext = crypto::decrypt(key, &envelope.encrypted, id.as_bytes())?;
+        serde_json::from_slice(&plaintext)
+            .map_err(|e| NoteError::CorruptedCiphertext(format!("note '{}' decrypted to invalid JSON: {}", id, e)).into())
+    }
+
+    pub fn delete_note(&self, id: &NoteId) -> io::Result<()> {
+        let lock = self.lock()?;
+        self.delete_note_locked(id, &lock)
     }
 
-    pub fn delete_note(&self, id: &str) -> io::Result<()> {
-        let filename = format!("{}.json", id);
-        let file_path = Path::new(&self.storage_dir).join(filename);
+    /// Same as [`FileStorage::delete_note`] for callers already holding the lock.
+    /// Leaves a tombstone so `notes sync` deletes the note on other replicas too.
+    pub fn delete_note_locked(&self, id: &NoteId, _lock: &StoreLock) -> io::Result<()> {
+        let file_path = self.note_path(id);
         
         if !file_path.exists() {
             return Err(io::Error::new(
@@ -63,10 +325,111 @@ impl FileStorage {
             ));
         }
         
+        let last = self.feed.as_ref().and_then(|_| self.load_note(id).ok());
+        dirsync::record_tombstone(self.storage_dir(), id)?;
         fs::remove_file(file_path)?;
+        self.update_index(|index| {
+            index.remove(id);
+            Ok(())
+        });
+        self.publish(FeedKind::Deleted, id, last.as_ref());
         Ok(())
     }
 
+    /// Metadata of every note from the index, newest first. Only notes whose
+    /// files changed since they were indexed are read and parsed.
+    pub fn list_summaries(&self) -> io::Result<Vec<NoteSummary>> {
+        let mut index = MetadataIndex::load(self.storage_dir(), self.key.as_ref());
+        if index.refresh(&self.note_files()?, |id| self.load_note(id))? {
+            if let Err(e) = index.save(self.storage_dir(), self.key.as_ref()) {
+                eprintln!("Warning: Failed to update the note index: {}", e);
+            }
+        }
+
+        let mut summaries: Vec<NoteSummary> = index.summaries().cloned().collect();
+        summaries.sort_by_key(|summary| Reverse(summary.created_at));
+        Ok(summaries)
+    }
+
+    /// Discards the metadata index and builds it again from every note file.
+    /// Returns the number of notes indexed.
+    pub fn rebuild_index(&self) -> io::Result<usize> {
+        let lock = self.lock()?;
+        self.rebuild_index_locked(&lock)
+    }
+
+    /// Same as [`FileStorage::rebuild_index`] for callers already holding the lock
+    pub fn rebuild_index_locked(&self, _lock: &StoreLock) -> io::Result<usize> {
+        let mut index = MetadataIndex::default();
+        index.refresh(&self.note_files()?, |id| self.load_note(id))?;
+        index.save(self.storage_dir(), self.key.as_ref())?;
+        Ok(index.len())
+    }
+
+    fn note_files(&self) -> io::Result<Vec<(NoteId, PathBuf)>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.storage_dir)? {
+            let path = entry?.path();
+            if !is_note_file(&path) {
+                continue;
+            }
+            if let Some(Ok(id)) = path.file_stem().and_then(|s| s.to_str()).map(NoteId::parse) {
+                files.push((id, path));
+            }
+        }
+        Ok(files)
+    }
+
+    pub fn manifest(&self) -> io::Result<StoreManifest> {
+        let has_notes = fs::read_dir(&self.storage_dir)?
+            .filter_map(|entry| entry.ok())
+            .any(|entry| is_note_file(&entry.path()));
+        Ok(StoreManifest::ensure(self.storage_dir(), has_notes)?)
+    }
+
+    /// Rewrites every note still stored in an older schema and records the
+    /// store as current. Returns the migrated notes with their old version;
+    /// with `dry_run` nothing is written.
+    pub fn migrate(&self, dry_run: bool) -> io::Result<Vec<(NoteId, u32)>> {
+        let _lock = self.lock()?;
+        let mut migrated = Vec::new();
+
+        for entry in fs::read_dir(&self.storage_dir)? {
+            let path = entry?.path();
+            if !is_note_file(&path) {
+                continue;
+            }
+            let id = match path.file_stem().and_then(|s| s.to_str()).map(NoteId::parse) {
+                Some(Ok(id)) => id,
+                _ => continue,
+            };
+
+            let document = self.decode_document(&id, &fs::read_to_string(&path)?)?;
+            let version = schema::document_version(&document)?;
+            if version == CURRENT_SCHEMA_VERSION {
+                continue;
+            }
+
+            let note = schema::note_from_document(document)?;
+            if !dry_run {
+                self.write_note(&note)?;
+            }
+            migrated.push((id, version));
+        }
+
+        if !dry_run {
+            let mut manifest = self.manifest()?;
+            if manifest.schema_version != CURRENT_SCHEMA_VERSION || !migrated.is_empty() {
+                manifest.schema_version = CURRENT_SCHEMA_VERSION;
+                manifest.migrated_at = Some(Utc::now());
+                manifest.save(self.storage_dir())?;
+            }
+        }
+
+        migrated.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(migrated)
+    }
+
     pub fn list_notes(&self) -> io::Result<Vec<Note>> {
         let mut notes = Vec::new();
         
@@ -76,12 +439,33 @@ impl FileStorage {
             let entry = entry?;
             let path = entry.path();
             
+            let file_name = path.file_name().and_then(|s| s.to_str());
+            if file_name == Some(crypto::VAULT_MANIFEST)
+                || file_name == Some(schema::STORE_MANIFEST)
+                || file_name == Some(index::INDEX_FILE)
+                || file_name == Some(dirsync::SYNC_STATE_FILE)
+            {
+                continue;
+            }
+
             if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("json") {
+                let id = match path.file_stem().and_then(|s| s.to_str()).map(NoteId::parse) {
+                    Some(Ok(id)) => id,
+                    _ => {
+                        eprintln!("Warning: Skipping file with an invalid note id {:?}", path);
+                        continue;
+                    }
+                };
                 match fs::read_to_string(&path) {
                     Ok(json_data) => {
-                        match serde_json::from_str::<Note>(&json_data) {
+                        match self.decode_note(&id, &json_data) {
                             Ok(note) => notes.push(note),
                             Err(e) => {
+                                // A locked vault affects every note, so report it instead of warning per file
+                                let e = NoteError::from(e);
+                                if let NoteError::VaultLocked(_) = e {
+                                    return Err(e.into());
+                                }
                                 eprintln!("Warning: Failed to parse note file {:?}: {}", path, e);
                             }
                         }
@@ -0,0 +1,146 @@
+use std::collections::BTreeMap;
+use chrono::Utc;
+use crate::error::{validate_tag, NoteError, Result};
+use crate::note::Note;
+use crate::storage::{FileStorage, StoreLock};
+
+pub const TAG_SEPARATOR: char = '/';
+
+/// Returns true when `tag` equals `ancestor` or lives below it, so `work/clients/acme`
+/// matches both `work/clients` and `work`
+pub fn is_same_or_descendant(tag: &str, ancestor: &str) -> bool {
+    match tag.strip_prefix(ancestor) {
+        Some(rest) => rest.is_empty() || rest.starts_with(TAG_SEPARATOR),
+        None => false,
+    }
+}
+
+/// All prefixes of a tag, from the root down to the tag itself
+pub fn ancestors(tag: &str) -> Vec<&str> {
+    tag.match_indices(TAG_SEPARATOR)
+        .map(|(index, _)| &tag[..index])
+        .chain(std::iter::once(tag))
+        .collect()
+}
+
+/// Number of notes carrying each tag, where a note tagged `a/b` also counts towards `a`
+pub fn tag_counts(notes: &[Note]) -> BTreeMap<String, usize> {
+    count_tags(notes.iter().map(|note| note.tags.as_slice()))
+}
+
+/// [`tag_counts`] over bare tag lists, such as those of index summaries
+pub fn count_tags<'a>(tag_lists: impl IntoIterator<Item = &'a [String]>) -> BTreeMap<String, usize> {
+    let mut counts = BTreeMap::new();
+    for tags in tag_lists {
+        let mut seen: Vec<&str> = Vec::new();
+        for tag in tags {
+            for ancestor in ancestors(tag) {
+                if !seen.contains(&ancestor) {
+                    seen.push(ancestor);
+                    *counts.entry(ancestor.to_string()).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+    counts
+}
+
+fn replace_prefix(tag: &str, from: &str, to: &str) -> String {
+    if is_same_or_descendant(tag, from) {
+        format!("{}{}", to, &tag[from.len()..])
+    } else {
+        tag.to_string()
+    }
+}
+
+/// Renames `old` and all of its descendants to live under `new`.
+/// Fails if `new` is already in use; use [`merge_tags`] to combine existing tags.
+pub fn rename_tag(storage: &FileStorage, old: &str, new: &str) -> Result<usize> {
+    validate_tag(old)?;
+    validate_tag(new)?;
+    if is_same_or_descendant(new, old) || is_same_or_descendant(old, new) {
+        return Err(NoteError::InvalidInput(format!("Cannot rename tag '{}' to '{}'", old, new)));
+    }
+
+    let lock = storage.lock()?;
+    let notes = load_tagged(storage, old)?;
+    if notes.iter().any(|note| note.tags.iter().any(|tag| is_same_or_descendant(tag, new))) {
+        return Err(NoteError::InvalidInput(format!(
+            "Tag '{}' already exists, use `notes tags merge {} {}` instead",
+            new, old, new
+        )));
+    }
+
+    rewrite_tags(storage, &lock, notes, old, |tag| Some(replace_prefix(tag, old, new)))
+}
+
+/// Moves every note tagged `source` (or below it) onto `target`, deduplicating tags
+pub fn merge_tags(storage: &FileStorage, source: &str, target: &str) -> Result<usize> {
+    validate_tag(source)?;
+    validate_tag(target)?;
+    if is_same_or_descendant(target, source) {
+        return Err(NoteError::InvalidInput(format!("Cannot merge tag '{}' into '{}'", source, target)));
+    }
+
+    let lock = storage.lock()?;
+    let notes = load_tagged(storage, source)?;
+    rewrite_tags(storage, &lock, notes, source, |tag| Some(replace_prefix(tag, source, target)))
+}
+
+/// Removes `tag` and all of its descendants from every note
+pub fn delete_tag(storage: &FileStorage, tag: &str) -> Result<usize> {
+    validate_tag(tag)?;
+
+    let lock = storage.lock()?;
+    let notes = load_tagged(storage, tag)?;
+    rewrite_tags(storage, &lock, notes, tag, |existing| {
+        if is_same_or_descendant(existing, tag) {
+            None
+        } else {
+            Some(existing.to_string())
+        }
+    })
+}
+
+/// Every note of the store, which must be locked so none changes before the
+/// rewrite. Fails with `NoteError::NotFound` when no note carries `tag`.
+fn load_tagged(storage: &FileStorage, tag: &str) -> Result<Vec<Note>> {
+    let notes = storage.list_notes()?;
+    if !notes.iter().any(|note| note.tags.iter().any(|existing| is_same_or_descendant(existing, tag))) {
+        return Err(NoteError::NotFound(format!("No notes tagged '{}'", tag)));
+    }
+    Ok(notes)
+}
+
+/// Computes the new tag set of every affected note and validates all of them
+/// before anything is written, so a bad rewrite leaves the store untouched.
+fn rewrite_tags<F>(storage: &FileStorage, lock: &StoreLock, notes: Vec<Note>, affected: &str, rewrite: F) -> Result<usize>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut changed = Vec::new();
+    for mut note in notes {
+        if !note.tags.iter().any(|tag| is_same_or_descendant(tag, affected)) {
+            continue;
+        }
+
+        let mut tags: Vec<String> = Vec::new();
+        for tag in &note.tags {
+            if let Some(new_tag) = rewrite(tag) {
+                validate_tag(&new_tag)?;
+                if !tags.contains(&new_tag) {
+                    tags.push(new_tag);
+                }
+            }
+        }
+
+        note.tags = tags;
+        note.updated_at = Utc::now();
+        changed.push(note);
+    }
+
+    for note in &mut changed {
+        storage.update_note_locked(note, lock)?;
+    }
+    Ok(changed.len())
+}
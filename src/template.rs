@@ -0,0 +1,193 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use chrono::{DateTime, Local};
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use crate::error::{validate_note_content, validate_note_title, validate_tag, NoteError, Result};
+use crate::note::Note;
+
+/// A reusable note skeleton stored as `<name>.json` in the templates directory.
+///
+/// The title, content, tags and metadata values may contain placeholders:
+/// `{{date}}`, `{{time}}`, `{{title}}`, `{{user}}` and `{{prompt:Label}}`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Template {
+    pub name: String,
+    #[serde(default = "default_title_pattern")]
+    pub title: String,
+    #[serde(default)]
+    pub content: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+static PLACEHOLDER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{\{\s*([^{}]+?)\s*\}\}").expect("valid placeholder pattern"));
+
+/// Asks the user for the answer to a `{{prompt:Label}}` placeholder
+type Prompt<'a> = Box<dyn FnMut(&str) -> Result<String> + 'a>;
+
+fn default_title_pattern() -> String {
+    "{{title}}".to_string()
+}
+
+/// Values available to placeholders while a template is rendered
+pub struct TemplateContext<'a> {
+    pub title: String,
+    pub user: String,
+    pub now: DateTime<Local>,
+    prompt: Prompt<'a>,
+    answers: HashMap<String, String>,
+}
+
+impl<'a> TemplateContext<'a> {
+    pub fn new<F>(title: String, prompt: F) -> Self
+    where
+        F: FnMut(&str) -> Result<String> + 'a,
+    {
+        Self {
+            title,
+            user: current_user(),
+            now: Local::now(),
+            prompt: Box::new(prompt),
+            answers: HashMap::new(),
+        }
+    }
+
+    /// Replaces every placeholder in `text`; each prompt label is asked at most once
+    pub fn substitute(&mut self, text: &str) -> Result<String> {
+        let mut error = None;
+        let rendered = PLACEHOLDER.replace_all(text, |caps: &Captures| {
+            match self.resolve(&caps[1]) {
+                Ok(value) => value,
+                Err(e) => {
+                    error.get_or_insert(e);
+                    String::new()
+                }
+            }
+        });
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(rendered.into_owned()),
+        }
+    }
+
+    fn resolve(&mut self, placeholder: &str) -> Result<String> {
+        if let Some(label) = placeholder.strip_prefix("prompt:") {
+            let label = label.trim();
+            if let Some(answer) = self.answers.get(label) {
+                return Ok(answer.clone());
+            }
+            let answer = (self.prompt)(label)?;
+            self.answers.insert(label.to_string(), answer.clone());
+            return Ok(answer);
+        }
+
+        match placeholder {
+            "date" => Ok(self.now.format("%Y-%m-%d").to_string()),
+            "time" => Ok(self.now.format("%H:%M").to_string()),
+            "title" => Ok(self.title.clone()),
+            "user" => Ok(self.user.clone()),
+            other => Err(NoteError::InvalidInput(format!("Unknown template placeholder '{{{{{}}}}}'", other))),
+        }
+    }
+}
+
+fn current_user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+impl Template {
+    /// Renders the template into a new, validated note
+    pub fn render(&self, context: &mut TemplateContext) -> Result<Note> {
+        let title = context.substitute(&self.title)?;
+        validate_note_title(&title)?;
+
+        let content = context.substitute(&self.content)?;
+        validate_note_content(&content)?;
+
+        let mut tags: Vec<String> = Vec::new();
+        for tag in &self.tags {
+            let tag = context.substitute(tag)?;
+            validate_tag(&tag)?;
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+
+        let mut note = Note::with_tags(title, content, tags);
+        for (key, value) in &self.metadata {
+            let value = context.substitute(value)?;
+            note.metadata.insert(key.clone(), value);
+        }
+        note.metadata.insert("template".to_string(), self.name.clone());
+
+        Ok(note)
+    }
+}
+
+pub struct TemplateStore {
+    templates_dir: PathBuf,
+}
+
+impl TemplateStore {
+    pub fn new(templates_dir: impl AsRef<Path>) -> io::Result<Self> {
+        let path = templates_dir.as_ref();
+        if !path.exists() {
+            fs::create_dir_all(path)?;
+        }
+
+        Ok(TemplateStore {
+            templates_dir: path.to_path_buf(),
+        })
+    }
+
+    pub fn load(&self, name: &str) -> Result<Template> {
+        let file_path = self.template_path(name)?;
+        if !file_path.exists() {
+            return Err(NoteError::NotFound(format!("Template '{}' not found in {:?}", name, self.templates_dir)));
+        }
+
+        let json_data = fs::read_to_string(file_path)?;
+        let template: Template = serde_json::from_str(&json_data)?;
+        Ok(template)
+    }
+
+    pub fn save(&self, template: &Template) -> Result<()> {
+        let file_path = self.template_path(&template.name)?;
+        let json_data = serde_json::to_string_pretty(template)?;
+        fs::write(file_path, json_data)?;
+        Ok(())
+    }
+
+    pub fn list(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.templates_dir)? {
+            let path = entry?.path();
+            if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("json") {
+                if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+                    names.push(stem.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn template_path(&self, name: &str) -> Result<PathBuf> {
+        let valid = !name.is_empty()
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(NoteError::InvalidInput(format!("Invalid template name '{}'", name)));
+        }
+        Ok(self.templates_dir.join(format!("{}.json", name)))
+    }
+}
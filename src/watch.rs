@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use crate::error::{NoteError, Result};
+use crate::note::{Note, NoteId};
+use crate::notebook::NotebookStore;
+use crate::storage::is_note_file;
+
+/// Longest wait between two scans, even without filesystem notifications
+pub const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Quiet period after a notification, so a burst of writes is reported once
+#[cfg(target_os = "linux")]
+const SETTLE_TIME: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Created,
+    Modified,
+    Removed,
+}
+
+/// A change to a note file, printed by `notes watch` as one JSON object per line
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WatchEvent {
+    pub event: ChangeKind,
+    pub notebook: String,
+    pub id: NoteId,
+    /// Title after the change, or the last known title of a removed note
+    pub title: Option<String>,
+    pub path: PathBuf,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+struct Seen {
+    modified: Option<DateTime<Utc>>,
+    len: u64,
+    title: Option<String>,
+}
+
+/// Watches every notebook for note files created, changed or removed by other
+/// tools. Files are compared by modification time and length on each scan; on
+/// Linux, inotify wakes the scan up as soon as something changes.
+pub struct Watcher<'a> {
+    notebooks: &'a NotebookStore,
+    seen: HashMap<(String, NoteId), Seen>,
+    #[cfg(target_os = "linux")]
+    inotify: inotify::Inotify,
+}
+
+impl<'a> Watcher<'a> {
+    /// Starts watching; notes that already exist are not reported
+    pub fn new(notebooks: &'a NotebookStore) -> Result<Self> {
+        let mut watcher = Watcher {
+            notebooks,
+            seen: HashMap::new(),
+            #[cfg(target_os = "linux")]
+            inotify: inotify::Inotify::new()?,
+        };
+        watcher.scan()?;
+        Ok(watcher)
+    }
+
+    /// Blocks until something changes on disk or `timeout` passes, then scans
+    pub fn wait(&mut self, timeout: Duration) -> Result<Vec<WatchEvent>> {
+        #[cfg(target_os = "linux")]
+        {
+            if self.inotify.wait(timeout)? {
+                std::thread::sleep(SETTLE_TIME);
+                self.inotify.drain()?;
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        std::thread::sleep(timeout);
+        self.scan()
+    }
+
+    /// Scans every notebook once and reports what changed since the previous scan.
+    /// Changed notes are reloaded and the metadata index of their notebook is refreshed.
+    pub fn scan(&mut self) -> Result<Vec<WatchEvent>> {
+        #[cfg(target_os = "linux")]
+        self.inotify.add_watch(self.notebooks.root())?;
+
+        let mut events = Vec::new();
+        let mut present = HashMap::new();
+        for (name, _) in self.notebooks.list()? {
+            let storage = self.notebooks.open(&name)?;
+            #[cfg(target_os = "linux")]
+            self.inotify.add_watch(storage.storage_dir())?;
+
+            for (id, path) in note_files(storage.storage_dir())? {
+                let metadata = match fs::metadata(&path) {
+                    Ok(metadata) => metadata,
+                    Err(_) => continue,
+                };
+                let modified = metadata.modified().ok().map(DateTime::<Utc>::from);
+                let key = (name.clone(), id.clone());
+
+                let previous = self.seen.get(&key);
+                if let Some(previous) = previous.filter(|p| p.modified == modified && p.len == metadata.len()) {
+                    present.insert(key, previous.clone());
+                    continue;
+                }
+
+                // Notes never reported (they were unreadable so far) show up as created
+                let kind = match previous {
+                    Some(Seen { title: Some(_), .. }) => ChangeKind::Modified,
+                    _ => ChangeKind::Created,
+                };
+                let title = match storage.load_note(&id) {
+                    Ok(note) => Some(note.title),
+                    Err(e) => {
+                        // Possibly caught mid-write; the next change to the file triggers a retry
+                        eprintln!("Warning: Failed to reload note file {:?}: {}", path, NoteError::from(e));
+                        None
+                    }
+                };
+                present.insert(key, Seen { modified, len: metadata.len(), title: title.clone() });
+                if title.is_some() {
+                    events.push(WatchEvent { event: kind, notebook: name.clone(), id, title, path, at: Utc::now() });
+                }
+            }
+        }
+
+        for ((notebook, id), seen) in &self.seen {
+            if seen.title.is_none() || present.contains_key(&(notebook.clone(), id.clone())) {
+                continue;
+            }
+            events.push(WatchEvent {
+                event: ChangeKind::Removed,
+                notebook: notebook.clone(),
+                id: id.clone(),
+                title: seen.title.clone(),
+                path: self.notebooks.root().join(notebook).join(format!("{}.json", id)),
+                at: Utc::now(),
+            });
+        }
+        self.seen = present;
+
+        let mut touched: Vec<&str> = events.iter().map(|e| e.notebook.as_str()).collect();
+        touched.sort_unstable();
+        touched.dedup();
+        for name in touched {
+            if self.notebooks.exists(name) {
+                self.notebooks.open(name)?.list_summaries()?;
+            }
+        }
+        Ok(events)
+    }
+}
+
+/// `(id, path)` of every note file in a directory, sorted by id
+pub(crate) fn note_files(dir: &Path) -> Result<Vec<(NoteId, PathBuf)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !is_note_file(&path) {
+            continue;
+        }
+        if let Some(Ok(id)) = path.file_stem().and_then(|s| s.to_str()).map(NoteId::parse) {
+            files.push((id, path));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Reloads a note reported by a [`WatchEvent`]; `None` once it was removed
+pub fn reload(notebooks: &NotebookStore, event: &WatchEvent) -> Result<Option<Note>> {
+    if event.event == ChangeKind::Removed {
+        return Ok(None);
+    }
+    Ok(Some(notebooks.open(&event.notebook)?.load_note(&event.id)?))
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use std::collections::HashSet;
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    const EVENTS: u32 = libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_MODIFY
+        | libc::IN_CLOSE_WRITE
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO
+        | libc::IN_DELETE_SELF
+        | libc::IN_MOVE_SELF;
+
+    /// Minimal inotify handle, used only to learn that something changed
+    pub struct Inotify {
+        fd: OwnedFd,
+        watched: HashSet<PathBuf>,
+    }
+
+    impl Inotify {
+        pub fn new() -> io::Result<Self> {
+            let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: `fd` was just returned by inotify_init1 and is owned by nobody else
+            Ok(Inotify { fd: unsafe { OwnedFd::from_raw_fd(fd) }, watched: HashSet::new() })
+        }
+
+        pub fn add_watch(&mut self, dir: &Path) -> io::Result<()> {
+            if self.watched.contains(dir) {
+                return Ok(());
+            }
+            let path = CString::new(dir.as_os_str().as_bytes())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), EVENTS) };
+            if wd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            self.watched.insert(dir.to_path_buf());
+            Ok(())
+        }
+
+        /// Waits until an event is pending; false when `timeout` passed first
+        pub fn wait(&mut self, timeout: Duration) -> io::Result<bool> {
+            let mut pollfd = libc::pollfd { fd: self.fd.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+            let millis = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+            match unsafe { libc::poll(&mut pollfd, 1, millis) } {
+                n if n < 0 => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        Ok(false)
+                    } else {
+                        Err(e)
+                    }
+                }
+                0 => Ok(false),
+                _ => Ok(true),
+            }
+        }
+
+        /// Discards pending events. A directory that was deleted or moved loses its
+        /// watch, so it is forgotten and watched again if it shows up on a later scan.
+        pub fn drain(&mut self) -> io::Result<()> {
+            let mut buffer = [0u8; 4096];
+            loop {
+                let n = unsafe { libc::read(self.fd.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len()) };
+                if n < 0 {
+                    let e = io::Error::last_os_error();
+                    return match e.kind() {
+                        io::ErrorKind::WouldBlock => Ok(()),
+                        io::ErrorKind::Interrupted => continue,
+                        _ => Err(e),
+                    };
+                }
+                if n == 0 {
+                    return Ok(());
+                }
+
+                let mut offset = 0;
+                while offset + std::mem::size_of::<libc::inotify_event>() <= n as usize {
+                    // SAFETY: the kernel writes whole, possibly unaligned, inotify_event records
+                    let event: libc::inotify_event =
+                        unsafe { std::ptr::read_unaligned(buffer[offset..].as_ptr().cast()) };
+                    if event.mask & (libc::IN_DELETE_SELF | libc::IN_MOVE_SELF | libc::IN_IGNORED) != 0 {
+                        self.watched.clear();
+                    }
+                    offset += std::mem::size_of::<libc::inotify_event>() + event.len as usize;
+                }
+            }
+        }
+    }
+}
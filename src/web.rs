@@ -0,0 +1,54 @@
+use crate::api::{ApiServer, API_PREFIX};
+use crate::crypto;
+use crate::error::Result;
+use crate::http::{Request, Response};
+
+pub const DEFAULT_WEB_BIND: &str = "127.0.0.1:7879";
+
+/// `(path, content type, contents)` of the UI files, compiled into the binary so it works offline
+const ASSETS: &[(&str, &str, &str)] = &[
+    ("/", "text/html; charset=utf-8", include_str!("web/index.html")),
+    ("/app.js", "text/javascript; charset=utf-8", include_str!("web/app.js")),
+    ("/app.css", "text/css; charset=utf-8", include_str!("web/app.css")),
+];
+
+/// The page only loads its own files, so markup that slips into a note cannot run scripts
+const CONTENT_SECURITY_POLICY: &str =
+    "default-src 'self'; img-src 'self' data:; object-src 'none'; base-uri 'none'; frame-ancestors 'none'";
+
+/// Serves the embedded web UI of `notes web` next to the API it talks to.
+/// The files need no token; every API call does.
+pub struct WebServer {
+    api: ApiServer,
+}
+
+impl WebServer {
+    pub fn new(api: ApiServer) -> Self {
+        WebServer { api }
+    }
+
+    pub fn handle(&self, request: &Request) -> Response {
+        if request.path.starts_with(API_PREFIX) {
+            return self.api.handle(request);
+        }
+        let path = match request.path.as_str() {
+            "/index.html" => "/",
+            path => path,
+        };
+        match (request.method.as_str(), ASSETS.iter().find(|(asset, _, _)| *asset == path)) {
+            ("GET", Some((_, content_type, contents))) => Response::new(200)
+                .with_header("Content-Type", content_type)
+                .with_header("Content-Security-Policy", CONTENT_SECURITY_POLICY)
+                .with_header("X-Content-Type-Options", "nosniff")
+                .with_header("Cache-Control", "no-cache")
+                .with_body(contents.as_bytes().to_vec()),
+            (_, Some(_)) => Response::error(405, "method not allowed"),
+            (_, None) => Response::error(404, "not found"),
+        }
+    }
+}
+
+/// Random API token for one run of `notes web`, handed to the browser in the URL fragment
+pub fn session_token() -> Result<String> {
+    Ok(crypto::random_bytes::<32>()?.iter().map(|b| format!("{:02x}", b)).collect())
+}
@@ -0,0 +1,315 @@
+// Web UI of `notes web`. Everything goes through the JSON API under /api/v1,
+// authenticated with the token `notes web` puts in the URL fragment.
+"use strict";
+
+const API = "/api/v1";
+const PAGE_SIZE = 50;
+
+const token = (() => {
+  const match = location.hash.match(/token=([0-9a-f]+)/);
+  if (match) {
+    sessionStorage.setItem("notes-token", match[1]);
+    history.replaceState(null, "", location.pathname);
+  }
+  return sessionStorage.getItem("notes-token");
+})();
+
+const $ = (id) => document.getElementById(id);
+const state = { items: [], total: 0, current: null, etag: null, editing: false, editingExisting: false };
+
+class ApiError extends Error {
+  constructor(status, message) {
+    super(message);
+    this.status = status;
+  }
+}
+
+async function api(method, path, { body, etag, text } = {}) {
+  const headers = { Authorization: `Bearer ${token}` };
+  if (body !== undefined) headers["Content-Type"] = "application/json";
+  if (etag) headers["If-Match"] = etag;
+  const response = await fetch(API + path, {
+    method,
+    headers,
+    body: body === undefined ? undefined : JSON.stringify(body),
+  });
+  if (!response.ok) {
+    const error = await response.json().catch(() => ({ error: response.statusText }));
+    throw new ApiError(response.status, error.error);
+  }
+  const value = response.status === 204 ? null : text ? await response.text() : await response.json();
+  return { value, etag: response.headers.get("ETag") };
+}
+
+function showStatus(message, isError) {
+  const status = $("status");
+  status.textContent = message;
+  status.className = isError ? "error" : "";
+  status.hidden = !message;
+}
+
+function report(error) {
+  if (error.status === 401) {
+    showStatus("Not authorized: open the address printed by `notes web` again.", true);
+  } else if (error.status === 412) {
+    showStatus("The note was changed elsewhere. It has been reloaded; apply your edit again.", true);
+    if (state.current) openNote(state.current.id);
+  } else {
+    showStatus(error.message, true);
+  }
+}
+
+function query(offset) {
+  const params = new URLSearchParams({ limit: PAGE_SIZE, offset });
+  const text = $("query").value.trim();
+  if (text) {
+    params.set("q", text);
+    params.set("field", $("field").value);
+  }
+  if ($("notebook").value) params.set("notebook", $("notebook").value);
+  if ($("tag").value) params.set("tag", $("tag").value);
+  return `${text ? "/search" : "/notes"}?${params}`;
+}
+
+async function loadNotes(append) {
+  try {
+    const offset = append ? state.items.length : 0;
+    const { value: page } = await api("GET", query(offset));
+    state.items = append ? state.items.concat(page.items) : page.items;
+    state.total = page.total;
+    renderList();
+  } catch (error) {
+    report(error);
+  }
+}
+
+function renderList() {
+  const list = $("notes");
+  list.replaceChildren();
+  for (const item of state.items) {
+    const entry = document.createElement("li");
+    entry.textContent = item.title;
+    const notebook = document.createElement("div");
+    notebook.className = "notebook";
+    notebook.textContent = [item.notebook].concat(item.tags).join(" · ");
+    entry.append(notebook);
+    if (state.current && state.current.id === item.id) entry.className = "selected";
+    entry.addEventListener("click", () => openNote(item.id));
+    list.append(entry);
+  }
+  $("more").hidden = state.items.length >= state.total;
+}
+
+async function loadFilters() {
+  try {
+    const { value: tags } = await api("GET", "/tags");
+    fillSelect($("tag"), tags.map((entry) => entry.tag));
+    const { value: notebooks } = await api("GET", "/notebooks");
+    fillSelect($("notebook"), notebooks.map((notebook) => notebook.name));
+  } catch (error) {
+    report(error);
+  }
+}
+
+function fillSelect(select, values) {
+  const selected = select.value;
+  select.replaceChildren(select.options[0]);
+  for (const value of values) select.append(new Option(value, value));
+  select.value = values.includes(selected) ? selected : "";
+}
+
+async function openNote(id) {
+  try {
+    const [{ value: note, etag }, { value: html }] = await Promise.all([
+      api("GET", `/notes/${id}`),
+      api("GET", `/notes/${id}/html`, { text: true }).catch((error) => {
+        if (error.status === 423) return { value: "<p><em>This note is locked.</em></p>" };
+        throw error;
+      }),
+    ]);
+    state.current = note;
+    state.etag = etag;
+    state.editing = false;
+    showNote(html);
+    renderList();
+  } catch (error) {
+    report(error);
+  }
+}
+
+function showNote(html) {
+  const note = state.current;
+  $("empty").hidden = true;
+  $("editor").hidden = true;
+  $("view").hidden = false;
+  $("title").textContent = note.title;
+  $("meta").textContent = `${note.notebook} · updated ${new Date(note.updated_at).toLocaleString()}`;
+  const tags = $("tags");
+  tags.replaceChildren();
+  for (const tag of note.tags) {
+    const chip = document.createElement("li");
+    chip.textContent = tag;
+    const remove = document.createElement("button");
+    remove.type = "button";
+    remove.textContent = "×";
+    remove.title = `Remove ${tag}`;
+    remove.addEventListener("click", () => changeTags({ remove: [tag] }));
+    chip.append(remove);
+    tags.append(chip);
+  }
+  // Rendered by the server, which escapes everything the note contains
+  $("content").innerHTML = html;
+}
+
+async function changeTags(change) {
+  try {
+    const { etag } = await api("POST", `/notes/${state.current.id}/tags`, { body: change, etag: state.etag });
+    state.etag = etag;
+    await openNote(state.current.id);
+    loadFilters();
+  } catch (error) {
+    report(error);
+  }
+}
+
+function edit(note) {
+  state.editing = true;
+  $("view").hidden = true;
+  $("empty").hidden = true;
+  $("editor").hidden = false;
+  $("edit-title").value = note ? note.title : "";
+  $("edit-tags").value = note ? note.tags.join(", ") : "";
+  $("edit-content").value = note ? note.content : "";
+  $("edit-content").disabled = Boolean(note && note.locked);
+  $("edit-title").focus();
+}
+
+async function save(event) {
+  event.preventDefault();
+  const fields = {
+    title: $("edit-title").value.trim(),
+    tags: $("edit-tags").value.split(",").map((tag) => tag.trim()).filter(Boolean),
+  };
+  const isNew = !state.editingExisting;
+  if (!$("edit-content").disabled) fields.content = $("edit-content").value;
+  try {
+    const { value: note } = isNew
+      ? await api("POST", "/notes", { body: { ...fields, notebook: $("notebook").value || undefined } })
+      : await api("PATCH", `/notes/${state.current.id}`, { body: fields, etag: state.etag });
+    showStatus("");
+    await openNote(note.id);
+    loadNotes(false);
+    loadFilters();
+  } catch (error) {
+    report(error);
+  }
+}
+
+async function remove() {
+  if (!confirm(`Delete "${state.current.title}"?`)) return;
+  try {
+    await api("DELETE", `/notes/${state.current.id}`, { etag: state.etag });
+    state.current = null;
+    $("view").hidden = true;
+    $("empty").hidden = false;
+    loadNotes(false);
+    loadFilters();
+  } catch (error) {
+    report(error);
+  }
+}
+
+async function followLink(title) {
+  try {
+    const params = new URLSearchParams({ q: title, field: "title", limit: PAGE_SIZE });
+    const { value: page } = await api("GET", `/search?${params}`);
+    const target = page.items.find((item) => item.title.toLowerCase() === title.toLowerCase());
+    if (target) openNote(target.id);
+    else showStatus(`No note is titled "${title}".`, true);
+  } catch (error) {
+    report(error);
+  }
+}
+
+// Live updates from the change feed; EventSource resumes from the last event by itself
+function follow() {
+  const feed = new EventSource(`${API}/events?access_token=${encodeURIComponent(token)}`);
+  let refresh = null;
+  const changed = (event) => {
+    const change = JSON.parse(event.data);
+    clearTimeout(refresh);
+    refresh = setTimeout(() => {
+      loadNotes(false);
+      loadFilters();
+    }, 200);
+    if (state.current && change.id === state.current.id && !state.editing) {
+      if (change.event === "deleted") {
+        state.current = null;
+        $("view").hidden = true;
+        $("empty").hidden = false;
+      } else {
+        openNote(change.id);
+      }
+    }
+  };
+  for (const kind of ["created", "updated", "tagged", "deleted"]) feed.addEventListener(kind, changed);
+  feed.onerror = () => {
+    // A 410 (cursor gone) closes the stream: reload everything and start over
+    if (feed.readyState === EventSource.CLOSED) {
+      loadNotes(false);
+      setTimeout(follow, 2000);
+    }
+  };
+}
+
+document.addEventListener("DOMContentLoaded", () => {
+  if (!token) {
+    showStatus("Open the address printed by `notes web`; it carries the access token.", true);
+    return;
+  }
+  let typing = null;
+  $("query").addEventListener("input", () => {
+    clearTimeout(typing);
+    typing = setTimeout(() => loadNotes(false), 250);
+  });
+  $("search").addEventListener("submit", (event) => {
+    event.preventDefault();
+    loadNotes(false);
+  });
+  for (const id of ["field", "notebook", "tag"]) $(id).addEventListener("change", () => loadNotes(false));
+  $("more").addEventListener("click", () => loadNotes(true));
+  $("new").addEventListener("click", () => {
+    state.editingExisting = false;
+    edit(null);
+  });
+  $("edit").addEventListener("click", () => {
+    state.editingExisting = true;
+    edit(state.current);
+  });
+  $("cancel").addEventListener("click", () => {
+    state.editing = false;
+    if (state.current) openNote(state.current.id);
+    else {
+      $("editor").hidden = true;
+      $("empty").hidden = false;
+    }
+  });
+  $("editor").addEventListener("submit", save);
+  $("delete").addEventListener("click", remove);
+  $("add-tag").addEventListener("submit", (event) => {
+    event.preventDefault();
+    const tag = $("new-tag").value.trim();
+    $("new-tag").value = "";
+    if (tag) changeTags({ add: [tag] });
+  });
+  $("content").addEventListener("click", (event) => {
+    const link = event.target.closest("a.wikilink");
+    if (link) {
+      event.preventDefault();
+      followLink(link.dataset.title);
+    }
+  });
+  loadNotes(false);
+  loadFilters();
+  follow();
+});
@@ -107,4 +107,2805 @@ mod note_manager_tests {
         
         let note_id = manager.add_note("Test Title", "Test content").unwrap();
         
-        assert!(!note_id.
\ No newline at end of file
+        assert!(!note_id.
+#[cfg(test)]
+mod template_tests {
+    use note_taking_app::error::NoteError;
+    use note_taking_app::template::{Template, TemplateContext, TemplateStore};
+    use std::collections::BTreeMap;
+    use tempfile::TempDir;
+
+    fn meeting_template() -> Template {
+        let mut metadata = BTreeMap::new();
+        metadata.insert("attendees".to_string(), "{{prompt:Attendees}}".to_string());
+        Template {
+            name: "meeting".to_string(),
+            title: "{{title}} ({{date}})".to_string(),
+            content: "Organizer: {{user}}\nAttendees: {{prompt:Attendees}}\n".to_string(),
+            tags: vec!["meeting".to_string()],
+            metadata,
+        }
+    }
+
+    #[test]
+    fn test_template_render_substitutes_placeholders() {
+        let mut asked = Vec::new();
+        let mut context = TemplateContext::new("Sprint 42 planning".to_string(), |label: &str| {
+            asked.push(label.to_string());
+            Ok("alice, bob".to_string())
+        });
+        context.user = "carol".to_string();
+        let date = context.now.format("%Y-%m-%d").to_string();
+
+        let note = meeting_template().render(&mut context).unwrap();
+        drop(context);
+
+        assert_eq!(note.title, format!("Sprint 42 planning ({})", date));
+        assert_eq!(note.content, "Organizer: carol\nAttendees: alice, bob\n");
+        assert_eq!(note.tags, vec!["meeting".to_string()]);
+        assert_eq!(note.metadata.get("attendees").map(String::as_str), Some("alice, bob"));
+        assert_eq!(note.metadata.get("template").map(String::as_str), Some("meeting"));
+        assert_eq!(asked, vec!["Attendees".to_string()]);
+    }
+
+    #[test]
+    fn test_template_render_validates_result() {
+        let mut template = meeting_template();
+        template.title = "{{prompt:Title}}".to_string();
+        let mut context = TemplateContext::new("ignored".to_string(), |_: &str| Ok("   ".to_string()));
+
+        assert!(matches!(template.render(&mut context), Err(NoteError::ValidationError(_))));
+    }
+
+    #[test]
+    fn test_template_unknown_placeholder() {
+        let mut template = meeting_template();
+        template.content = "{{weather}}".to_string();
+        let mut context = TemplateContext::new("Title".to_string(), |_: &str| Ok(String::new()));
+
+        assert!(matches!(template.render(&mut context), Err(NoteError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn test_template_store_roundtrip() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = TemplateStore::new(temp_dir.path()).unwrap();
+
+        store.save(&meeting_template()).unwrap();
+
+        assert_eq!(store.list().unwrap(), vec!["meeting".to_string()]);
+        assert_eq!(store.load("meeting").unwrap(), meeting_template());
+        assert!(matches!(store.load("missing"), Err(NoteError::NotFound(_))));
+        assert!(matches!(store.load("../meeting"), Err(NoteError::InvalidInput(_))));
+    }
+}
+
+#[cfg(test)]
+mod journal_tests {
+    use chrono::NaiveDate;
+    use note_taking_app::config::JournalConfig;
+    use note_taking_app::journal;
+    use note_taking_app::storage::FileStorage;
+    use note_taking_app::template::TemplateStore;
+    use tempfile::TempDir;
+
+    fn setup() -> (TempDir, FileStorage, TemplateStore) {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::new(temp_dir.path().join("notes").to_str().unwrap()).unwrap();
+        let templates = TemplateStore::new(temp_dir.path().join("templates")).unwrap();
+        (temp_dir, storage, templates)
+    }
+
+    fn date(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+    }
+
+    fn no_prompt(label: &str) -> note_taking_app::Result<String> {
+        panic!("unexpected prompt for {}", label)
+    }
+
+    #[test]
+    fn test_parse_journal_date() {
+        let today = date("2024-03-10");
+
+        assert_eq!(journal::parse_journal_date("today", today).unwrap(), today);
+        assert_eq!(journal::parse_journal_date("yesterday", today).unwrap(), date("2024-03-09"));
+        assert_eq!(journal::parse_journal_date("2024-01-31", today).unwrap(), date("2024-01-31"));
+        assert!(journal::parse_journal_date("31/01/2024", today).is_err());
+    }
+
+    #[test]
+    fn test_open_entry_creates_once() {
+        let (_temp_dir, storage, templates) = setup();
+        let config = JournalConfig::default();
+
+        let (first, created) = journal::open_entry(&storage, &templates, &config, date("2024-03-10"), no_prompt).unwrap();
+        assert!(created);
+        assert_eq!(first.title, "Journal 2024-03-10");
+        assert!(first.has_tag("journal"));
+
+        let (second, created) = journal::open_entry(&storage, &templates, &config, date("2024-03-10"), no_prompt).unwrap();
+        assert!(!created);
+        assert_eq!(second.id, first.id);
+        assert_eq!(storage.list_notes().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_invalid_title_format_is_an_error() {
+        let (_temp_dir, storage, templates) = setup();
+        let config = JournalConfig { title_format: "Journal %Q".to_string(), ..JournalConfig::default() };
+
+        let result = journal::open_entry(&storage, &templates, &config, date("2024-03-10"), no_prompt);
+        assert!(matches!(result, Err(note_taking_app::NoteError::InvalidInput(_))));
+        assert!(storage.list_notes().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_last_week_and_neighbours() {
+        let (_temp_dir, storage, templates) = setup();
+        let config = JournalConfig::default();
+
+        for day in ["2024-03-01", "2024-03-05", "2024-03-09", "2024-03-10"] {
+            journal::open_entry(&storage, &templates, &config, date(day), no_prompt).unwrap();
+        }
+
+        let week = journal::last_week(&storage, date("2024-03-10")).unwrap();
+        let days: Vec<_> = week.iter().filter_map(journal::entry_date).collect();
+        assert_eq!(days, vec![date("2024-03-10"), date("2024-03-09"), date("2024-03-05")]);
+
+        let (previous, next) = journal::neighbours(&storage, date("2024-03-05")).unwrap();
+        assert_eq!(previous, Some(date("2024-03-01")));
+        assert_eq!(next, Some(date("2024-03-09")));
+    }
+}
+
+#[cfg(test)]
+mod tag_hierarchy_tests {
+    use note_taking_app::error::{validate_tag, NoteError};
+    use note_taking_app::note::Note;
+    use note_taking_app::storage::FileStorage;
+    use note_taking_app::tags;
+    use tempfile::TempDir;
+
+    fn tagged(title: &str, tags: &[&str]) -> Note {
+        Note::with_tags(title.to_string(), String::new(), tags.iter().map(|t| t.to_string()).collect())
+    }
+
+    fn setup(notes: &[Note]) -> (TempDir, FileStorage) {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::new(temp_dir.path().to_str().unwrap()).unwrap();
+        for note in notes {
+            storage.save_note(note).unwrap();
+        }
+        (temp_dir, storage)
+    }
+
+    #[test]
+    fn test_hierarchical_tag_validation() {
+        assert!(validate_tag("work/clients/acme").is_ok());
+        assert!(validate_tag("work//acme").is_err());
+        assert!(validate_tag("/work").is_err());
+        assert!(validate_tag("work/").is_err());
+    }
+
+    #[test]
+    fn test_parent_tag_matches_descendants() {
+        let note = tagged("Acme kickoff", &["work/clients/acme"]);
+
+        assert!(note.has_tag_or_descendant("work"));
+        assert!(note.has_tag_or_descendant("work/clients"));
+        assert!(note.has_tag_or_descendant("work/clients/acme"));
+        assert!(!note.has_tag_or_descendant("work/client"));
+        assert!(!note.has_tag_or_descendant("personal"));
+    }
+
+    #[test]
+    fn test_tag_counts_roll_up() {
+        let notes = vec![
+            tagged("a", &["work/clients/acme", "work/clients/globex"]),
+            tagged("b", &["work/internal"]),
+            tagged("c", &["personal"]),
+        ];
+
+        let counts = tags::tag_counts(&notes);
+        assert_eq!(counts["work"], 2);
+        assert_eq!(counts["work/clients"], 1);
+        assert_eq!(counts["work/clients/acme"], 1);
+        assert_eq!(counts["personal"], 1);
+    }
+
+    #[test]
+    fn test_rename_tag_rewrites_descendants() {
+        let (_temp_dir, storage) = setup(&[
+            tagged("a", &["work/clients/acme"]),
+            tagged("b", &["work", "urgent"]),
+            tagged("c", &["personal"]),
+        ]);
+
+        assert_eq!(tags::rename_tag(&storage, "work", "job").unwrap(), 2);
+
+        let counts = tags::tag_counts(&storage.list_notes().unwrap());
+        assert!(!counts.contains_key("work"));
+        assert_eq!(counts["job/clients/acme"], 1);
+        assert_eq!(counts["job"], 2);
+        assert!(matches!(tags::rename_tag(&storage, "personal", "job"), Err(NoteError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn test_merge_and_delete_tags() {
+        let (_temp_dir, storage) = setup(&[
+            tagged("a", &["todo", "tasks"]),
+            tagged("b", &["todo/home"]),
+        ]);
+
+        assert_eq!(tags::merge_tags(&storage, "todo", "tasks").unwrap(), 2);
+        let counts = tags::tag_counts(&storage.list_notes().unwrap());
+        assert_eq!(counts.get("todo"), None);
+        assert_eq!(counts["tasks"], 2);
+        assert_eq!(counts["tasks/home"], 1);
+
+        assert_eq!(tags::delete_tag(&storage, "tasks").unwrap(), 2);
+        assert!(tags::tag_counts(&storage.list_notes().unwrap()).is_empty());
+        assert!(matches!(tags::delete_tag(&storage, "tasks"), Err(NoteError::NotFound(_))));
+        assert!(matches!(tags::merge_tags(&storage, "todo", "tasks"), Err(NoteError::NotFound(_))));
+    }
+}
+
+#[cfg(test)]
+mod notebook_tests {
+    use note_taking_app::error::NoteError;
+    use note_taking_app::note::Note;
+    use note_taking_app::notebook::NotebookStore;
+    use note_taking_app::storage::FileStorage;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_loose_notes_move_into_default_notebook() {
+        let temp_dir = TempDir::new().unwrap();
+        let legacy = FileStorage::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let note = Note::new("Legacy".to_string(), "Before notebooks".to_string());
+        legacy.save_note(&note).unwrap();
+
+        let notebooks = NotebookStore::new(temp_dir.path(), "personal").unwrap();
+
+        assert_eq!(notebooks.list().unwrap(), vec![("personal".to_string(), 1)]);
+        assert_eq!(notebooks.open("personal").unwrap().load_note(&note.id).unwrap(), note);
+    }
+
+    #[test]
+    fn test_notebooks_are_listed_independently() {
+        let temp_dir = TempDir::new().unwrap();
+        let notebooks = NotebookStore::new(temp_dir.path(), "personal").unwrap();
+        let team = notebooks.create("team").unwrap();
+        notebooks.create("project-x").unwrap();
+
+        team.save_note(&Note::new("Standup".to_string(), String::new())).unwrap();
+
+        assert_eq!(notebooks.open("team").unwrap().list_notes().unwrap().len(), 1);
+        assert!(notebooks.open("project-x").unwrap().list_notes().unwrap().is_empty());
+        assert!(matches!(notebooks.create("team"), Err(NoteError::InvalidInput(_))));
+        assert!(matches!(notebooks.open("missing"), Err(NoteError::NotFound(_))));
+        assert!(notebooks.create("../escape").is_err());
+    }
+
+    #[test]
+    fn test_rename_move_and_delete_notebooks() {
+        let temp_dir = TempDir::new().unwrap();
+        let notebooks = NotebookStore::new(temp_dir.path(), "personal").unwrap();
+        let work = notebooks.create("work").unwrap();
+        notebooks.create("archive").unwrap();
+        let note = Note::new("Quarterly plan".to_string(), String::new());
+        work.save_note(&note).unwrap();
+
+        notebooks.rename("work", "job").unwrap();
+        assert_eq!(notebooks.locate(&note.id).unwrap(), Some("job".to_string()));
+
+        assert_eq!(notebooks.move_note(&note.id, "archive").unwrap(), "job");
+        assert_eq!(notebooks.locate(&note.id).unwrap(), Some("archive".to_string()));
+
+        assert!(matches!(notebooks.delete("archive", false), Err(NoteError::InvalidInput(_))));
+        assert_eq!(notebooks.delete("archive", true).unwrap(), 1);
+        assert!(!notebooks.exists("archive"));
+    }
+}
+
+#[cfg(test)]
+mod attachment_tests {
+    use note_taking_app::attachment::AttachmentStore;
+    use note_taking_app::export;
+    use note_taking_app::note::Note;
+    use note_taking_app::storage::FileStorage;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_attachments_are_deduplicated() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = AttachmentStore::new(temp_dir.path().join("attachments")).unwrap();
+        let source = temp_dir.path().join("diagram.png");
+        fs::write(&source, b"not really a png").unwrap();
+
+        let first = store.store_file(&source, None).unwrap();
+        let second = store.store_file(&source, Some("copy.png")).unwrap();
+
+        assert_eq!(first.name, "diagram.png");
+        assert_eq!(first.mime, "image/png");
+        assert_eq!(first.size, 16);
+        assert_eq!(first.hash, second.hash);
+        assert_eq!(store.list_hashes().unwrap(), vec![first.hash.clone()]);
+        assert_eq!(store.read(&second).unwrap(), b"not really a png");
+    }
+
+    #[test]
+    fn test_note_attachment_lookup() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = AttachmentStore::new(temp_dir.path()).unwrap();
+        let mut note = Note::new("Spec".to_string(), String::new());
+
+        note.add_attachment(store.store_bytes(b"v1", "spec.pdf", "application/pdf").unwrap());
+        note.add_attachment(store.store_bytes(b"v2", "spec.pdf", "application/pdf").unwrap());
+        assert_eq!(note.attachments.len(), 1);
+
+        let hash = note.attachments[0].hash.clone();
+        assert_eq!(note.find_attachment(&hash[..8]).map(|a| a.name.as_str()), Some("spec.pdf"));
+        assert!(note.remove_attachment("spec.pdf").is_some());
+        assert!(note.attachments.is_empty());
+    }
+
+    #[test]
+    fn test_notes_without_attachments_field_still_load() {
+        let json = r#"{
+            "id": "3f2c1a9e-8b7d-4c6e-9f0a-1b2c3d4e5f60",
+            "title": "Old note",
+            "content": "",
+            "tags": [],
+            "created_at": "2024-01-01T00:00:00Z",
+            "updated_at": "2024-01-01T00:00:00Z",
+            "is_archived": false,
+            "metadata": {}
+        }"#;
+
+        let note: Note = serde_json::from_str(json).unwrap();
+        assert!(note.attachments.is_empty());
+    }
+
+    #[test]
+    fn test_json_bundle_roundtrip_includes_attachments() {
+        let source_dir = TempDir::new().unwrap();
+        let source_attachments = AttachmentStore::new(source_dir.path().join("attachments")).unwrap();
+        let mut note = Note::new("Invoice".to_string(), String::new());
+        note.add_attachment(source_attachments.store_bytes(b"%PDF-1.7", "invoice.pdf", "application/pdf").unwrap());
+
+        let bundle = export::build_json_bundle(vec![note.clone()], &source_attachments).unwrap();
+        let json = serde_json::to_string(&bundle).unwrap();
+
+        let target_dir = TempDir::new().unwrap();
+        let storage = FileStorage::new(target_dir.path().join("notes").to_str().unwrap()).unwrap();
+        let target_attachments = AttachmentStore::new(target_dir.path().join("attachments")).unwrap();
+        let restored: export::JsonBundle = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(export::restore_json_bundle(&restored, &storage, &target_attachments).unwrap(), 1);
+        let loaded = storage.load_note(&note.id).unwrap();
+        assert_eq!(target_attachments.read(&loaded.attachments[0]).unwrap(), b"%PDF-1.7");
+    }
+
+    #[test]
+    fn test_attachment_names_with_paths_are_rejected() {
+        let temp_dir = TempDir::new().unwrap();
+        let attachments = AttachmentStore::new(temp_dir.path().join("attachments")).unwrap();
+        let storage = FileStorage::new(temp_dir.path().join("notes").to_str().unwrap()).unwrap();
+        let mut note = Note::new("Dotfiles".to_string(), String::new());
+        let mut attachment = attachments.store_bytes(b"rm -rf ~", "bashrc", "text/plain").unwrap();
+        attachment.name = "../../.bashrc".to_string();
+        note.attachments.push(attachment);
+
+        let bundle = export::build_json_bundle(vec![note.clone()], &attachments).unwrap();
+        assert!(export::restore_json_bundle(&bundle, &storage, &attachments).is_err());
+        assert!(storage.load_note(&note.id).is_err());
+        assert!(storage.save_note(&note).is_err());
+
+        // A note file edited by hand does not load either
+        let json = serde_json::to_string(&note).unwrap();
+        fs::write(storage.storage_dir().join(format!("{}.json", note.id)), json).unwrap();
+        assert!(storage.load_note(&note.id).is_err());
+    }
+}
+
+#[cfg(test)]
+mod encryption_tests {
+    use note_taking_app::crypto::{self, KdfParams, Vault};
+    use note_taking_app::error::NoteError;
+    use note_taking_app::note::Note;
+    use note_taking_app::storage::FileStorage;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn cheap_kdf() -> KdfParams {
+        KdfParams::generate_with_cost(64, 1, 1).unwrap()
+    }
+
+    #[test]
+    fn test_encrypted_store_roundtrip_without_plaintext_on_disk() {
+        let temp_dir = TempDir::new().unwrap();
+        let key = Vault::init(temp_dir.path(), "correct horse", cheap_kdf()).unwrap();
+        let storage = FileStorage::new(temp_dir.path().to_str().unwrap()).unwrap().with_key(key);
+        let note = Note::new("Salary negotiation".to_string(), "Ask for 10%".to_string());
+
+        storage.save_note(&note).unwrap();
+
+        let on_disk = fs::read_to_string(temp_dir.path().join(format!("{}.json", note.id))).unwrap();
+        assert!(!on_disk.contains("Salary"));
+        assert!(!on_disk.contains("Ask for 10%"));
+        assert_eq!(storage.load_note(&note.id).unwrap(), note);
+        assert_eq!(storage.list_notes().unwrap(), vec![note]);
+    }
+
+    #[test]
+    fn test_wrong_passphrase_and_locked_vault() {
+        let temp_dir = TempDir::new().unwrap();
+        let key = Vault::init(temp_dir.path(), "correct horse", cheap_kdf()).unwrap();
+        let storage = FileStorage::new(temp_dir.path().to_str().unwrap()).unwrap().with_key(key);
+        let note = Note::new("Secret".to_string(), String::new());
+        storage.save_note(&note).unwrap();
+
+        let vault = Vault::load(temp_dir.path()).unwrap();
+        assert!(matches!(vault.unlock("battery staple"), Err(NoteError::WrongPassphrase)));
+        assert!(vault.unlock("correct horse").is_ok());
+
+        let without_key = FileStorage::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let err = NoteError::from(without_key.load_note(&note.id).unwrap_err());
+        assert!(matches!(err, NoteError::VaultLocked(_)));
+        assert!(matches!(NoteError::from(without_key.list_notes().unwrap_err()), NoteError::VaultLocked(_)));
+    }
+
+    #[test]
+    fn test_tampered_ciphertext_is_reported_as_corrupted() {
+        let temp_dir = TempDir::new().unwrap();
+        let key = Vault::init(temp_dir.path(), "correct horse", cheap_kdf()).unwrap();
+        let storage = FileStorage::new(temp_dir.path().to_str().unwrap()).unwrap().with_key(key);
+        let first = Note::new("First".to_string(), String::new());
+        let second = Note::new("Second".to_string(), String::new());
+        storage.save_note(&first).unwrap();
+        storage.save_note(&second).unwrap();
+
+        // Swapping files between ids must not go unnoticed
+        fs::copy(
+            temp_dir.path().join(format!("{}.json", first.id)),
+            temp_dir.path().join(format!("{}.json", second.id)),
+        ).unwrap();
+
+        let err = NoteError::from(storage.load_note(&second.id).unwrap_err());
+        assert!(matches!(err, NoteError::CorruptedCiphertext(_)));
+    }
+
+    #[test]
+    fn test_per_note_lock() {
+        let kdf = cheap_kdf();
+        let key = kdf.derive_key("note secret").unwrap();
+        let mut note = Note::new("Diary".to_string(), "Dear diary".to_string());
+
+        crypto::lock_note(&mut note, &key, kdf).unwrap();
+        assert!(note.is_locked());
+        assert!(note.content.is_empty());
+
+        let locked = note.locked.clone().unwrap();
+        assert!(matches!(locked.derive_key("wrong"), Err(NoteError::WrongPassphrase)));
+        assert_eq!(crypto::reveal_note(&note, &locked.derive_key("note secret").unwrap()).unwrap(), "Dear diary");
+
+        crypto::unlock_note(&mut note, &key).unwrap();
+        assert!(!note.is_locked());
+        assert_eq!(note.content, "Dear diary");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_agent_caches_keys() {
+        use note_taking_app::agent::{self, AgentClient};
+        use std::time::Duration;
+
+        let temp_dir = TempDir::new().unwrap();
+        let socket = temp_dir.path().join("agent").join("agent.sock");
+        let server_socket = socket.clone();
+        std::thread::spawn(move || agent::run_agent(&server_socket));
+
+        let client = AgentClient::new(&socket);
+        for _ in 0..50 {
+            if client.is_running() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        let key = cheap_kdf().derive_key("pw").unwrap();
+        client.put("vault:test", &key, Duration::from_secs(60)).unwrap();
+        assert_eq!(client.get("vault:test").unwrap().as_bytes(), key.as_bytes());
+        assert!(client.get("vault:other").is_none());
+
+        client.forget(None).unwrap();
+        assert!(client.get("vault:test").is_none());
+
+        // A timeout past what `Instant` can hold means the key never expires
+        client.put("vault:forever", &key, Duration::from_secs(u64::MAX)).unwrap();
+        assert!(client.get("vault:forever").is_some());
+        client.forget(None).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_agent_socket_must_be_private() {
+        use note_taking_app::agent::{self, AgentClient};
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let shared = temp_dir.path().join("shared");
+        std::fs::create_dir(&shared).unwrap();
+        std::fs::set_permissions(&shared, std::fs::Permissions::from_mode(0o777)).unwrap();
+        let socket = shared.join("agent.sock");
+        assert!(matches!(agent::run_agent(&socket), Err(NoteError::InvalidInput(_))));
+        assert!(AgentClient::new(&socket).ensure_running().is_err());
+
+        // A missing directory is created closed to other users
+        let socket = temp_dir.path().join("fresh").join("agent.sock");
+        let server_socket = socket.clone();
+        std::thread::spawn(move || agent::run_agent(&server_socket));
+        let client = AgentClient::new(&socket);
+        for _ in 0..50 {
+            if client.is_running() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        assert!(client.is_running());
+        let mode = std::fs::metadata(temp_dir.path().join("fresh")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+}
+
+#[cfg(test)]
+mod note_id_tests {
+    use note_taking_app::error::NoteError;
+    use note_taking_app::note::{Note, NoteId};
+    use note_taking_app::storage::FileStorage;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_note_id_accepts_uuids() {
+        let id = NoteId::parse("3F2C1A9E-8B7D-4C6E-9F0A-1B2C3D4E5F60").unwrap();
+        assert_eq!(id.as_str(), "3f2c1a9e-8b7d-4c6e-9f0a-1b2c3d4e5f60");
+
+        let generated = NoteId::new();
+        assert_eq!(NoteId::parse(generated.as_str()).unwrap(), generated);
+    }
+
+    #[test]
+    fn test_note_id_rejects_hostile_input() {
+        let hostile = [
+            "",
+            "../../etc/passwd",
+            "../3f2c1a9e-8b7d-4c6e-9f0a-1b2c3d4e5f60",
+            "3f2c1a9e-8b7d-4c6e-9f0a-1b2c3d4e5f60/../../x",
+            "/etc/passwd",
+            "..\\..\\windows\\system32",
+            "3f2c1a9e-8b7d-4c6e-9f0a-1b2c3d4e5f60\0",
+            "{3f2c1a9e-8b7d-4c6e-9f0a-1b2c3d4e5f60}",
+            "3f2c1a9e8b7d4c6e9f0a1b2c3d4e5f60",
+            "urn:uuid:3f2c1a9e-8b7d-4c6e-9f0a-1b2c3d4e5f60",
+        ];
+
+        for input in hostile {
+            assert!(
+                matches!(NoteId::parse(input), Err(NoteError::InvalidInput(_))),
+                "accepted hostile id {:?}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_note_files_with_hostile_ids_are_rejected() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::new(temp_dir.path().join("notes").to_str().unwrap()).unwrap();
+        let mut json = serde_json::to_value(Note::new("Evil".to_string(), String::new())).unwrap();
+        json["id"] = serde_json::Value::String("../../escaped".to_string());
+
+        assert!(serde_json::from_value::<Note>(json.clone()).is_err());
+
+        // A planted file is skipped instead of being loaded and re-saved elsewhere
+        fs::write(temp_dir.path().join("notes").join("not-an-id.json"), json.to_string()).unwrap();
+        assert!(storage.list_notes().unwrap().is_empty());
+    }
+}
+
+#[cfg(test)]
+mod concurrency_tests {
+    use note_taking_app::error::NoteError;
+    use note_taking_app::note::Note;
+    use note_taking_app::storage::FileStorage;
+    use std::sync::Arc;
+    use std::thread;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_stale_update_is_rejected() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let note = Note::new("Shared".to_string(), "v0".to_string());
+        storage.save_note(&note).unwrap();
+
+        let mut first = storage.load_note(&note.id).unwrap();
+        let mut second = storage.load_note(&note.id).unwrap();
+
+        first.content = "from first".to_string();
+        storage.update_note(&mut first).unwrap();
+        assert_eq!(first.revision, 1);
+
+        second.content = "from second".to_string();
+        let err = NoteError::from(storage.update_note(&mut second).unwrap_err());
+        assert!(matches!(err, NoteError::Conflict(_)));
+        assert_eq!(second.revision, 0);
+        assert_eq!(storage.load_note(&note.id).unwrap().content, "from first");
+    }
+
+    #[test]
+    fn test_concurrent_writers_do_not_lose_updates() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().to_str().unwrap().to_string();
+        let note = Note::new("Counter".to_string(), "0".to_string());
+        FileStorage::new(&path).unwrap().save_note(&note).unwrap();
+        let id = Arc::new(note.id.clone());
+
+        let workers: Vec<_> = (0..8)
+            .map(|_| {
+                let path = path.clone();
+                let id = Arc::clone(&id);
+                thread::spawn(move || {
+                    let storage = FileStorage::new(&path).unwrap();
+                    loop {
+                        let mut note = storage.load_note(&id).unwrap();
+                        let count: u32 = note.content.parse().unwrap();
+                        note.content = (count + 1).to_string();
+                        match storage.update_note(&mut note).map_err(NoteError::from) {
+                            Ok(()) => break,
+                            Err(NoteError::Conflict(_)) => continue,
+                            Err(e) => panic!("unexpected error: {}", e),
+                        }
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        let note = FileStorage::new(&path).unwrap().load_note(&id).unwrap();
+        assert_eq!(note.content, "8");
+        assert_eq!(note.revision, 8);
+    }
+}
+
+#[cfg(test)]
+mod schema_tests {
+    use note_taking_app::schema::{self, StoreManifest, CURRENT_SCHEMA_VERSION, LEGACY_SCHEMA_VERSION, STORE_MANIFEST};
+    use note_taking_app::storage::FileStorage;
+    use note_taking_app::note::{Note, NoteId};
+    use note_taking_app::NoteError;
+    use serde_json::{json, Value};
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn legacy_note(id: &str) -> Value {
+        json!({
+            "id": id,
+            "title": "Old note",
+            "content": "written before versioning",
+            "tags": ["old"],
+            "created_at": "2023-01-01T00:00:00Z",
+            "updated_at": "2023-01-01T00:00:00Z",
+            "is_archived": false,
+            "metadata": {}
+        })
+    }
+
+    #[test]
+    fn test_legacy_document_is_migrated() {
+        let id = NoteId::new();
+        let mut document = legacy_note(id.as_str());
+
+        assert_eq!(schema::migrate_document(&mut document).unwrap(), LEGACY_SCHEMA_VERSION);
+        assert_eq!(document["schema_version"], json!(CURRENT_SCHEMA_VERSION));
+        assert_eq!(document["revision"], json!(0));
+        assert_eq!(document["attachments"], json!([]));
+    }
+
+    #[test]
+    fn test_saved_notes_carry_current_version() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let note = Note::new("Fresh".to_string(), "content".to_string());
+        storage.save_note(&note).unwrap();
+
+        let raw: Value = serde_json::from_str(
+            &fs::read_to_string(temp_dir.path().join(format!("{}.json", note.id))).unwrap(),
+        ).unwrap();
+        assert_eq!(raw["schema_version"], json!(CURRENT_SCHEMA_VERSION));
+        assert_eq!(storage.list_notes().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_newer_note_is_refused() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let id = NoteId::new();
+        let mut document = legacy_note(id.as_str());
+        document["schema_version"] = json!(CURRENT_SCHEMA_VERSION + 1);
+        fs::write(temp_dir.path().join(format!("{}.json", id)), document.to_string()).unwrap();
+
+        let err = NoteError::from(storage.load_note(&id).unwrap_err());
+        assert!(matches!(err, NoteError::IncompatibleSchema(_)));
+        assert!(err.to_string().contains("upgrade notes"));
+    }
+
+    #[test]
+    fn test_newer_store_is_refused() {
+        let temp_dir = TempDir::new().unwrap();
+        StoreManifest {
+            schema_version: CURRENT_SCHEMA_VERSION + 1,
+            created_at: chrono::Utc::now(),
+            migrated_at: None,
+        }
+        .save(temp_dir.path())
+        .unwrap();
+
+        let err = NoteError::from(FileStorage::new(temp_dir.path().to_str().unwrap()).err().unwrap());
+        assert!(matches!(err, NoteError::IncompatibleSchema(_)));
+    }
+
+    #[test]
+    fn test_migrate_rewrites_legacy_store() {
+        let temp_dir = TempDir::new().unwrap();
+        let id = NoteId::new();
+        let path = temp_dir.path().join(format!("{}.json", id));
+        fs::write(&path, legacy_note(id.as_str()).to_string()).unwrap();
+
+        let storage = FileStorage::new(temp_dir.path().to_str().unwrap()).unwrap();
+        assert_eq!(storage.manifest().unwrap().schema_version, LEGACY_SCHEMA_VERSION);
+        assert_eq!(storage.load_note(&id).unwrap().title, "Old note");
+
+        assert_eq!(storage.migrate(true).unwrap(), vec![(id.clone(), LEGACY_SCHEMA_VERSION)]);
+        assert!(!fs::read_to_string(&path).unwrap().contains("schema_version"));
+
+        assert_eq!(storage.migrate(false).unwrap().len(), 1);
+        assert!(fs::read_to_string(&path).unwrap().contains("schema_version"));
+        let manifest = StoreManifest::load(temp_dir.path()).unwrap().unwrap();
+        assert_eq!(manifest.schema_version, CURRENT_SCHEMA_VERSION);
+        assert!(manifest.migrated_at.is_some());
+        assert!(storage.migrate(false).unwrap().is_empty());
+        assert!(temp_dir.path().join(STORE_MANIFEST).is_file());
+    }
+}
+
+#[cfg(test)]
+mod doctor_tests {
+    use note_taking_app::attachment::AttachmentStore;
+    use note_taking_app::doctor::{self, IssueKind, QUARANTINE_DIR};
+    use note_taking_app::note::{Note, NoteId};
+    use note_taking_app::notebook::NotebookStore;
+    use chrono::Duration;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn setup() -> (TempDir, NotebookStore, AttachmentStore) {
+        let temp_dir = TempDir::new().unwrap();
+        let notebooks = NotebookStore::new(temp_dir.path().join("notes"), "personal").unwrap();
+        notebooks.open_or_create("personal").unwrap();
+        let attachments = AttachmentStore::new(temp_dir.path().join("attachments")).unwrap();
+        (temp_dir, notebooks, attachments)
+    }
+
+    fn kinds(report: &doctor::DoctorReport) -> Vec<&IssueKind> {
+        report.issues.iter().map(|issue| &issue.kind).collect()
+    }
+
+    #[test]
+    fn test_healthy_store() {
+        let (_temp_dir, notebooks, attachments) = setup();
+        let storage = notebooks.open("personal").unwrap();
+        let mut note = Note::new("Fine".to_string(), "All good".to_string());
+        note.add_attachment(attachments.store_bytes(b"data", "data.txt", "text/plain").unwrap());
+        storage.save_note(&note).unwrap();
+
+        let report = doctor::check(&notebooks, &attachments, false).unwrap();
+        assert!(report.is_healthy());
+        assert_eq!(report.notes_checked, 1);
+    }
+
+    #[test]
+    fn test_reports_without_fixing() {
+        let (_temp_dir, notebooks, attachments) = setup();
+        let storage = notebooks.open("personal").unwrap();
+        let garbage = storage.storage_dir().join(format!("{}.json", NoteId::new()));
+        fs::write(&garbage, "{ not json").unwrap();
+
+        let mut note = Note::new("Broken".to_string(), "x".to_string());
+        note.tags = vec!["bad tag".to_string(), "ok".to_string()];
+        note.updated_at = note.created_at - Duration::hours(1);
+        storage.save_note(&note).unwrap();
+        attachments.store_bytes(b"orphan", "orphan.txt", "text/plain").unwrap();
+
+        let report = doctor::check(&notebooks, &attachments, false).unwrap();
+        let kinds = kinds(&report);
+        assert!(kinds.iter().any(|k| matches!(k, IssueKind::Unparsable(_))));
+        assert!(kinds.iter().any(|k| matches!(k, IssueKind::InvalidTag { tag, .. } if tag == "bad tag")));
+        assert!(kinds.contains(&&IssueKind::TimestampsReversed));
+        assert!(kinds.iter().any(|k| matches!(k, IssueKind::OrphanAttachment { .. })));
+        assert_eq!(report.remaining(), report.issues.len());
+        assert!(garbage.exists());
+    }
+
+    #[test]
+    fn test_fix_repairs_and_quarantines() {
+        let (temp_dir, notebooks, attachments) = setup();
+        let storage = notebooks.open("personal").unwrap();
+        let garbage = storage.storage_dir().join(format!("{}.json", NoteId::new()));
+        fs::write(&garbage, "{ not json").unwrap();
+
+        let mut note = Note::new("Repairable".to_string(), "x".to_string());
+        note.tags = vec!["bad tag".to_string()];
+        let gone = attachments.store_bytes(b"gone", "gone.txt", "text/plain").unwrap();
+        attachments.remove(&gone.hash).unwrap();
+        note.add_attachment(gone);
+        note.updated_at = note.created_at - Duration::hours(1);
+        storage.save_note(&note).unwrap();
+
+        let report = doctor::check(&notebooks, &attachments, true).unwrap();
+        assert!(report.issues.iter().filter(|i| !matches!(i.kind, IssueKind::Unparsable(_))).all(|i| i.fixed));
+        assert!(!garbage.exists());
+        assert!(temp_dir.path().join("notes").join(QUARANTINE_DIR).join("personal").join(garbage.file_name().unwrap()).is_file());
+
+        let repaired = storage.load_note(&note.id).unwrap();
+        assert_eq!(repaired.tags, vec!["bad-tag".to_string()]);
+        assert!(repaired.updated_at >= repaired.created_at);
+        assert!(repaired.attachments.is_empty());
+        assert!(doctor::check(&notebooks, &attachments, false).unwrap().is_healthy());
+    }
+
+    #[test]
+    fn test_id_mismatch_and_duplicates() {
+        let (_temp_dir, notebooks, attachments) = setup();
+        let personal = notebooks.open("personal").unwrap();
+        let work = notebooks.create("work").unwrap();
+
+        let note = Note::new("Twice".to_string(), "x".to_string());
+        personal.save_note(&note).unwrap();
+        work.save_note(&note).unwrap();
+
+        let misnamed = Note::new("Misnamed".to_string(), "x".to_string());
+        personal.save_note(&misnamed).unwrap();
+        let wrong_path = personal.storage_dir().join(format!("{}.json", NoteId::new()));
+        fs::rename(personal.storage_dir().join(format!("{}.json", misnamed.id)), &wrong_path).unwrap();
+
+        let report = doctor::check(&notebooks, &attachments, true).unwrap();
+        let duplicate = report.issues.iter().find(|i| matches!(i.kind, IssueKind::DuplicateId { .. })).unwrap();
+        assert!(!duplicate.fixed);
+        let mismatch = report.issues.iter().find(|i| matches!(i.kind, IssueKind::IdMismatch { .. })).unwrap();
+        assert!(mismatch.fixed);
+        assert!(!wrong_path.exists());
+        assert_eq!(personal.load_note(&misnamed.id).unwrap().title, "Misnamed");
+    }
+
+    #[test]
+    fn test_stale_index_and_long_multibyte_tags() {
+        let (_temp_dir, notebooks, attachments) = setup();
+        let storage = notebooks.open("personal").unwrap();
+        let mut note = Note::new("Accents".to_string(), "x".to_string());
+        note.tags = vec!["é".repeat(30)];
+        storage.save_note(&note).unwrap();
+        let removed = Note::new("Removed by hand".to_string(), "x".to_string());
+        storage.save_note(&removed).unwrap();
+        fs::remove_file(storage.storage_dir().join(format!("{}.json", removed.id))).unwrap();
+
+        let report = doctor::check(&notebooks, &attachments, false).unwrap();
+        assert!(kinds(&report).contains(&&IssueKind::StaleIndex));
+
+        let report = doctor::check(&notebooks, &attachments, true).unwrap();
+        assert!(report.issues.iter().all(|issue| issue.fixed));
+        assert_eq!(storage.load_note(&note.id).unwrap().tags, vec!["é".repeat(25)]);
+        assert!(doctor::check(&notebooks, &attachments, false).unwrap().is_healthy());
+    }
+}
+
+#[cfg(test)]
+mod backup_tests {
+    use flate2::read::GzDecoder;
+    use note_taking_app::attachment::AttachmentStore;
+    use note_taking_app::backup::{BackupSources, BackupStore, SnapshotKind};
+    use note_taking_app::config::BackupConfig;
+    use note_taking_app::crypto::{KdfParams, Vault};
+    use note_taking_app::note::Note;
+    use note_taking_app::notebook::NotebookStore;
+    use note_taking_app::storage::FileStorage;
+    use std::fs;
+    use std::io::Read;
+    use std::path::{Path, PathBuf};
+    use tempfile::TempDir;
+
+    struct Fixture {
+        _temp_dir: TempDir,
+        sources: BackupSources,
+        notebooks: NotebookStore,
+        attachments: AttachmentStore,
+        backups: BackupStore,
+    }
+
+    fn setup() -> Fixture {
+        let temp_dir = TempDir::new().unwrap();
+        let sources = BackupSources {
+            notes_dir: temp_dir.path().join("notes"),
+            attachments_dir: temp_dir.path().join("attachments"),
+            templates_dir: temp_dir.path().join("templates"),
+            config_file: Some(temp_dir.path().join("config.toml")),
+        };
+        fs::write(sources.config_file.as_ref().unwrap(), "[general]\n").unwrap();
+        let notebooks = NotebookStore::new(&sources.notes_dir, "personal").unwrap();
+        let attachments = AttachmentStore::new(&sources.attachments_dir).unwrap();
+        let backups = BackupStore::new(temp_dir.path().join("backups")).unwrap();
+        Fixture { _temp_dir: temp_dir, sources, notebooks, attachments, backups }
+    }
+
+    #[test]
+    fn test_full_restore_rolls_back() {
+        let f = setup();
+        let storage = f.notebooks.open_or_create("personal").unwrap();
+        let kept = Note::new("Kept".to_string(), "before".to_string());
+        storage.save_note(&kept).unwrap();
+
+        let snapshot = f.backups.create(&f.sources, SnapshotKind::Manual).unwrap();
+        assert_eq!(f.backups.list().unwrap(), vec![snapshot.clone()]);
+
+        let imported = Note::new("Bad import".to_string(), "oops".to_string());
+        storage.save_note(&imported).unwrap();
+        fs::write(f.sources.config_file.as_ref().unwrap(), "[general]\ndefault_editor = \"vim\"\n").unwrap();
+        fs::write(f.sources.notes_dir.join("sync-log.json"), "after").unwrap();
+        fs::write(storage.storage_dir().join("sync.json"), "after").unwrap();
+
+        let safety = f.backups.restore(&snapshot.name, &f.sources).unwrap();
+        assert_eq!(safety.kind, SnapshotKind::PreRestore);
+
+        let storage = f.notebooks.open("personal").unwrap();
+        let notes = storage.list_notes().unwrap();
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].id, kept.id);
+        // Sync state and config are not rolled back with the notes
+        assert_eq!(fs::read_to_string(f.sources.notes_dir.join("sync-log.json")).unwrap(), "after");
+        assert_eq!(fs::read_to_string(storage.storage_dir().join("sync.json")).unwrap(), "after");
+        assert!(fs::read_to_string(f.sources.config_file.as_ref().unwrap()).unwrap().contains("vim"));
+        assert_eq!(f.backups.list().unwrap().len(), 2);
+
+        // The copies staged next to the live directories are gone once swapped in
+        let leftovers: Vec<_> = fs::read_dir(f._temp_dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.starts_with('.'))
+            .collect();
+        assert!(leftovers.is_empty(), "{:?}", leftovers);
+    }
+
+    #[test]
+    fn test_restore_single_note_with_attachment() {
+        let f = setup();
+        let storage = f.notebooks.open_or_create("personal").unwrap();
+        let mut note = Note::new("Report".to_string(), "original".to_string());
+        let attachment = f.attachments.store_bytes(b"figure", "figure.png", "image/png").unwrap();
+        note.add_attachment(attachment.clone());
+        storage.save_note(&note).unwrap();
+        let other = Note::new("Other".to_string(), "unchanged".to_string());
+        storage.save_note(&other).unwrap();
+
+        let snapshot = f.backups.create(&f.sources, SnapshotKind::Manual).unwrap();
+
+        storage.delete_note(&note.id).unwrap();
+        f.attachments.remove(&attachment.hash).unwrap();
+        let mut changed = storage.load_note(&other.id).unwrap();
+        changed.content = "edited later".to_string();
+        storage.update_note(&mut changed).unwrap();
+
+        let notebook = f.backups.restore_note(snapshot.name.trim_end_matches(".tar.gz"), &note.id, &f.notebooks, &f.attachments, "personal").unwrap();
+        assert_eq!(notebook, "personal");
+        assert_eq!(storage.load_note(&note.id).unwrap().content, "original");
+        assert!(f.attachments.contains(&attachment.hash));
+        assert_eq!(storage.load_note(&other.id).unwrap().content, "edited later");
+    }
+
+    /// Files under `dir` whose bytes contain `needle`, looking inside gzip archives
+    fn plaintext_copies(dir: &Path, needle: &[u8]) -> Vec<PathBuf> {
+        let mut found = Vec::new();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                found.extend(plaintext_copies(&path, needle));
+                continue;
+            }
+            let mut data = fs::read(&path).unwrap();
+            if path.to_string_lossy().ends_with(".tar.gz") {
+                let mut unpacked = Vec::new();
+                GzDecoder::new(data.as_slice()).read_to_end(&mut unpacked).unwrap();
+                data = unpacked;
+            }
+            if data.windows(needle.len()).any(|window| window == needle) {
+                found.push(path);
+            }
+        }
+        found
+    }
+
+    #[test]
+    fn test_encrypting_a_store_leaves_no_plaintext_copies() {
+        let f = setup();
+        let storage = f.notebooks.open_or_create("personal").unwrap();
+        let mut note = Note::new("Salary negotiation".to_string(), "Ask for more".to_string());
+        let attachment = f.attachments.store_bytes(b"payslip figures", "payslip.txt", "text/plain").unwrap();
+        note.add_attachment(attachment.clone());
+        storage.save_note(&note).unwrap();
+        FileStorage::new(storage.storage_dir().join(".sync-base/desk").to_str().unwrap())
+            .unwrap()
+            .save_note(&note)
+            .unwrap();
+        let quarantine = f.sources.notes_dir.join(".quarantine/personal");
+        fs::create_dir_all(&quarantine).unwrap();
+        fs::write(quarantine.join("broken.json"), "{\"title\": \"Salary negotiation\"").unwrap();
+        let snapshot = f.backups.create(&f.sources, SnapshotKind::Manual).unwrap();
+        assert!(!plaintext_copies(f._temp_dir.path(), b"Salary").is_empty());
+
+        let key = Vault::init(&f.sources.notes_dir, "correct horse", KdfParams::generate_with_cost(64, 1, 1).unwrap()).unwrap();
+        let notebooks = f.notebooks.clone().with_key(key.clone());
+        let attachments = AttachmentStore::new(&f.sources.attachments_dir).unwrap().with_key(key);
+        assert_eq!(notebooks.encrypt_all().unwrap(), 1);
+        assert_eq!(attachments.encrypt_all().unwrap(), 1);
+        assert_eq!(f.backups.encrypt_snapshots(&notebooks, "personal").unwrap(), 1);
+
+        for needle in [&b"Salary"[..], b"Ask for more", b"payslip figures"] {
+            assert_eq!(plaintext_copies(f._temp_dir.path(), needle), Vec::<PathBuf>::new());
+        }
+        assert!(!quarantine.exists());
+        assert!(f.attachments.read(&attachment).is_err());
+        assert_eq!(attachments.read(&attachment).unwrap(), b"payslip figures");
+
+        notebooks.open("personal").unwrap().delete_note(&note.id).unwrap();
+        attachments.remove(&attachment.hash).unwrap();
+        f.backups.restore_note(&snapshot.name, &note.id, &notebooks, &attachments, "personal").unwrap();
+        assert_eq!(notebooks.open("personal").unwrap().load_note(&note.id).unwrap().content, "Ask for more");
+        assert_eq!(attachments.read(&attachment).unwrap(), b"payslip figures");
+    }
+
+    #[test]
+    fn test_auto_backup_once_per_day_and_prune() {
+        let f = setup();
+        let policy = BackupConfig {
+            dir: f.backups.dir().to_path_buf(),
+            auto: true,
+            keep_daily: 2,
+            keep_weekly: 0,
+        };
+
+        assert!(f.backups.auto_backup(&f.sources, &policy).unwrap().is_some());
+        assert!(f.backups.auto_backup(&f.sources, &policy).unwrap().is_none());
+
+        for day in ["20200101", "20200102", "20200103"] {
+            fs::write(f.backups.dir().join(format!("notes-{}-120000000-auto.tar.gz", day)), b"").unwrap();
+        }
+        fs::write(f.backups.dir().join("notes-20190101-120000000-manual.tar.gz"), b"").unwrap();
+
+        let removed = f.backups.prune(&policy).unwrap();
+        assert_eq!(removed.len(), 2);
+        let remaining: Vec<_> = f.backups.list().unwrap().into_iter().map(|s| (s.kind, s.name)).collect();
+        assert_eq!(remaining.len(), 3);
+        assert!(remaining.iter().any(|(kind, name)| *kind == SnapshotKind::Auto && name.contains("20200103")));
+        assert!(remaining.iter().any(|(kind, _)| *kind == SnapshotKind::Manual));
+    }
+}
+
+#[cfg(test)]
+mod git_store_tests {
+    use note_taking_app::error::NoteError;
+    use note_taking_app::git::GitStore;
+    use note_taking_app::note::Note;
+    use note_taking_app::notebook::NotebookStore;
+    use std::path::Path;
+    use std::process::Command;
+    use tempfile::TempDir;
+
+    fn bare_remote(dir: &Path) -> String {
+        let remote = dir.join("remote.git");
+        let status = Command::new("git").args(["init", "--quiet", "--bare"]).arg(&remote).status().unwrap();
+        assert!(status.success());
+        remote.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_commits_and_note_log() {
+        let temp_dir = TempDir::new().unwrap();
+        let git = GitStore::open(temp_dir.path().join("notes"), "main").unwrap();
+        let notebooks = NotebookStore::new(git.dir(), "personal").unwrap();
+        let storage = notebooks.open_or_create("personal").unwrap();
+
+        let mut note = Note::new("Tracked".to_string(), "v1".to_string());
+        storage.save_note(&note).unwrap();
+        assert!(git.commit_all("Create note 'Tracked'").unwrap());
+        assert!(!git.commit_all("Nothing changed").unwrap());
+
+        note.content = "v2".to_string();
+        storage.update_note(&mut note).unwrap();
+        git.commit_all("Update note 'Tracked'").unwrap();
+
+        let log = git.log(&note.id).unwrap();
+        let subjects: Vec<&str> = log.iter().map(|c| c.subject.as_str()).collect();
+        assert_eq!(subjects, vec!["Update note 'Tracked'", "Create note 'Tracked'"]);
+    }
+
+    #[test]
+    fn test_reopening_commits_nothing() {
+        let temp_dir = TempDir::new().unwrap();
+        let git = GitStore::open(temp_dir.path().join("notes"), "main").unwrap();
+        let storage = NotebookStore::new(git.dir(), "personal").unwrap().open_or_create("personal").unwrap();
+        let note = Note::new("Edited elsewhere".to_string(), String::new());
+        storage.save_note(&note).unwrap();
+
+        let git = GitStore::open(git.dir(), "main").unwrap();
+        assert!(git.log(&note.id).unwrap().is_empty());
+        assert!(git.commit_all("Create note 'Edited elsewhere'").unwrap());
+        assert_eq!(git.log(&note.id).unwrap()[0].subject, "Create note 'Edited elsewhere'");
+    }
+
+    #[test]
+    fn test_sync_through_bare_remote() {
+        let temp_dir = TempDir::new().unwrap();
+        let remote = bare_remote(temp_dir.path());
+
+        let alice = GitStore::open(temp_dir.path().join("alice"), "main").unwrap();
+        let alice_notes = NotebookStore::new(alice.dir(), "personal").unwrap().open_or_create("personal").unwrap();
+        let note = Note::new("Shared".to_string(), "from alice".to_string());
+        alice_notes.save_note(&note).unwrap();
+        alice.commit_all("Create note 'Shared'").unwrap();
+        assert_eq!(alice.sync(&remote, "main").unwrap().pushed, 2);
+
+        let bob = GitStore::open(temp_dir.path().join("bob"), "main").unwrap();
+        let report = bob.sync(&remote, "main").unwrap();
+        assert!(report.pulled >= 1);
+        assert_eq!(report.pushed, 0);
+        let bob_notes = NotebookStore::new(bob.dir(), "personal").unwrap().open("personal").unwrap();
+        let mut shared = bob_notes.load_note(&note.id).unwrap();
+        assert_eq!(shared.content, "from alice");
+
+        shared.content = "edited by bob".to_string();
+        bob_notes.update_note(&mut shared).unwrap();
+        bob.commit_all("Update note 'Shared'").unwrap();
+        assert_eq!(bob.sync(&remote, "main").unwrap().pushed, 1);
+
+        assert_eq!(alice.sync(&remote, "main").unwrap().pulled, 1);
+        assert_eq!(alice_notes.load_note(&note.id).unwrap().content, "edited by bob");
+    }
+
+    #[test]
+    fn test_sync_conflict_is_rolled_back() {
+        let temp_dir = TempDir::new().unwrap();
+        let remote = bare_remote(temp_dir.path());
+
+        let alice = GitStore::open(temp_dir.path().join("alice"), "main").unwrap();
+        let alice_notes = NotebookStore::new(alice.dir(), "personal").unwrap().open_or_create("personal").unwrap();
+        let mut note = Note::new("Contested".to_string(), "base".to_string());
+        alice_notes.save_note(&note).unwrap();
+        alice.commit_all("Create note").unwrap();
+        alice.sync(&remote, "main").unwrap();
+
+        let bob = GitStore::open(temp_dir.path().join("bob"), "main").unwrap();
+        bob.sync(&remote, "main").unwrap();
+        let bob_notes = NotebookStore::new(bob.dir(), "personal").unwrap().open("personal").unwrap();
+
+        note.content = "alice's version".to_string();
+        alice_notes.update_note(&mut note).unwrap();
+        alice.commit_all("Alice edits").unwrap();
+        alice.sync(&remote, "main").unwrap();
+
+        let mut theirs = bob_notes.load_note(&note.id).unwrap();
+        theirs.content = "bob's version".to_string();
+        bob_notes.update_note(&mut theirs).unwrap();
+        bob.commit_all("Bob edits").unwrap();
+
+        let err = bob.sync(&remote, "main").unwrap_err();
+        assert!(matches!(err, NoteError::Conflict(_)));
+        assert_eq!(bob_notes.load_note(&note.id).unwrap().content, "bob's version");
+        assert!(!bob.dir().join(".git").join("rebase-merge").exists());
+    }
+}
+
+#[cfg(test)]
+mod markdown_storage_tests {
+    use note_taking_app::error::NoteError;
+    use note_taking_app::markdown::{self, ExternalChange, MarkdownStorage};
+    use note_taking_app::note::Note;
+    use note_taking_app::storage::Storage;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_round_trip_as_markdown() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = MarkdownStorage::new(temp_dir.path()).unwrap();
+        let mut note = Note::new("Meeting Notes: Q1!".to_string(), "# Agenda\n\n- budget\n".to_string());
+        note.tags = vec!["work/meetings".to_string()];
+        note.metadata.insert("room".to_string(), "4B".to_string());
+        storage.save_note(&note).unwrap();
+
+        let path = temp_dir.path().join("meeting-notes-q1.md");
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with("---\n"));
+        assert!(text.contains(&format!("id: {}", note.id)));
+        assert!(text.ends_with("---\n# Agenda\n\n- budget\n"));
+
+        assert_eq!(storage.load_note(&note.id).unwrap(), note);
+        assert_eq!(MarkdownStorage::new(temp_dir.path()).unwrap().list_notes().unwrap(), vec![note]);
+    }
+
+    #[test]
+    fn test_title_change_renames_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = MarkdownStorage::new(temp_dir.path()).unwrap();
+        let mut note = Note::new("Draft".to_string(), "text".to_string());
+        storage.save_note(&note).unwrap();
+        storage.save_note(&Note::new("Final plan".to_string(), "taken".to_string())).unwrap();
+
+        note.title = "Final Plan".to_string();
+        storage.update_note(&mut note).unwrap();
+
+        assert!(!temp_dir.path().join("draft.md").exists());
+        assert_eq!(storage.path_of(&note.id).unwrap(), temp_dir.path().join("final-plan-2.md"));
+        assert_eq!(markdown::slugify("  Ünïcode & Spaces  "), "ünïcode-spaces");
+        assert_eq!(markdown::slugify("!!!"), "untitled");
+    }
+
+    #[test]
+    fn test_detects_external_changes() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = MarkdownStorage::new(temp_dir.path()).unwrap();
+        let note = Note::new("Shopping".to_string(), "milk".to_string());
+        storage.save_note(&note).unwrap();
+        let stale = storage.load_note(&note.id).unwrap();
+
+        let path = storage.path_of(&note.id).unwrap();
+        fs::write(temp_dir.path().join("ideas.md"), "# Big Idea\n\nWrite it down.\n").unwrap();
+        let edited = fs::read_to_string(&path).unwrap().replace("milk", "milk and eggs, bought by hand");
+        fs::write(&path, &edited).unwrap();
+
+        let changes = storage.scan().unwrap();
+        assert!(changes.contains(&ExternalChange::Modified { id: note.id.clone(), path: path.clone() }));
+        let added = changes.iter().find_map(|c| match c {
+            ExternalChange::Added { id, .. } => Some(id.clone()),
+            _ => None,
+        }).unwrap();
+        let adopted = storage.load_note(&added).unwrap();
+        assert_eq!(adopted.title, "Big Idea");
+        assert!(fs::read_to_string(temp_dir.path().join("ideas.md")).unwrap().starts_with("---\n"));
+
+        let reloaded = storage.load_note(&note.id).unwrap();
+        assert_eq!(reloaded.content, "milk and eggs, bought by hand");
+        assert_eq!(reloaded.revision, 1);
+        // The edited file is left as written; the new revision lives beside it
+        assert_eq!(fs::read_to_string(&path).unwrap(), edited);
+        assert_eq!(MarkdownStorage::new(temp_dir.path()).unwrap().load_note(&note.id).unwrap().revision, 1);
+
+        let mut stale = stale;
+        stale.content = "overwrite".to_string();
+        let err = NoteError::from(storage.update_note(&mut stale).unwrap_err());
+        assert!(matches!(err, NoteError::Conflict(_)));
+
+        fs::remove_file(&path).unwrap();
+        assert!(storage.scan().unwrap().contains(&ExternalChange::Removed { id: note.id.clone(), path }));
+    }
+
+    #[test]
+    fn test_copied_file_gets_new_id() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = MarkdownStorage::new(temp_dir.path()).unwrap();
+        let note = Note::new("Original".to_string(), "body".to_string());
+        storage.save_note(&note).unwrap();
+        fs::copy(temp_dir.path().join("original.md"), temp_dir.path().join("original-copy.md")).unwrap();
+
+        let notes = storage.list_notes().unwrap();
+        assert_eq!(notes.len(), 2);
+        assert_ne!(notes[0].id, notes[1].id);
+        assert!(notes.iter().any(|n| n.id == note.id));
+    }
+}
+
+#[cfg(test)]
+mod index_tests {
+    use note_taking_app::crypto::{KdfParams, Vault};
+    use note_taking_app::index::{MetadataIndex, INDEX_FILE};
+    use note_taking_app::note::Note;
+    use note_taking_app::storage::FileStorage;
+    use note_taking_app::tags;
+    use serde_json::Value;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn storage(temp_dir: &TempDir) -> FileStorage {
+        FileStorage::new(temp_dir.path().to_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_index_follows_saves_and_deletes() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = storage(&temp_dir);
+        let mut note = Note::new("Groceries".to_string(), "milk, eggs".to_string());
+        note.tags = vec!["home/errands".to_string()];
+        storage.save_note(&note).unwrap();
+        let other = Note::new("Standup".to_string(), "x".repeat(300));
+        storage.save_note(&other).unwrap();
+
+        let index = MetadataIndex::load(temp_dir.path(), None);
+        assert_eq!(index.len(), 2);
+        let summary = index.get(&note.id).unwrap();
+        assert_eq!(summary.title, "Groceries");
+        assert_eq!(summary.size, 10);
+        assert_eq!(summary.preview, "milk, eggs");
+        assert_eq!(index.get(&other.id).unwrap().preview.chars().count(), 103);
+
+        storage.delete_note(&other.id).unwrap();
+        let summaries = storage.list_summaries().unwrap();
+        assert_eq!(summaries.len(), 1);
+        assert!(summaries[0].has_tag_or_descendant("home"));
+        assert_eq!(storage.list_notes().unwrap(), vec![note]);
+    }
+
+    #[test]
+    fn test_outside_changes_are_reindexed() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = storage(&temp_dir);
+        let note = Note::new("Draft".to_string(), "text".to_string());
+        storage.save_note(&note).unwrap();
+        storage.save_note(&Note::new("Gone".to_string(), String::new())).unwrap();
+
+        // Edit one note and remove another without going through the storage
+        let path = temp_dir.path().join(format!("{}.json", note.id));
+        let mut document: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        document["title"] = Value::String("Final version".to_string());
+        fs::write(&path, serde_json::to_string_pretty(&document).unwrap()).unwrap();
+        let gone = storage.list_summaries().unwrap().into_iter().find(|s| s.title == "Gone").unwrap();
+        fs::remove_file(temp_dir.path().join(format!("{}.json", gone.id))).unwrap();
+
+        let summaries = storage.list_summaries().unwrap();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].title, "Final version");
+        assert_eq!(MetadataIndex::load(temp_dir.path(), None).len(), 1);
+    }
+
+    #[test]
+    fn test_rebuild_replaces_damaged_index() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = storage(&temp_dir);
+        for title in ["One", "Two", "Three"] {
+            storage.save_note(&Note::new(title.to_string(), String::new())).unwrap();
+        }
+        fs::write(temp_dir.path().join(INDEX_FILE), "not json").unwrap();
+        assert!(MetadataIndex::load(temp_dir.path(), None).is_empty());
+
+        assert_eq!(storage.rebuild_index().unwrap(), 3);
+        assert_eq!(MetadataIndex::load(temp_dir.path(), None).len(), 3);
+        assert_eq!(storage.list_notes().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_encrypted_store_keeps_index_encrypted() {
+        let temp_dir = TempDir::new().unwrap();
+        let key = Vault::init(temp_dir.path(), "correct horse", KdfParams::generate_with_cost(64, 1, 1).unwrap()).unwrap();
+        let storage = storage(&temp_dir).with_key(key.clone());
+        storage.save_note(&Note::new("Salary negotiation".to_string(), "Ask for 10%".to_string())).unwrap();
+
+        let raw = fs::read_to_string(temp_dir.path().join(INDEX_FILE)).unwrap();
+        assert!(!raw.contains("Salary"));
+        assert!(MetadataIndex::load(temp_dir.path(), None).is_empty());
+        assert_eq!(MetadataIndex::load(temp_dir.path(), Some(&key)).len(), 1);
+        assert_eq!(storage.list_summaries().unwrap()[0].title, "Salary negotiation");
+    }
+
+    #[test]
+    fn test_tag_counts_from_summaries() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = storage(&temp_dir);
+        for tag_list in [vec!["work/clients/acme"], vec!["work", "home"]] {
+            let mut note = Note::new("Tagged".to_string(), String::new());
+            note.tags = tag_list.into_iter().map(String::from).collect();
+            storage.save_note(&note).unwrap();
+        }
+
+        let summaries = storage.list_summaries().unwrap();
+        let counts = tags::count_tags(summaries.iter().map(|s| s.tags.as_slice()));
+        assert_eq!(counts, tags::tag_counts(&storage.list_notes().unwrap()));
+        assert_eq!(counts["work"], 2);
+        assert_eq!(counts["work/clients"], 1);
+    }
+}
+
+#[cfg(test)]
+mod memory_storage_tests {
+    use note_taking_app::error::NoteError;
+    use note_taking_app::memory::MemoryStorage;
+    use note_taking_app::note::{Note, NoteId};
+    use note_taking_app::storage::Storage;
+    use std::io;
+
+    #[test]
+    fn test_behaves_like_file_storage() {
+        let storage = MemoryStorage::new();
+        let mut older = Note::new("Older".to_string(), "first".to_string());
+        older.created_at -= chrono::Duration::hours(1);
+        let newer = Note::new("Newer".to_string(), "second".to_string());
+        storage.save_note(&older).unwrap();
+        storage.save_note(&newer).unwrap();
+
+        assert_eq!(storage.load_note(&newer.id).unwrap(), newer);
+        assert_eq!(storage.list_notes().unwrap(), vec![newer.clone(), older.clone()]);
+        assert_eq!(storage.list_summaries().unwrap()[1].title, "Older");
+
+        let mut stale = storage.load_note(&older.id).unwrap();
+        let mut edited = stale.clone();
+        edited.content = "edited".to_string();
+        storage.update_note(&mut edited).unwrap();
+        assert_eq!(edited.revision, stale.revision + 1);
+        match NoteError::from(storage.update_note(&mut stale).unwrap_err()) {
+            NoteError::Conflict(_) => {}
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+
+        storage.delete_note(&newer.id).unwrap();
+        let missing = storage.load_note(&newer.id).unwrap_err();
+        assert_eq!(missing.kind(), io::ErrorKind::NotFound);
+        assert_eq!(storage.delete_note(&NoteId::new()).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(storage.len(), 1);
+        assert_eq!(storage.write_count(), 4);
+    }
+
+    #[test]
+    fn test_nth_write_fails_without_changing_the_store() {
+        let note = Note::new("Kept".to_string(), String::new());
+        let storage = MemoryStorage::with_notes(vec![note.clone()]);
+        storage.fail_write(2);
+
+        storage.save_note(&Note::new("First".to_string(), String::new())).unwrap();
+        let mut edited = note.clone();
+        edited.title = "Changed".to_string();
+        assert!(storage.update_note(&mut edited).is_err());
+        assert_eq!(edited.revision, note.revision);
+        assert_eq!(storage.load_note(&note.id).unwrap(), note);
+
+        // The fault fires once
+        storage.update_note(&mut edited).unwrap();
+        assert_eq!(storage.write_count(), 2);
+    }
+
+    #[test]
+    fn test_corrupted_notes_fail_reads_and_are_skipped_in_listings() {
+        let damaged = Note::new("Damaged".to_string(), String::new());
+        let healthy = Note::new("Healthy".to_string(), String::new());
+        let storage = MemoryStorage::with_notes(vec![damaged.clone(), healthy.clone()]);
+        storage.corrupt(&damaged.id);
+
+        assert_eq!(storage.load_note(&damaged.id).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(storage.list_notes().unwrap(), vec![healthy]);
+
+        storage.clear_faults();
+        assert_eq!(storage.load_note(&damaged.id).unwrap(), damaged);
+    }
+}
+
+#[cfg(test)]
+mod watch_tests {
+    use note_taking_app::index::MetadataIndex;
+    use note_taking_app::note::Note;
+    use note_taking_app::notebook::NotebookStore;
+    use note_taking_app::watch::{self, ChangeKind, Watcher};
+    use std::fs;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_reports_outside_changes_and_refreshes_index() {
+        let temp_dir = TempDir::new().unwrap();
+        let notebooks = NotebookStore::new(temp_dir.path(), "default").unwrap();
+        let existing = Note::new("Existing".to_string(), String::new());
+        notebooks.open_or_create("default").unwrap().save_note(&existing).unwrap();
+
+        let mut watcher = Watcher::new(&notebooks).unwrap();
+        assert!(watcher.scan().unwrap().is_empty());
+
+        // Another process writes through its own store
+        let other = NotebookStore::new(temp_dir.path(), "default").unwrap();
+        let mut note = Note::new("Synced in".to_string(), "from laptop".to_string());
+        other.open("default").unwrap().save_note(&note).unwrap();
+        let events = watcher.scan().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event, ChangeKind::Created);
+        assert_eq!(events[0].title.as_deref(), Some("Synced in"));
+        assert_eq!(watch::reload(&notebooks, &events[0]).unwrap(), Some(note.clone()));
+
+        // An edit that bypasses the store entirely still reaches the index
+        let path = events[0].path.clone();
+        note.title = "Edited elsewhere".to_string();
+        fs::write(&path, serde_json::to_string_pretty(&note).unwrap()).unwrap();
+        let events = watcher.scan().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event, ChangeKind::Modified);
+        let index = MetadataIndex::load(&temp_dir.path().join("default"), None);
+        assert_eq!(index.get(&note.id).unwrap().title, "Edited elsewhere");
+
+        fs::remove_file(&path).unwrap();
+        let events = watcher.scan().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event, ChangeKind::Removed);
+        assert_eq!(events[0].title.as_deref(), Some("Edited elsewhere"));
+        assert!(watcher.scan().unwrap().is_empty());
+
+        let line = serde_json::to_value(&events[0]).unwrap();
+        assert_eq!(line["event"], "removed");
+        assert_eq!(line["notebook"], "default");
+    }
+
+    #[test]
+    fn test_wait_wakes_up_on_change() {
+        let temp_dir = TempDir::new().unwrap();
+        let notebooks = NotebookStore::new(temp_dir.path(), "default").unwrap();
+        notebooks.open_or_create("default").unwrap();
+        let mut watcher = Watcher::new(&notebooks).unwrap();
+
+        let root = temp_dir.path().to_path_buf();
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            let notebooks = NotebookStore::new(&root, "default").unwrap();
+            notebooks.open("default").unwrap().save_note(&Note::new("Late".to_string(), String::new())).unwrap();
+        });
+
+        let started = Instant::now();
+        let mut events = Vec::new();
+        while events.is_empty() && started.elapsed() < Duration::from_secs(10) {
+            events = watcher.wait(watch::POLL_INTERVAL).unwrap();
+        }
+        writer.join().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].title.as_deref(), Some("Late"));
+    }
+}
+
+#[cfg(test)]
+mod dirsync_tests {
+    use chrono::{Duration, Utc};
+    use note_taking_app::dirsync::{self, SyncState, CONFLICT_OF_KEY};
+    use note_taking_app::note::Note;
+    use note_taking_app::notebook::NotebookStore;
+    use note_taking_app::storage::FileStorage;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn store(dir: &TempDir) -> FileStorage {
+        FileStorage::new(dir.path().to_str().unwrap()).unwrap()
+    }
+
+    fn days(days: i64) -> Duration {
+        Duration::days(days)
+    }
+
+    #[test]
+    fn test_copies_new_and_changed_notes_both_ways() {
+        let (laptop_dir, desk_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let (laptop, desk) = (store(&laptop_dir), store(&desk_dir));
+        let mut shared = Note::new("Shared".to_string(), "v1".to_string());
+        laptop.save_note(&shared).unwrap();
+        desk.save_note(&Note::new("Desk only".to_string(), String::new())).unwrap();
+
+        let summary = dirsync::sync_stores(&laptop, &desk).unwrap();
+        assert_eq!((summary.pulled, summary.pushed), (1, 1));
+        assert_eq!(laptop.list_notes().unwrap().len(), 2);
+
+        shared.content = "v2".to_string();
+        desk.update_note(&mut shared).unwrap();
+        let summary = dirsync::sync_stores(&laptop, &desk).unwrap();
+        assert_eq!((summary.pulled, summary.pushed), (1, 0));
+        assert_eq!(laptop.load_note(&shared.id).unwrap(), shared);
+
+        assert!(dirsync::sync_stores(&laptop, &desk).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_deletes_propagate_unless_edited_on_the_other_side() {
+        let (laptop_dir, desk_dir, shelf_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap());
+        let (laptop, desk, shelf) = (store(&laptop_dir), store(&desk_dir), store(&shelf_dir));
+        let gone = Note::new("Gone".to_string(), String::new());
+        let mut revived = Note::new("Revived".to_string(), String::new());
+        laptop.save_note(&gone).unwrap();
+        laptop.save_note(&revived).unwrap();
+        dirsync::sync_stores(&laptop, &desk).unwrap();
+        dirsync::sync_stores(&desk, &shelf).unwrap();
+
+        laptop.delete_note(&gone.id).unwrap();
+        laptop.delete_note(&revived.id).unwrap();
+        revived.content = "still needed".to_string();
+        desk.update_note(&mut revived).unwrap();
+        assert!(SyncState::load(laptop_dir.path()).unwrap().tombstones.contains_key(&gone.id));
+
+        let summary = dirsync::sync_stores(&laptop, &desk).unwrap();
+        assert_eq!((summary.deleted_there, summary.pulled), (1, 1));
+        assert!(desk.load_note(&gone.id).is_err());
+        assert_eq!(laptop.load_note(&revived.id).unwrap().content, "still needed");
+
+        // The tombstone travels, and the desk keeps it until the shelf has seen the delete too
+        assert!(SyncState::load(laptop_dir.path()).unwrap().tombstones.is_empty());
+        let state = SyncState::load(desk_dir.path()).unwrap();
+        assert!(state.tombstones.contains_key(&gone.id));
+        assert!(!state.tombstones.contains_key(&revived.id));
+
+        assert_eq!(dirsync::sync_stores(&desk, &shelf).unwrap().deleted_there, 1);
+        assert!(shelf.load_note(&gone.id).is_err());
+        for dir in [&desk_dir, &shelf_dir] {
+            assert!(SyncState::load(dir.path()).unwrap().tombstones.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_old_tombstones_expire() {
+        let (laptop_dir, desk_dir, shelf_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap());
+        let laptop = NotebookStore::new(laptop_dir.path(), "default").unwrap();
+        let desk = NotebookStore::new(desk_dir.path(), "default").unwrap();
+        let shelf = NotebookStore::new(shelf_dir.path(), "default").unwrap();
+        let (old, recent) = (Note::new("Old".to_string(), String::new()), Note::new("Recent".to_string(), String::new()));
+        let work = laptop.create("work").unwrap();
+        work.save_note(&old).unwrap();
+        work.save_note(&recent).unwrap();
+        dirsync::sync_notebooks(&laptop, &desk, days(90)).unwrap();
+        dirsync::sync_notebooks(&laptop, &shelf, days(90)).unwrap();
+
+        // The shelf has not synced since, so the laptop holds on to both tombstones
+        work.delete_note(&old.id).unwrap();
+        work.delete_note(&recent.id).unwrap();
+        dirsync::sync_notebooks(&laptop, &desk, days(90)).unwrap();
+        let dir = laptop_dir.path().join("work");
+        let mut state = SyncState::load(&dir).unwrap();
+        assert_eq!(state.tombstones.len(), 2);
+        state.tombstones.insert(old.id.clone(), Utc::now() - days(100));
+        state.save(&dir).unwrap();
+        dirsync::sync_notebooks(&laptop, &desk, days(90)).unwrap();
+
+        let tombstones = SyncState::load(&dir).unwrap().tombstones;
+        assert!(!tombstones.contains_key(&old.id));
+        assert!(tombstones.contains_key(&recent.id));
+
+        // Without any known peer there is no telling who still has the note
+        let mut state = SyncState::load(&dir).unwrap();
+        state.peers.clear();
+        assert!(!state.prune_tombstones());
+        assert!(state.tombstones.contains_key(&recent.id));
+    }
+
+    #[test]
+    fn test_concurrent_edits_without_shared_history_keep_both_versions() {
+        let (laptop_dir, desk_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let (laptop, desk) = (store(&laptop_dir), store(&desk_dir));
+        // Copied by hand rather than synced, so there is no common version to merge against
+        let note = Note::new("Plan".to_string(), "draft".to_string());
+        laptop.save_note(&note).unwrap();
+        desk.save_note(&note).unwrap();
+
+        let mut older = note.clone();
+        older.content = "laptop edit".to_string();
+        laptop.update_note(&mut older).unwrap();
+        let mut newer = note.clone();
+        newer.content = "desk edit".to_string();
+        newer.updated_at = older.updated_at + chrono::Duration::seconds(5);
+        desk.update_note(&mut newer).unwrap();
+
+        let summary = dirsync::sync_stores(&laptop, &desk).unwrap();
+        assert_eq!(summary.conflicts.len(), 1);
+        let (id, copy_id) = &summary.conflicts[0];
+        assert_eq!(id, &note.id);
+        for side in [&laptop, &desk] {
+            assert_eq!(side.load_note(&note.id).unwrap().content, "desk edit");
+            let copy = side.load_note(copy_id).unwrap();
+            assert_eq!(copy.content, "laptop edit");
+            assert_eq!(copy.metadata.get(CONFLICT_OF_KEY), Some(&note.id.to_string()));
+        }
+        assert!(dirsync::sync_stores(&laptop, &desk).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_syncs_notebooks_and_refuses_unreadable_notes() {
+        let (laptop_dir, desk_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let laptop = NotebookStore::new(laptop_dir.path(), "default").unwrap();
+        let desk = NotebookStore::new(desk_dir.path(), "default").unwrap();
+        laptop.create("work").unwrap().save_note(&Note::new("Standup".to_string(), String::new())).unwrap();
+
+        let results = dirsync::sync_notebooks(&laptop, &desk, days(90)).unwrap();
+        assert_eq!(dirsync::total(&results).pushed, 1);
+        assert_eq!(desk.open("work").unwrap().list_notes().unwrap().len(), 1);
+        assert!(dirsync::sync_notebooks(&laptop, &laptop, days(90)).is_err());
+
+        let note = desk.open("work").unwrap().list_notes().unwrap().remove(0);
+        fs::write(desk_dir.path().join("work").join(format!("{}.json", note.id)), "{ damaged").unwrap();
+        assert!(dirsync::sync_notebooks(&laptop, &desk, days(90)).is_err());
+        assert!(laptop.open("work").unwrap().load_note(&note.id).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod merge_tests {
+    use note_taking_app::dirsync;
+    use note_taking_app::merge::{self, CONFLICT_TAG};
+    use note_taking_app::note::Note;
+    use note_taking_app::storage::FileStorage;
+    use tempfile::TempDir;
+
+    const LABELS: (&str, &str) = ("laptop", "desk");
+
+    fn versions(content: &str) -> (Note, Note, Note) {
+        let base = Note::new("Plan".to_string(), content.to_string());
+        (base.clone(), base.clone(), base)
+    }
+
+    #[test]
+    fn test_edits_to_different_lines_merge_cleanly() {
+        let base = "one\ntwo\nthree\nfour\n";
+        let (text, conflicts) = merge::merge_text(base, "ONE\ntwo\nthree\nfour\n", "one\ntwo\nthree\nfour\nfive\n", LABELS);
+        assert_eq!(text, "ONE\ntwo\nthree\nfour\nfive\n");
+        assert_eq!(conflicts, 0);
+
+        let (text, conflicts) = merge::merge_text(base, "one\nthree\nfour\n", "one\ntwo\nthree\nFOUR", LABELS);
+        assert_eq!(text, "one\nthree\nFOUR");
+        assert_eq!(conflicts, 0);
+    }
+
+    #[test]
+    fn test_overlapping_edits_get_conflict_markers() {
+        let (text, conflicts) = merge::merge_text("a\nb\nc\n", "a\nours\nc\n", "a\ntheirs\nc\n", LABELS);
+        assert_eq!(conflicts, 1);
+        assert_eq!(text, "a\n<<<<<<< laptop\nours\n=======\ntheirs\n>>>>>>> desk\nc\n");
+
+        let (mut base, mut ours, mut theirs) = versions("a\nb\nc\n");
+        base.tags = vec!["work".to_string()];
+        ours.content = "a\nours\nc\n".to_string();
+        theirs.content = "a\ntheirs\nc\n".to_string();
+        let merged = merge::merge_notes(&base, &ours, &theirs, LABELS).unwrap();
+        assert!(!merged.is_clean());
+        assert_eq!(merged.note.tags, vec![CONFLICT_TAG.to_string()]);
+    }
+
+    #[test]
+    fn test_fields_merge_independently() {
+        let (mut base, mut ours, mut theirs) = versions("text\n");
+        base.tags = vec!["a".to_string(), "b".to_string()];
+        base.metadata.insert("room".to_string(), "4B".to_string());
+        base.metadata.insert("owner".to_string(), "sam".to_string());
+        base.revision = 3;
+
+        ours.tags = vec!["b".to_string(), "c".to_string()];
+        ours.metadata = base.metadata.clone();
+        ours.metadata.insert("room".to_string(), "5A".to_string());
+        ours.title = "Plan v2".to_string();
+        ours.revision = 4;
+
+        theirs.tags = vec!["a".to_string(), "b".to_string(), "d".to_string()];
+        theirs.metadata = base.metadata.clone();
+        theirs.metadata.remove("owner");
+        theirs.is_archived = true;
+        theirs.revision = 5;
+
+        let merged = merge::merge_notes(&base, &ours, &theirs, LABELS).unwrap();
+        assert!(merged.is_clean());
+        let note = merged.note;
+        assert_eq!(note.title, "Plan v2");
+        assert_eq!(note.tags, vec!["b".to_string(), "c".to_string(), "d".to_string()]);
+        assert_eq!(note.metadata.len(), 1);
+        assert_eq!(note.metadata["room"], "5A");
+        assert!(note.is_archived);
+        assert_eq!(note.revision, 6);
+    }
+
+    #[test]
+    fn test_conflicting_title_keeps_both_values() {
+        let (base, mut ours, mut theirs) = versions("text\n");
+        ours.title = "Older title".to_string();
+        theirs.title = "Newer title".to_string();
+        theirs.updated_at = ours.updated_at + chrono::Duration::seconds(1);
+
+        let merged = merge::merge_notes(&base, &ours, &theirs, LABELS).unwrap();
+        assert_eq!(merged.conflicts, 1);
+        assert_eq!(merged.note.title, "Newer title");
+        assert_eq!(merged.note.metadata["conflict:title"], "Older title");
+        assert!(merged.note.tags.contains(&CONFLICT_TAG.to_string()));
+
+        let other = Note::new("Other".to_string(), String::new());
+        assert!(merge::merge_notes(&base, &ours, &other, LABELS).is_err());
+    }
+
+    #[test]
+    fn test_sync_merges_concurrent_edits() {
+        let (laptop_dir, desk_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let laptop = FileStorage::new(laptop_dir.path().to_str().unwrap()).unwrap();
+        let desk = FileStorage::new(desk_dir.path().to_str().unwrap()).unwrap();
+        let note = Note::new("Plan".to_string(), "intro\nbody\noutro\n".to_string());
+        laptop.save_note(&note).unwrap();
+        dirsync::sync_stores(&laptop, &desk).unwrap();
+
+        let mut on_laptop = note.clone();
+        on_laptop.content = "INTRO\nbody\noutro\n".to_string();
+        laptop.update_note(&mut on_laptop).unwrap();
+        let mut on_desk = note.clone();
+        on_desk.content = "intro\nbody\nOUTRO\n".to_string();
+        on_desk.tags.push("reviewed".to_string());
+        desk.update_note(&mut on_desk).unwrap();
+
+        let summary = dirsync::sync_stores(&laptop, &desk).unwrap();
+        assert_eq!(summary.merged, 1);
+        assert!(summary.conflicts.is_empty());
+        for side in [&laptop, &desk] {
+            let merged = side.load_note(&note.id).unwrap();
+            assert_eq!(merged.content, "INTRO\nbody\nOUTRO\n");
+            assert_eq!(merged.tags, vec!["reviewed".to_string()]);
+        }
+        assert_eq!(laptop.list_notes().unwrap().len(), 1);
+
+        // The merged version is the base of the next round
+        let mut again = laptop.load_note(&note.id).unwrap();
+        again.content = "INTRO\nbody\nOUTRO\nmore\n".to_string();
+        laptop.update_note(&mut again).unwrap();
+        let mut other = desk.load_note(&note.id).unwrap();
+        other.content = "INTRO\nchanged\nOUTRO\n".to_string();
+        desk.update_note(&mut other).unwrap();
+        let summary = dirsync::sync_stores(&laptop, &desk).unwrap();
+        assert_eq!(summary.merged, 1);
+        assert_eq!(desk.load_note(&note.id).unwrap().content, "INTRO\nchanged\nOUTRO\nmore\n");
+    }
+}
+
+#[cfg(test)]
+mod httpsync_tests {
+    use note_taking_app::api::ApiServer;
+    use note_taking_app::crypto::KdfParams;
+    use note_taking_app::dirsync;
+    use note_taking_app::error::NoteError;
+    use note_taking_app::http::{self, Client, Url};
+    use note_taking_app::httpsync::{self, PushItem, PushRequest, PushResponse, RemoteOptions, SyncServer, SEALED_NOTEBOOK};
+    use note_taking_app::note::Note;
+    use note_taking_app::notebook::NotebookStore;
+    use note_taking_app::sealed::{Keyring, KEYRING_FILE};
+    use std::fs;
+    use std::net::TcpListener;
+    use std::path::Path;
+    use std::thread;
+    use tempfile::TempDir;
+
+    fn notebooks(dir: &TempDir) -> NotebookStore {
+        NotebookStore::new(dir.path(), "default").unwrap()
+    }
+
+    /// Starts a sync server for `dir` on a free localhost port
+    fn serve(dir: &TempDir) -> String {
+        let server = SyncServer::new(notebooks(dir)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || http::run(listener, move |request| server.handle(&request)));
+        url
+    }
+
+    fn plain(batch_size: usize) -> RemoteOptions<'static> {
+        let mut options = RemoteOptions::new(|_| Err(NoteError::InvalidInput("no passphrase".to_string())));
+        options.batch_size = batch_size;
+        options
+    }
+
+    /// Answers "New sync passphrase" prompts with `new` and all others with `current`
+    fn encrypted(current: &'static str, new: &'static str) -> RemoteOptions<'static> {
+        let mut options = RemoteOptions::new(move |prompt| Ok(if prompt.starts_with("New") { new } else { current }.to_string()));
+        options.kdf = || KdfParams::generate_with_cost(64, 1, 1);
+        options
+    }
+
+    fn all_files(dir: &Path, files: &mut Vec<std::path::PathBuf>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                all_files(&path, files);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sync_takes_the_api_tokens() {
+        let (server_dir, laptop_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let api = ApiServer::new(notebooks(&server_dir), "default", vec!["secret".to_string()]).unwrap();
+        let sync = SyncServer::new(notebooks(&server_dir)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || http::run(listener, move |request| api.authorize(&request, |request| sync.handle(request))));
+
+        let laptop = notebooks(&laptop_dir);
+        laptop.open_or_create("default").unwrap().save_note(&Note::new("Private".to_string(), String::new())).unwrap();
+        assert!(httpsync::sync_remote(&laptop, &url, plain(10)).is_err());
+        assert!(notebooks(&server_dir).open_or_create("default").unwrap().list_notes().unwrap().is_empty());
+
+        let mut options = plain(10);
+        options.token = Some("secret".to_string());
+        assert_eq!(dirsync::total(&httpsync::sync_remote(&laptop, &url, options).unwrap()).pushed, 1);
+        assert_eq!(notebooks(&server_dir).open("default").unwrap().list_notes().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_two_devices_sync_through_server() {
+        let (server_dir, laptop_dir, desk_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap());
+        let url = serve(&server_dir);
+        let (laptop, desk) = (notebooks(&laptop_dir), notebooks(&desk_dir));
+        assert_ne!(httpsync::device_id(&laptop).unwrap(), httpsync::device_id(&desk).unwrap());
+
+        let mut shared = Note::new("Shared".to_string(), "v1".to_string());
+        let gone = Note::new("Gone".to_string(), String::new());
+        laptop.open_or_create("work").unwrap().save_note(&shared).unwrap();
+        laptop.open_or_create("work").unwrap().save_note(&gone).unwrap();
+        let total = dirsync::total(&httpsync::sync_remote(&laptop, &url, plain(10)).unwrap());
+        assert_eq!(total.pushed, 2);
+
+        let total = dirsync::total(&httpsync::sync_remote(&desk, &url, plain(10)).unwrap());
+        assert_eq!(total.pulled, 2);
+        let work = desk.open("work").unwrap();
+        shared.content = "v2".to_string();
+        work.update_note(&mut shared).unwrap();
+        work.delete_note(&gone.id).unwrap();
+        let total = dirsync::total(&httpsync::sync_remote(&desk, &url, plain(10)).unwrap());
+        assert_eq!((total.pushed, total.deleted_there), (1, 1));
+
+        let total = dirsync::total(&httpsync::sync_remote(&laptop, &url, plain(10)).unwrap());
+        assert_eq!((total.pulled, total.deleted_here), (1, 1));
+        let work = laptop.open("work").unwrap();
+        assert_eq!(work.load_note(&shared.id).unwrap().content, "v2");
+        assert!(work.load_note(&gone.id).is_err());
+        assert!(httpsync::sync_remote(&laptop, &url, plain(10)).unwrap().iter().all(|(_, s)| s.is_empty()));
+    }
+
+    #[test]
+    fn test_changes_are_fetched_in_batches_from_the_saved_cursor() {
+        let (server_dir, client_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let default = notebooks(&server_dir).open_or_create("default").unwrap();
+        for i in 0..5 {
+            default.save_note(&Note::new(format!("Note {}", i), String::new())).unwrap();
+        }
+        let url = serve(&server_dir);
+        let client = notebooks(&client_dir);
+
+        let total = dirsync::total(&httpsync::sync_remote(&client, &url, plain(2)).unwrap());
+        assert_eq!(total.pulled, 5);
+
+        // Notes written directly into the served directory are picked up too
+        default.save_note(&Note::new("Late".to_string(), String::new())).unwrap();
+        let total = dirsync::total(&httpsync::sync_remote(&client, &url, plain(2)).unwrap());
+        assert_eq!(total.pulled, 1);
+        assert_eq!(client.open("default").unwrap().list_notes().unwrap().len(), 6);
+    }
+
+    #[test]
+    fn test_concurrent_edits_are_merged_on_the_client() {
+        let (server_dir, laptop_dir, desk_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap());
+        let url = serve(&server_dir);
+        let (laptop, desk) = (notebooks(&laptop_dir), notebooks(&desk_dir));
+        let note = Note::new("Plan".to_string(), "one\ntwo\nthree\n".to_string());
+        laptop.open_or_create("default").unwrap().save_note(&note).unwrap();
+        httpsync::sync_remote(&laptop, &url, plain(10)).unwrap();
+        httpsync::sync_remote(&desk, &url, plain(10)).unwrap();
+
+        let mut on_laptop = laptop.open("default").unwrap().load_note(&note.id).unwrap();
+        on_laptop.content = "ONE\ntwo\nthree\n".to_string();
+        laptop.open("default").unwrap().update_note(&mut on_laptop).unwrap();
+        let mut on_desk = desk.open("default").unwrap().load_note(&note.id).unwrap();
+        on_desk.content = "one\ntwo\nTHREE\n".to_string();
+        desk.open("default").unwrap().update_note(&mut on_desk).unwrap();
+
+        httpsync::sync_remote(&laptop, &url, plain(10)).unwrap();
+        let total = dirsync::total(&httpsync::sync_remote(&desk, &url, plain(10)).unwrap());
+        assert_eq!((total.merged, total.pushed, total.rejected), (1, 1, 0));
+        httpsync::sync_remote(&laptop, &url, plain(10)).unwrap();
+        assert_eq!(laptop.open("default").unwrap().load_note(&note.id).unwrap().content, "ONE\ntwo\nTHREE\n");
+    }
+
+    #[test]
+    fn test_push_with_stale_base_is_rejected() {
+        let server_dir = TempDir::new().unwrap();
+        let url = serve(&server_dir);
+        let client = Client::new(&url).unwrap();
+        let mut note = Note::new("Draft".to_string(), String::new());
+        let push = |note: &Note, base: Option<String>| -> PushResponse {
+            let items = vec![PushItem {
+                notebook: "default".to_string(),
+                id: note.id.clone(),
+                base,
+                note: Some(note.clone()),
+                sealed: None,
+                hash: None,
+            }];
+            let request = PushRequest { device: "test".to_string(), items };
+            client.post_json("/sync/v1/push", &request).unwrap().json_body().unwrap()
+        };
+
+        assert_eq!(push(&note, None).accepted.len(), 1);
+        note.content = "edited".to_string();
+        assert_eq!(push(&note, Some("stale".to_string())).rejected.len(), 1);
+        assert_eq!(client.get("/sync/v1/nothing").unwrap().status, 404);
+        assert_eq!(Url::parse("http://localhost:7878/notes/").unwrap().prefix, "/notes");
+        assert!(Url::parse("https://localhost").is_err());
+        assert_eq!(http::percent_decode("a%20b+c%zz"), "a b+c%zz");
+        let query = http::parse_query("q=C%2B%2B+tips&tag=a+b");
+        assert_eq!((query["q"].as_str(), query["tag"].as_str()), ("C++ tips", "a b"));
+    }
+
+    #[test]
+    fn test_truncated_body_closes_the_connection() {
+        use std::io::{Read, Write};
+        let server_dir = TempDir::new().unwrap();
+        let url = serve(&server_dir);
+        let mut stream = std::net::TcpStream::connect(url.trim_start_matches("http://")).unwrap();
+        write!(stream, "POST /sync/v1/push HTTP/1.1\r\nContent-Length: {}\r\n\r\n{{}}", http::MAX_BODY).unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).unwrap();
+        assert!(reply.is_empty());
+    }
+
+    #[test]
+    fn test_encrypted_server_stores_no_plaintext() {
+        let (server_dir, laptop_dir, desk_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap());
+        let url = serve(&server_dir);
+        let (laptop, desk) = (notebooks(&laptop_dir), notebooks(&desk_dir));
+        let mut note = Note::new("Quarterly salaries".to_string(), "Alice earns plenty".to_string());
+        note.tags = vec!["confidential-hr".to_string()];
+        laptop.open_or_create("payroll").unwrap().save_note(&note).unwrap();
+
+        let mut options = encrypted("hunter2", "hunter2");
+        options.encrypt = true;
+        let total = dirsync::total(&httpsync::sync_remote(&laptop, &url, options).unwrap());
+        assert_eq!(total.pushed, 1);
+        assert!(server_dir.path().join(SEALED_NOTEBOOK).join(format!("{}.json", note.id)).is_file());
+
+        let mut files = Vec::new();
+        all_files(server_dir.path(), &mut files);
+        for file in &files {
+            let data = String::from_utf8_lossy(&fs::read(file).unwrap()).into_owned();
+            for secret in ["Quarterly salaries", "Alice earns plenty", "confidential-hr", "payroll"] {
+                assert!(!data.contains(secret), "{:?} contains {:?}", file, secret);
+            }
+        }
+
+        let total = dirsync::total(&httpsync::sync_remote(&desk, &url, encrypted("hunter2", "")).unwrap());
+        assert_eq!(total.pulled, 1);
+        assert_eq!(desk.open("payroll").unwrap().load_note(&note.id).unwrap().content, "Alice earns plenty");
+
+        // Moving a note to another notebook travels inside the ciphertext
+        desk.open_or_create("archive").unwrap();
+        desk.move_note(&note.id, "archive").unwrap();
+        assert_eq!(dirsync::total(&httpsync::sync_remote(&desk, &url, encrypted("hunter2", "")).unwrap()).pushed, 1);
+        assert_eq!(dirsync::total(&httpsync::sync_remote(&laptop, &url, encrypted("hunter2", "")).unwrap()).pulled, 1);
+        assert_eq!(laptop.locate(&note.id).unwrap().as_deref(), Some("archive"));
+
+        // Plaintext pushes are refused once the server holds a keyring
+        let items = vec![PushItem {
+            notebook: "payroll".to_string(),
+            id: note.id.clone(),
+            base: None,
+            note: Some(note.clone()),
+            sealed: None,
+            hash: None,
+        }];
+        let request = PushRequest { device: "test".to_string(), items };
+        assert_eq!(Client::new(&url).unwrap().post_json("/sync/v1/push", &request).unwrap().status, 400);
+        assert!(httpsync::sync_remote(&notebooks(&TempDir::new().unwrap()), &url, plain(10)).is_err());
+    }
+
+    #[test]
+    fn test_encryption_is_refused_for_a_server_with_plaintext_notes() {
+        let (server_dir, client_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        notebooks(&server_dir).open_or_create("default").unwrap().save_note(&Note::new("Open".to_string(), String::new())).unwrap();
+        let url = serve(&server_dir);
+
+        let mut options = encrypted("hunter2", "hunter2");
+        options.encrypt = true;
+        assert!(httpsync::sync_remote(&notebooks(&client_dir), &url, options).is_err());
+        assert!(!server_dir.path().join(KEYRING_FILE).exists());
+    }
+
+    #[test]
+    fn test_wrong_sync_passphrase_is_rejected() {
+        let (server_dir, laptop_dir, desk_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap());
+        let url = serve(&server_dir);
+        let laptop = notebooks(&laptop_dir);
+        laptop.open_or_create("default").unwrap().save_note(&Note::new("Private".to_string(), String::new())).unwrap();
+        let mut options = encrypted("hunter2", "hunter2");
+        options.encrypt = true;
+        httpsync::sync_remote(&laptop, &url, options).unwrap();
+
+        let desk = notebooks(&desk_dir);
+        assert!(matches!(httpsync::sync_remote(&desk, &url, encrypted("letmein", "")), Err(NoteError::WrongPassphrase)));
+        assert!(desk.list().unwrap().iter().all(|(_, count)| *count == 0));
+    }
+
+    #[test]
+    fn test_sync_key_rotation_seals_every_note_again() {
+        let (server_dir, laptop_dir, desk_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap());
+        let url = serve(&server_dir);
+        let (laptop, desk) = (notebooks(&laptop_dir), notebooks(&desk_dir));
+        let mut note = Note::new("Plan".to_string(), "v1".to_string());
+        laptop.open_or_create("default").unwrap().save_note(&note).unwrap();
+        laptop.open_or_create("default").unwrap().save_note(&Note::new("Other".to_string(), String::new())).unwrap();
+        let mut options = encrypted("old secret", "old secret");
+        options.encrypt = true;
+        httpsync::sync_remote(&laptop, &url, options).unwrap();
+        httpsync::sync_remote(&desk, &url, encrypted("old secret", "")).unwrap();
+
+        let mut options = encrypted("old secret", "new secret");
+        options.rotate_key = true;
+        let total = dirsync::total(&httpsync::sync_remote(&laptop, &url, options).unwrap());
+        assert_eq!((total.pushed, total.rejected), (2, 0));
+        let keyring: Keyring = serde_json::from_str(&fs::read_to_string(server_dir.path().join(KEYRING_FILE)).unwrap()).unwrap();
+        assert_eq!((keyring.version, keyring.retired.len()), (2, 1));
+        for entry in fs::read_dir(server_dir.path().join(SEALED_NOTEBOOK)).unwrap() {
+            let stored: serde_json::Value = serde_json::from_str(&fs::read_to_string(entry.unwrap().path()).unwrap()).unwrap();
+            assert_eq!(stored["sealed"]["key_id"], keyring.key_id.as_str());
+        }
+
+        assert!(matches!(httpsync::sync_remote(&desk, &url, encrypted("old secret", "")), Err(NoteError::WrongPassphrase)));
+        let total = dirsync::total(&httpsync::sync_remote(&desk, &url, encrypted("new secret", "")).unwrap());
+        assert_eq!((total.pulled, total.pushed, total.conflicts.len()), (0, 0, 0));
+
+        let default = desk.open("default").unwrap();
+        note = default.load_note(&note.id).unwrap();
+        note.content = "v2".to_string();
+        default.update_note(&mut note).unwrap();
+        httpsync::sync_remote(&desk, &url, encrypted("new secret", "")).unwrap();
+        httpsync::sync_remote(&laptop, &url, encrypted("new secret", "")).unwrap();
+        assert_eq!(laptop.open("default").unwrap().load_note(&note.id).unwrap().content, "v2");
+    }
+}
+
+#[cfg(test)]
+mod api_tests {
+    use note_taking_app::api::{ApiNote, ApiServer, ApiSummary, EventBatch, Page, API_PREFIX};
+    use note_taking_app::backup::{AutoBackup, BackupSources, BackupStore, SnapshotKind};
+    use note_taking_app::config::BackupConfig;
+    use note_taking_app::crypto::{KdfParams, Vault};
+    use note_taking_app::feed::FeedKind;
+    use note_taking_app::git::GitStore;
+    use note_taking_app::http::{self, Client, Response};
+    use note_taking_app::note::Note;
+    use note_taking_app::notebook::NotebookStore;
+    use serde_json::json;
+    use std::net::TcpListener;
+    use std::thread;
+    use tempfile::TempDir;
+
+    const TOKEN: &str = "test-token";
+
+    /// Starts the API for `dir` on a free localhost port, returning its URL
+    fn start(dir: &TempDir) -> String {
+        let notebooks = NotebookStore::new(dir.path(), "default").unwrap();
+        listen(ApiServer::new(notebooks, "default", vec![TOKEN.to_string()]).unwrap())
+    }
+
+    fn listen(server: ApiServer) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}{}", listener.local_addr().unwrap(), API_PREFIX);
+        thread::spawn(move || http::run(listener, move |request| server.handle(&request)));
+        url
+    }
+
+    /// Starts the API and returns a client that sends the configured token
+    fn serve(dir: &TempDir) -> Client {
+        Client::new(&start(dir)).unwrap().with_header("Authorization", &format!("Bearer {}", TOKEN))
+    }
+
+    fn send(client: &Client, method: &str, path: &str, body: serde_json::Value) -> Response {
+        client.send(method, path, Some(&serde_json::to_vec(&body).unwrap())).unwrap()
+    }
+
+    #[test]
+    fn test_commits_of_an_encrypted_store_leave_titles_out() {
+        let dir = TempDir::new().unwrap();
+        let git = GitStore::open(dir.path(), "main").unwrap();
+        let key = Vault::init(dir.path(), "correct horse", KdfParams::generate_with_cost(64, 1, 1).unwrap()).unwrap();
+        let notebooks = NotebookStore::new(dir.path(), "default").unwrap().with_key(key);
+        let server = ApiServer::new(notebooks, "default", vec![TOKEN.to_string()]).unwrap().with_git(git.clone());
+        let client = Client::new(&listen(server)).unwrap().with_header("Authorization", &format!("Bearer {}", TOKEN));
+
+        let created: ApiNote = send(&client, "POST", "/notes", json!({"title": "Secret plan", "tags": ["merger"]})).json_body().unwrap();
+        assert_eq!(send(&client, "POST", "/tags/rename", json!({"from": "merger", "to": "deal"})).status, 200);
+
+        let subjects: Vec<String> = git.log(&created.note.id).unwrap().into_iter().map(|commit| commit.subject).collect();
+        assert_eq!(subjects, vec!["Rewrite tags of 1 note(s)".to_string(), format!("Create note {}", created.note.id)]);
+    }
+
+    #[test]
+    fn test_writes_take_the_daily_snapshot() {
+        let dir = TempDir::new().unwrap();
+        let sources = BackupSources {
+            notes_dir: dir.path().join("notes"),
+            attachments_dir: dir.path().join("attachments"),
+            templates_dir: dir.path().join("templates"),
+            config_file: None,
+        };
+        let store = BackupStore::new(dir.path().join("backups")).unwrap();
+        let policy = BackupConfig { dir: store.dir().to_path_buf(), ..BackupConfig::default() };
+        let notebooks = NotebookStore::new(&sources.notes_dir, "default").unwrap();
+        let server = ApiServer::new(notebooks, "default", vec![TOKEN.to_string()]).unwrap()
+            .with_auto_backup(AutoBackup { store: store.clone(), sources, policy });
+        let client = Client::new(&listen(server)).unwrap().with_header("Authorization", &format!("Bearer {}", TOKEN));
+
+        assert_eq!(client.get("/notes").unwrap().status, 200);
+        assert!(store.list().unwrap().is_empty());
+
+        assert_eq!(send(&client, "POST", "/notes", json!({"title": "First"})).status, 201);
+        let snapshots = store.list().unwrap();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].kind, SnapshotKind::Auto);
+
+        assert_eq!(send(&client, "POST", "/notes", json!({"title": "Second"})).status, 201);
+        assert_eq!(store.list().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_notes_crud_with_etags() {
+        let dir = TempDir::new().unwrap();
+        let client = serve(&dir);
+
+        let response = send(&client, "POST", "/notes", json!({"title": "Standup", "content": "notes", "tags": ["work"]}));
+        assert_eq!(response.status, 201);
+        let created: ApiNote = response.json_body().unwrap();
+        assert_eq!(created.notebook, "default");
+        let path = format!("/notes/{}", created.note.id);
+        let etag = response.header("etag").unwrap().to_string();
+
+        let response = client.get(&path).unwrap();
+        assert_eq!(response.header("etag"), Some(etag.as_str()));
+        assert_eq!(response.json_body::<ApiNote>().unwrap().note.title, "Standup");
+        assert_eq!(client.clone().with_header("If-None-Match", &etag).get(&path).unwrap().status, 304);
+
+        // Updates need the current ETag
+        assert_eq!(send(&client, "PATCH", &path, json!({"content": "edited"})).status, 428);
+        let tagged = send(&client.clone().with_header("If-Match", &etag), "POST", &format!("{}/tags", path), json!({"add": ["daily"]}));
+        assert_eq!(tagged.status, 200);
+        let stale = send(&client.clone().with_header("If-Match", &etag), "PATCH", &path, json!({"content": "edited"}));
+        assert_eq!(stale.status, 412);
+
+        let current = tagged.header("etag").unwrap().to_string();
+        let weak = format!("W/{}", current);
+        assert_eq!(client.clone().with_header("If-None-Match", &weak).get(&path).unwrap().status, 304);
+        let weak_match = send(&client.clone().with_header("If-Match", &weak), "PATCH", &path, json!({"content": "edited"}));
+        assert_eq!(weak_match.status, 412);
+        let response = send(&client.clone().with_header("If-Match", &current), "PATCH", &path, json!({"content": "edited"}));
+        assert_eq!(response.status, 200);
+        let updated: ApiNote = response.json_body().unwrap();
+        assert_eq!((updated.note.content.as_str(), updated.note.tags.len(), updated.note.revision), ("edited", 2, 2));
+        assert_ne!(response.header("etag"), Some(current.as_str()));
+
+        assert_eq!(client.send("DELETE", &path, None).unwrap().status, 204);
+        assert_eq!(client.get(&path).unwrap().status, 404);
+    }
+
+    #[test]
+    fn test_requests_need_a_configured_bearer_token() {
+        let dir = TempDir::new().unwrap();
+        let url = start(&dir);
+        let client = Client::new(&url).unwrap().with_header("Authorization", &format!("Bearer {}", TOKEN));
+
+        let anonymous = Client::new(&url).unwrap().get("/notes").unwrap();
+        assert_eq!((anonymous.status, anonymous.header("www-authenticate")), (401, Some("Bearer")));
+        let wrong = Client::new(&url).unwrap().with_header("Authorization", "Bearer nope").get("/notes").unwrap();
+        assert_eq!(wrong.status, 401);
+        assert_eq!(client.get("/notes").unwrap().status, 200);
+        assert_eq!(client.get("/nothing").unwrap().status, 404);
+        assert!(ApiServer::new(NotebookStore::new(dir.path(), "default").unwrap(), "default", Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_listing_and_search_are_paginated() {
+        let dir = TempDir::new().unwrap();
+        let notebooks = NotebookStore::new(dir.path(), "default").unwrap();
+        let default = notebooks.open_or_create("default").unwrap();
+        for i in 0..5 {
+            let mut note = Note::new(format!("Meeting {}", i), format!("Agenda {}", i));
+            note.created_at += chrono::Duration::seconds(i);
+            note.tags = vec![if i % 2 == 0 { "work/meetings" } else { "home" }.to_string()];
+            default.save_note(&note).unwrap();
+        }
+        notebooks.open_or_create("team").unwrap().save_note(&Note::new("Team meeting".to_string(), String::new())).unwrap();
+        let client = serve(&dir);
+
+        let page: Page<ApiSummary> = client.get("/notes?limit=2&offset=1").unwrap().json_body().unwrap();
+        assert_eq!((page.total, page.items.len(), page.offset), (6, 2, 1));
+        let page: Page<ApiSummary> = client.get("/notes?tag=work&notebook=default").unwrap().json_body().unwrap();
+        assert_eq!(page.total, 3);
+        assert_eq!(page.items[0].summary.title, "Meeting 4");
+
+        let page: Page<ApiSummary> = client.get("/search?q=meeting&field=title").unwrap().json_body().unwrap();
+        assert_eq!(page.total, 6);
+        let page: Page<ApiSummary> = client.get("/search?q=meeting&field=title&case_sensitive=true").unwrap().json_body().unwrap();
+        assert_eq!((page.total, page.items[0].notebook.as_str()), (1, "team"));
+        let page: Page<ApiSummary> = client.get("/search?q=agenda%203&field=content").unwrap().json_body().unwrap();
+        assert_eq!(page.total, 1);
+        assert_eq!(client.get("/search?q=x&field=nowhere").unwrap().status, 400);
+    }
+
+    #[test]
+    fn test_tag_operations() {
+        let dir = TempDir::new().unwrap();
+        let client = serve(&dir);
+        for tags in [json!(["todo"]), json!(["todo/urgent", "ideas"])] {
+            send(&client, "POST", "/notes", json!({"title": "Note", "tags": tags}));
+        }
+
+        let counts: serde_json::Value = client.get("/tags").unwrap().json_body().unwrap();
+        assert_eq!(counts, json!([{"tag": "ideas", "count": 1}, {"tag": "todo", "count": 2}, {"tag": "todo/urgent", "count": 1}]));
+        let renamed: serde_json::Value = send(&client, "POST", "/tags/rename", json!({"from": "todo", "to": "tasks"})).json_body().unwrap();
+        assert_eq!(renamed["updated"], 2);
+        assert_eq!(send(&client, "POST", "/tags/delete", json!({"tag": "ideas"})).json_body::<serde_json::Value>().unwrap()["updated"], 1);
+
+        let counts: serde_json::Value = client.get("/tags").unwrap().json_body().unwrap();
+        assert_eq!(counts, json!([{"tag": "tasks", "count": 2}, {"tag": "tasks/urgent", "count": 1}]));
+        assert_eq!(send(&client, "POST", "/tags/rename", json!({"from": "bad tag", "to": "x"})).status, 400);
+
+        // A notebook without the tag is skipped; a tag no notebook has is an error
+        NotebookStore::new(dir.path(), "default").unwrap().create("team").unwrap();
+        let merged: serde_json::Value = send(&client, "POST", "/tags/merge", json!({"from": "tasks/urgent", "to": "tasks"})).json_body().unwrap();
+        assert_eq!(merged["updated"], 1);
+        assert_eq!(send(&client, "POST", "/tags/merge", json!({"from": "ideas", "to": "tasks"})).status, 404);
+    }
+
+    #[test]
+    fn test_change_feed_resumes_from_a_cursor() {
+        let dir = TempDir::new().unwrap();
+        let client = serve(&dir);
+        let start: EventBatch = client.get("/events?wait=0").unwrap().json_body().unwrap();
+        assert_eq!(start, EventBatch { events: Vec::new(), cursor: 0 });
+
+        let created: ApiNote = send(&client, "POST", "/notes", json!({"title": "Plan"})).json_body().unwrap();
+        let path = format!("/notes/{}", created.note.id);
+        let etag = client.get(&path).unwrap().header("etag").unwrap().to_string();
+        send(&client.clone().with_header("If-Match", &etag), "POST", &format!("{}/tags", path), json!({"add": ["work"]}));
+
+        let batch: EventBatch = client.get("/events?cursor=0&wait=0").unwrap().json_body().unwrap();
+        let kinds: Vec<FeedKind> = batch.events.iter().map(|event| event.event).collect();
+        assert_eq!(kinds, vec![FeedKind::Created, FeedKind::Tagged]);
+        assert_eq!((batch.cursor, batch.events[1].tags.as_slice()), (2, ["work".to_string()].as_slice()));
+
+        // A waiting client wakes up on the next change
+        let waiting = client.clone();
+        let next = thread::spawn(move || waiting.get("/events?cursor=2&wait=10").unwrap().json_body::<EventBatch>().unwrap());
+        thread::sleep(std::time::Duration::from_millis(300));
+        assert_eq!(client.send("DELETE", &path, None).unwrap().status, 204);
+        let batch = next.join().unwrap();
+        assert_eq!(batch.events.len(), 1);
+        assert_eq!((batch.events[0].event, batch.events[0].title.as_deref(), batch.cursor), (FeedKind::Deleted, Some("Plan"), 3));
+
+        assert_eq!(client.get("/events?cursor=7&wait=0").unwrap().status, 410);
+    }
+
+    #[test]
+    fn test_change_feed_as_server_sent_events() {
+        let dir = TempDir::new().unwrap();
+        let url = start(&dir);
+        let client = Client::new(&url).unwrap().with_header("Accept", "text/event-stream");
+        let authorized = client.clone().with_header("Authorization", &format!("Bearer {}", TOKEN));
+        send(&authorized, "POST", "/notes", json!({"title": "First"}));
+
+        assert_eq!(client.get("/events?cursor=0").unwrap().status, 401);
+        assert_eq!(client.get(&format!("/notes?access_token={}", TOKEN)).unwrap().status, 401);
+        let response = client.get(&format!("/events?cursor=0&access_token={}", TOKEN)).unwrap();
+        assert_eq!(response.header("content-type"), Some("text/event-stream"));
+        let body = String::from_utf8(response.body).unwrap();
+        assert!(body.starts_with("retry: "));
+        assert!(body.contains("id: 1\nevent: created\ndata: {"));
+
+        // Reconnects resume from Last-Event-ID; without changes the stream still names the cursor
+        let response = authorized.clone().with_header("Last-Event-ID", "1").get("/events?wait=0").unwrap();
+        assert!(String::from_utf8(response.body).unwrap().ends_with("id: 1\n\n"));
+    }
+}
+
+#[cfg(test)]
+mod feed_tests {
+    use note_taking_app::feed::{ChangeFeed, FeedEvent, FeedKind, FEED_FILE};
+    use note_taking_app::note::Note;
+    use note_taking_app::notebook::NotebookStore;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_notebook_changes_are_published() {
+        let dir = TempDir::new().unwrap();
+        let notebooks = NotebookStore::new(dir.path(), "default").unwrap();
+        notebooks.create("work").unwrap();
+        let storage = notebooks.open_or_create("default").unwrap();
+
+        let mut note = Note::new("Draft".to_string(), "text".to_string());
+        storage.save_note(&note).unwrap();
+        note.content = "more text".to_string();
+        storage.update_note(&mut note).unwrap();
+        note.add_tag("ideas".to_string());
+        storage.update_note(&mut note).unwrap();
+        notebooks.move_note(&note.id, "work").unwrap();
+
+        let events = ChangeFeed::new(dir.path()).since(0).unwrap().unwrap();
+        let summary: Vec<(u64, FeedKind, &str)> = events.iter().map(|e| (e.seq, e.event, e.notebook.as_str())).collect();
+        assert_eq!(summary, vec![
+            (1, FeedKind::Created, "default"),
+            (2, FeedKind::Updated, "default"),
+            (3, FeedKind::Tagged, "default"),
+            (4, FeedKind::Created, "work"),
+            (5, FeedKind::Deleted, "default"),
+        ]);
+        assert_eq!((events[2].revision, events[4].title.as_deref()), (Some(2), Some("Draft")));
+    }
+
+    #[test]
+    fn test_cursors_outside_the_feed_are_rejected() {
+        let dir = TempDir::new().unwrap();
+        let feed = ChangeFeed::new(dir.path());
+        assert_eq!(feed.latest().unwrap(), 0);
+        assert_eq!(feed.since(0).unwrap(), Some(Vec::new()));
+        assert_eq!(feed.since(1).unwrap(), None);
+
+        let note = Note::new("A".to_string(), String::new());
+        for _ in 0..3 {
+            feed.append(FeedEvent::new(FeedKind::Updated, "default", &note.id, Some(&note))).unwrap();
+        }
+        assert_eq!(feed.since(1).unwrap().unwrap().len(), 2);
+
+        // Half-written lines are skipped, and numbering carries on after them
+        let path = dir.path().join(FEED_FILE);
+        let mut contents = fs::read_to_string(&path).unwrap();
+        contents.push_str("{\"seq\": 4, \"eve");
+        fs::write(&path, &contents).unwrap();
+        assert_eq!(feed.append(FeedEvent::new(FeedKind::Deleted, "default", &note.id, None)).unwrap(), 4);
+        assert_eq!(feed.since(0).unwrap().unwrap().len(), 4);
+
+        // Once the oldest events are dropped, their cursors are gone
+        fs::write(&path, contents.lines().skip(1).take(2).map(|line| format!("{}\n", line)).collect::<String>()).unwrap();
+        assert_eq!(feed.since(0).unwrap(), None);
+        assert_eq!(feed.since(1).unwrap().unwrap().len(), 2);
+    }
+}
+
+#[cfg(test)]
+mod render_tests {
+    use note_taking_app::render::markdown_to_html;
+
+    #[test]
+    fn test_markdown_blocks() {
+        let html = markdown_to_html("# Plan\n\nFirst line\nsecond line\n\n- [x] done\n- open\n  continued\n\n1. one\n2. two\n\n> quoted\n\n---\n```rust\nlet a = 1 < 2;\n```");
+        assert_eq!(html, "<h1>Plan</h1>\n\
+            <p>First line<br>\nsecond line</p>\n\
+            <ul>\n<li><input type=\"checkbox\" checked disabled> done</li>\n<li>open continued</li>\n</ul>\n\
+            <ol>\n<li>one</li>\n<li>two</li>\n</ol>\n\
+            <blockquote>\n<p>quoted</p>\n</blockquote>\n\
+            <hr>\n\
+            <pre><code class=\"language-rust\">let a = 1 &lt; 2;</code></pre>\n");
+    }
+
+    #[test]
+    fn test_markdown_inline() {
+        assert_eq!(
+            markdown_to_html("**bold** *em* `a<b` ~~old~~ snake_case_name [[Other note]] [site](https://example.com)"),
+            "<p><strong>bold</strong> <em>em</em> <code>a&lt;b</code> <del>old</del> snake_case_name \
+             <a class=\"wikilink\" href=\"#\" data-title=\"Other note\">Other note</a> \
+             <a href=\"https://example.com\" rel=\"noopener noreferrer\" target=\"_blank\">site</a></p>\n"
+        );
+        assert_eq!(markdown_to_html("#tag and 2 * 3"), "<p>#tag and 2 * 3</p>\n");
+    }
+
+    #[test]
+    fn test_markup_in_notes_is_escaped() {
+        let html = markdown_to_html("<script>alert(1)</script>\n\n[click](javascript:alert(1)) [[\"><img src=x>]]");
+        assert!(!html.contains("<script") && !html.contains("<img"));
+        assert!(html.contains("&lt;script&gt;"));
+        assert!(!html.contains("javascript:"));
+        assert!(html.contains("data-title=\"&quot;&gt;&lt;img src=x&gt;\""));
+    }
+}
+
+#[cfg(test)]
+mod web_tests {
+    use note_taking_app::api::ApiServer;
+    use note_taking_app::http::Request;
+    use note_taking_app::note::Note;
+    use note_taking_app::notebook::NotebookStore;
+    use note_taking_app::web::{session_token, WebServer};
+    use tempfile::TempDir;
+
+    fn get(server: &WebServer, path: &str, token: Option<&str>) -> note_taking_app::http::Response {
+        let mut request = Request { method: "GET".to_string(), path: path.to_string(), ..Request::default() };
+        if let Some(token) = token {
+            request.headers.insert("authorization".to_string(), format!("Bearer {}", token));
+        }
+        server.handle(&request)
+    }
+
+    #[test]
+    fn test_web_ui_is_embedded_next_to_the_api() {
+        let dir = TempDir::new().unwrap();
+        let notebooks = NotebookStore::new(dir.path(), "default").unwrap();
+        let mut note = Note::new("Readme".to_string(), "# Hello <b>".to_string());
+        note.add_tag("docs".to_string());
+        notebooks.open_or_create("default").unwrap().save_note(&note).unwrap();
+        let token = session_token().unwrap();
+        assert_eq!(token.len(), 64);
+        let server = WebServer::new(ApiServer::new(notebooks, "default", vec![token.clone()]).unwrap());
+
+        let page = get(&server, "/", None);
+        assert_eq!(page.status, 200);
+        assert!(page.header("content-security-policy").unwrap().contains("default-src 'self'"));
+        let html = String::from_utf8(page.body).unwrap();
+        assert!(html.contains("src=\"/app.js\"") && !html.contains("http"));
+        assert_eq!(get(&server, "/app.js", None).header("content-type"), Some("text/javascript; charset=utf-8"));
+        assert_eq!(get(&server, "/app.css", None).status, 200);
+        assert_eq!(get(&server, "/missing", None).status, 404);
+
+        let path = format!("/api/v1/notes/{}/html", note.id);
+        assert_eq!(get(&server, &path, None).status, 401);
+        let rendered = get(&server, &path, Some(&token));
+        assert_eq!(String::from_utf8(rendered.body).unwrap(), "<h1>Hello &lt;b&gt;</h1>\n");
+        let notebooks: serde_json::Value = get(&server, "/api/v1/notebooks", Some(&token)).json_body().unwrap();
+        assert_eq!(notebooks, serde_json::json!([{"name": "default", "notes": 1}]));
+    }
+}
+
+#[cfg(test)]
+mod lsp_tests {
+    use note_taking_app::jsonrpc::{self, Message};
+    use note_taking_app::lsp::{self, file_uri, LanguageServer};
+    use note_taking_app::note::Note;
+    use note_taking_app::notebook::NotebookStore;
+    use serde_json::{json, Value};
+    use std::io::Cursor;
+    use tempfile::TempDir;
+
+    const URI: &str = "file:///notes/draft.md";
+
+    fn setup() -> (TempDir, NotebookStore, Note) {
+        let dir = TempDir::new().unwrap();
+        let notebooks = NotebookStore::new(dir.path(), "default").unwrap();
+        let mut note = Note::new("Project Plan".to_string(), "Ship the **first** milestone.".to_string());
+        note.add_tag("work/planning".to_string());
+        notebooks.open_or_create("default").unwrap().save_note(&note).unwrap();
+        (dir, notebooks, note)
+    }
+
+    fn message(id: Option<i64>, method: &str, params: Value) -> Message {
+        let mut value = json!({ "jsonrpc": "2.0", "method": method, "params": params });
+        if let Some(id) = id {
+            value["id"] = json!(id);
+        }
+        Message::parse(value).unwrap()
+    }
+
+    fn open(server: &mut LanguageServer, text: &str) -> Value {
+        let params = json!({ "textDocument": { "uri": URI, "languageId": "markdown", "version": 1, "text": text } });
+        server.handle(message(None, "textDocument/didOpen", params)).remove(0)
+    }
+
+    fn request(server: &mut LanguageServer, method: &str, line: u32, character: u32) -> Value {
+        let params = json!({ "textDocument": { "uri": URI }, "position": { "line": line, "character": character } });
+        server.handle(message(Some(1), method, params)).remove(0)["result"].clone()
+    }
+
+    #[test]
+    fn test_completes_tags_and_links() {
+        let (_dir, notebooks, _) = setup();
+        let mut server = LanguageServer::new(notebooks);
+        open(&mut server, "# Draft\nsee #wo\nand [[Pro");
+
+        let tags = request(&mut server, "textDocument/completion", 1, 7);
+        assert_eq!(tags[0]["label"], "work");
+        assert_eq!(tags[1]["textEdit"]["newText"], "work/planning");
+        assert_eq!(tags[0]["textEdit"]["range"]["start"], json!({ "line": 1, "character": 5 }));
+
+        let links = request(&mut server, "textDocument/completion", 2, 9);
+        assert_eq!(links[0]["textEdit"]["newText"], "Project Plan]]");
+        assert_eq!(request(&mut server, "textDocument/completion", 0, 3), json!([]));
+    }
+
+    #[test]
+    fn test_links_resolve_to_notes() {
+        let (dir, notebooks, note) = setup();
+        let mut server = LanguageServer::new(notebooks);
+        open(&mut server, "Read [[project plan]] first, héllo [[Project Plan]]");
+
+        let definition = request(&mut server, "textDocument/definition", 0, 10);
+        let path = dir.path().join("default").join(format!("{}.json", note.id));
+        assert_eq!(definition["uri"], file_uri(&path));
+
+        // Columns count UTF-16 units, so the second link starts after "héllo "
+        let hover = request(&mut server, "textDocument/hover", 0, 36);
+        assert_eq!(hover["contents"]["value"], "**Project Plan** · #work/planning\n\nShip the **first** milestone.");
+        assert_eq!(hover["range"]["start"]["character"], 35);
+        assert_eq!(request(&mut server, "textDocument/hover", 0, 2), Value::Null);
+
+        let symbols = server.handle(message(Some(2), "workspace/symbol", json!({ "query": "plan" }))).remove(0);
+        assert_eq!(symbols["result"][0]["name"], "Project Plan");
+        assert_eq!(symbols["result"][0]["containerName"], "default");
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let (_dir, notebooks, _) = setup();
+        let mut server = LanguageServer::new(notebooks);
+        let long_title = "x".repeat(120);
+        let text = format!("# {}\nTags: #ok #bad//tag #{}. `#code` [[Missing]] [[Project Plan]]\n```\n#a//b\n```", long_title, "t".repeat(60));
+        let published = open(&mut server, &text);
+        assert_eq!(published["method"], "textDocument/publishDiagnostics");
+        let diagnostics = published["params"]["diagnostics"].as_array().unwrap();
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d["message"].as_str().unwrap()).collect();
+        assert_eq!(messages, vec![
+            "Title cannot exceed 100 characters",
+            "Tag path segments cannot be empty",
+            "Tag cannot exceed 50 characters",
+            "No note is titled 'Missing'",
+        ]);
+        assert_eq!(diagnostics[1]["range"], json!({ "start": { "line": 1, "character": 10 }, "end": { "line": 1, "character": 19 } }));
+
+        let front_matter = "---\nid: 7a1c9c4e-0d7c-4c57-8f53-0c6b1d2f9e10\ntitle: Fine\ntags:\n- good\n- bad tag\ncreated_at: 2024-01-01T00:00:00Z\nupdated_at: 2024-01-01T00:00:00Z\n---\nBody #fine\n";
+        let diagnostics = open(&mut server, front_matter)["params"]["diagnostics"].clone();
+        assert_eq!(diagnostics.as_array().unwrap().len(), 1);
+        assert_eq!((diagnostics[0]["message"].as_str(), diagnostics[0]["range"]["start"]["line"].as_u64()), (Some("Tags cannot contain spaces"), Some(5)));
+    }
+
+    #[test]
+    fn test_serves_framed_messages_until_exit() {
+        let (_dir, notebooks, _) = setup();
+        let mut input = Vec::new();
+        for value in [
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }),
+            json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} }),
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "textDocument/formatting", "params": {} }),
+            json!({ "jsonrpc": "2.0", "id": 3, "method": "shutdown" }),
+            json!({ "jsonrpc": "2.0", "method": "exit" }),
+            json!({ "jsonrpc": "2.0", "id": 4, "method": "shutdown" }),
+        ] {
+            jsonrpc::write_framed(&mut input, &value).unwrap();
+        }
+        let mut output = Vec::new();
+        lsp::run(notebooks, Cursor::new(input), &mut output).unwrap();
+
+        let mut reader = Cursor::new(output);
+        let mut replies = Vec::new();
+        while let Some(body) = jsonrpc::read_framed(&mut reader).unwrap() {
+            replies.push(serde_json::from_slice::<Value>(&body).unwrap());
+        }
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[0]["result"]["capabilities"]["hoverProvider"], true);
+        assert_eq!(replies[1]["error"]["code"], jsonrpc::METHOD_NOT_FOUND);
+        assert_eq!(replies[2], json!({ "jsonrpc": "2.0", "id": 3, "result": null }));
+    }
+}
+
+#[cfg(test)]
+mod rpc_tests {
+    use note_taking_app::error::NoteError;
+    use note_taking_app::jsonrpc::{self, RpcError};
+    use note_taking_app::rpc::{self, IdParams, SearchParams};
+    use note_taking_app::note::Note;
+    use note_taking_app::notebook::NotebookStore;
+    use note_taking_app::storage::FileStorage;
+    use serde_json::{json, Value};
+    use std::io::Cursor;
+    use tempfile::TempDir;
+
+    fn setup() -> (TempDir, FileStorage) {
+        let dir = TempDir::new().unwrap();
+        let storage = NotebookStore::new(dir.path(), "default").unwrap().open_or_create("default").unwrap();
+        (dir, storage)
+    }
+
+    fn dispatch(storage: &FileStorage, method: &str, params: Value) -> Result<Value, RpcError> {
+        match method {
+            "view_note" => {
+                let p: IdParams = rpc::params(params)?;
+                Ok(serde_json::to_value(storage.load_note(&p.id)?).unwrap())
+            }
+            "search" => {
+                let query = rpc::params::<SearchParams>(params)?.to_query();
+                let titles: Vec<String> = storage.list_notes()?.into_iter()
+                    .filter(|note| query.matches(note))
+                    .map(|note| note.title)
+                    .collect();
+                Ok(json!(titles))
+            }
+            "ping" => Ok(json!("pong")),
+            _ => Err(RpcError::method_not_found(method)),
+        }
+    }
+
+    fn run(storage: &FileStorage, input: &str) -> Vec<Value> {
+        let mut output = Vec::new();
+        rpc::serve(Cursor::new(input), &mut output, |method, params| dispatch(storage, method, params)).unwrap();
+        String::from_utf8(output).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_answers_one_line_per_request() {
+        let (_dir, storage) = setup();
+        let note = Note::new("Groceries".to_string(), "milk".to_string());
+        storage.save_note(&note).unwrap();
+
+        let input = format!(
+            "{}\n\n{}\n{}\n",
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "view_note", "params": { "id": note.id } }),
+            json!({ "jsonrpc": "2.0", "method": "ping" }),
+            json!({ "jsonrpc": "2.0", "id": "s", "method": "search", "params": { "query": "MILK" } }),
+        );
+        let replies = run(&storage, &input);
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0]["id"], 1);
+        assert_eq!(replies[0]["result"]["title"], "Groceries");
+        assert_eq!(replies[1], json!({ "jsonrpc": "2.0", "id": "s", "result": ["Groceries"] }));
+    }
+
+    #[test]
+    fn test_batches() {
+        let (_dir, storage) = setup();
+        let batch = json!([
+            { "jsonrpc": "2.0", "id": 1, "method": "ping" },
+            { "jsonrpc": "2.0", "method": "ping" },
+            { "jsonrpc": "2.0", "id": 2, "method": "nope" },
+            42,
+        ]);
+        let replies = run(&storage, &format!("{}\n", batch));
+        assert_eq!(replies.len(), 1);
+        let replies = replies[0].as_array().unwrap();
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[0]["result"], "pong");
+        assert_eq!(replies[1]["error"]["code"], jsonrpc::METHOD_NOT_FOUND);
+        assert_eq!(replies[2]["error"]["code"], jsonrpc::INVALID_REQUEST);
+        assert_eq!(replies[2]["id"], Value::Null);
+
+        // Nothing is sent back for a batch of notifications
+        let notifications = json!([{ "jsonrpc": "2.0", "method": "ping" }, { "jsonrpc": "2.0", "method": "nope" }]);
+        assert!(run(&storage, &format!("{}\n", notifications)).is_empty());
+
+        let empty = run(&storage, "[]\n");
+        assert_eq!(empty[0]["error"]["code"], jsonrpc::INVALID_REQUEST);
+    }
+
+    #[test]
+    fn test_malformed_input() {
+        let (_dir, storage) = setup();
+        let replies = run(&storage, "{\"jsonrpc\": \"2.0\", \"id\": 1\n{\"id\": 7, \"method\": \"ping\"}\n");
+        assert_eq!(replies[0]["error"]["code"], jsonrpc::PARSE_ERROR);
+        assert_eq!(replies[0]["id"], Value::Null);
+        assert_eq!(replies[1]["error"]["code"], jsonrpc::INVALID_REQUEST);
+        assert_eq!(replies[1]["id"], 7);
+
+        let missing = json!({ "jsonrpc": "2.0", "id": 3, "method": "view_note" });
+        let replies = run(&storage, &format!("{}\n", missing));
+        assert_eq!(replies[0]["error"]["code"], jsonrpc::INVALID_PARAMS);
+        assert!(replies[0]["error"]["message"].as_str().unwrap().contains("missing field `id`"));
+    }
+
+    #[test]
+    fn test_errors_carry_the_note_error_kind() {
+        let (_dir, storage) = setup();
+        let missing = Note::new("Gone".to_string(), String::new());
+        let request = json!({ "jsonrpc": "2.0", "id": 1, "method": "view_note", "params": { "id": missing.id } });
+        let replies = run(&storage, &format!("{}\n", request));
+        let error = &replies[0]["error"];
+        assert_eq!(error["code"], jsonrpc::NOT_FOUND);
+        assert_eq!(error["data"]["kind"], "NotFound");
+
+        let error = RpcError::from(NoteError::Conflict("changed elsewhere".to_string()));
+        assert_eq!(error.code, jsonrpc::CONFLICT);
+        assert_eq!(error.message, "Conflict: changed elsewhere");
+        assert_eq!(error.data, Some(json!({ "kind": "Conflict" })));
+        assert_eq!(RpcError::from(NoteError::WrongPassphrase).code, jsonrpc::WRONG_PASSPHRASE);
+    }
+}